@@ -0,0 +1,178 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Benchmarks of the write, read and recovery paths. The size of the directory
+//! recovered can be set by `RAFT_ENGINE_BENCH_RECOVERY_MB`, 256 by default.
+
+use std::env;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use raft::eraftpb::Entry;
+use raft_engine::util::ReadableSize;
+use raft_engine::{Config, FileEngine, RaftEngine};
+use tempfile::TempDir;
+
+const ENTRY_SIZES: [usize; 2] = [256, 4096];
+const THREADS: [usize; 3] = [1, 4, 16];
+const FETCH_ENTRIES: u64 = 64;
+const REGIONS: u64 = 64;
+
+// Ways entries are cached when they're written: (name, cache size limit, cached
+// as encoded).
+const CACHE_MODES: [(&str, u64, bool); 3] = [
+    ("single_thread", 1 << 30, false),
+    ("encoded_cache", 1 << 30, true),
+    ("no_cache", 0, false),
+];
+
+fn new_engine(prefix: &str, cache_size_limit: u64) -> (TempDir, Config, FileEngine) {
+    new_engine_with(prefix, cache_size_limit, false)
+}
+
+fn new_engine_with(
+    prefix: &str,
+    cache_size_limit: u64,
+    cache_encoded_entries: bool,
+) -> (TempDir, Config, FileEngine) {
+    let dir = tempfile::Builder::new().prefix(prefix).tempdir().unwrap();
+    let mut cfg = Config::default();
+    cfg.dir = dir.path().to_str().unwrap().to_owned();
+    cfg.cache_size_limit = ReadableSize(cache_size_limit);
+    cfg.cache_encoded_entries = cache_encoded_entries;
+    let engine = FileEngine::new(cfg.clone());
+    (dir, cfg, engine)
+}
+
+fn entry(index: u64, size: usize) -> Entry {
+    let mut e = Entry::new();
+    e.set_index(index);
+    e.set_term(1);
+    e.set_data(vec![b'x'; size]);
+    e
+}
+
+fn bench_append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("append");
+    for &(name, cache_size_limit, encoded) in &CACHE_MODES {
+        for &size in &ENTRY_SIZES {
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, &size| {
+                let (_dir, _, engine) = new_engine_with("bench_append", cache_size_limit, encoded);
+                let mut n = 0;
+                b.iter(|| {
+                    // Spread entries over regions, each without gaps.
+                    engine
+                        .append(n % REGIONS + 1, vec![entry(n / REGIONS + 1, size)])
+                        .unwrap();
+                    n += 1;
+                });
+            });
+        }
+    }
+    for &threads in &THREADS {
+        let size = ENTRY_SIZES[0];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("concurrent", threads),
+            &threads,
+            |b, &threads| {
+                let (_dir, _, engine) = new_engine("bench_append", ReadableSize::gb(1).0);
+                let engine = Arc::new(engine);
+                let mut next_index = 1;
+                b.iter_custom(|iters| {
+                    let per_thread = iters / threads as u64 + 1;
+                    let begin = next_index;
+                    next_index += per_thread;
+                    let start = Instant::now();
+                    let handles: Vec<_> = (0..threads as u64)
+                        .map(|region_id| {
+                            let engine = engine.clone();
+                            thread::spawn(move || {
+                                for index in begin..begin + per_thread {
+                                    engine
+                                        .append(region_id + 1, vec![entry(index, size)])
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for h in handles {
+                        h.join().unwrap();
+                    }
+                    start.elapsed()
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_cold_fetch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cold_fetch");
+    for &size in &ENTRY_SIZES {
+        // Entries are read from files with the cache disabled.
+        let (_dir, _, engine) = new_engine("bench_cold_fetch", 0);
+        let count = ReadableSize::mb(64).0 / size as u64;
+        for index in 1..=count {
+            engine.append(1, vec![entry(index, size)]).unwrap();
+        }
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("get_entry", size), &size, |b, _| {
+            let mut index = 0;
+            b.iter(|| {
+                index = index % count + 1;
+                engine.get_entry(1, index).unwrap().unwrap();
+            });
+        });
+
+        group.throughput(Throughput::Bytes(size as u64 * FETCH_ENTRIES));
+        group.bench_with_input(BenchmarkId::new("fetch_entries", size), &size, |b, _| {
+            let mut begin = 1;
+            let mut entries = Vec::with_capacity(FETCH_ENTRIES as usize);
+            b.iter(|| {
+                if begin + FETCH_ENTRIES > count + 1 {
+                    begin = 1;
+                }
+                entries.clear();
+                engine
+                    .fetch_entries_to(1, begin, begin + FETCH_ENTRIES, None, &mut entries)
+                    .unwrap();
+                begin += FETCH_ENTRIES;
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_recovery(c: &mut Criterion) {
+    let size_mb = env::var("RAFT_ENGINE_BENCH_RECOVERY_MB")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(256);
+    let entry_size = ENTRY_SIZES[1];
+    let (_dir, cfg, engine) = new_engine("bench_recovery", ReadableSize::gb(1).0);
+    let count = ReadableSize::mb(size_mb).0 / entry_size as u64;
+    for n in 0..count {
+        engine
+            .append(n % REGIONS + 1, vec![entry(n / REGIONS + 1, entry_size)])
+            .unwrap();
+    }
+    engine.sync().unwrap();
+    drop(engine);
+
+    let mut group = c.benchmark_group("recovery");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(30))
+        .throughput(Throughput::Bytes(ReadableSize::mb(size_mb).0));
+    group.bench_function(BenchmarkId::new("open", format!("{}MB", size_mb)), |b| {
+        b.iter(|| FileEngine::open(cfg.clone()).unwrap());
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_append, bench_cold_fetch, bench_recovery);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raft_engine::log_batch::{self, LogBatch, CHECKSUM_LEN, HEADER_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = LogBatch::from_bytes(&mut &data[..], 1, 0);
+
+    // Seal the rest as a batch with a valid checksum, so that items are decoded.
+    if let Some((&compression_type, payload)) = data.split_first() {
+        let mut batch = vec![0; HEADER_LEN];
+        batch.extend_from_slice(payload);
+        batch.extend_from_slice(&[0; CHECKSUM_LEN]);
+        let header = ((batch.len() as u64 - 8) << 8) | u64::from(compression_type % 2);
+        batch[..8].copy_from_slice(&header.to_be_bytes());
+        log_batch::set_sequence(&mut batch, 1);
+        let _ = LogBatch::from_bytes(&mut batch.as_slice(), 1, 0);
+    }
+});
@@ -0,0 +1,133 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    stopped: bool,
+}
+
+/// Items queued by writers to be applied in order by one consumer, with a
+/// watermark of applied items. Readers call `wait` to observe all items queued
+/// before, e.g. writes returned to their callers.
+pub(crate) struct ApplyQueue<T> {
+    state: Mutex<QueueState<T>>,
+    // Notified when items are queued or the queue is stopped.
+    queued: Condvar,
+    // Counts of items queued and applied, only increased with `state` locked.
+    pushed: AtomicU64,
+    applied: AtomicU64,
+    // Notified when items are applied.
+    done: Condvar,
+}
+
+impl<T> ApplyQueue<T> {
+    pub fn new() -> ApplyQueue<T> {
+        ApplyQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                stopped: false,
+            }),
+            queued: Condvar::new(),
+            pushed: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            done: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.items.push_back(item);
+        self.pushed.fetch_add(1, Ordering::AcqRel);
+        self.queued.notify_one();
+    }
+
+    /// Block until an item is queued, and take it. `finish` must be called once
+    /// it's applied. Return `None` once stopped, dropping items left.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                state.items.clear();
+                return None;
+            }
+            if let Some(item) = state.items.pop_front() {
+                return Some(item);
+            }
+            state = self.queued.wait(state).unwrap();
+        }
+    }
+
+    pub fn finish(&self) {
+        let _state = self.state.lock().unwrap();
+        self.applied.fetch_add(1, Ordering::AcqRel);
+        self.done.notify_all();
+    }
+
+    /// Wait until items queued before are applied.
+    pub fn wait(&self) {
+        let target = self.pushed.load(Ordering::Acquire);
+        if self.applied.load(Ordering::Acquire) >= target {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        while self.applied.load(Ordering::Acquire) < target && !state.stopped {
+            state = self.done.wait(state).unwrap();
+        }
+    }
+
+    /// Items queued but not applied yet.
+    #[cfg(test)]
+    pub fn pending(&self) -> u64 {
+        self.pushed.load(Ordering::Acquire) - self.applied.load(Ordering::Acquire)
+    }
+
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        self.queued.notify_all();
+        self.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_apply_queue() {
+        let queue = Arc::new(ApplyQueue::new());
+        let applied = Arc::new(Mutex::new(vec![]));
+        let (q, a) = (queue.clone(), applied.clone());
+        let consumer = thread::spawn(move || {
+            while let Some(item) = q.pop() {
+                a.lock().unwrap().push(item);
+                q.finish();
+            }
+        });
+
+        for i in 0..100 {
+            queue.push(i);
+            if i % 10 == 0 {
+                // Items queued are applied in order once waited.
+                queue.wait();
+                assert_eq!(*applied.lock().unwrap(), (0..=i).collect::<Vec<_>>());
+            }
+        }
+        queue.wait();
+        assert_eq!(queue.pending(), 0);
+        assert_eq!(applied.lock().unwrap().len(), 100);
+
+        queue.stop();
+        consumer.join().unwrap();
+        // Waits don't block once stopped.
+        queue.push(100);
+        queue.wait();
+        assert_eq!(queue.pending(), 1);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+// Chunks grow from the min size to the max one, so that regions with few small
+// pieces don't hold much spare space.
+const MIN_CHUNK_SIZE: usize = 512;
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A piece of bytes allocated in an `Arena`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArenaSlice {
+    chunk: u64,
+    offset: usize,
+    len: usize,
+}
+
+impl ArenaSlice {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// A bump allocator of byte pieces in chunks, for data kept in memtables. Pieces
+/// are never freed one by one, but chunks are freed wholesale: ones only holding
+/// pieces allocated before a piece by `free_before`, and pieces allocated since a
+/// piece by `truncate`. Many small pieces take few allocations this way, and
+/// they are freed together as entries are compacted in order.
+#[derive(Clone, Default)]
+pub struct Arena {
+    chunks: VecDeque<Vec<u8>>,
+    // Id of the first chunk, increased as chunks are freed.
+    first_chunk: u64,
+    next_chunk_size: usize,
+    // Bytes allocated for chunks.
+    capacity: usize,
+}
+
+impl Arena {
+    pub fn alloc(&mut self, data: &[u8]) -> ArenaSlice {
+        let fits = match self.chunks.back() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= data.len(),
+            None => false,
+        };
+        if !fits && !data.is_empty() {
+            let size = cmp::max(self.next_chunk_size, MIN_CHUNK_SIZE);
+            self.next_chunk_size = cmp::min(size * 2, MAX_CHUNK_SIZE);
+            // Larger pieces take chunks of their own.
+            let chunk = Vec::with_capacity(cmp::max(size, data.len()));
+            self.capacity += chunk.capacity();
+            self.chunks.push_back(chunk);
+        }
+        let chunk = self.first_chunk + self.chunks.len().saturating_sub(1) as u64;
+        let offset = match self.chunks.back_mut() {
+            Some(last) => {
+                let offset = last.len();
+                last.extend_from_slice(data);
+                offset
+            }
+            None => 0,
+        };
+        ArenaSlice {
+            chunk,
+            offset,
+            len: data.len(),
+        }
+    }
+
+    pub fn get(&self, slice: ArenaSlice) -> &[u8] {
+        if slice.len == 0 {
+            return &[];
+        }
+        let chunk = &self.chunks[(slice.chunk - self.first_chunk) as usize];
+        &chunk[slice.offset..slice.offset + slice.len]
+    }
+
+    /// Overwrite the piece with `data` of no more bytes, returning the shrunk piece.
+    pub fn overwrite(&mut self, slice: ArenaSlice, data: &[u8]) -> ArenaSlice {
+        assert!(data.len() <= slice.len);
+        if !data.is_empty() {
+            let chunk = &mut self.chunks[(slice.chunk - self.first_chunk) as usize];
+            chunk[slice.offset..slice.offset + data.len()].copy_from_slice(data);
+        }
+        ArenaSlice {
+            len: data.len(),
+            ..slice
+        }
+    }
+
+    /// Free chunks holding only pieces allocated before `slice`.
+    pub fn free_before(&mut self, slice: ArenaSlice) {
+        while self.first_chunk < slice.chunk {
+            match self.chunks.pop_front() {
+                Some(chunk) => self.capacity -= chunk.capacity(),
+                None => break,
+            }
+            self.first_chunk += 1;
+        }
+    }
+
+    /// Free `slice` and all pieces allocated after it, so that their space is
+    /// allocated again.
+    pub fn truncate(&mut self, slice: ArenaSlice) {
+        let pos = slice.chunk.saturating_sub(self.first_chunk) as usize;
+        if pos < self.chunks.len() {
+            for chunk in self.chunks.drain(pos + 1..) {
+                self.capacity -= chunk.capacity();
+            }
+            self.chunks[pos].truncate(slice.offset);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.first_chunk += self.chunks.len() as u64;
+        self.chunks.clear();
+        self.next_chunk_size = MIN_CHUNK_SIZE;
+        self.capacity = 0;
+    }
+
+    /// Bytes allocated for chunks.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena() {
+        let mut arena = Arena::default();
+        let empty = arena.alloc(b"");
+        assert_eq!(arena.get(empty), b"");
+        assert_eq!(arena.capacity(), 0);
+
+        let pieces: Vec<_> = (0..100u8).map(|i| arena.alloc(&[i; 100])).collect();
+        for (i, piece) in pieces.iter().enumerate() {
+            assert_eq!(arena.get(*piece), &[i as u8; 100][..]);
+        }
+        // Chunks grow up to the max size.
+        assert!(arena.chunks.len() < 10);
+        assert!(arena.capacity() < 2 * 100 * 100);
+        let large = arena.alloc(&[7; 3 * MAX_CHUNK_SIZE]);
+        assert_eq!(arena.get(large), &[7; 3 * MAX_CHUNK_SIZE][..]);
+
+        // Reuse space of freed pieces at the back.
+        let chunks = arena.chunks.len();
+        arena.truncate(large);
+        arena.truncate(pieces[90]);
+        assert_eq!(arena.get(pieces[89]), &[89; 100][..]);
+        let again = arena.alloc(&[1; 100]);
+        assert_eq!(again, pieces[90]);
+        assert!(arena.chunks.len() < chunks);
+        let allocated: usize = arena.chunks.iter().map(|c| c.capacity()).sum();
+        assert_eq!(arena.capacity(), allocated);
+
+        // Free chunks at the front.
+        let capacity = arena.capacity();
+        arena.free_before(pieces[80]);
+        assert!(arena.capacity() < capacity);
+        let allocated: usize = arena.chunks.iter().map(|c| c.capacity()).sum();
+        assert_eq!(arena.capacity(), allocated);
+        assert_eq!(arena.get(pieces[80]), &[80; 100][..]);
+        assert_eq!(arena.get(again), &[1; 100][..]);
+
+        let shrunk = arena.overwrite(again, &[2; 10]);
+        assert_eq!(arena.get(shrunk), &[2; 10][..]);
+
+        arena.clear();
+        assert_eq!(arena.capacity(), 0);
+        let piece = arena.alloc(b"piece");
+        assert_eq!(arena.get(piece), b"piece");
+        // An empty piece after others frees chunks before the last one.
+        let empty = arena.alloc(b"");
+        arena.free_before(empty);
+        assert_eq!(arena.get(piece), b"piece");
+    }
+}
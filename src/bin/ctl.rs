@@ -0,0 +1,229 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Offline tools for raft log directories.
+
+use std::env;
+use std::process;
+
+use raft_engine::check::{check_dir, inspect_file};
+use raft_engine::compare::compare_dirs;
+use raft_engine::dir_lock::force_unlock;
+use raft_engine::migrate::migrate_dir;
+use raft_engine::{Config, FileEngine};
+
+const USAGE: &str = "Usage:
+    raft-engine-ctl check <dir>
+        Validate headers, batches and checksums of all files in the directory.
+    raft-engine-ctl compare <left-dir> <right-dir>
+        Compare entries and key value pairs of all regions in two directories.
+    raft-engine-ctl dump <dir> --region <region-id>
+        Print all items of the region persisted in the directory in log order.
+    raft-engine-ctl migrate <from-dir> <to-dir>
+        Rewrite files of an older format version into an empty directory.
+    raft-engine-ctl print-file <dir> --file <file-num> --offset <offset>
+        Print the batch at the offset of the file, or the bytes around it in hex
+        if it can't be decoded, e.g. at a corruption reported by check.
+    raft-engine-ctl truncate <dir> --region <region-id> --index <index>
+        Drop entries of the region after the index, for unsafe recovery when the
+        quorum is lost. The raft state is adjusted accordingly.
+    raft-engine-ctl unlock <dir>
+        Remove the lock of the directory left by a process that has exited.";
+
+fn check(args: &[String]) -> i32 {
+    if args.len() != 1 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    match check_dir(&args[0]) {
+        Ok(problems) => {
+            for p in &problems {
+                println!("{}", p);
+            }
+            if problems.is_empty() {
+                println!("no problem found");
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("check failed: {}", e);
+            2
+        }
+    }
+}
+
+fn compare(args: &[String]) -> i32 {
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    match compare_dirs(&args[0], &args[1]) {
+        Ok(divergences) => {
+            for d in &divergences {
+                println!("{}", d);
+            }
+            if divergences.is_empty() {
+                println!("no divergence found");
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("compare failed: {}", e);
+            2
+        }
+    }
+}
+
+fn dump(args: &[String]) -> i32 {
+    let region_id = match args {
+        [_, flag, id] if flag == "--region" => id.parse::<u64>(),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let region_id = match region_id {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("invalid region id: {}", e);
+            return 2;
+        }
+    };
+    let cfg = Config {
+        dir: args[0].clone(),
+        ..Default::default()
+    };
+    match FileEngine::open_observer(cfg).and_then(|engine| engine.dump_region(region_id)) {
+        Ok(items) => {
+            for item in &items {
+                println!("{}", item);
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("dump failed: {}", e);
+            1
+        }
+    }
+}
+
+fn migrate(args: &[String]) -> i32 {
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    match migrate_dir(&args[0], &args[1]) {
+        Ok(count) => {
+            println!("migrated {} files", count);
+            0
+        }
+        Err(e) => {
+            eprintln!("migrate failed: {}", e);
+            1
+        }
+    }
+}
+
+fn print_file(args: &[String]) -> i32 {
+    let (file_num, offset) = match args {
+        [_, f1, num, f2, offset] if f1 == "--file" && f2 == "--offset" => {
+            (num.parse::<u64>(), offset.parse::<u64>())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let (file_num, offset) = match (file_num, offset) {
+        (Ok(num), Ok(offset)) => (num, offset),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("invalid argument: {}", e);
+            return 2;
+        }
+    };
+    match inspect_file(&args[0], file_num, offset) {
+        Ok(inspection) => {
+            println!("{}", inspection);
+            0
+        }
+        Err(e) => {
+            eprintln!("print-file failed: {}", e);
+            1
+        }
+    }
+}
+
+fn truncate(args: &[String]) -> i32 {
+    let (region_id, index) = match args {
+        [_, f1, id, f2, index] if f1 == "--region" && f2 == "--index" => {
+            (id.parse::<u64>(), index.parse::<u64>())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let (region_id, index) = match (region_id, index) {
+        (Ok(id), Ok(index)) => (id, index),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("invalid argument: {}", e);
+            return 2;
+        }
+    };
+    let cfg = Config {
+        dir: args[0].clone(),
+        ..Default::default()
+    };
+    match FileEngine::open(cfg).and_then(|engine| engine.unsafe_truncate_region(region_id, index)) {
+        Ok(count) => {
+            println!("dropped {} entries", count);
+            0
+        }
+        Err(e) => {
+            eprintln!("truncate failed: {}", e);
+            1
+        }
+    }
+}
+
+fn unlock(args: &[String]) -> i32 {
+    if args.len() != 1 {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+    match force_unlock(&args[0]) {
+        Ok(Some(owner)) => {
+            println!("removed lock held by {}", owner);
+            0
+        }
+        Ok(None) => {
+            println!("not locked");
+            0
+        }
+        Err(e) => {
+            eprintln!("unlock failed: {}", e);
+            1
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match args.first().map(String::as_str) {
+        Some("check") => check(&args[1..]),
+        Some("compare") => compare(&args[1..]),
+        Some("dump") => dump(&args[1..]),
+        Some("migrate") => migrate(&args[1..]),
+        Some("print-file") => print_file(&args[1..]),
+        Some("truncate") => truncate(&args[1..]),
+        Some("unlock") => unlock(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    };
+    process::exit(code);
+}
@@ -0,0 +1,377 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::dictionary::Dictionaries;
+use crate::engine::verify_file;
+use crate::format::{self, CompressionType, Timestamp};
+use crate::log_batch::{ItemSummary, LogBatch};
+use crate::pipe_log;
+use crate::{Error, Result};
+
+// Bytes dumped before and after an offset that can't be decoded.
+const DUMP_WINDOW: u64 = 64;
+const DUMP_LINE_LEN: usize = 16;
+
+/// A problem of a raft log directory found by `check_dir`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// A file between the first and the last one doesn't exist.
+    MissingFile { file_num: u64 },
+    /// The file can't be read or decoded. `offset` is the position of the first
+    /// broken batch or header, or `None` if the file can't be read at all.
+    BadFile {
+        file_num: u64,
+        offset: Option<u64>,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MissingFile { file_num } => write!(f, "file {} is missing", file_num),
+            Problem::BadFile {
+                file_num,
+                offset: Some(offset),
+                reason,
+            } => write!(
+                f,
+                "file {} is corrupted at offset {}: {}",
+                file_num, offset, reason
+            ),
+            Problem::BadFile {
+                file_num,
+                offset: None,
+                reason,
+            } => write!(f, "file {} is corrupted: {}", file_num, reason),
+        }
+    }
+}
+
+/// Check all raft log files in `dir` without opening an engine: the file numbers
+/// must be continuous, and headers, batch layouts and checksums of each file must
+/// be valid. A torn tail of the last file is reported too, though recovery may
+/// tolerate it.
+pub fn check_dir(dir: &str) -> Result<Vec<Problem>> {
+    let files = pipe_log::list_log_files(Path::new(dir))?;
+    let dicts = Dictionaries::default();
+    dicts.load_dir(Path::new(dir))?;
+    let mut problems = vec![];
+    for (i, (file_num, path)) in files.iter().enumerate() {
+        if i > 0 {
+            for missing in files[i - 1].0 + 1..*file_num {
+                problems.push(Problem::MissingFile { file_num: missing });
+            }
+        }
+        let res = fs::read(path)
+            .map_err(Error::from)
+            .and_then(|content| verify_file(*file_num, &content, &dicts));
+        match res {
+            Ok(()) => {}
+            Err(Error::Corruption(file_num, offset, reason)) => problems.push(Problem::BadFile {
+                file_num,
+                offset: Some(offset),
+                reason,
+            }),
+            Err(e) => problems.push(Problem::BadFile {
+                file_num: *file_num,
+                offset: None,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    Ok(problems)
+}
+
+/// What's at an offset of a log file, see `inspect_file`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inspection {
+    FileHeader {
+        version: String,
+    },
+    /// A batch of `len` bytes after its header.
+    Batch {
+        len: u64,
+        compression_type: CompressionType,
+        sequence: u64,
+        timestamp: Timestamp,
+        items: Vec<ItemSummary>,
+    },
+    /// A hole of `len` bytes including its header.
+    Hole {
+        len: u64,
+    },
+    TailBarrier,
+    /// The end of the file, or space allocated but never written.
+    Unwritten,
+    /// Bytes that can't be decoded. `window` is the content around the offset,
+    /// starting at `window_start`.
+    Undecodable {
+        reason: String,
+        window_start: u64,
+        window: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inspection::FileHeader { version } => write!(f, "file header, version {}", version),
+            Inspection::Batch {
+                len,
+                compression_type,
+                sequence,
+                timestamp,
+                items,
+            } => {
+                write!(
+                    f,
+                    "batch of {} bytes, compression {:?}, sequence {}, timestamp {}",
+                    len, compression_type, sequence, timestamp.0
+                )?;
+                for item in items {
+                    match item {
+                        ItemSummary::Entries {
+                            region_id,
+                            first_index,
+                            last_index,
+                        } => write!(
+                            f,
+                            "\n  region {} entries [{}, {}]",
+                            region_id, first_index, last_index
+                        )?,
+                        ItemSummary::Clean { region_id } => {
+                            write!(f, "\n  region {} clean", region_id)?
+                        }
+                        ItemSummary::Put {
+                            region_id,
+                            key,
+                            value,
+                        } => write!(
+                            f,
+                            "\n  region {} put {:?} ({} bytes)",
+                            region_id,
+                            key,
+                            value.len()
+                        )?,
+                        ItemSummary::Delete { region_id, key } => {
+                            write!(f, "\n  region {} delete {:?}", region_id, key)?
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Inspection::Hole { len } => write!(f, "hole of {} bytes", len),
+            Inspection::TailBarrier => write!(f, "tail barrier"),
+            Inspection::Unwritten => write!(f, "unwritten"),
+            Inspection::Undecodable {
+                reason,
+                window_start,
+                window,
+            } => {
+                write!(f, "undecodable: {}", reason)?;
+                // Like `hexdump -C`.
+                for (i, line) in window.chunks(DUMP_LINE_LEN).enumerate() {
+                    let offset = window_start + (i * DUMP_LINE_LEN) as u64;
+                    write!(f, "\n{:08x} ", offset)?;
+                    for j in 0..DUMP_LINE_LEN {
+                        match line.get(j) {
+                            Some(b) => write!(f, " {:02x}", b)?,
+                            None => write!(f, "   ")?,
+                        }
+                    }
+                    let text: String = line
+                        .iter()
+                        .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                        .collect();
+                    write!(f, "  |{}|", text)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Decode what's at `offset` of a raft log file in `dir`, or take the bytes
+/// around it if it can't be decoded, so that reports of corruptions can include
+/// the content on disk.
+pub fn inspect_file(dir: &str, file_num: u64, offset: u64) -> Result<Inspection> {
+    let dicts = Dictionaries::default();
+    dicts.load_dir(Path::new(dir))?;
+    let content = fs::read(pipe_log::log_file_path(dir, file_num))?;
+    if offset > content.len() as u64 {
+        return Err(box_err!(
+            "Offset {} is beyond the end of file {} of {} bytes",
+            offset,
+            file_num,
+            content.len()
+        ));
+    }
+    let res = if offset == 0 {
+        let magic_len = pipe_log::FILE_MAGIC_HEADER.len();
+        pipe_log::check_file_header(file_num, &content).map(|_| Inspection::FileHeader {
+            version: String::from_utf8_lossy(
+                &content[magic_len..magic_len + pipe_log::VERSION.len()],
+            )
+            .into_owned(),
+        })
+    } else {
+        inspect_batch(file_num, &content[offset as usize..], offset, &dicts)
+    };
+    res.or_else(|e| {
+        let window_start = offset.saturating_sub(DUMP_WINDOW);
+        let window_end = cmp::min(offset + DUMP_WINDOW, content.len() as u64);
+        Ok(Inspection::Undecodable {
+            reason: e.to_string(),
+            window_start,
+            window: content[window_start as usize..window_end as usize].to_vec(),
+        })
+    })
+}
+
+fn inspect_batch(
+    file_num: u64,
+    buf: &[u8],
+    offset: u64,
+    dicts: &Dictionaries,
+) -> Result<Inspection> {
+    if let Some(len) = format::decode_hole_header(buf) {
+        return Ok(Inspection::Hole { len });
+    }
+    if format::is_tail_barrier(buf) {
+        return Ok(Inspection::TailBarrier);
+    }
+    if format::is_unwritten(buf) {
+        return Ok(Inspection::Unwritten);
+    }
+    let (len, compression_type) = format::decode_batch_header(buf)?;
+    match LogBatch::from_bytes(&mut &buf[..], file_num, offset, dicts)? {
+        Some(batch) => Ok(Inspection::Batch {
+            len,
+            compression_type,
+            sequence: batch.sequence,
+            timestamp: batch.timestamp,
+            items: batch.summary(file_num).items,
+        }),
+        None => Ok(Inspection::Unwritten),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use raft::eraftpb::Entry;
+
+    use crate::util::ReadableSize;
+    use crate::{Config, FileEngine, RaftEngine};
+
+    #[test]
+    fn test_check_dir() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_check_dir")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        {
+            let mut cfg = Config::default();
+            cfg.dir = path.to_owned();
+            cfg.target_file_size = ReadableSize(128);
+            let engine = FileEngine::new(cfg);
+            let mut entry = Entry::new();
+            for i in 1..=5 {
+                entry.set_index(i);
+                entry.set_data(vec![b'x'; 128]);
+                engine.append(1, vec![entry.clone()]).unwrap();
+            }
+            engine.sync().unwrap();
+        }
+        assert!(check_dir(path).unwrap().is_empty());
+
+        let files = pipe_log::list_log_files(dir.path()).unwrap();
+        assert!(files.len() >= 4);
+        // Flip a byte in the last batch of the second file.
+        let mut file = OpenOptions::new().write(true).open(&files[1].1).unwrap();
+        file.seek(SeekFrom::End(-5)).unwrap();
+        file.write_all(b"?").unwrap();
+        drop(file);
+        let offset = match &check_dir(path).unwrap()[0] {
+            Problem::BadFile {
+                offset: Some(offset),
+                ..
+            } => *offset,
+            p => panic!("unexpected {:?}", p),
+        };
+        match inspect_file(path, files[1].0, offset).unwrap() {
+            Inspection::Undecodable {
+                window_start,
+                window,
+                ..
+            } => {
+                assert!(window_start < offset);
+                assert!(!window.is_empty());
+            }
+            i => panic!("unexpected {}", i),
+        }
+        match inspect_file(path, files[0].0, 0).unwrap() {
+            Inspection::FileHeader { version } => {
+                assert_eq!(version.as_bytes(), pipe_log::VERSION)
+            }
+            i => panic!("unexpected {}", i),
+        }
+        let header_len = pipe_log::FILE_HEADER_LEN as u64;
+        match inspect_file(path, files[0].0, header_len).unwrap() {
+            Inspection::Batch {
+                sequence, items, ..
+            } => {
+                assert_eq!(sequence, 1);
+                assert_eq!(
+                    items,
+                    vec![ItemSummary::Entries {
+                        region_id: 1,
+                        first_index: 1,
+                        last_index: 1,
+                    }]
+                );
+            }
+            i => panic!("unexpected {}", i),
+        }
+        assert!(inspect_file(path, files[0].0, u64::MAX).is_err());
+        fs::remove_file(&files[2].1).unwrap();
+        // Break the header of the last file.
+        let last = files.last().unwrap();
+        fs::write(&last.1, b"bad").unwrap();
+
+        let problems = check_dir(path).unwrap();
+        assert_eq!(problems.len(), 3);
+        match &problems[0] {
+            Problem::BadFile {
+                file_num,
+                offset: Some(_),
+                ..
+            } => assert_eq!(*file_num, files[1].0),
+            p => panic!("unexpected {:?}", p),
+        }
+        assert_eq!(
+            problems[1],
+            Problem::MissingFile {
+                file_num: files[2].0
+            }
+        );
+        match &problems[2] {
+            Problem::BadFile {
+                file_num,
+                offset: Some(0),
+                ..
+            } => assert_eq!(*file_num, last.0),
+            p => panic!("unexpected {:?}", p),
+        }
+    }
+}
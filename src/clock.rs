@@ -0,0 +1,166 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::worker::Worker;
+
+/// Source of time for time-based behaviors of the engine: ages of archived files,
+/// waits of buffered writes and periodic background tasks like sync and purge. The engine uses `SystemClock` unless another
+/// one is given, e.g. a `ManualClock` in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Call `tick` named `name` every `interval` of this clock's time, until the
+    /// returned handle is dropped.
+    fn schedule(&self, name: &str, interval: Duration, tick: Box<dyn FnMut() + Send>) -> Worker;
+}
+
+/// The wall clock, running periodic tasks in background threads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn schedule(&self, name: &str, interval: Duration, tick: Box<dyn FnMut() + Send>) -> Worker {
+        Worker::spawn(name, interval, tick)
+    }
+}
+
+type Task = Arc<Mutex<Box<dyn FnMut() + Send>>>;
+
+struct ManualState {
+    now: SystemTime,
+    next_task_id: u64,
+    // (id, next run, interval, task)
+    tasks: Vec<(u64, SystemTime, Duration, Task)>,
+}
+
+/// A clock only moved by `advance`. Periodic tasks run in the thread calling
+/// `advance`, once for each of their intervals passed, so tests can check their
+/// effects without sleeping. Intervals of tasks must not be zero.
+#[derive(Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> ManualClock {
+        ManualClock {
+            state: Arc::new(Mutex::new(ManualState {
+                now,
+                next_task_id: 0,
+                tasks: vec![],
+            })),
+        }
+    }
+
+    /// Move the clock forward by `duration` and run the tasks due in between, in
+    /// the order of their scheduled time.
+    pub fn advance(&self, duration: Duration) {
+        let target = {
+            let mut state = self.state.lock().unwrap();
+            state.now += duration;
+            state.now
+        };
+        loop {
+            // Tasks may read the clock or cancel tasks, so they're called without
+            // the lock held.
+            let task = {
+                let mut state = self.state.lock().unwrap();
+                let due = state
+                    .tasks
+                    .iter_mut()
+                    .filter(|t| t.1 <= target)
+                    .min_by_key(|t| t.1);
+                match due {
+                    Some((_, next, interval, task)) => {
+                        *next += *interval;
+                        task.clone()
+                    }
+                    None => break,
+                }
+            };
+            let mut tick = task.lock().unwrap();
+            (*tick)();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new(SystemTime::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().unwrap().now
+    }
+
+    fn schedule(&self, _name: &str, interval: Duration, tick: Box<dyn FnMut() + Send>) -> Worker {
+        assert!(interval > Duration::from_secs(0));
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_task_id;
+            state.next_task_id += 1;
+            let next = state.now + interval;
+            state
+                .tasks
+                .push((id, next, interval, Arc::new(Mutex::new(tick))));
+            id
+        };
+        let state = self.state.clone();
+        Worker::with_cancel(move || state.lock().unwrap().tasks.retain(|t| t.0 != id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_manual_clock() {
+        let start = SystemTime::now();
+        let clock = ManualClock::new(start);
+        let fast = Arc::new(AtomicU64::new(0));
+        let slow = Arc::new(AtomicU64::new(0));
+        let f = fast.clone();
+        let fast_worker = clock.schedule(
+            "fast",
+            Duration::from_secs(1),
+            Box::new(move || {
+                f.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+        let (s, c) = (slow.clone(), clock.clone());
+        let _slow_worker = clock.schedule(
+            "slow",
+            Duration::from_secs(3),
+            Box::new(move || {
+                // The clock can be read by tasks.
+                assert!(c.now() >= start + Duration::from_secs(3));
+                s.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        clock.advance(Duration::from_millis(999));
+        assert_eq!(fast.load(Ordering::SeqCst), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(fast.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(6));
+        assert_eq!(fast.load(Ordering::SeqCst), 6);
+        assert_eq!(slow.load(Ordering::SeqCst), 2);
+
+        drop(fast_worker);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(fast.load(Ordering::SeqCst), 6);
+        assert_eq!(slow.load(Ordering::SeqCst), 3);
+    }
+}
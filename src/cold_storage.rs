@@ -0,0 +1,61 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::Result;
+
+/// A flat object store holding log files that are too old to be kept on local
+/// disk, e.g. an S3-compatible bucket. Objects are named after the log files.
+pub trait ObjectStorage: Send + Sync {
+    fn put(&self, name: &str, content: &[u8]) -> Result<()>;
+
+    fn get(&self, name: &str) -> Result<Vec<u8>>;
+
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// List names of all objects.
+    fn list(&self) -> Result<Vec<String>>;
+}
+
+/// An `ObjectStorage` backed by a local directory, for tests and for remote
+/// storages mounted as a file system.
+pub struct LocalObjectStorage {
+    dir: PathBuf,
+}
+
+impl LocalObjectStorage {
+    pub fn new(dir: &str) -> Result<LocalObjectStorage> {
+        fs::create_dir_all(dir)?;
+        Ok(LocalObjectStorage {
+            dir: PathBuf::from(dir),
+        })
+    }
+}
+
+impl ObjectStorage for LocalObjectStorage {
+    fn put(&self, name: &str, content: &[u8]) -> Result<()> {
+        fs::write(self.dir.join(name), content)?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.dir.join(name))?)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        fs::remove_file(self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+}
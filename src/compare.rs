@@ -0,0 +1,242 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::engine::FileEngine;
+use crate::{Config, RaftEngine, Result};
+
+const FETCH_BATCH: u64 = 1024;
+
+/// A difference between two raft log directories.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Divergence {
+    /// The first and last indexes of entries differ. `None` means no entries.
+    EntriesRange {
+        region_id: u64,
+        left: Option<(u64, u64)>,
+        right: Option<(u64, u64)>,
+    },
+    /// Terms of the entry differ. Only the first such entry of a region is reported.
+    Term {
+        region_id: u64,
+        index: u64,
+        left: u64,
+        right: u64,
+    },
+    /// Values of the key differ. `None` means the key doesn't exist.
+    Kv {
+        region_id: u64,
+        key: Vec<u8>,
+        left: Option<Vec<u8>>,
+        right: Option<Vec<u8>>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::EntriesRange {
+                region_id,
+                left,
+                right,
+            } => write!(
+                f,
+                "region {} entries range differs: {:?} vs {:?}",
+                region_id, left, right
+            ),
+            Divergence::Term {
+                region_id,
+                index,
+                left,
+                right,
+            } => write!(
+                f,
+                "region {} term of entry {} differs: {} vs {}",
+                region_id, index, left, right
+            ),
+            Divergence::Kv {
+                region_id,
+                key,
+                left,
+                right,
+            } => write!(
+                f,
+                "region {} value of key {:?} differs: {:?} vs {:?}",
+                region_id, key, left, right
+            ),
+        }
+    }
+}
+
+/// Open two raft log directories without modifying them, and compare entries
+/// ranges, terms and key value pairs of all regions.
+pub fn compare_dirs(left_dir: &str, right_dir: &str) -> Result<Vec<Divergence>> {
+    // Named by side, as the directories may be the same.
+    let open = |dir: &str, side: &str| {
+        FileEngine::open_observer(Config {
+            dir: dir.to_owned(),
+            name: format!("{}-{}-observer", dir, side),
+            ..Default::default()
+        })
+    };
+    let (left, right) = (open(left_dir, "left")?, open(right_dir, "right")?);
+
+    let mut region_ids = left.region_ids();
+    region_ids.extend(right.region_ids());
+    region_ids.sort_unstable();
+    region_ids.dedup();
+
+    let mut divergences = vec![];
+    for region_id in region_ids {
+        compare_entries(&left, &right, region_id, &mut divergences)?;
+        compare_kvs(&left, &right, region_id, &mut divergences)?;
+    }
+    Ok(divergences)
+}
+
+fn compare_entries(
+    left: &FileEngine,
+    right: &FileEngine,
+    region_id: u64,
+    divergences: &mut Vec<Divergence>,
+) -> Result<()> {
+    let left_range = left.entries_range(region_id);
+    let right_range = right.entries_range(region_id);
+    if left_range != right_range {
+        divergences.push(Divergence::EntriesRange {
+            region_id,
+            left: left_range,
+            right: right_range,
+        });
+    }
+    let (begin, end) = match (left_range, right_range) {
+        (Some(l), Some(r)) => (cmp::max(l.0, r.0), cmp::min(l.1, r.1) + 1),
+        _ => return Ok(()),
+    };
+
+    let (mut left_entries, mut right_entries) = (vec![], vec![]);
+    let mut batch_begin = begin;
+    while batch_begin < end {
+        let batch_end = cmp::min(batch_begin + FETCH_BATCH, end);
+        left_entries.clear();
+        right_entries.clear();
+        left.fetch_entries_to(region_id, batch_begin, batch_end, None, &mut left_entries)?;
+        right.fetch_entries_to(region_id, batch_begin, batch_end, None, &mut right_entries)?;
+        for (l, r) in left_entries.iter().zip(&right_entries) {
+            if l.get_term() != r.get_term() {
+                divergences.push(Divergence::Term {
+                    region_id,
+                    index: l.get_index(),
+                    left: l.get_term(),
+                    right: r.get_term(),
+                });
+                return Ok(());
+            }
+        }
+        batch_begin = batch_end;
+    }
+    Ok(())
+}
+
+fn compare_kvs(
+    left: &FileEngine,
+    right: &FileEngine,
+    region_id: u64,
+    divergences: &mut Vec<Divergence>,
+) -> Result<()> {
+    let mut kvs = BTreeMap::<_, (Option<Vec<u8>>, Option<Vec<u8>>)>::new();
+    for (key, value) in left.region_kvs(region_id)? {
+        kvs.entry(key).or_default().0 = Some(value);
+    }
+    for (key, value) in right.region_kvs(region_id)? {
+        kvs.entry(key).or_default().1 = Some(value);
+    }
+    for (key, (left, right)) in kvs {
+        if left != right {
+            divergences.push(Divergence::Kv {
+                region_id,
+                key,
+                left,
+                right,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use raft::eraftpb::Entry;
+
+    use crate::log_batch::LogBatch;
+
+    fn append(engine: &FileEngine, region_id: u64, indexes: std::ops::Range<u64>, term: u64) {
+        let mut entries = vec![];
+        for i in indexes {
+            let mut e = Entry::new();
+            e.set_index(i);
+            e.set_term(term);
+            entries.push(e);
+        }
+        engine.append(region_id, entries).unwrap();
+    }
+
+    #[test]
+    fn test_compare_dirs() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_compare_dirs")
+            .tempdir()
+            .unwrap();
+        let left_dir = dir.path().join("left").to_str().unwrap().to_owned();
+        let right_dir = dir.path().join("right").to_str().unwrap().to_owned();
+
+        {
+            let mut cfg = Config::default();
+            cfg.dir = left_dir.clone();
+            let left = FileEngine::new(cfg.clone());
+            cfg.dir = right_dir.clone();
+            let right = FileEngine::new(cfg);
+
+            for engine in &[&left, &right] {
+                append(engine, 1, 1..10, 1);
+                append(engine, 2, 1..10, 1);
+                let mut batch = LogBatch::new();
+                batch.put(1, b"k1", b"v1");
+                engine.consume(&mut batch, true).unwrap();
+            }
+            append(&left, 2, 5..10, 2);
+            append(&right, 3, 1..5, 1);
+            let mut batch = LogBatch::new();
+            batch.put(1, b"k2", b"v2");
+            left.consume(&mut batch, true).unwrap();
+        }
+
+        assert!(compare_dirs(&left_dir, &left_dir).unwrap().is_empty());
+        assert_eq!(
+            compare_dirs(&left_dir, &right_dir).unwrap(),
+            vec![
+                Divergence::Kv {
+                    region_id: 1,
+                    key: b"k2".to_vec(),
+                    left: Some(b"v2".to_vec()),
+                    right: None,
+                },
+                Divergence::Term {
+                    region_id: 2,
+                    index: 5,
+                    left: 2,
+                    right: 1,
+                },
+                Divergence::EntriesRange {
+                    region_id: 3,
+                    left: None,
+                    right: Some((1, 4)),
+                },
+            ]
+        );
+    }
+}
@@ -11,18 +11,190 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{util::ReadableSize, Result};
+use std::fmt;
+
+use serde::de::{self, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::util::{ReadableDuration, ReadableSize};
+use crate::Result;
+
+/// How recovery deals with a corrupted batch. Configured by name, or by the
+/// integers of old configs, 0 and 1 in the order of the variants.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecoveryMode {
+    /// Batches from a corrupted one at the tail of the active file on are dropped,
+    /// as left by a crash in the middle of a write.
+    TolerateCorruptedTailRecords,
+    /// Any corrupted batch fails recovery.
+    AbsoluteConsistency,
+}
+
+impl<'de> Deserialize<'de> for RecoveryMode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ModeVisitor;
+
+        impl<'de> Visitor<'de> for ModeVisitor {
+            type Value = RecoveryMode;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a recovery mode name, or 0 or 1")
+            }
+
+            fn visit_i64<E>(self, mode: i64) -> std::result::Result<RecoveryMode, E>
+            where
+                E: de::Error,
+            {
+                match mode {
+                    0 => Ok(RecoveryMode::TolerateCorruptedTailRecords),
+                    1 => Ok(RecoveryMode::AbsoluteConsistency),
+                    _ => Err(E::invalid_value(Unexpected::Signed(mode), &self)),
+                }
+            }
+
+            fn visit_u64<E>(self, mode: u64) -> std::result::Result<RecoveryMode, E>
+            where
+                E: de::Error,
+            {
+                match mode {
+                    0 | 1 => self.visit_i64(mode as i64),
+                    _ => Err(E::invalid_value(Unexpected::Unsigned(mode), &self)),
+                }
+            }
+
+            fn visit_str<E>(self, mode: &str) -> std::result::Result<RecoveryMode, E>
+            where
+                E: de::Error,
+            {
+                match mode {
+                    "tolerate-corrupted-tail-records" => {
+                        Ok(RecoveryMode::TolerateCorruptedTailRecords)
+                    }
+                    "absolute-consistency" => Ok(RecoveryMode::AbsoluteConsistency),
+                    _ => Err(E::invalid_value(Unexpected::Str(mode), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(ModeVisitor)
+    }
+}
+
+/// Implementations of `MemTableAccessor`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemTableType {
+    /// `MemTable`, indexes of entries in a deque with the latest entries cached.
+    Deque,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub dir: String,
-    pub recovery_mode: i32,
+    pub recovery_mode: RecoveryMode,
     pub bytes_per_sync: ReadableSize,
     pub target_file_size: ReadableSize,
+    /// 0 disables the entry cache, memtables only keep indexes of entries and all
+    /// entries are read from files.
     pub cache_size_limit: ReadableSize,
     pub total_size_limit: ReadableSize,
+    /// Verify checksums of all batches in all files before recovering, and report
+    /// every corruption found instead of stopping at the first one.
+    pub verify_on_recovery: bool,
+    /// Don't verify checksums of batches in sealed files in recovery, only parse
+    /// them, e.g. for files restored from a verified checkpoint, where a faster
+    /// startup matters more. Batches of the active file are still verified, as
+    /// its tail may be torn by a crash.
+    pub skip_recovery_checksum: bool,
+    /// Only the newest files of this total size are kept on local disk, older files
+    /// are moved to cold storage if the engine has one. 0 means keeping all files.
+    pub cold_file_threshold: ReadableSize,
+    /// Purged files are moved into this directory instead of being removed if set.
+    pub archive_dir: String,
+    /// Oldest archived files are removed once the archive exceeds this size. 0 means no limit.
+    pub archive_retention_size: ReadableSize,
+    /// Archived files older than this are removed. 0 means no limit.
+    pub archive_retention_age: ReadableDuration,
+    /// Log files with batches written within this duration are neither purged nor
+    /// punched holes in, whatever the size limits, e.g. to keep recent history for
+    /// audits and debugging. Files of unknown times, i.e. migrated from older
+    /// formats, aren't kept. 0 means no limit.
+    pub retention_min_age: ReadableDuration,
+    /// Gc keeps at least this many of the latest entries of each region, whatever
+    /// the index it's asked to compact to, including compaction by force for size
+    /// limits. 0 means no limit.
+    pub retention_min_entries: u64,
+    /// While files keep growing beyond `total_size_limit`, regions of more entries
+    /// are rewritten, up to this many, and more files are considered inactive. 0
+    /// means always using `compact_threshold`.
+    pub max_compact_threshold: usize,
+    /// Reject appended entries not directly following the last entry of the region
+    /// with `Error::AppendConflict`, instead of overwriting the conflicting ones. For
+    /// catching bugs of callers in tests, as raft overwrites entries after conflicts.
+    pub strict_append: bool,
+    /// How entries and key value pairs of a region are indexed in memory.
+    pub memtable_type: MemTableType,
+    /// Values of key value pairs larger than this aren't kept in memtables, only
+    /// where they're in files, and are read from files when they're got. 0 means
+    /// keeping all values in memtables.
+    pub max_memtable_value_size: ReadableSize,
+    /// Labels metrics of the engine and prefixes names of its background threads,
+    /// so that engines in one process can be told apart. Engines alive in one
+    /// process can't share a name. Defaults to `dir`, suffixed by `-observer` for
+    /// observers.
+    pub name: String,
+    /// Write all batches uncompressed, for fast disks where compressing large
+    /// batches costs more CPU than it saves. Can be changed at runtime by
+    /// `FileEngine::set_compression_enabled`.
+    pub disable_compression: bool,
+    /// Path of a dictionary to compress small batches with, e.g. trained by
+    /// `Dictionary::train`. Only the last 64KB of it is used. Can be changed at
+    /// runtime by `FileEngine::set_compression_dictionary`.
+    pub compression_dictionary: String,
+    /// Drop pages read by background scans of whole files, i.e. verification,
+    /// scrubbing, hole punching, dumping and offloading, from the OS page cache,
+    /// so that they don't evict pages used by foreground reads. Only takes effect
+    /// on Linux.
+    pub scan_bypass_page_cache: bool,
+    /// Read entries from sealed files, i.e. all but the active one, through
+    /// read-only memory maps of the files shared by readers, instead of a read
+    /// syscall each. Saves syscalls and copies of readers catching up on files in
+    /// the page cache, e.g. followers, at the cost of address space of the maps.
+    pub mmap_sealed_files: bool,
+    /// Cache written entries as they're encoded in their batches, instead of as
+    /// decoded ones, so that they're not copied out of the batches field by field
+    /// but decoded again when read from the cache. Saves memory and CPU of writes
+    /// for regions whose cached entries are mostly compacted before read.
+    pub cache_encoded_entries: bool,
+    /// Apply written batches to memtables in a dedicated thread instead of the
+    /// writer's, so that writes return without waiting for locks of memtables.
+    /// Reads wait for writes returned before them to be applied, so they're
+    /// visible as if applied by writers.
+    pub async_apply: bool,
+    /// Buffer writes without sync until this many bytes are buffered, or the oldest
+    /// of them has waited `write_buffer_wait`, and then write them as one batch with
+    /// sync, so that small writes share the cost of a batch and its sync. A buffered
+    /// write returns the approximate size of its batch, as it will be written.
+    /// Writes with sync, reads, gc and snapshots write buffered ones first.
+    /// Buffered writes failing to be written stay buffered, and a write that would
+    /// overflow the buffer fails if they still can't be written. 0 means writes
+    /// aren't buffered. Can't be used with `strict_append`.
+    pub write_buffer_size: ReadableSize,
+    pub write_buffer_wait: ReadableDuration,
+    /// Split batches of items of more regions than this, e.g. of store-level
+    /// migrations touching thousands of regions, into batches of at most this many
+    /// regions, to bound allocations of decoding a batch in recovery. Each of them
+    /// is written and recovered atomically on its own, but not the whole batch: if
+    /// a part fails to be written, parts before it stay written, and the write
+    /// fails with `Error::PartiallyWritten` telling how many. 0 means batches
+    /// aren't split.
+    pub max_batch_regions: usize,
 
     // Use raftstore.cfg.raft_log_gc_threshold
     #[doc(hidden)]
@@ -39,11 +211,33 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             dir: "".to_owned(),
-            recovery_mode: 0,
+            recovery_mode: RecoveryMode::TolerateCorruptedTailRecords,
             bytes_per_sync: ReadableSize::kb(256),
             target_file_size: ReadableSize::mb(128),
             cache_size_limit: ReadableSize::gb(2),
             total_size_limit: ReadableSize::gb(20),
+            verify_on_recovery: false,
+            skip_recovery_checksum: false,
+            cold_file_threshold: ReadableSize(0),
+            archive_dir: "".to_owned(),
+            archive_retention_size: ReadableSize(0),
+            archive_retention_age: ReadableDuration::secs(0),
+            retention_min_age: ReadableDuration::secs(0),
+            retention_min_entries: 0,
+            max_compact_threshold: 0,
+            strict_append: false,
+            memtable_type: MemTableType::Deque,
+            max_memtable_value_size: ReadableSize(0),
+            name: "".to_owned(),
+            disable_compression: false,
+            compression_dictionary: "".to_owned(),
+            scan_bypass_page_cache: false,
+            mmap_sealed_files: false,
+            cache_encoded_entries: false,
+            async_apply: false,
+            write_buffer_size: ReadableSize(0),
+            write_buffer_wait: ReadableDuration::micros(500),
+            max_batch_regions: 0,
             compact_threshold: 0,
             region_size: ReadableSize::mb(0),
         }
@@ -64,7 +258,7 @@ impl Config {
             ));
         }
 
-        if self.cache_size_limit.0 < self.target_file_size.0 {
+        if self.cache_size_limit.0 != 0 && self.cache_size_limit.0 < self.target_file_size.0 {
             return Err(box_err!(
                 "Cache size limit {:?} less than target file size {:?}",
                 self.cache_size_limit,
@@ -80,10 +274,28 @@ impl Config {
             ));
         }
 
-        if self.recovery_mode < 0 || self.recovery_mode > 1 {
+        if !self.archive_dir.is_empty() && self.archive_dir == self.dir {
+            return Err(box_err!(
+                "Archive directory {} can't be the same as raft log directory",
+                self.archive_dir
+            ));
+        }
+
+        if self.max_compact_threshold != 0 && self.max_compact_threshold < self.compact_threshold {
+            return Err(box_err!(
+                "Max compact threshold {} is less than compact threshold {}",
+                self.max_compact_threshold,
+                self.compact_threshold
+            ));
+        }
+
+        if self.write_buffer_size.0 > 0 && self.strict_append {
+            return Err(box_err!("Write buffer can't be used with strict append"));
+        }
+
+        if self.skip_recovery_checksum && self.verify_on_recovery {
             return Err(box_err!(
-                "Unknown recovery mode {} for raftengine",
-                self.recovery_mode
+                "Recovery checksums can't be skipped with verify on recovery"
             ));
         }
 
@@ -95,20 +307,54 @@ impl Config {
 mod tests {
     use super::*;
 
+    use serde::de::value::{Error as DeError, I64Deserializer, StrDeserializer, U64Deserializer};
+    use serde::de::IntoDeserializer;
+
+    #[test]
+    fn test_recovery_mode_names() {
+        let parse = |name: &str| {
+            let de: StrDeserializer<DeError> = name.into_deserializer();
+            RecoveryMode::deserialize(de)
+        };
+        assert_eq!(
+            parse("tolerate-corrupted-tail-records").unwrap(),
+            RecoveryMode::TolerateCorruptedTailRecords
+        );
+        assert_eq!(
+            parse("absolute-consistency").unwrap(),
+            RecoveryMode::AbsoluteConsistency
+        );
+        assert!(parse("1").is_err());
+        assert!(parse("AbsoluteConsistency").is_err());
+
+        // Integers of old configs.
+        let parse_i64 = |mode: i64| {
+            let de: I64Deserializer<DeError> = mode.into_deserializer();
+            RecoveryMode::deserialize(de)
+        };
+        assert_eq!(
+            parse_i64(0).unwrap(),
+            RecoveryMode::TolerateCorruptedTailRecords
+        );
+        assert_eq!(parse_i64(1).unwrap(), RecoveryMode::AbsoluteConsistency);
+        assert!(parse_i64(-1).is_err());
+        assert!(parse_i64(2).is_err());
+        let parse_u64 = |mode: u64| {
+            let de: U64Deserializer<DeError> = mode.into_deserializer();
+            RecoveryMode::deserialize(de)
+        };
+        assert_eq!(parse_u64(1).unwrap(), RecoveryMode::AbsoluteConsistency);
+        assert!(parse_u64(2).is_err());
+    }
+
     #[test]
     fn test_config_validate() {
         let mut cfg = Config::new();
         assert!(cfg.validate().is_ok());
 
-        cfg.recovery_mode = 1;
+        cfg.recovery_mode = RecoveryMode::AbsoluteConsistency;
         assert!(cfg.validate().is_ok());
 
-        cfg.recovery_mode = 2;
-        assert!(cfg.validate().is_err());
-
-        cfg.recovery_mode = -1;
-        assert!(cfg.validate().is_err());
-
         cfg = Config::new();
         cfg.target_file_size = ReadableSize::kb(20);
         cfg.total_size_limit = ReadableSize::kb(10);
@@ -121,5 +367,33 @@ mod tests {
         cfg.cache_size_limit = ReadableSize::mb(1);
         cfg.total_size_limit = ReadableSize::mb(10);
         assert!(cfg.validate().is_ok());
+
+        cfg.cache_size_limit = ReadableSize(0);
+        assert!(cfg.validate().is_ok());
+        cfg.cache_size_limit = ReadableSize::mb(1);
+
+        cfg.dir = "raft".to_owned();
+        cfg.archive_dir = "raft".to_owned();
+        assert!(cfg.validate().is_err());
+        cfg.archive_dir = "archive".to_owned();
+        assert!(cfg.validate().is_ok());
+
+        cfg.compact_threshold = 100;
+        cfg.max_compact_threshold = 10;
+        assert!(cfg.validate().is_err());
+        cfg.max_compact_threshold = 1000;
+        assert!(cfg.validate().is_ok());
+
+        cfg.write_buffer_size = ReadableSize::kb(64);
+        cfg.strict_append = true;
+        assert!(cfg.validate().is_err());
+        cfg.strict_append = false;
+        assert!(cfg.validate().is_ok());
+
+        cfg.skip_recovery_checksum = true;
+        cfg.verify_on_recovery = true;
+        assert!(cfg.validate().is_err());
+        cfg.verify_on_recovery = false;
+        assert!(cfg.validate().is_ok());
     }
 }
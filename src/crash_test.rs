@@ -0,0 +1,238 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Simulated power loss. Writes of log files are kept in the page cache until
+//! synced, so a crash may drop any suffix of a file beyond its last synced length.
+//! The pipe log reports synced lengths here, and `crash` truncates files to a
+//! random length in between, as the disk could be found after a power loss.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use raft::eraftpb::Entry;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::check::{check_dir, Problem};
+use crate::config::RecoveryMode;
+use crate::engine::FileEngine;
+use crate::log_batch::LogBatch;
+use crate::pipe_log;
+use crate::util::{HashMap, ReadableSize};
+use crate::{Config, RaftEngine};
+
+lazy_static! {
+    // Path of a log file -> length of its durable prefix.
+    static ref DURABLE: Mutex<HashMap<PathBuf, u64>> = Mutex::new(HashMap::default());
+}
+
+pub(crate) fn record_durable(path: &Path, len: u64) {
+    DURABLE.lock().unwrap().insert(path.to_owned(), len);
+}
+
+/// Drop a random part of the unsynced suffix of each log file in `dir`. Files
+/// without a record are left alone, as they were found on disk when opened.
+fn crash(dir: &Path, rng: &mut StdRng) {
+    let mut durable = DURABLE.lock().unwrap();
+    for (_, path) in pipe_log::list_log_files(dir).unwrap() {
+        let len = fs::metadata(&path).unwrap().len();
+        let synced = match durable.get(&path) {
+            Some(&synced) if synced < len => synced,
+            _ => continue,
+        };
+        let kept = rng.gen_range(synced..=len);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(kept).unwrap();
+        durable.insert(path, kept);
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RegionModel {
+    entries: BTreeMap<u64, Vec<u8>>,
+    kvs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+type Model = BTreeMap<u64, RegionModel>;
+
+const REGIONS: u64 = 4;
+
+// Apply a random write to the engine and the model, return whether it's synced.
+fn random_write(engine: &FileEngine, model: &mut Model, rng: &mut StdRng, op: u64) -> bool {
+    let region_id = rng.gen_range(1..=REGIONS);
+    let region = model.entry(region_id).or_default();
+    let mut batch = LogBatch::new();
+    match rng.gen_range(0..10) {
+        0..=5 => {
+            // Append, possibly overwriting a few entries at the tail.
+            let last = region.entries.keys().next_back().copied().unwrap_or(0);
+            let first = region.entries.keys().next().copied().unwrap_or(1);
+            let begin = rng.gen_range(cmp::max(first, last.saturating_sub(3))..=last + 1);
+            region.entries.split_off(&begin);
+            let mut entries = vec![];
+            for index in begin..begin + rng.gen_range(1..8) {
+                let data = format!("{}-{}", op, index).repeat(rng.gen_range(1..16));
+                let mut e = Entry::new();
+                e.set_index(index);
+                e.set_data(data.clone().into_bytes());
+                region.entries.insert(index, data.into_bytes());
+                entries.push(e);
+            }
+            batch.add_entries(region_id, entries);
+        }
+        6 | 7 => {
+            let key = format!("k{}", rng.gen_range(0..4)).into_bytes();
+            let value = format!("v{}", op).into_bytes();
+            batch.put(region_id, &key, &value);
+            region.kvs.insert(key, value);
+        }
+        8 => {
+            let key = format!("k{}", rng.gen_range(0..4)).into_bytes();
+            batch.delete(region_id, &key);
+            region.kvs.remove(&key);
+        }
+        _ => {
+            batch.clean_region(region_id);
+            model.remove(&region_id);
+        }
+    }
+    let sync = rng.gen_range(0..5) == 0;
+    engine.consume(&mut batch, sync).unwrap();
+    sync
+}
+
+fn recovered_model(engine: &FileEngine) -> Model {
+    let mut model = Model::new();
+    for region_id in 1..=REGIONS {
+        let mut region = RegionModel::default();
+        if let Some((first, last)) = engine.entries_range(region_id) {
+            let mut entries = vec![];
+            engine
+                .fetch_entries_to(region_id, first, last + 1, None, &mut entries)
+                .unwrap();
+            for e in entries {
+                region.entries.insert(e.get_index(), e.get_data().to_vec());
+            }
+        }
+        region.kvs = engine.region_kvs(region_id).unwrap().into_iter().collect();
+        if region != RegionModel::default() {
+            model.insert(region_id, region);
+        }
+    }
+    model
+}
+
+// Run a random workload, crash, reopen and check that the recovered data is the
+// state after some batch no earlier than the last synced one.
+fn run_crash_test(seed: u64, recovery_mode: RecoveryMode) {
+    let dir = tempfile::Builder::new()
+        .prefix("test_crash_consistency")
+        .tempdir()
+        .unwrap();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut cfg = Config::default();
+    cfg.dir = dir.path().to_str().unwrap().to_owned();
+    cfg.target_file_size = ReadableSize(1024);
+    cfg.bytes_per_sync = ReadableSize(0);
+    cfg.recovery_mode = recovery_mode;
+
+    let engine = FileEngine::new(cfg.clone());
+    let mut model = Model::new();
+    // Models after each batch, empty models dropped.
+    let mut history = vec![model.clone()];
+    let mut last_synced = 0;
+    for op in 0..rng.gen_range(1..200) {
+        if random_write(&engine, &mut model, &mut rng, op) {
+            last_synced = history.len();
+        }
+        model.retain(|_, r| *r != RegionModel::default());
+        history.push(model.clone());
+    }
+    if rng.gen_range(0..2) == 0 {
+        engine.sync().unwrap();
+        last_synced = history.len() - 1;
+    }
+    drop(engine);
+    crash(dir.path(), &mut rng);
+
+    let problems = check_dir(&cfg.dir).unwrap();
+    let res = panic::catch_unwind(AssertUnwindSafe(|| FileEngine::open(cfg.clone())));
+    let engine = match res {
+        Ok(Ok(engine)) => engine,
+        res => {
+            // Only a torn tail of the last file may fail recovery, and only in
+            // absolute consistency mode.
+            let last_file_num = pipe_log::list_log_files(dir.path())
+                .unwrap()
+                .last()
+                .unwrap()
+                .0;
+            assert_eq!(
+                recovery_mode,
+                RecoveryMode::AbsoluteConsistency,
+                "seed {}: {:?}",
+                seed,
+                res.err()
+            );
+            assert!(!problems.is_empty(), "seed {}", seed);
+            for p in &problems {
+                match p {
+                    Problem::BadFile { file_num, .. } if *file_num == last_file_num => {}
+                    p => panic!("seed {}: unexpected {}", seed, p),
+                }
+            }
+            return;
+        }
+    };
+    let recovered = recovered_model(&engine);
+    assert!(
+        history[last_synced..].contains(&recovered),
+        "seed {}: recovered {:?} not in history after batch {}",
+        seed,
+        recovered,
+        last_synced
+    );
+}
+
+#[test]
+fn test_crash_consistency_tolerate_tail() {
+    for seed in 0..32 {
+        run_crash_test(seed, RecoveryMode::TolerateCorruptedTailRecords);
+    }
+}
+
+#[test]
+fn test_crash_consistency_absolute() {
+    for seed in 0..32 {
+        run_crash_test(seed, RecoveryMode::AbsoluteConsistency);
+    }
+}
+
+// Only the last file is recovered with a torn tail tolerated, so a file must be
+// durable as a whole once it's rotated, even if no write to it was synced.
+#[test]
+fn test_rotated_file_durable() {
+    let dir = tempfile::Builder::new()
+        .prefix("test_rotated_file_durable")
+        .tempdir()
+        .unwrap();
+    let mut cfg = Config::default();
+    cfg.dir = dir.path().to_str().unwrap().to_owned();
+    cfg.target_file_size = ReadableSize::kb(1);
+    let engine = FileEngine::new(cfg);
+    let mut entry = Entry::new();
+    entry.set_data(vec![b'x'; 256]);
+    for index in 1..=8 {
+        entry.set_index(index);
+        engine.append(1, vec![entry.clone()]).unwrap();
+    }
+    let files = pipe_log::list_log_files(dir.path()).unwrap();
+    assert!(files.len() > 2);
+    let durable = DURABLE.lock().unwrap();
+    for (_, path) in &files[..files.len() - 1] {
+        assert_eq!(durable[path], fs::metadata(path).unwrap().len());
+    }
+}
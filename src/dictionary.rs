@@ -0,0 +1,209 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crc32fast::Hasher;
+
+use crate::util::HashMap;
+use crate::Result;
+
+const DICT_SUFFIX: &str = ".lz4dict";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// lz4 only refers to the last 64KB of a dictionary.
+pub const MAX_DICT_SIZE: usize = 64 * 1024;
+
+/// Content prepended to small batches when they are compressed, so that commands
+/// similar to ones in the dictionary are compressed well though they are short.
+/// A dictionary is identified by the checksum of its content, which is written
+/// with each batch compressed with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dictionary {
+    id: u32,
+    content: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Only the last `MAX_DICT_SIZE` bytes of `content` are kept.
+    pub fn new(mut content: Vec<u8>) -> Dictionary {
+        if content.len() > MAX_DICT_SIZE {
+            content.drain(..content.len() - MAX_DICT_SIZE);
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&content);
+        Dictionary {
+            id: hasher.finalize(),
+            content,
+        }
+    }
+
+    /// Build a dictionary of at most `max_size` bytes from samples of written
+    /// data, e.g. encoded entries. lz4 finds matches nearer to the end of the
+    /// dictionary with shorter offsets, so more frequent samples are put later,
+    /// and the least frequent ones are dropped if it's full.
+    pub fn train(samples: &[&[u8]], max_size: usize) -> Dictionary {
+        // Sample -> (count, first position).
+        let mut counts: HashMap<&[u8], (usize, usize)> = HashMap::default();
+        for (i, sample) in samples.iter().enumerate() {
+            counts.entry(sample).or_insert((0, i)).0 += 1;
+        }
+        let mut samples: Vec<_> = counts.into_iter().collect();
+        samples.sort_by_key(|(_, (count, pos))| (*count, *pos));
+        let mut content = Vec::new();
+        for (sample, _) in samples {
+            content.extend_from_slice(sample);
+        }
+        let max_size = std::cmp::min(max_size, MAX_DICT_SIZE);
+        if content.len() > max_size {
+            content.drain(..content.len() - max_size);
+        }
+        Dictionary::new(content)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+}
+
+/// Dictionaries known to an engine by id, for decoding batches compressed with
+/// any of them.
+#[derive(Default)]
+pub struct Dictionaries {
+    dicts: RwLock<HashMap<u32, Arc<Dictionary>>>,
+}
+
+impl Dictionaries {
+    /// Make the dictionary known to decoders of batches.
+    pub fn register(&self, dict: Arc<Dictionary>) {
+        self.dicts.write().unwrap().insert(dict.id, dict);
+    }
+
+    pub fn get(&self, id: u32) -> Option<Arc<Dictionary>> {
+        self.dicts.read().unwrap().get(&id).cloned()
+    }
+
+    /// Register dictionaries saved in `dir`, and return their ids.
+    pub fn load_dir(&self, dir: &Path) -> Result<Vec<u32>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let file_name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if is_dict_file(name) => name,
+                _ => continue,
+            };
+            ids.push(self.load(file_name, fs::read(&path)?)?);
+        }
+        Ok(ids)
+    }
+
+    /// Register the dictionary saved as `file_name`, and return its id.
+    pub fn load(&self, file_name: &str, content: Vec<u8>) -> Result<u32> {
+        let dict = Dictionary::new(content);
+        if file_name != file_name_of(dict.id) {
+            return Err(box_err!(
+                "Dictionary {} is corrupted, checksum {:08x}",
+                file_name,
+                dict.id
+            ));
+        }
+        let id = dict.id;
+        self.register(Arc::new(dict));
+        Ok(id)
+    }
+}
+
+/// Name of the file of the dictionary, in a raft log directory or an object
+/// storage.
+pub fn file_name_of(id: u32) -> String {
+    format!("{:08x}{}", id, DICT_SUFFIX)
+}
+
+pub fn is_dict_file(file_name: &str) -> bool {
+    file_name.ends_with(DICT_SUFFIX)
+}
+
+fn dict_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(file_name_of(id))
+}
+
+/// Keep the dictionary in `dir` besides log files, so that it's loaded together
+/// with them to decode batches compressed with it.
+pub fn save(dir: &Path, dict: &Dictionary) -> Result<()> {
+    let path = dict_path(dir, dict.id);
+    if path.exists() {
+        return Ok(());
+    }
+    let tmp_path = dir.join(format!("{:08x}{}{}", dict.id, DICT_SUFFIX, TMP_SUFFIX));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&dict.content)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Remove the dictionary saved in `dir` once no file there refers to it.
+pub fn remove(dir: &Path, id: u32) -> Result<()> {
+    match fs::remove_file(dict_path(dir, id)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Copy dictionaries saved in `from` into `to`, along with log files copied
+/// there, and return the count of copied ones.
+pub fn copy_dir(from: &Path, to: &Path) -> Result<usize> {
+    let dicts = Dictionaries::default();
+    let ids = dicts.load_dir(from)?;
+    for id in &ids {
+        save(to, &dicts.get(*id).unwrap())?;
+    }
+    Ok(ids.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_train_dictionary() {
+        let samples: Vec<&[u8]> = vec![b"put k1", b"rare", b"put k2", b"put k1", b"put k1"];
+        let dict = Dictionary::train(&samples, 1024);
+        assert_eq!(dict.content(), b"rareput k2put k1");
+        let dict = Dictionary::train(&samples, 8);
+        assert_eq!(dict.content(), b"k2put k1");
+        assert_eq!(Dictionary::new(dict.content().to_vec()), dict);
+
+        let dir = tempfile::Builder::new()
+            .prefix("test_train_dictionary")
+            .tempdir()
+            .unwrap();
+        save(dir.path(), &dict).unwrap();
+        save(dir.path(), &dict).unwrap();
+        let dicts = Dictionaries::default();
+        assert_eq!(dicts.load_dir(dir.path()).unwrap(), vec![dict.id()]);
+        assert_eq!(dicts.get(dict.id()).as_deref(), Some(&dict));
+        // Known only to the registry it's loaded into.
+        assert!(Dictionaries::default().get(dict.id()).is_none());
+
+        let copied = dir.path().join("copied");
+        fs::create_dir(&copied).unwrap();
+        assert_eq!(copy_dir(dir.path(), &copied).unwrap(), 1);
+        remove(dir.path(), dict.id()).unwrap();
+        remove(dir.path(), dict.id()).unwrap();
+        assert!(Dictionaries::default()
+            .load_dir(dir.path())
+            .unwrap()
+            .is_empty());
+
+        fs::write(dict_path(&copied, dict.id()), b"broken").unwrap();
+        assert!(dicts.load_dir(&copied).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Exclusive ownership of a raft log directory by one writer. The owner holds
+//! an `flock` on the `LOCK` file in the directory, which records the pid and the
+//! start time of the owning process for diagnosis, and removes the file when it
+//! closes the directory. The kernel releases the `flock` of a crashed process,
+//! so the file it leaves behind is simply taken over by the next owner.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::{Error, Result};
+
+pub const LOCK_FILE_NAME: &str = "LOCK";
+
+/// The process holding the lock of a directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LockOwner {
+    pub pid: u32,
+    /// Start time of the process in clock ticks since boot, telling it apart from
+    /// a later process reusing its pid. `None` if it's unknown on the platform.
+    pub start_time: Option<u64>,
+}
+
+impl LockOwner {
+    pub fn current() -> LockOwner {
+        let pid = process::id();
+        LockOwner {
+            pid,
+            start_time: process_start_time(pid),
+        }
+    }
+
+    /// Whether the owner is still running.
+    pub fn is_alive(&self) -> bool {
+        let res = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+        // The process exists but belongs to another user.
+        if res != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EPERM) {
+            return false;
+        }
+        match (self.start_time, process_start_time(self.pid)) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!("{} {}\n", self.pid, self.start_time.unwrap_or(0))
+    }
+
+    fn decode(s: &str) -> Option<LockOwner> {
+        let mut fields = s.split_whitespace();
+        let pid = fields.next()?.parse().ok()?;
+        let start_time: u64 = fields.next()?.parse().ok()?;
+        Some(LockOwner {
+            pid,
+            start_time: Some(start_time).filter(|t| *t > 0),
+        })
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "process {}", self.pid)?;
+        if let Some(start_time) = self.start_time {
+            write!(f, " (started at tick {})", start_time)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may contain spaces, so fields are counted
+    // after it. The start time is the 22nd field.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+fn read_owner(path: &Path) -> Result<Option<LockOwner>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match LockOwner::decode(&content) {
+        Some(owner) => Ok(Some(owner)),
+        None => Err(box_err!(
+            "Lock file {} is corrupted, remove it manually if no process owns the directory",
+            path.display()
+        )),
+    }
+}
+
+/// The lock of a directory held by the current process, released when dropped.
+pub struct DirLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl DirLock {
+    /// Lock `dir`, failing with `Error::Locked` if another owner holds it. A lock
+    /// file left by an owner that has exited is taken over.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE_NAME);
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if !try_flock(&file)? {
+                return Err(locked_error(dir, &path));
+            }
+            // The file may have been removed by the previous owner after it was
+            // opened here, in which case the lock is on an unlinked file.
+            match fs::metadata(&path) {
+                Ok(m) => {
+                    let locked = file.metadata()?;
+                    if m.dev() != locked.dev() || m.ino() != locked.ino() {
+                        continue;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+            if let Ok(Some(stale)) = read_owner(&path) {
+                info!(
+                    "Took over lock of {} left by exited {}",
+                    dir.display(),
+                    stale
+                );
+            }
+            let mut lock = DirLock { path, file };
+            lock.file.set_len(0)?;
+            lock.file
+                .write_all(LockOwner::current().encode().as_bytes())?;
+            lock.file.sync_all()?;
+            File::open(dir)?.sync_all()?;
+            return Ok(lock);
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // Removed before the `flock` is released by closing the file, so that a
+        // process waiting on it notices the file is gone.
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Remove lock file {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Take the `flock` of `file` without blocking, `false` if it's held by others.
+fn try_flock(file: &File) -> Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(e.into())
+    }
+}
+
+fn locked_error(dir: &Path, path: &Path) -> Error {
+    match read_owner(path) {
+        Ok(Some(holder)) => Error::Locked(dir.display().to_string(), holder, true),
+        // The owner is still writing its identity, or has just unlocked.
+        _ => box_err!(
+            "Raft log directory {} is locked by another process",
+            dir.display()
+        ),
+    }
+}
+
+/// The owner recorded in the lock file of `dir`, `None` if there is none. It
+/// may have exited without removing the file.
+pub fn lock_owner(dir: &str) -> Result<Option<LockOwner>> {
+    read_owner(&Path::new(dir).join(LOCK_FILE_NAME))
+}
+
+/// Remove the lock file of `dir` left by a process that has exited, e.g.
+/// crashed, returning the owner recorded in it, or `None` if there is none.
+/// Fail with `Error::Locked` if the directory is locked. Opening the directory
+/// takes such a file over anyway, this only tidies it up.
+pub fn force_unlock(dir: &str) -> Result<Option<LockOwner>> {
+    let path = Path::new(dir).join(LOCK_FILE_NAME);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !try_flock(&file)? {
+        return Err(locked_error(Path::new(dir), &path));
+    }
+    let owner = read_owner(&path).ok().flatten();
+    fs::remove_file(&path)?;
+    File::open(dir)?.sync_all()?;
+    match owner {
+        Some(owner) => info!("Removed lock of {} held by exited {}", dir, owner),
+        None => info!("Removed lock of {}", dir),
+    }
+    Ok(owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_lock() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_dir_lock")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        assert_eq!(lock_owner(path).unwrap(), None);
+        assert_eq!(force_unlock(path).unwrap(), None);
+
+        let lock = DirLock::acquire(dir.path()).unwrap();
+        let owner = lock_owner(path).unwrap().unwrap();
+        assert_eq!(owner, LockOwner::current());
+        assert!(owner.is_alive());
+        // A live owner is protected.
+        match DirLock::acquire(dir.path()) {
+            Err(Error::Locked(_, holder, true)) => assert_eq!(holder, owner),
+            _ => panic!("locked twice"),
+        }
+        assert!(matches!(force_unlock(path), Err(Error::Locked(..))));
+        drop(lock);
+        assert_eq!(lock_owner(path).unwrap(), None);
+
+        // Left by an exited process, the file is taken over. Another process
+        // reusing its pid is told apart by the start time.
+        let lock_path = dir.path().join(LOCK_FILE_NAME);
+        let mut stale_owners = vec![LockOwner {
+            pid: 0x7fff_fff0,
+            start_time: None,
+        }];
+        if let Some(start_time) = owner.start_time {
+            stale_owners.push(LockOwner {
+                pid: owner.pid,
+                start_time: Some(start_time + 1),
+            });
+        }
+        for stale in stale_owners {
+            fs::write(&lock_path, stale.encode()).unwrap();
+            assert!(!stale.is_alive());
+            assert_eq!(lock_owner(path).unwrap(), Some(stale));
+            let lock = DirLock::acquire(dir.path()).unwrap();
+            assert_eq!(lock_owner(path).unwrap(), Some(owner));
+            drop(lock);
+            fs::write(&lock_path, stale.encode()).unwrap();
+            assert_eq!(force_unlock(path).unwrap(), Some(stale));
+            assert_eq!(lock_owner(path).unwrap(), None);
+        }
+
+        fs::write(&lock_path, b"broken").unwrap();
+        assert!(lock_owner(path).is_err());
+        drop(DirLock::acquire(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_reopen_after_crash() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_reopen_after_crash")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        let lock = DirLock::acquire(dir.path()).unwrap();
+        // A crash closes the file without removing it.
+        unsafe { libc::close(lock.file.as_raw_fd()) };
+        std::mem::forget(lock);
+        assert_eq!(lock_owner(path).unwrap(), Some(LockOwner::current()));
+
+        let engine = crate::FileEngine::open(crate::Config {
+            dir: path.to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            DirLock::acquire(dir.path()),
+            Err(Error::Locked(_, _, true))
+        ));
+        drop(engine);
+        assert_eq!(lock_owner(path).unwrap(), None);
+    }
+}
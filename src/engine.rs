@@ -1,40 +1,64 @@
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::BufRead;
-use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::ops::Range;
+use std::sync::atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::{cmp, fmt, mem, u64};
 
-use protobuf::Message as PbMsg;
 use raft::eraftpb::Entry;
 
-use crate::util::{HashMap, HashSet, RAFT_LOG_STATE_KEY};
+use crate::util::{to_usize, HashMap, HashSet, RAFT_LOG_STATE_KEY};
 
-use crate::config::Config;
+use crate::clock::{Clock, SystemClock};
+use crate::cold_storage::ObjectStorage;
+pub use crate::config::RecoveryMode;
+use crate::config::{Config, MemTableType};
+use crate::dictionary::{Dictionaries, Dictionary};
+use crate::entry_cache::EntryCache;
+use crate::entry_codec;
+use crate::format::{self, CompressionType, Timestamp, CHECKSUM_LEN, HEADER_LEN};
+use crate::hot_region::{HotRegions, RecentRegions, RegionWrites};
 use crate::log_batch::{
-    self, Command, CompressionType, LogBatch, LogItemType, OpType, CHECKSUM_LEN, HEADER_LEN,
+    self, BatchSummary, Command, EntryRetention, KeyValue, LogBatch, LogItem, LogItemType, OpType,
 };
-use crate::memtable::{EntryIndex, MemTable};
+use crate::memory::{MemoryCharge, MemoryComponent, MemoryLimiter, MemoryTrace, TracedBuffer};
+use crate::memtable::{EntryIndex, KvValue, MemTable, MemTableAccessor, MemTableFactory};
 use crate::metrics::*;
-use crate::pipe_log::{PipeLog, FILE_MAGIC_HEADER, VERSION};
-use crate::{codec, CacheStats, RaftEngine, RaftLocalState, Result};
+use crate::pipe_log::{self, FilePin, PipeLog, FILE_HEADER_LEN};
+use crate::prefetch::{PrefetchRequest, Prefetcher};
+use crate::recovery_observer::RecoveryObserver;
+use crate::worker::{TaskHealth, Watchdog, Worker};
+use crate::{codec, CacheStats, Error, RaftEngine, RaftLocalState, Result};
 
-const SLOTS_COUNT: usize = 128;
+mod apply;
+mod generation;
+mod holes;
+mod snapshot;
+mod strict_append;
+mod write_buffer;
 
-#[derive(Clone, Copy, Debug)]
-#[repr(i32)]
-pub enum RecoveryMode {
-    TolerateCorruptedTailRecords = 0,
-    AbsoluteConsistency = 1,
-}
+use self::apply::{Applier, PendingWrites};
+use self::generation::Generation;
+pub use self::snapshot::Snapshot;
+use self::snapshot::SnapshotState;
+use self::write_buffer::WriteBuffer;
 
-impl From<i32> for RecoveryMode {
-    fn from(i: i32) -> RecoveryMode {
-        assert!(
-            RecoveryMode::TolerateCorruptedTailRecords as i32 <= i
-                && i <= RecoveryMode::AbsoluteConsistency as i32
-        );
-        unsafe { mem::transmute(i) }
-    }
+const SLOTS_COUNT: usize = 128;
+const EXPORT_BATCH_ENTRIES: u64 = 1024;
+const HOT_REGIONS_CAPACITY: usize = 256;
+// Bytes of entries read ahead for sequential fetches.
+const PREFETCH_CAPACITY: u64 = 16 * 1024 * 1024;
+// Count of hot regions labeled in metrics.
+const HOT_REGIONS_REPORTED: usize = 10;
+
+// Where an entry is found, a file is pinned until the location is dropped.
+enum EntryLocation<'a> {
+    Cached(Entry),
+    File(EntryIndex, FilePin<'a>),
 }
 
 struct FileEngineInner {
@@ -42,17 +66,152 @@ struct FileEngineInner {
 
     // Multiple slots
     // region_id -> MemTable.
-    memtables: Vec<RwLock<HashMap<u64, MemTable>>>,
+    memtables: Vec<RwLock<HashMap<u64, Box<dyn MemTableAccessor>>>>,
 
     // Persistent entries.
     pipe_log: PipeLog,
 
     cache_stats: Arc<SharedCacheStats>,
+
+    // Receivers of summaries of written batches.
+    subscribers: Subscribers<BatchSummary>,
+    // Receivers of tokens of writes become durable.
+    durable_subscribers: Subscribers<WriteToken>,
+    // The last token sent to them, held while sending so that tokens are sent in
+    // order.
+    durable_notified: Mutex<WriteToken>,
+
+    // For an observer, the position after the last applied batch.
+    tail_position: Mutex<(u64, u64)>,
+
+    // Count of regions rewritten since opened.
+    rewrites: AtomicU64,
+    rewrite_tuner: Mutex<RewriteTuner>,
+
+    // Bytes written by users and by rewrites since opened.
+    foreground_bytes: AtomicU64,
+    rewrite_bytes: AtomicU64,
+    // Batches read from files but not applied, as batches of the same or later
+    // sequences are applied already.
+    skipped_batches: AtomicU64,
+
+    memory_limiter: Option<Arc<dyn MemoryLimiter>>,
+    // Caches entries instead of memtables if set.
+    entry_cache: Option<Arc<dyn EntryCache>>,
+    // Creates memtables instead of `cfg.memtable_type` if set.
+    memtable_factory: Option<Arc<dyn MemTableFactory>>,
+
+    // Notified of changes applied during recovery only, dropped once recovered.
+    recovery_observer: Option<Arc<dyn RecoveryObserver>>,
+
+    // Regions writing the most bytes recently.
+    hot_regions: Mutex<HotRegions>,
+    // Regions by their last read or write, for picking ones to evict or rewrite.
+    // Sharded like memtables, so that touching one only contends within its slot.
+    recent_regions: RecentRegions,
+
+    // Serializes conditional puts.
+    put_if_lock: Mutex<()>,
+
+    // With `strict_append`, held from checking a batch to appending it, and the
+    // (sequence, last index) of regions appended by batches not applied yet, or
+    // `None` if cleaned, so that concurrent appends are checked in order.
+    strict_appends: Mutex<HashMap<u64, (u64, Option<u64>)>>,
+
+    // Regions with clean commands being written, and how many. They are not
+    // rewritten, or a rewrite written after the clean command brings them back on
+    // recovery.
+    cleaning: Mutex<HashMap<u64, usize>>,
+
+    // The latest clean command of each region. Rewritten items carry the sequence
+    // of when they were read, and are dropped if a later clean is known.
+    generations: Mutex<HashMap<u64, Generation>>,
+
+    // Alive snapshots, see `Snapshot`.
+    snapshots: RwLock<Vec<Arc<SnapshotState>>>,
+
+    // Writes not applied to memtables yet, notified when one is done.
+    pending_writes: Mutex<PendingWrites>,
+    writes_done: Condvar,
+    // Applies written batches to memtables if `async_apply` is set.
+    applier: Option<Applier>,
+    // Buffers writes without sync if `write_buffer_size` is set.
+    write_buffer: Option<WriteBuffer>,
+
+    prefetcher: Prefetcher,
+
+    // Drives background tasks.
+    clock: Arc<dyn Clock>,
+    // Health of background tasks, gone once they're stopped.
+    background_tasks: Mutex<Vec<Weak<Mutex<TaskHealth>>>>,
+
+    metrics: Arc<EngineMetrics>,
+}
+
+// Where recovery evicts cached entries: entries of files before `file_num` are
+// evicted from slots before `slot`, and from all slots for earlier files.
+struct EvictCursor {
+    file_num: u64,
+    slot: usize,
+}
+
+// Receivers of messages sent by writers, who check for them without locking, and
+// send to them with the lock released, so that concurrent writers don't wait for
+// each other.
+struct Subscribers<T> {
+    count: AtomicUsize,
+    senders: Mutex<Vec<Arc<Sender<T>>>>,
+}
+
+impl<T: Clone> Subscribers<T> {
+    fn new() -> Subscribers<T> {
+        Subscribers {
+            count: AtomicUsize::new(0),
+            senders: Mutex::new(vec![]),
+        }
+    }
+
+    fn subscribe(&self) -> Receiver<T> {
+        let (tx, rx) = mpsc::channel();
+        let mut senders = self.senders.lock().unwrap();
+        senders.push(Arc::new(tx));
+        self.count.store(senders.len(), Ordering::Release);
+        rx
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    fn send(&self, msg: T) {
+        if self.is_empty() {
+            return;
+        }
+        let senders = self.senders.lock().unwrap().clone();
+        // Receivers are gone if sending fails.
+        let gone: Vec<_> = senders
+            .into_iter()
+            .filter(|tx| tx.send(msg.clone()).is_err())
+            .collect();
+        if !gone.is_empty() {
+            let mut senders = self.senders.lock().unwrap();
+            senders.retain(|tx| !gone.iter().any(|g| Arc::ptr_eq(tx, g)));
+            self.count.store(senders.len(), Ordering::Release);
+        }
+    }
+}
+
+impl Drop for FileEngineInner {
+    fn drop(&mut self) {
+        // Writes accepted are written as if they weren't buffered.
+        self.flush_in_background();
+    }
 }
 
 impl FileEngineInner {
-    // recover from disk.
-    fn recover(&mut self, recovery_mode: RecoveryMode) -> Result<()> {
+    // recover from disk, starting at the batch at `from`, (file_num, offset), if
+    // given. Batches before it are skipped, but their sequences are still needed.
+    fn recover(&mut self, recovery_mode: RecoveryMode, from: Option<(u64, u64)>) -> Result<()> {
         // Get first file number and last file number.
         let (first_file_num, active_file_num) = {
             (
@@ -60,742 +219,6406 @@ impl FileEngineInner {
                 self.pipe_log.active_file_num(),
             )
         };
+        let (from_file, from_offset) = from.unwrap_or((first_file_num, 0));
+        if from_file < first_file_num || from_file > active_file_num {
+            return Err(box_err!(
+                "Can't recover from file {}, the files are {} to {}",
+                from_file,
+                first_file_num,
+                active_file_num
+            ));
+        }
+        self.pipe_log.seek_read_file(from_file);
 
         let start = Instant::now();
 
         // Iterate files one by one
-        let mut current_read_file = first_file_num;
+        let mut current_read_file = from_file;
+        let mut latest_sequence = 0;
+        let mut evict_cursor = EvictCursor {
+            file_num: from_file,
+            slot: SLOTS_COUNT,
+        };
         loop {
             if current_read_file > active_file_num {
                 break;
             }
 
             // Read a file
-            let content = {
-                self.pipe_log
-                    .read_next_file()
-                    .unwrap_or_else(|e| {
-                        panic!(
-                            "Read content of file {} failed, error {:?}",
-                            current_read_file, e
-                        )
-                    })
-                    .unwrap_or_else(|| panic!("Expect has content, but get None"))
+            let content = match self.pipe_log.read_next_file()? {
+                Some(content) => content,
+                None => return Err(box_err!("File {} disappeared", current_read_file)),
             };
+            let _charge = self.charge_recovery_buffer(current_read_file, content.len() as u64)?;
+            let _traced = TracedBuffer::new(
+                &self.cache_stats.memory_trace,
+                MemoryComponent::RecoveryBuffer,
+                content.len() as u64,
+            );
 
             // Verify file header
             let mut buf = content.as_slice();
-            if buf.len() < FILE_MAGIC_HEADER.len() || !buf.starts_with(FILE_MAGIC_HEADER) {
-                if current_read_file != active_file_num {
-                    panic!("Raft log file {} is corrupted.", current_read_file);
-                } else {
-                    self.pipe_log.truncate_active_log(0).unwrap();
-                    break;
-                }
+            if current_read_file == active_file_num
+                && self.pipe_log.is_read_only()
+                && buf.len() < FILE_HEADER_LEN
+            {
+                // The writer may be writing the header.
+                *self.tail_position.get_mut().unwrap() = (current_read_file, 0);
+                break;
             }
+            let header_len = pipe_log::check_file_header(current_read_file, buf)?;
 
             // Iterate all LogBatch in one file
             let start_ptr = buf.as_ptr();
-            buf.consume(FILE_MAGIC_HEADER.len() + VERSION.len());
-            let mut offset = (FILE_MAGIC_HEADER.len() + VERSION.len()) as u64;
+            buf.consume(header_len);
+            let mut offset = header_len as u64;
+            // The active file may end with a torn batch, which only its checksum
+            // tells apart.
+            let decode = if self.cfg.skip_recovery_checksum && current_read_file < active_file_num {
+                LogBatch::from_trusted_bytes
+            } else {
+                LogBatch::from_bytes
+            };
             loop {
-                match LogBatch::from_bytes(&mut buf, current_read_file, offset) {
+                // Batches before the start are reflected in the state of the caller,
+                // and only parsed.
+                let skipped = current_read_file == from_file && offset < from_offset;
+                let decode = if skipped {
+                    LogBatch::from_trusted_bytes
+                } else {
+                    decode
+                };
+                match decode(
+                    &mut buf,
+                    current_read_file,
+                    offset,
+                    self.pipe_log.dictionaries(),
+                ) {
                     Ok(Some(log_batch)) => {
-                        self.apply_to_memtable(log_batch, current_read_file);
+                        self.pipe_log
+                            .record_batch_time(current_read_file, log_batch.timestamp);
+                        if skipped {
+                            latest_sequence = cmp::max(latest_sequence, log_batch.sequence);
+                            offset = (buf.as_ptr() as usize - start_ptr as usize) as u64;
+                            if offset > from_offset {
+                                return Err(box_err!(
+                                    "Can't recover from offset {} of file {}, it's in the batch \
+                                     ending at {}",
+                                    from_offset,
+                                    from_file,
+                                    offset
+                                ));
+                            }
+                        } else if log_batch.sequence > latest_sequence {
+                            latest_sequence = log_batch.sequence;
+                            let mut log_batch = log_batch;
+                            if !self.make_room_in_cache(
+                                entries_bytes(&log_batch),
+                                &mut evict_cursor,
+                                current_read_file,
+                            ) {
+                                // Larger than the whole cache, it's indexed only.
+                                drop_entries(&mut log_batch);
+                            }
+                            self.apply_to_memtable(log_batch, current_read_file);
+                        } else {
+                            // A batch written twice, or a stale one. Applying it
+                            // again may bring back overwritten data.
+                            self.skipped_batches.fetch_add(1, Ordering::Relaxed);
+                            warn!(
+                                "[{}] Skip duplicate or out of order batch with sequence {} \
+                                 in file {}, offset {}, the latest sequence is {}.",
+                                self.cfg.name,
+                                log_batch.sequence,
+                                current_read_file,
+                                offset,
+                                latest_sequence
+                            );
+                        }
                         offset = (buf.as_ptr() as usize - start_ptr as usize) as u64;
                     }
+                    Ok(None) if skipped => {
+                        return Err(box_err!(
+                            "Can't recover from offset {} of file {}, it ends at {}",
+                            from_offset,
+                            from_file,
+                            offset
+                        ));
+                    }
                     Ok(None) => {
-                        info!("Recovered raft log file {}.", current_read_file);
+                        if current_read_file == active_file_num
+                            && !buf.is_empty()
+                            && !self.pipe_log.is_read_only()
+                        {
+                            // Drop whatever is left after the barrier, and continue
+                            // writing from it.
+                            self.pipe_log.truncate_active_log(offset)?;
+                        }
+                        info!(
+                            "[{}] Recovered raft log file {}.",
+                            self.cfg.name, current_read_file
+                        );
                         break;
                     }
                     Err(e) => {
                         // There may be a pre-allocated space at the tail of the active log.
-                        if current_read_file == active_file_num {
+                        if current_read_file == active_file_num && self.pipe_log.is_read_only() {
+                            // The batch may be being written.
+                            break;
+                        } else if current_read_file == active_file_num
+                            && format::is_unwritten(&content[offset as usize..])
+                        {
+                            // Nothing is written after the last batch, e.g. the size of
+                            // the file is persisted before its data by a crash. It's not
+                            // corruption, so it's dropped in any recovery mode.
+                            info!(
+                                "[{}] Truncate unwritten tail of last log file {} from offset {}.",
+                                self.cfg.name, current_read_file, offset
+                            );
+                            self.pipe_log.truncate_active_log(offset)?;
+                            break;
+                        } else if current_read_file == active_file_num {
                             match recovery_mode {
                                 RecoveryMode::TolerateCorruptedTailRecords => {
                                     warn!(
-                                        "Encounter err {:?}, incomplete batch in last log file {}, \
+                                        "[{}] Encounter err {:?}, incomplete batch in last log file {}, \
                                          offset {}, truncate it in TolerateCorruptedTailRecords \
-                                         recovery mode.",
+                                         recovery mode.", self.cfg.name,
                                         e,
                                         current_read_file,
                                         offset
                                     );
-                                    self.pipe_log.truncate_active_log(offset as usize).unwrap();
+                                    self.pipe_log.truncate_active_log(offset)?;
                                     break;
                                 }
                                 RecoveryMode::AbsoluteConsistency => {
-                                    panic!(
-                                        "Encounter err {:?}, incomplete batch in last log file {}, \
-                                         offset {}, panic in AbsoluteConsistency recovery mode.",
-                                        e,
+                                    return Err(Error::Corruption(
                                         current_read_file,
-                                        offset
-                                    );
+                                        offset,
+                                        format!(
+                                            "incomplete batch in last log file in \
+                                             AbsoluteConsistency recovery mode: {}",
+                                            e
+                                        ),
+                                    ));
                                 }
                             }
                         } else {
-                            panic!("Corruption occur in middle log file {}", current_read_file);
+                            return Err(Error::Corruption(
+                                current_read_file,
+                                offset,
+                                format!("corrupted batch in middle log file: {}", e),
+                            ));
                         }
                     }
                 }
             }
 
-            // Only keep latest entries in cache, keep cache below limited size.
-            if self.cfg.cache_size_limit.0 > 0
-                && (current_read_file - first_file_num) * self.cfg.target_file_size.0
-                    > self.cfg.cache_size_limit.0
-            {
-                let total_files_in_cache =
-                    self.cfg.cache_size_limit.0 / self.cfg.target_file_size.0;
-                if current_read_file > total_files_in_cache {
-                    for memtables in &self.memtables {
-                        let mut memtables = memtables.write().unwrap();
-                        for memtable in memtables.values_mut() {
-                            memtable.evict_old_from_cache(current_read_file - total_files_in_cache);
-                        }
-                    }
-                }
+            if current_read_file == active_file_num {
+                *self.tail_position.get_mut().unwrap() = (current_read_file, offset);
             }
 
             current_read_file += 1;
         }
 
-        info!("Recover raft log takes {:?}", start.elapsed());
+        if latest_sequence == 0 && from_file > first_file_num {
+            // Nothing is recovered, new batches follow the skipped ones.
+            latest_sequence = self.last_sequence_before(from_file)?;
+        }
+        self.pipe_log.set_latest_sequence(latest_sequence);
+        info!(
+            "[{}] Recover raft log takes {:?}",
+            self.cfg.name,
+            start.elapsed()
+        );
 
         Ok(())
     }
 
-    fn apply_to_memtable(&self, log_batch: LogBatch, file_num: u64) {
-        for item in log_batch.items.borrow_mut().drain(..) {
-            match item.item_type {
-                LogItemType::Entries => {
-                    let entries_to_add = item.entries.unwrap();
-                    let region_id = entries_to_add.region_id;
-                    let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-                        .write()
-                        .unwrap();
-                    let memtable = memtables.entry(region_id).or_insert_with(|| {
-                        let cache_limit = self.cfg.region_size.0 / 2;
-                        let cache_stats = self.cache_stats.clone();
-                        MemTable::new(region_id, cache_limit, cache_stats)
-                    });
-                    memtable.append(
-                        entries_to_add.entries,
-                        entries_to_add.entries_index.into_inner(),
-                    );
+    // The sequence of the last batch in files before `file_num`.
+    fn last_sequence_before(&self, file_num: u64) -> Result<u64> {
+        for file_num in (self.pipe_log.first_file_num()..file_num).rev() {
+            let content = self.pipe_log.read_file(file_num)?;
+            let mut buf = content.as_slice();
+            let mut offset = pipe_log::check_file_header(file_num, buf)? as u64;
+            buf.consume(offset as usize);
+            let mut latest_sequence = 0;
+            while let Some(log_batch) = LogBatch::from_trusted_bytes(
+                &mut buf,
+                file_num,
+                offset,
+                self.pipe_log.dictionaries(),
+            )? {
+                latest_sequence = cmp::max(latest_sequence, log_batch.sequence);
+                offset = (content.len() - buf.len()) as u64;
+            }
+            if latest_sequence > 0 {
+                return Ok(latest_sequence);
+            }
+        }
+        Ok(0)
+    }
+
+    // Apply batches appended by the writer since last time, for an observer.
+    fn catch_up(&self) -> Result<usize> {
+        if !self.pipe_log.is_read_only() {
+            return Err(box_err!("Only an observer can catch up"));
+        }
+        let header_len = FILE_HEADER_LEN;
+        let mut tail_position = self.tail_position.lock().unwrap();
+        let mut applied = 0;
+        loop {
+            let (file_num, start_offset) = *tail_position;
+            // Check rotation before reading, so that the file is complete if rotated.
+            let rotated =
+                file_num < self.pipe_log.active_file_num() || self.pipe_log.follow_next_file()?;
+            let content = self.pipe_log.read_tail(file_num, start_offset)?;
+
+            let mut buf = content.as_slice();
+            let mut offset = start_offset;
+            if start_offset == 0 {
+                if buf.len() < header_len && !rotated {
+                    // The writer may be writing the header.
+                    break;
                 }
-                LogItemType::CMD => {
-                    let command = item.command.unwrap();
-                    match command {
-                        Command::Clean { region_id } => {
-                            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-                                .write()
-                                .unwrap();
-                            memtables.remove(&region_id);
+                pipe_log::check_file_header(file_num, buf)?;
+                buf.consume(header_len);
+                offset = header_len as u64;
+            }
+            loop {
+                match LogBatch::from_bytes(&mut buf, file_num, offset, self.pipe_log.dictionaries())
+                {
+                    Ok(Some(log_batch)) => {
+                        self.pipe_log
+                            .record_batch_time(file_num, log_batch.timestamp);
+                        if log_batch.sequence > self.pipe_log.latest_sequence() {
+                            self.pipe_log.set_latest_sequence(log_batch.sequence);
+                            self.apply_to_memtable(log_batch, file_num);
+                            applied += 1;
+                        } else {
+                            self.skipped_batches.fetch_add(1, Ordering::Relaxed);
                         }
+                        offset = start_offset + (content.len() - buf.len()) as u64;
                     }
-                }
-                LogItemType::KV => {
-                    let kv = item.kv.unwrap();
-                    let mut memtables = self.memtables[kv.region_id as usize % SLOTS_COUNT]
-                        .write()
-                        .unwrap();
-                    let memtable = memtables.entry(kv.region_id).or_insert_with(|| {
-                        let cache_limit = self.cfg.region_size.0 / 2;
-                        let stats = self.cache_stats.clone();
-                        MemTable::new(kv.region_id, cache_limit, stats)
-                    });
-                    match kv.op_type {
-                        OpType::Put => {
-                            memtable.put(kv.key, kv.value.unwrap(), file_num);
-                        }
-                        OpType::Del => {
-                            memtable.delete(kv.key.as_slice());
-                        }
+                    Ok(None) => break,
+                    Err(e) if rotated => {
+                        return Err(Error::Corruption(file_num, offset, e.to_string()));
                     }
+                    // The batch may be being written.
+                    Err(_) => break,
                 }
             }
+
+            if !rotated {
+                *tail_position = (file_num, offset);
+                break;
+            }
+            *tail_position = (file_num + 1, 0);
         }
+        drop(tail_position);
+        self.follow_purges()?;
+        Ok(applied)
     }
 
-    // Rewrite inactive region's entries and key/value pairs,
-    // so the old files can be dropped ASAP.
-    #[allow(dead_code)]
-    fn rewrite_inactive(&self) -> bool {
-        let inactive_file_num = {
-            self.pipe_log
-                .files_before(self.cfg.cache_size_limit.0 as usize)
-        };
-
-        if inactive_file_num == 0 {
-            return false;
+    // For an observer, drop entries in files purged by the writer, which it has
+    // compacted, and close the files.
+    fn follow_purges(&self) -> Result<()> {
+        let first_file_num = self.pipe_log.writer_first_file_num();
+        if first_file_num == self.pipe_log.first_file_num() {
+            return Ok(());
         }
-
-        let mut has_write = false;
-        let mut memory_usage = 0;
-        for slot in 0..SLOTS_COUNT {
-            let mut memtables = self.memtables[slot].write().unwrap();
-            for memtable in memtables.values_mut() {
-                memory_usage += memtable.entries_size();
-
-                let min_file_num = match memtable.min_file_num() {
-                    Some(file_num) => file_num,
+        for memtables in &self.memtables {
+            let mut memtables = memtables.write().unwrap();
+            for (region_id, memtable) in memtables.iter_mut() {
+                let first_index = match memtable.first_index() {
+                    Some(index) => index,
                     None => continue,
                 };
-
-                // Has no entry in inactive files, skip.
-                if min_file_num >= inactive_file_num {
+                let mut index = first_index;
+                while memtable
+                    .entry_index(index)
+                    .map_or(false, |e| e.file_num < first_file_num)
+                {
+                    index += 1;
+                }
+                if index == first_index {
                     continue;
                 }
+                self.freeze_for_snapshots(*region_id, Some(memtable.as_ref()), None);
+                let cache_memory = memtable.cache_memory();
+                memtable.compact_to(index);
+                if let Some(cache) = &self.entry_cache {
+                    cache.evict(*region_id, index);
+                }
+                self.cache_stats
+                    .reclaim(cache_memory - memtable.cache_memory());
+            }
+        }
+        self.pipe_log.follow_purges(first_file_num)
+    }
 
-                // Has entries in inactive files, at the same time the total entries is less
-                // than `compact_threshold`, compaction will not be triggered, so we need rewrite
-                // these entries, so the old files can be dropped ASAP.
-                if memtable.entries_count() < self.cfg.compact_threshold {
-                    REWRITE_COUNTER.inc();
-                    REWRITE_ENTRIES_COUNT_HISTOGRAM.observe(memtable.entries_count() as f64);
-                    has_write = true;
-
-                    // Dump all entries
-                    // Not all entries are in cache always, we may need read remains
-                    // entries from file.
-                    let mut ents = Vec::with_capacity(memtable.entries_count());
-                    let mut ents_idx = Vec::with_capacity(memtable.entries_count());
-                    memtable.fetch_all(&mut ents, &mut ents_idx);
-                    let mut all_ents = Vec::with_capacity(memtable.entries_count());
-                    for i in ents_idx {
-                        let e = self.read_entry_from_file(&i).unwrap_or_else(|e| {
-                            panic!(
-                                "Read entry from file {} at offset {} failed \
-                                     when rewriting, err {:?}",
-                                i.file_num, i.offset, e
-                            )
-                        });
-                        all_ents.push(e);
-                    }
-                    all_ents.extend(ents.into_iter());
-                    let log_batch = LogBatch::new();
-                    log_batch.add_entries(memtable.region_id(), all_ents);
-
-                    // Dump all key value pairs
-                    let mut kvs = vec![];
-                    memtable.fetch_all_kvs(&mut kvs);
-                    for kv in &kvs {
-                        log_batch.put(memtable.region_id(), &kv.0, &kv.1);
-                    }
-
-                    // Rewrite to new log file
-                    let mut file_num = 0;
-                    self.pipe_log
-                        .append_log_batch(&log_batch, false, &mut file_num)
-                        .unwrap();
+    // Verify checksums of all batches in all files, without touching memtables.
+    fn verify_files(&self, recovery_mode: RecoveryMode) -> Result<()> {
+        let first_file_num = self.pipe_log.first_file_num();
+        let active_file_num = self.pipe_log.active_file_num();
+        let start = Instant::now();
 
-                    // Apply to memtable.
-                    // FIXME: using slef.apply_to_memtable here will cause deadlock.
-                    for item in log_batch.items.borrow_mut().drain(..) {
-                        match item.item_type {
-                            LogItemType::Entries => {
-                                let entries_to_add = item.entries.unwrap();
-                                assert_eq!(entries_to_add.region_id, memtable.region_id());
-                                memtable.append(
-                                    entries_to_add.entries,
-                                    entries_to_add.entries_index.into_inner(),
-                                );
-                            }
-                            LogItemType::CMD => {
-                                panic!("Memtable doesn't contain command item.");
-                            }
-                            LogItemType::KV => {
-                                let kv = item.kv.unwrap();
-                                assert_eq!(kv.region_id, memtable.region_id());
-                                match kv.op_type {
-                                    OpType::Put => {
-                                        memtable.put(kv.key, kv.value.unwrap(), file_num);
-                                    }
-                                    OpType::Del => {
-                                        memtable.delete(kv.key.as_slice());
-                                    }
-                                }
+        let mut corruptions = vec![];
+        for file_num in first_file_num..=active_file_num {
+            let content = self.pipe_log.scan_file(file_num)?;
+            if let Err(e) = verify_file(file_num, &content, self.pipe_log.dictionaries()) {
+                // A broken tail of the active log will be truncated by recovery.
+                if file_num == active_file_num {
+                    // Corruption at the end, like a partially written file header,
+                    // isn't followed by an unwritten tail.
+                    if let Error::Corruption(_, offset, _) = &e {
+                        match content.get(*offset as usize..) {
+                            Some(tail) if !tail.is_empty() && format::is_unwritten(tail) => {
+                                continue
                             }
+                            _ => {}
                         }
                     }
+                    if let RecoveryMode::TolerateCorruptedTailRecords = recovery_mode {
+                        warn!(
+                            "[{}] Tolerate corrupted tail of active log file: {}",
+                            self.cfg.name, e
+                        );
+                        continue;
+                    }
                 }
+                error!("[{}] {}", self.cfg.name, e);
+                corruptions.push(e);
             }
         }
-        RAFTENGINE_MEMORY_USAGE_GAUGE.set(memory_usage as f64);
 
-        has_write
+        info!(
+            "[{}] Verify raft log files [{}, {}] takes {:?}",
+            self.cfg.name,
+            first_file_num,
+            active_file_num,
+            start.elapsed()
+        );
+        if !corruptions.is_empty() {
+            return Err(box_err!(
+                "{} corrupted raft log files: {:?}",
+                corruptions.len(),
+                corruptions
+            ));
+        }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    fn regions_need_force_compact(&self) -> HashSet<u64> {
-        // first_file_num: the oldest file number.
-        // current_file_num: current file number.
-        // inactive_file_num: files before this one should not in cache.
-        // gc_file_num: entries in these files should compact by force.
-        let (inactive_file_num, gc_file_num) = {
-            (
-                self.pipe_log
-                    .files_before(self.cfg.cache_size_limit.0 as usize),
-                self.pipe_log
-                    .files_before(self.cfg.total_size_limit.0 as usize),
-            )
-        };
-
-        let mut regions = HashSet::default();
-        let region_entries_size_limit = self.cfg.region_size.0 * 2 / 3;
-        for slot in 0..SLOTS_COUNT {
-            let memtables = self.memtables[slot].read().unwrap();
-            for memtable in memtables.values() {
-                // Total size of entries for this region exceed limit.
-                if memtable.entries_size() > region_entries_size_limit {
-                    info!(
-                        "region {}'s total raft log size {} exceed limit \
-                         need force compaction",
-                        memtable.region_id(),
-                        memtable.entries_size(),
-                    );
-                    regions.insert(memtable.region_id());
-                    continue;
-                }
-
-                if inactive_file_num == 0 || gc_file_num == 0 {
-                    continue;
-                }
-
-                let min_file_num = match memtable.min_file_num() {
-                    Some(file_num) => file_num,
-                    None => continue,
+    // Decode all batches in files, and collect items of the region.
+    fn dump_region(&self, region_id: u64) -> Result<Vec<DumpItem>> {
+        let active_file_num = self.pipe_log.active_file_num();
+        let mut items = vec![];
+        for file_num in self.pipe_log.first_file_num()..=active_file_num {
+            let content = self.pipe_log.scan_file(file_num)?;
+            let header_len = pipe_log::check_file_header(file_num, &content)?;
+            let mut buf = &content[header_len..];
+            let mut offset = header_len as u64;
+            loop {
+                let log_batch = match LogBatch::from_bytes(
+                    &mut buf,
+                    file_num,
+                    offset,
+                    self.pipe_log.dictionaries(),
+                ) {
+                    Ok(Some(log_batch)) => log_batch,
+                    Ok(None) => break,
+                    // The tail of the active file may be being written.
+                    Err(_) if file_num == active_file_num => break,
+                    Err(e) => return Err(Error::Corruption(file_num, offset, e.to_string())),
                 };
-
-                // Has entries left behind too far, this happens when
-                // some followers left behind for a long time.
-                if min_file_num < gc_file_num {
-                    info!(
-                        "region {}'s some followers left behind too far, \
-                         need force compaction",
-                        memtable.region_id()
-                    );
-                    regions.insert(memtable.region_id());
+                for item in log_batch.items.borrow_mut().drain(..) {
+                    let content = match item.item_type {
+                        LogItemType::Entries => {
+                            let entries = item.entries.unwrap();
+                            if entries.region_id != region_id {
+                                continue;
+                            }
+                            DumpContent::Entries(entries.entries_index.into_inner().into_vec())
+                        }
+                        LogItemType::CMD => match item.command.unwrap() {
+                            Command::Clean { region_id: id } if id == region_id => {
+                                DumpContent::Clean
+                            }
+                            _ => continue,
+                        },
+                        LogItemType::KV => {
+                            let kv = item.kv.unwrap();
+                            if kv.region_id != region_id {
+                                continue;
+                            }
+                            match kv.op_type {
+                                OpType::Put => DumpContent::Put(kv.key, kv.value.unwrap()),
+                                OpType::Del => DumpContent::Delete(kv.key),
+                            }
+                        }
+                    };
+                    items.push(DumpItem {
+                        file_num,
+                        offset,
+                        sequence: log_batch.sequence,
+                        timestamp: log_batch.timestamp,
+                        content,
+                    });
                 }
+                offset = (content.len() - buf.len()) as u64;
             }
         }
-        NEED_COMPACT_REGIONS_HISTOGRAM.observe(regions.len() as f64);
-
-        regions
+        Ok(items)
     }
 
-    #[allow(dead_code)]
-    fn evict_old_from_cache(&self) {
-        let inactive_file_num = self
-            .pipe_log
-            .files_before(self.cfg.cache_size_limit.0 as usize);
-
-        if inactive_file_num == 0 {
+    // Verify the next inactive file after `next_file_num`, wrapping around to the
+    // oldest one once all inactive files are visited.
+    fn scrub_next_file(&self, next_file_num: &mut u64, listener: &dyn Fn(&Error)) {
+        let first_file_num = self.pipe_log.first_file_num();
+        let active_file_num = self.pipe_log.active_file_num();
+        if *next_file_num < first_file_num || *next_file_num >= active_file_num {
+            *next_file_num = first_file_num;
+        }
+        // The active file is still being written.
+        if *next_file_num >= active_file_num {
             return;
         }
 
-        for slot in 0..SLOTS_COUNT {
-            let mut memtables = self.memtables[slot].write().unwrap();
-            for memtable in memtables.values_mut() {
-                memtable.evict_old_from_cache(inactive_file_num);
-            }
+        let file_num = *next_file_num;
+        *next_file_num += 1;
+        let res = self
+            .pipe_log
+            .scan_file(file_num)
+            .and_then(|content| verify_file(file_num, &content, self.pipe_log.dictionaries()));
+        if file_num < self.pipe_log.first_file_num() {
+            // Purged during scrubbing.
+            return;
+        }
+        self.metrics.scrub_files.inc();
+        if let Err(e) = res {
+            error!(
+                "[{}] Scrub raft log file {} failed: {}",
+                self.cfg.name, file_num, e
+            );
+            self.metrics.scrub_corruptions.inc();
+            listener(&e);
         }
     }
 
-    #[allow(dead_code)]
-    fn purge_expired_files(&self) -> Result<()> {
-        let mut min_file_num = u64::MAX;
-        for memtables in &self.memtables {
-            let memtables = memtables.read().unwrap();
-            let file_num = memtables.values().fold(u64::MAX, |min, x| {
-                cmp::min(min, x.min_file_num().map_or(u64::MAX, |num| num))
-            });
-            if file_num < min_file_num {
-                min_file_num = file_num;
+    // Charge a buffer of recovery against the memory limiter, evicting the entry
+    // cache if the quota is exceeded.
+    fn charge_recovery_buffer(&self, file_num: u64, bytes: u64) -> Result<Option<MemoryCharge>> {
+        let limiter = match &self.memory_limiter {
+            Some(limiter) => limiter,
+            None => return Ok(None),
+        };
+        if let Some(charge) = MemoryCharge::try_new(limiter, bytes) {
+            return Ok(Some(charge));
+        }
+        // Regions recovered earliest are evicted first, until the buffer fits.
+        for region_id in self.coldest_regions(usize::MAX) {
+            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                .write()
+                .unwrap();
+            match memtables.get_mut(&region_id) {
+                Some(memtable) if memtable.cache_size() > 0 => {
+                    memtable.evict_old_from_cache(u64::MAX)
+                }
+                _ => continue,
+            }
+            drop(memtables);
+            if let Some(charge) = MemoryCharge::try_new(limiter, bytes) {
+                return Ok(Some(charge));
             }
         }
-
-        self.pipe_log.purge_to(min_file_num)
+        Err(box_err!(
+            "Memory quota is exceeded by {} bytes to recover raft log file {}",
+            bytes,
+            file_num
+        ))
     }
 
-    fn compact_to(&self, region_id: u64, index: u64) -> u64 {
-        let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-            .write()
-            .unwrap();
-        if let Some(memtable) = memtables.get_mut(&region_id) {
-            return memtable.compact_to(index);
-        }
-        0
+    // The `n` regions read or written least recently, the coldest first.
+    fn coldest_regions(&self, n: usize) -> Vec<u64> {
+        self.recent_regions.coldest(n)
     }
 
-    fn compact_cache_to(&self, region_id: u64, index: u64) {
-        let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-            .write()
-            .unwrap();
-        if let Some(memtable) = memtables.get_mut(&region_id) {
-            memtable.compact_cache_to(index);
+    fn new_memtable(&self, region_id: u64) -> Box<dyn MemTableAccessor> {
+        let cache_stats = self.cache_stats.clone();
+        if let Some(factory) = &self.memtable_factory {
+            return factory.new_memtable(region_id, cache_stats);
+        }
+        match self.cfg.memtable_type {
+            MemTableType::Deque => {
+                if self.cfg.cache_size_limit.0 == 0 || self.entry_cache.is_some() {
+                    return Box::new(MemTable::without_cache(region_id, cache_stats));
+                }
+                let cache_limit = self.cfg.region_size.0 / 2;
+                Box::new(MemTable::new(region_id, cache_limit, cache_stats))
+            }
         }
     }
 
-    fn write(&self, log_batch: LogBatch, sync: bool) -> Result<usize> {
-        let mut file_num = 0;
-        let bytes = self
-            .pipe_log
-            .append_log_batch(&log_batch, sync, &mut file_num)?;
-        self.post_append_to_file(log_batch, file_num);
-        Ok(bytes)
+    // Put a key value pair to the memtable, leaving the value in the file if it's
+    // too large to be kept in memory.
+    fn put_kv(&self, memtable: &mut dyn MemTableAccessor, kv: KeyValue, file_num: u64) {
+        let limit = self.cfg.max_memtable_value_size.0 as usize;
+        let value = kv.value.unwrap();
+        match kv.value_index.into_inner() {
+            Some(value_index) if limit > 0 && value.len() > limit && value_index.file_num != 0 => {
+                memtable.put_in_file(kv.key, value_index)
+            }
+            _ => memtable.put(kv.key, value, file_num),
+        }
     }
 
-    fn sync(&self) -> Result<()> {
-        self.pipe_log.sync();
-        Ok(())
+    // The value of a key value pair, read from the file if it's left there.
+    fn load_value(&self, value: KvValue) -> Result<Vec<u8>> {
+        match value {
+            KvValue::InMemory(value) => Ok(value),
+            KvValue::InFile(value_index) => self.read_entry_bytes_from_file(&value_index),
+        }
     }
 
-    #[allow(dead_code)]
-    fn kv_count(&self, region_id: u64) -> usize {
-        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-            .read()
-            .unwrap();
-        if let Some(memtable) = memtables.get(&region_id) {
-            return memtable.kvs_total_count();
+    // What's kept of written entries for memtables, see `new_memtable`.
+    fn entry_retention(&self) -> EntryRetention {
+        if self.entry_cache.is_some() {
+            EntryRetention::Decoded
+        } else if self.cfg.cache_size_limit.0 == 0 {
+            EntryRetention::None
+        } else if self.cfg.cache_encoded_entries {
+            EntryRetention::Encoded
+        } else {
+            EntryRetention::Decoded
         }
-        0
     }
 
-    fn put_msg<M: protobuf::Message>(&self, region_id: u64, key: &[u8], m: &M) -> Result<()> {
-        let log_batch = LogBatch::new();
-        log_batch.put_msg(region_id, key, m)?;
-        self.write(log_batch, false).map(|_| ())
-    }
+    // Evict cached entries of the oldest files recovered, slot by slot, until
+    // `bytes` more entries fit in `cache_size_limit`, so the cache doesn't outgrow
+    // it during recovery but by a chunk of the arena the entries are cached in.
+    // Return false if they never fit, as they're more than the limit.
+    fn make_room_in_cache(
+        &self,
+        bytes: u64,
+        cursor: &mut EvictCursor,
+        current_file_num: u64,
+    ) -> bool {
+        let limit = self.cfg.cache_size_limit.0;
+        if limit == 0 || self.entry_cache.is_some() {
+            return true;
+        }
+        if bytes > limit {
+            return false;
+        }
+        let trace = &self.cache_stats.memory_trace;
+        // Entries of the current file are evicted at last, in a round of all slots.
+        let mut last_round = false;
+        while trace.bytes(MemoryComponent::EntryCache) + bytes > limit {
+            if cursor.slot == SLOTS_COUNT {
+                if cursor.file_num > current_file_num {
+                    if last_round {
+                        break;
+                    }
+                    last_round = true;
+                }
+                cursor.file_num = cmp::min(cursor.file_num + 1, current_file_num + 1);
+                cursor.slot = 0;
+            }
+            let mut memtables = self.memtables[cursor.slot].write().unwrap();
+            for memtable in memtables.values_mut() {
+                memtable.evict_old_from_cache(cursor.file_num);
+            }
+            cursor.slot += 1;
+        }
+        true
+    }
 
-    fn get(&self, region_id: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-            .read()
-            .unwrap();
-        if let Some(memtable) = memtables.get(&region_id) {
-            Ok(memtable.get(key))
+    fn apply_to_memtable(&self, log_batch: LogBatch, file_num: u64) {
+        let stale = self.stale_regions(&log_batch, file_num);
+        let encoded = log_batch.encoded.borrow();
+        for item in log_batch.items.borrow_mut().drain(..) {
+            if !stale.is_empty() && stale.contains(&item_region_id(&item)) {
+                continue;
+            }
+            match item.item_type {
+                LogItemType::Entries => {
+                    let entries_to_add = item.entries.unwrap();
+                    let region_id = entries_to_add.region_id;
+                    let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                        .write()
+                        .unwrap();
+                    self.freeze_for_snapshots(
+                        region_id,
+                        memtables.get(&region_id).map(|m| m.as_ref()),
+                        Some(log_batch.sequence),
+                    );
+                    let memtable = memtables
+                        .entry(region_id)
+                        .or_insert_with(|| self.new_memtable(region_id));
+                    if let Some(cache) = &self.entry_cache {
+                        if !entries_to_add.entries.is_empty() {
+                            cache.insert(region_id, &entries_to_add.entries);
+                        }
+                    }
+                    let entries_index = entries_to_add.entries_index.into_inner();
+                    match encoded.as_deref() {
+                        Some(encoded) => memtable.append_encoded(encoded, entries_index),
+                        None => memtable.append(entries_to_add.entries, entries_index),
+                    }
+                    memtable.update_position(file_num, log_batch.offset);
+                    self.recent_regions.touch(region_id);
+                }
+                LogItemType::CMD => {
+                    let command = item.command.unwrap();
+                    match command {
+                        Command::Clean { region_id } => {
+                            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                                .write()
+                                .unwrap();
+                            self.freeze_for_snapshots(
+                                region_id,
+                                memtables.get(&region_id).map(|m| m.as_ref()),
+                                Some(log_batch.sequence),
+                            );
+                            if let Some(memtable) = memtables.remove(&region_id) {
+                                // The cache is released when the memtable is dropped.
+                                self.cache_stats.reclaim(memtable.cache_memory());
+                            }
+                            self.record_clean(region_id, log_batch.sequence, file_num);
+                            self.prefetcher.remove_region(region_id);
+                            self.recent_regions.remove(region_id);
+                            if let Some(observer) = &self.recovery_observer {
+                                observer.on_clean(region_id);
+                            }
+                            if let Some(cache) = &self.entry_cache {
+                                cache.remove_region(region_id);
+                            }
+                        }
+                        Command::Fence { .. } => {}
+                    }
+                }
+                LogItemType::KV => {
+                    let kv = item.kv.unwrap();
+                    let mut memtables = self.memtables[kv.region_id as usize % SLOTS_COUNT]
+                        .write()
+                        .unwrap();
+                    self.freeze_for_snapshots(
+                        kv.region_id,
+                        memtables.get(&kv.region_id).map(|m| m.as_ref()),
+                        Some(log_batch.sequence),
+                    );
+                    let memtable = memtables
+                        .entry(kv.region_id)
+                        .or_insert_with(|| self.new_memtable(kv.region_id));
+                    if let Some(observer) = &self.recovery_observer {
+                        match kv.op_type {
+                            OpType::Put => {
+                                observer.on_put(kv.region_id, &kv.key, kv.value.as_ref().unwrap())
+                            }
+                            OpType::Del => observer.on_delete(kv.region_id, &kv.key),
+                        }
+                    }
+                    let region_id = kv.region_id;
+                    match kv.op_type {
+                        OpType::Put => self.put_kv(memtable.as_mut(), kv, file_num),
+                        OpType::Del => memtable.delete(kv.key.as_slice()),
+                    }
+                    memtable.update_position(file_num, log_batch.offset);
+                    self.recent_regions.touch(region_id);
+                }
+            }
+        }
+    }
+
+    // Rewrite all entries and key/value pairs of the region to the active file,
+    // after a clean command of it if `clean`, which hides stale batches of it written
+    // before. The slot of the region must be locked for writing.
+    fn rewrite_memtable(&self, memtable: &mut dyn MemTableAccessor, clean: bool) -> Result<()> {
+        if self
+            .cleaning
+            .lock()
+            .unwrap()
+            .contains_key(&memtable.region_id())
+        {
+            // Removed when the clean command is applied.
+            return Ok(());
+        }
+        let _traced = TracedBuffer::new(
+            &self.cache_stats.memory_trace,
+            MemoryComponent::RewriteBuffer,
+            memtable.entries_size(),
+        );
+
+        // Dump all entries
+        // Not all entries are in cache always, we may need read remains
+        // entries from file.
+        let mut ents = Vec::with_capacity(memtable.entries_count());
+        let mut ents_idx = Vec::with_capacity(memtable.entries_count());
+        memtable.fetch_all(&mut ents, &mut ents_idx);
+        let mut all_ents = Vec::with_capacity(memtable.entries_count());
+        // Ranges read of each file, whose pages are dropped after the rewrite.
+        let mut read_ranges: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
+        for i in ents_idx {
+            all_ents.push(self.read_entry_from_file(&i)?);
+            read_ranges
+                .entry(i.file_num)
+                .or_default()
+                .push(entry_read_range(&i));
+        }
+        all_ents.extend(ents.into_iter());
+
+        // Dump all key value pairs
+        let mut values = vec![];
+        memtable.fetch_all_kv_values(&mut values);
+        let mut kvs = Vec::with_capacity(values.len());
+        for (key, value) in values {
+            if let KvValue::InFile(i) = &value {
+                read_ranges
+                    .entry(i.file_num)
+                    .or_default()
+                    .push(entry_read_range(i));
+            }
+            kvs.push((key, self.load_value(value)?));
+        }
+        if all_ents.is_empty() && kvs.is_empty() && !clean {
+            return Ok(());
+        }
+
+        // Clean commands of the region are applied with the slot locked, so none is
+        // written since the dump.
+        let region_id = memtable.region_id();
+        let generation = self.generation(region_id);
+        let mut log_batch = LogBatch::new();
+        log_batch.add_command(Command::Fence {
+            region_id,
+            generation,
+        });
+        if clean {
+            log_batch.add_command(Command::Clean { region_id });
+        }
+        if !all_ents.is_empty() {
+            log_batch.add_entries(region_id, all_ents);
+        }
+        for kv in &kvs {
+            log_batch.put(region_id, &kv.0, &kv.1);
+        }
+
+        // Rewrite to new log file
+        let mut file_num = 0;
+        let bytes = self
+            .pipe_log
+            .append_log_batch(&mut log_batch, false, &mut file_num)?;
+        if file_num != 0 {
+            self.rewrites.fetch_add(1, Ordering::Relaxed);
+            self.rewrite_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        // The rewritten data is read from the new copy since. Pages of the active
+        // file are left alone, as other regions' recent entries are read from them.
+        let active_file_num = self.pipe_log.active_file_num();
+        for (read_file_num, ranges) in read_ranges {
+            if read_file_num < active_file_num {
+                self.pipe_log.drop_page_cache(read_file_num, &ranges);
+            }
+        }
+
+        memtable.update_position(file_num, log_batch.offset);
+        if clean {
+            self.record_clean(region_id, log_batch.sequence, file_num);
+        }
+        // Apply to memtable.
+        // FIXME: using slef.apply_to_memtable here will cause deadlock.
+        for item in log_batch.items.borrow_mut().drain(..) {
+            match item.item_type {
+                LogItemType::Entries => {
+                    let entries_to_add = item.entries.unwrap();
+                    assert_eq!(entries_to_add.region_id, memtable.region_id());
+                    memtable.append(
+                        entries_to_add.entries,
+                        entries_to_add.entries_index.into_inner(),
+                    );
+                }
+                LogItemType::CMD => {
+                    // The fence of the region.
+                }
+                LogItemType::KV => {
+                    let kv = item.kv.unwrap();
+                    assert_eq!(kv.region_id, memtable.region_id());
+                    match kv.op_type {
+                        OpType::Put => self.put_kv(memtable, kv, file_num),
+                        OpType::Del => memtable.delete(kv.key.as_slice()),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Rewrite inactive region's entries and key/value pairs,
+    // so the old files can be dropped ASAP.
+    fn rewrite_inactive(&self) -> Result<bool> {
+        self.wait_written();
+        let (compact_threshold, inactive_size) = {
+            let mut tuner = self.rewrite_tuner.lock().unwrap();
+            tuner.update(self.pipe_log.total_size(), self.cfg.total_size_limit.0);
+            tuner.limits(&self.cfg)
+        };
+        self.metrics.rewrite_threshold.set(compact_threshold as f64);
+        // Retained files can't be purged, so they aren't rewritten either.
+        let inactive_file_num = cmp::min(
+            self.pipe_log.files_before(inactive_size),
+            self.retained_file_num(),
+        );
+
+        if inactive_file_num == 0 {
+            return Ok(false);
+        }
+
+        let mut candidates = HashSet::default();
+        let mut memory_usage = 0;
+        for memtables in &self.memtables {
+            for memtable in memtables.read().unwrap().values() {
+                memory_usage += memtable.entries_size();
+                if needs_rewrite(memtable.as_ref(), inactive_file_num, compact_threshold) {
+                    candidates.insert(memtable.region_id());
+                }
+            }
+        }
+        self.metrics.memory_usage.set(memory_usage as f64);
+
+        // Colder regions are rewritten first, as hot ones are likely to be compacted
+        // by their own gc soon.
+        let mut has_write = false;
+        for region_id in self.coldest_regions(usize::MAX) {
+            if !candidates.contains(&region_id) {
+                continue;
+            }
+            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                .write()
+                .unwrap();
+            let memtable = match memtables.get_mut(&region_id) {
+                Some(memtable) => memtable,
+                None => continue,
+            };
+            // Compacted or rewritten since checked.
+            if !needs_rewrite(memtable.as_ref(), inactive_file_num, compact_threshold) {
+                continue;
+            }
+            self.metrics.rewrites.inc();
+            self.metrics
+                .rewrite_entries_count
+                .observe(memtable.entries_count() as f64);
+            has_write = true;
+
+            self.rewrite_memtable(memtable.as_mut(), false)?;
+        }
+
+        Ok(has_write)
+    }
+
+    // Files older than the inactive threshold should be purged, after regions in
+    // them are rewritten if needed. Return the bytes of live entries in them and the
+    // count of them kept by live data.
+    fn rewrite_backlog(&self) -> (u64, u64) {
+        let inactive_file_num = self.inactive_file_num();
+        if inactive_file_num == 0 {
+            return (0, 0);
+        }
+        let mut backlog_bytes = 0;
+        for memtables in &self.memtables {
+            for memtable in memtables.read().unwrap().values() {
+                for (_, usage) in memtable.usage_before(inactive_file_num) {
+                    backlog_bytes += usage.entries_size;
+                }
+            }
+        }
+        let min_file_num = cmp::max(self.pipe_log.first_file_num(), self.min_file_num());
+        (
+            backlog_bytes,
+            inactive_file_num.saturating_sub(min_file_num),
+        )
+    }
+
+    fn thread_name(&self, task: &str) -> String {
+        format!("{}-{}", self.cfg.name, task)
+    }
+
+    fn update_metrics(&self) {
+        let (mut memory_usage, mut cache_usage) = (0, 0);
+        for (slot, memtables) in self.memtables.iter().enumerate() {
+            let memtables = memtables.read().unwrap();
+            for memtable in memtables.values() {
+                memory_usage += memtable.entries_size();
+                cache_usage += memtable.cache_memory();
+            }
+            self.metrics
+                .slot_regions_count(slot)
+                .set(memtables.len() as f64);
+        }
+        self.metrics.memory_usage.set(memory_usage as f64);
+        if let Some(cache) = &self.entry_cache {
+            cache_usage += cache.size();
+        }
+        self.metrics.cache_usage.set(cache_usage as f64);
+        for (component, bytes) in self.cache_stats.memory_trace.snapshot() {
+            self.metrics.memory_trace(component).set(bytes as f64);
+        }
+        {
+            let mut hot_regions = self.hot_regions.lock().unwrap();
+            self.metrics
+                .set_hot_regions(&hot_regions.top(HOT_REGIONS_REPORTED));
+            hot_regions.decay();
+        }
+        let files = self.pipe_log.active_file_num() - self.pipe_log.first_file_num() + 1;
+        self.metrics.pipe_files_count.set(files as f64);
+        let (backlog_bytes, blocked_files) = self.rewrite_backlog();
+        self.metrics.rewrite_backlog_bytes.set(backlog_bytes as f64);
+        self.metrics.purge_blocked_files.set(blocked_files as f64);
+        let foreground_bytes = self.foreground_bytes.load(Ordering::Relaxed);
+        if foreground_bytes > 0 {
+            let disk_bytes = self.pipe_log.disk_bytes_written();
+            self.metrics
+                .write_amplification
+                .set(disk_bytes as f64 / foreground_bytes as f64);
+        }
+    }
+
+    // Files before it are old enough that entries in them should be compacted by
+    // force, 0 if none.
+    fn gc_file_num(&self) -> u64 {
+        // Entries in files out of the cache are checked only.
+        if self.pipe_log.files_before(self.cfg.cache_size_limit.0) == 0 {
+            return 0;
+        }
+        self.pipe_log.files_before(self.cfg.total_size_limit.0)
+    }
+
+    // The index entries of the region before which should be compacted by force,
+    // `None` if nothing needs to be compacted.
+    fn force_compact_index(
+        &self,
+        memtable: &dyn MemTableAccessor,
+        gc_file_num: u64,
+    ) -> Option<u64> {
+        let (first_index, last_index) = (memtable.first_index()?, memtable.last_index()?);
+        let mut compact_index = first_index;
+
+        // Total size of entries for this region exceed limit, keep the latest entries
+        // within the limit.
+        let region_entries_size_limit = self.cfg.region_size.0 * 2 / 3;
+        if region_entries_size_limit > 0 && memtable.entries_size() > region_entries_size_limit {
+            let mut size = 0;
+            let mut index = last_index + 1;
+            while index > first_index {
+                size += memtable.entry_index(index - 1).unwrap().len;
+                if size > region_entries_size_limit {
+                    break;
+                }
+                index -= 1;
+            }
+            compact_index = index;
+        }
+
+        // Has entries left behind too far, this happens when some followers left
+        // behind for a long time. Entries are written to files in the order of
+        // indexes, so the first one in files after `gc_file_num` is searched.
+        if gc_file_num > 0 && memtable.entry_index(first_index).unwrap().file_num < gc_file_num {
+            let (mut low, mut high) = (first_index, last_index + 1);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if memtable.entry_index(mid).unwrap().file_num < gc_file_num {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            compact_index = cmp::max(compact_index, low);
+        }
+
+        let compact_index = self.retained_compact_index(memtable, compact_index);
+        if compact_index > first_index {
+            Some(compact_index)
         } else {
-            Ok(None)
+            None
         }
     }
 
-    fn get_msg<M: protobuf::Message>(&self, region_id: u64, key: &[u8]) -> Result<Option<M>> {
-        match self.get(region_id, key)? {
-            Some(value) => {
-                let mut m = M::new();
-                m.merge_from_bytes(&value)?;
-                Ok(Some(m))
+    #[allow(dead_code)]
+    fn regions_need_force_compact(&self) -> HashSet<u64> {
+        let gc_file_num = self.gc_file_num();
+        let mut regions = HashSet::default();
+        for slot in 0..SLOTS_COUNT {
+            let memtables = self.memtables[slot].read().unwrap();
+            for memtable in memtables.values() {
+                if let Some(index) = self.force_compact_index(memtable.as_ref(), gc_file_num) {
+                    info!(
+                        "[{}] region {} needs force compaction to {}",
+                        self.cfg.name,
+                        memtable.region_id(),
+                        index
+                    );
+                    regions.insert(memtable.region_id());
+                }
             }
-            None => Ok(None),
         }
+        self.metrics
+            .need_compact_regions
+            .observe(regions.len() as f64);
+
+        regions
     }
 
-    fn get_entry(&self, region_id: u64, log_idx: u64) -> Result<Option<Entry>> {
-        // Fetch from cache
-        let entry_idx = {
+    #[allow(dead_code)]
+    fn evict_old_from_cache(&self) {
+        let inactive_file_num = self.pipe_log.files_before(self.cfg.cache_size_limit.0);
+
+        if inactive_file_num == 0 {
+            return;
+        }
+
+        for slot in 0..SLOTS_COUNT {
+            let mut memtables = self.memtables[slot].write().unwrap();
+            for memtable in memtables.values_mut() {
+                memtable.evict_old_from_cache(inactive_file_num);
+            }
+        }
+    }
+
+    fn rewrite_region(&self, region_id: u64) -> Result<bool> {
+        self.wait_written();
+        let _rewrite = self.start_rewrite(region_id);
+        let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+            .write()
+            .unwrap();
+        let memtable = match memtables.get_mut(&region_id) {
+            Some(memtable) => memtable,
+            None => return Ok(false),
+        };
+        if memtable.min_file_num().is_none() {
+            return Ok(false);
+        }
+        self.metrics.rewrites.inc();
+        self.metrics
+            .rewrite_entries_count
+            .observe(memtable.entries_count() as f64);
+        self.rewrite_memtable(memtable.as_mut(), false)?;
+        Ok(true)
+    }
+
+    // Return the count of purged files.
+    fn unsafe_destroy_region(&self, region_id: u64) -> Result<u64> {
+        self.wait_written();
+        let max_file_num = {
             let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
                 .read()
                 .unwrap();
-            if let Some(memtable) = memtables.get(&region_id) {
-                match memtable.get_entry(log_idx) {
-                    (Some(entry), _) => return Ok(Some(entry)),
-                    (None, Some(idx)) => idx,
-                    (None, None) => return Ok(None),
-                }
-            } else {
-                return Ok(None);
+            match memtables.get(&region_id).and_then(|m| m.max_file_num()) {
+                Some(file_num) => file_num,
+                None => return Ok(0),
+            }
+        };
+        let log_batch = LogBatch::new();
+        log_batch.clean_region(region_id);
+        self.write(log_batch, true)?;
+
+        // Move other regions off the files shared with the destroyed one. Like
+        // `rewrite_region`, each is rewritten with its writes held.
+        for slot in 0..SLOTS_COUNT {
+            let regions: Vec<u64> = self.memtables[slot]
+                .read()
+                .unwrap()
+                .values()
+                .filter(|m| m.min_file_num().map_or(false, |n| n <= max_file_num))
+                .map(|m| m.region_id())
+                .collect();
+            for region_id in regions {
+                let _rewrite = self.start_rewrite(region_id);
+                let mut memtables = self.memtables[slot].write().unwrap();
+                let memtable = match memtables.get_mut(&region_id) {
+                    Some(m) if m.min_file_num().map_or(false, |n| n <= max_file_num) => m,
+                    // Cleaned or rewritten meanwhile.
+                    _ => continue,
+                };
+                self.metrics.rewrites.inc();
+                self.metrics
+                    .rewrite_entries_count
+                    .observe(memtable.entries_count() as f64);
+                self.rewrite_memtable(memtable.as_mut(), false)?;
+            }
+        }
+        self.sync_log()?;
+
+        let first_file_num = self.pipe_log.first_file_num();
+        self.purge_expired_files()?;
+        Ok(self.pipe_log.first_file_num() - first_file_num)
+    }
+
+    // The oldest file referenced by memtables or kept for snapshots, `u64::MAX` if
+    // none.
+    fn min_file_num(&self) -> u64 {
+        let mut min_file_num = self
+            .snapshots
+            .read()
+            .unwrap()
+            .iter()
+            .fold(u64::MAX, |min, s| cmp::min(min, s.first_file_num));
+        for memtables in &self.memtables {
+            let memtables = memtables.read().unwrap();
+            let file_num = memtables.values().fold(u64::MAX, |min, x| {
+                cmp::min(min, x.min_file_num().map_or(u64::MAX, |num| num))
+            });
+            if file_num < min_file_num {
+                min_file_num = file_num;
             }
+        }
+        min_file_num
+    }
+
+    // Files from it on have batches written within `retention_min_age`, and are
+    // kept from purging and hole punching. `u64::MAX` if none.
+    fn retained_file_num(&self) -> u64 {
+        let min_age = self.cfg.retention_min_age.0;
+        if min_age == Duration::from_secs(0) {
+            return u64::MAX;
+        }
+        let cutoff = match self.clock.now().checked_sub(min_age) {
+            Some(cutoff) => cutoff,
+            None => return 0,
         };
+        let batch_times = self.pipe_log.batch_times();
+        batch_times
+            .into_iter()
+            .find(|(_, _, newest)| newest.physical() >= cutoff)
+            .map_or(u64::MAX, |(file_num, _, _)| file_num)
+    }
+
+    // The index to compact the region to instead of `index`, keeping
+    // `retention_min_entries` of its latest entries.
+    fn retained_compact_index(&self, memtable: &dyn MemTableAccessor, index: u64) -> u64 {
+        match memtable.last_index() {
+            Some(last_index) if self.cfg.retention_min_entries > 0 => cmp::min(
+                index,
+                (last_index + 1).saturating_sub(self.cfg.retention_min_entries),
+            ),
+            _ => index,
+        }
+    }
+
+    // The oldest file to keep in purges: referenced by memtables, kept for
+    // snapshots, or the active file, which is kept even if nothing references it.
+    fn purge_file_num(&self) -> u64 {
+        // Read before waiting, so that writes not waited for are written to it or
+        // a later file.
+        let active_file_num = self.pipe_log.active_file_num();
+        // Batches being written or queued to the applier aren't in memtables yet.
+        self.wait_for_writes();
+        cmp::min(self.min_file_num(), active_file_num)
+    }
+
+    fn purge_expired_files(&self) -> Result<()> {
+        let file_num = self.purge_file_num();
+        let file_num = cmp::min(file_num, self.retained_file_num());
+        let file_num = self.keep_clean_commands(file_num);
+        let old_first_file_num = self.pipe_log.first_file_num();
+        self.pipe_log.purge_to(file_num)?;
+        let first_file_num = self.pipe_log.first_file_num();
+        self.metrics
+            .expired_files_purged
+            .observe((first_file_num - old_first_file_num) as f64);
+        self.metrics
+            .pipe_files_count
+            .set((self.pipe_log.active_file_num() - first_file_num + 1) as f64);
+        self.forget_generations(first_file_num);
+        Ok(())
+    }
+
+    // Files before it are rewritten by `rewrite_inactive`, 0 if none.
+    fn inactive_file_num(&self) -> u64 {
+        let (_, inactive_size) = self.rewrite_tuner.lock().unwrap().limits(&self.cfg);
+        self.pipe_log.files_before(inactive_size)
+    }
+
+    fn compact_to(&self, region_id: u64, index: u64) -> GcStats {
+        self.wait_written();
+        let (stats, min_file_num) = {
+            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                .write()
+                .unwrap();
+            let memtable = match memtables.get_mut(&region_id) {
+                Some(memtable) => memtable,
+                None => return GcStats::default(),
+            };
+            let index = self.retained_compact_index(memtable.as_ref(), index);
+            if memtable.first_index().map_or(false, |first| first < index) {
+                self.freeze_for_snapshots(region_id, Some(memtable.as_ref()), None);
+            }
+            let (size, cache_memory) = (memtable.entries_size(), memtable.cache_memory());
+            let min_file_num = memtable.min_file_num();
+            let entries = memtable.compact_to(index) as usize;
+            if let Some(cache) = &self.entry_cache {
+                cache.evict(region_id, index);
+            }
+            let stats = GcStats {
+                entries,
+                bytes: size - memtable.entries_size(),
+                cache_bytes: cache_memory - memtable.cache_memory(),
+            };
+            self.cache_stats.reclaim(stats.cache_bytes);
+            (
+                stats,
+                min_file_num.filter(|n| memtable.min_file_num() != Some(*n)),
+            )
+        };
+
+        // The region doesn't pin the oldest file any more, purge it if possible.
+        if min_file_num == Some(self.pipe_log.first_file_num()) {
+            if let Err(e) = self.purge_expired_files() {
+                warn!(
+                    "[{}] Purge raft log after gc of region {} failed: {}",
+                    self.cfg.name, region_id, e
+                );
+            }
+        }
+        stats
+    }
+
+    fn compact_cache_to(&self, region_id: u64, index: u64) {
+        let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+            .write()
+            .unwrap();
+        if let Some(memtable) = memtables.get_mut(&region_id) {
+            let cache_memory = memtable.cache_memory();
+            memtable.compact_cache_to(index);
+            self.cache_stats
+                .reclaim(cache_memory - memtable.cache_memory());
+        }
+        if let Some(cache) = &self.entry_cache {
+            cache.evict(region_id, index);
+        }
+    }
+
+    // Drop all cached entries of the region, return the released bytes.
+    fn evict_region_cache(&self, region_id: u64) -> u64 {
+        let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+            .write()
+            .unwrap();
+        let mut released = 0;
+        if let Some(memtable) = memtables.get_mut(&region_id) {
+            if let Some(last_index) = memtable.last_index() {
+                released = memtable.cache_memory();
+                memtable.compact_cache_to(last_index + 1);
+                self.cache_stats.reclaim(released);
+            }
+        }
+        if let Some(cache) = &self.entry_cache {
+            cache.remove_region(region_id);
+        }
+        released
+    }
+
+    fn write_batch(&self, log_batch: LogBatch, sync: bool) -> Result<usize> {
+        self.write_batch_with_token(log_batch, sync)
+            .map(|(bytes, _)| bytes)
+    }
+
+    // Write the batch, and return the bytes written and the token of the write.
+    fn write_batch_with_token(
+        &self,
+        log_batch: LogBatch,
+        sync: bool,
+    ) -> Result<(usize, WriteToken)> {
+        self.try_write_batch(log_batch, sync).map_err(|(e, _)| e)
+    }
+
+    // Like `write_batch_with_token`, but hand back items not written on failure.
+    fn try_write_batch(
+        &self,
+        mut log_batch: LogBatch,
+        sync: bool,
+    ) -> std::result::Result<(usize, WriteToken), (Error, Box<LogBatch>)> {
+        let regions = batch_regions(&log_batch);
+        let max_regions = self.cfg.max_batch_regions;
+        if max_regions > 0 && regions > max_regions {
+            let mut parts = split_batch(log_batch, max_regions).into_iter();
+            let count = parts.len();
+            let mut bytes = 0;
+            let mut token = WriteToken(0);
+            // Written in order, so syncing the last one syncs all of them.
+            for i in 0..count {
+                let part = parts.next().unwrap();
+                match self.try_write_batch(part, sync && i + 1 == count) {
+                    Ok(written) => {
+                        bytes += written.0;
+                        token = cmp::max(token, written.1);
+                    }
+                    Err((e, unwritten)) => {
+                        for part in parts {
+                            unwritten.items.borrow_mut().extend(part.items.into_inner());
+                        }
+                        // Written parts can't be taken back, so the caller must not
+                        // write them again.
+                        let e = if i > 0 {
+                            Error::PartiallyWritten(i, count, Box::new(e))
+                        } else {
+                            e
+                        };
+                        return Err((e, unwritten));
+                    }
+                }
+            }
+            return Ok((bytes, token));
+        }
+        self.metrics.batch_regions_count.observe(regions as f64);
+        self.metrics
+            .batch_entries_count
+            .observe(batch_entries(&log_batch) as f64);
+        let pending = self.start_write(&log_batch);
+        let mut strict_appends = None;
+        if self.cfg.strict_append {
+            let appends = self.strict_appends.lock().unwrap();
+            match self.check_append(&log_batch, &appends) {
+                Ok(last_indexes) => strict_appends = Some((appends, last_indexes)),
+                Err(e) => return Err((e, Box::new(log_batch))),
+            }
+        }
+        let cleaned = cleaned_regions(&log_batch);
+        for region_id in &cleaned {
+            // Wait for rewrites of the region holding the slot, so they are written
+            // before the clean command.
+            let _slot = self.memtables[*region_id as usize % SLOTS_COUNT]
+                .read()
+                .unwrap();
+            *self.cleaning.lock().unwrap().entry(*region_id).or_default() += 1;
+        }
+        log_batch.retention = self.entry_retention();
+        let mut file_num = 0;
+        let res = self
+            .pipe_log
+            .append_log_batch(&mut log_batch, sync, &mut file_num);
+        let bytes = match res {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.finish_cleaning(&cleaned);
+                return Err((e, Box::new(log_batch)));
+            }
+        };
+        if let Some((mut appends, last_indexes)) = strict_appends {
+            if file_num != 0 {
+                for (region_id, last_index) in last_indexes {
+                    appends.insert(region_id, (log_batch.sequence, last_index));
+                }
+            }
+        }
+        self.foreground_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if file_num != 0 {
+            self.record_hot_regions(&log_batch);
+        }
+        // Nothing is written for an empty batch, which is durable with the writes
+        // before.
+        let token = WriteToken(if file_num == 0 {
+            self.pipe_log.latest_sequence()
+        } else {
+            log_batch.sequence
+        });
+        if self.subscribers.is_empty() || file_num == 0 {
+            self.apply_written(pending, log_batch, file_num, cleaned);
+        } else {
+            let summary = log_batch.summary(file_num);
+            self.apply_written(pending, log_batch, file_num, cleaned);
+            self.subscribers.send(summary);
+        }
+        self.notify_durable();
+        Ok((bytes, token))
+    }
+
+    fn finish_cleaning(&self, regions: &[u64]) {
+        let mut cleaning = self.cleaning.lock().unwrap();
+        for region_id in regions {
+            if let Some(count) = cleaning.get_mut(region_id) {
+                *count -= 1;
+                if *count == 0 {
+                    cleaning.remove(region_id);
+                }
+            }
+        }
+    }
+
+    // Count each region once per batch, with the bytes of its items in the batch.
+    fn record_hot_regions(&self, log_batch: &LogBatch) {
+        let mut region_bytes: Vec<(u64, u64)> = vec![];
+        for item in log_batch.items.borrow().iter() {
+            let (region_id, bytes) = match item.item_type {
+                LogItemType::Entries => {
+                    let entries = item.entries.as_ref().unwrap();
+                    let bytes = entries.entries_index.borrow().iter().map(|i| i.len).sum();
+                    (entries.region_id, bytes)
+                }
+                LogItemType::CMD => continue,
+                LogItemType::KV => {
+                    let kv = item.kv.as_ref().unwrap();
+                    let value_len = kv.value.as_ref().map_or(0, |v| v.len());
+                    (kv.region_id, (kv.key.len() + value_len) as u64)
+                }
+            };
+            match region_bytes.iter_mut().find(|(id, _)| *id == region_id) {
+                Some((_, b)) => *b += bytes,
+                None => region_bytes.push((region_id, bytes)),
+            }
+        }
+        let mut hot_regions = self.hot_regions.lock().unwrap();
+        for (region_id, bytes) in region_bytes {
+            hot_regions.record(region_id, bytes);
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.flush_writes()?;
+        self.sync_log()
+    }
+
+    fn sync_log(&self) -> Result<()> {
+        self.pipe_log.sync()?;
+        self.notify_durable();
+        Ok(())
+    }
+
+    // Send the token of the last durable write to subscribers if it's new.
+    fn notify_durable(&self) {
+        let token = WriteToken(self.pipe_log.durable_sequence());
+        if self.durable_subscribers.is_empty() {
+            return;
+        }
+        let mut notified = self.durable_notified.lock().unwrap();
+        if token <= *notified {
+            return;
+        }
+        *notified = token;
+        self.durable_subscribers.send(token);
+    }
+
+    // Write without sync, leaving the batch to be synced later, and return its
+    // token.
+    fn write_deferred(&self, log_batch: LogBatch) -> Result<WriteToken> {
+        // Buffered writes are written before, like batches that can't be merged.
+        self.flush_writes()?;
+        let (_, token) = self.write_batch_with_token(log_batch, false)?;
+        Ok(token)
+    }
+
+    #[allow(dead_code)]
+    fn kv_count(&self, region_id: u64) -> usize {
+        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+            .read()
+            .unwrap();
+        if let Some(memtable) = memtables.get(&region_id) {
+            return memtable.kvs_total_count();
+        }
+        0
+    }
+
+    fn put_msg<M: protobuf::Message>(&self, region_id: u64, key: &[u8], m: &M) -> Result<()> {
+        let log_batch = LogBatch::new();
+        log_batch.put_msg(region_id, key, m)?;
+        self.write(log_batch, false).map(|_| ())
+    }
+
+    // Call `f` with the memtable of the region, as of `snapshot` if given.
+    fn with_memtable<R, F>(&self, snapshot: Option<&SnapshotState>, region_id: u64, f: F) -> R
+    where
+        F: FnOnce(Option<&dyn MemTableAccessor>) -> R,
+    {
+        if snapshot.is_none() {
+            self.wait_written();
+        }
+        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+            .read()
+            .unwrap();
+        if let Some(snapshot) = snapshot {
+            if let Some(frozen) = snapshot.regions.lock().unwrap().get(&region_id) {
+                return f(frozen.as_deref());
+            }
+        } else if memtables.contains_key(&region_id) {
+            self.recent_regions.touch(region_id);
+        }
+        f(memtables.get(&region_id).map(|m| m.as_ref()))
+    }
+
+    fn get(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let value = self.with_memtable(snapshot, region_id, |memtable| -> Result<_> {
+            match memtable.and_then(|m| m.get_value(key)) {
+                Some(KvValue::InFile(idx)) => {
+                    // Keep the file from being purged after the memtable is unlocked.
+                    let pin = self.pipe_log.pin(idx.file_num)?;
+                    Ok(Some((KvValue::InFile(idx), Some(pin))))
+                }
+                value => Ok(value.map(|v| (v, None))),
+            }
+        })?;
+        value
+            .map(|(value, _pin)| self.load_value(value))
+            .transpose()
+    }
+
+    fn get_msg<M: protobuf::Message>(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        key: &[u8],
+    ) -> Result<Option<M>> {
+        match self.get(snapshot, region_id, key)? {
+            Some(value) => {
+                let mut m = M::new();
+                m.merge_from_bytes(&value)?;
+                Ok(Some(m))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn locate_entry(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        log_idx: u64,
+    ) -> Result<Option<EntryLocation<'_>>> {
+        self.with_memtable(snapshot, region_id, |memtable| {
+            if let Some(memtable) = memtable {
+                match memtable.get_entry(log_idx) {
+                    (Some(entry), _) => return Ok(Some(EntryLocation::Cached(entry))),
+                    (None, Some(idx)) => {
+                        if let Some(entry) = self.get_cached_entry(snapshot, region_id, log_idx) {
+                            return Ok(Some(EntryLocation::Cached(entry)));
+                        }
+                        // Keep the file from being purged after the memtable is unlocked.
+                        let pin = self.pipe_log.pin(idx.file_num)?;
+                        return Ok(Some(EntryLocation::File(idx, pin)));
+                    }
+                    (None, None) => {}
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    // Get the entry from the entry cache given by the host application, which only
+    // keeps the latest entries, so it's not read for snapshots.
+    fn get_cached_entry(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        index: u64,
+    ) -> Option<Entry> {
+        if snapshot.is_some() {
+            return None;
+        }
+        let entry = self.entry_cache.as_ref()?.get(region_id, index);
+        if entry.is_some() {
+            self.cache_stats.hit_cache(1);
+        } else {
+            self.cache_stats.miss_cache(1);
+        }
+        entry
+    }
+
+    fn get_entry(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        log_idx: u64,
+    ) -> Result<Option<Entry>> {
+        // Fetch from cache
+        let entry_idx = match self.locate_entry(snapshot, region_id, log_idx)? {
+            Some(EntryLocation::Cached(entry)) => return Ok(Some(entry)),
+            Some(EntryLocation::File(idx, _pin)) => idx,
+            None => return Ok(None),
+        };
+
+        // Read from file
+        let entry = self.read_entry_from_file(&entry_idx).map_err(|e| {
+            error!(
+                "[{}] Read entry from file for region {} index {} failed, err {}",
+                self.cfg.name, region_id, log_idx, e
+            );
+            e
+        })?;
+        Ok(Some(entry))
+    }
+
+    fn get_entry_bytes(&self, region_id: u64, log_idx: u64) -> Result<Option<Vec<u8>>> {
+        match self.locate_entry(None, region_id, log_idx)? {
+            Some(EntryLocation::Cached(entry)) => Ok(Some(entry_codec::encode_entry(&entry)?)),
+            Some(EntryLocation::File(idx, _pin)) => {
+                let bytes = self.read_entry_bytes_from_file(&idx)?;
+                Ok(Some(entry_codec::upgrade_encoded(bytes)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Read the encoded entry without decoding it.
+    fn read_entry_bytes_from_file(&self, entry_index: &EntryIndex) -> Result<Vec<u8>> {
+        let (offset, len) = entry_read_range(entry_index);
+        let content = self.pipe_log.fread(entry_index.file_num, offset, len)?;
+        extract_entry_bytes(entry_index, &content, self.pipe_log.dictionaries())
+    }
+
+    fn read_entry_from_file(&self, entry_index: &EntryIndex) -> Result<Entry> {
+        let entry_content = self.read_entry_bytes_from_file(entry_index)?;
+        decode_entry(entry_index, &entry_content)
+    }
+
+    // Read entries of the same file with as few reads as possible.
+    fn read_entries_from_file(
+        &self,
+        entries_index: &[EntryIndex],
+        vec: &mut Vec<Entry>,
+    ) -> Result<()> {
+        let prefetched: Vec<_> = entries_index
+            .iter()
+            .map(|idx| self.prefetcher.take(idx))
+            .collect();
+        let ranges: Vec<_> = entries_index
+            .iter()
+            .zip(&prefetched)
+            .filter(|(_, p)| p.is_none())
+            .map(|(idx, _)| entry_read_range(idx))
+            .collect();
+        let file_num = entries_index[0].file_num;
+        let mut contents = self.pipe_log.fread_many(file_num, &ranges)?.into_iter();
+        for (idx, prefetched) in entries_index.iter().zip(prefetched) {
+            let entry_content = match prefetched {
+                Some(entry_content) => entry_content,
+                None => extract_entry_bytes(
+                    idx,
+                    &contents.next().unwrap(),
+                    self.pipe_log.dictionaries(),
+                )?,
+            };
+            vec.push(decode_entry(idx, &entry_content)?);
+        }
+        Ok(())
+    }
+
+    // Read entries following sequential fetches ahead into the prefetcher.
+    fn prefetch(&self) -> Result<()> {
+        self.prefetcher.evict_before(self.pipe_log.first_file_num());
+        let requests = self.prefetcher.take_requests();
+        let res = self.prefetch_requests(&requests);
+        for req in &requests {
+            self.prefetcher.finish(req);
+        }
+        res
+    }
+
+    fn prefetch_requests(&self, requests: &[PrefetchRequest]) -> Result<()> {
+        for req in requests {
+            if self.prefetcher.is_full() {
+                break;
+            }
+            let mut entries_idx = vec![];
+            let mut pins = Vec::new();
+            {
+                let memtables = self.memtables[req.region_id as usize % SLOTS_COUNT]
+                    .read()
+                    .unwrap();
+                if let Some(memtable) = memtables.get(&req.region_id) {
+                    memtable.fetch_uncached_indexes(req.begin, req.end, &mut entries_idx);
+                }
+                for idx in &entries_idx {
+                    if pins.last().map_or(true, |(n, _)| *n != idx.file_num) {
+                        pins.push((idx.file_num, self.pipe_log.pin(idx.file_num)?));
+                    }
+                }
+            }
+            for (file_num, _) in &pins {
+                let file_entries: Vec<_> = entries_idx
+                    .iter()
+                    .filter(|idx| idx.file_num == *file_num)
+                    .collect();
+                let ranges: Vec<_> = file_entries
+                    .iter()
+                    .map(|idx| entry_read_range(idx))
+                    .collect();
+                let contents = self.pipe_log.fread_many(*file_num, &ranges)?;
+                for (idx, content) in file_entries.into_iter().zip(contents) {
+                    if !self.prefetcher.insert(
+                        req,
+                        idx,
+                        extract_entry_bytes(idx, &content, self.pipe_log.dictionaries())?,
+                    ) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Return the count and the total encoded size of fetched entries.
+    pub fn fetch_entries_to(
+        &self,
+        snapshot: Option<&SnapshotState>,
+        region_id: u64,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        vec: &mut Vec<Entry>,
+    ) -> Result<(usize, usize)> {
+        let fetched = self.with_memtable(snapshot, region_id, |memtable| -> Result<_> {
+            let memtable = match memtable {
+                Some(memtable) => memtable,
+                None => return Ok(None),
+            };
+            let mut entries = Vec::with_capacity((end - begin) as usize);
+            let mut entries_idx = Vec::with_capacity((end - begin) as usize);
+            let size =
+                memtable.fetch_entries_to(begin, end, max_size, &mut entries, &mut entries_idx)?;
+
+            // Read files without blocking writes of the region.
+            let mut pins = Vec::new();
+            for idx in &entries_idx {
+                if pins.last().map_or(true, |(n, _)| *n != idx.file_num) {
+                    pins.push((idx.file_num, self.pipe_log.pin(idx.file_num)?));
+                }
+            }
+            Ok(Some((entries, entries_idx, size, pins)))
+        })?;
+        if let Some((entries, entries_idx, size, _pins)) = fetched {
+            let count = entries.len() + entries_idx.len();
+            let cached: Vec<_> = entries_idx
+                .iter()
+                .map(|idx| self.get_cached_entry(snapshot, region_id, idx.index))
+                .collect();
+            let missed: Vec<_> = entries_idx
+                .iter()
+                .zip(&cached)
+                .filter(|(_, e)| e.is_none())
+                .map(|(idx, _)| idx.clone())
+                .collect();
+            let mut read = Vec::with_capacity(missed.len());
+            let mut start = 0;
+            for i in 1..=missed.len() {
+                if i == missed.len() || missed[i].file_num != missed[start].file_num {
+                    self.read_entries_from_file(&missed[start..i], &mut read)?;
+                    start = i;
+                }
+            }
+            let mut read = read.into_iter();
+            vec.extend(
+                cached
+                    .into_iter()
+                    .map(|e| e.or_else(|| read.next()).unwrap()),
+            );
+            vec.extend(entries.into_iter());
+            self.prefetcher
+                .on_fetch(region_id, begin, begin + count as u64);
+            return Ok((count, size as usize));
+        }
+        Ok((0, 0))
+    }
+
+    fn post_append_to_file(&self, log_batch: LogBatch, file_num: u64) {
+        // 0 means write nothing.
+        if file_num == 0 {
+            return;
+        }
+        self.apply_to_memtable(log_batch, file_num);
+    }
+}
+
+fn item_region_id(item: &LogItem) -> u64 {
+    match item.item_type {
+        LogItemType::Entries => item.entries.as_ref().unwrap().region_id,
+        LogItemType::KV => item.kv.as_ref().unwrap().region_id,
+        LogItemType::CMD => match *item.command.as_ref().unwrap() {
+            Command::Clean { region_id } | Command::Fence { region_id, .. } => region_id,
+        },
+    }
+}
+
+// Number of regions with items in the batch.
+fn batch_regions(batch: &LogBatch) -> usize {
+    let mut regions: Vec<_> = batch.items.borrow().iter().map(item_region_id).collect();
+    regions.sort_unstable();
+    regions.dedup();
+    regions.len()
+}
+
+// Number of entries in the batch, before it's written.
+fn batch_entries(batch: &LogBatch) -> usize {
+    batch
+        .items
+        .borrow()
+        .iter()
+        .filter_map(|item| item.entries.as_ref())
+        .map(|entries| entries.entries.len())
+        .sum()
+}
+
+// Split the batch into batches of items of at most `max_regions` regions each,
+// keeping items of each region in order in one batch.
+fn split_batch(batch: LogBatch, max_regions: usize) -> Vec<LogBatch> {
+    let (compression, overwrite) = (batch.compression, batch.overwrite);
+    let mut parts: Vec<LogBatch> = vec![];
+    let mut region_parts: HashMap<u64, usize> = HashMap::default();
+    for item in batch.items.into_inner() {
+        let next = region_parts.len() / max_regions;
+        let part = *region_parts.entry(item_region_id(&item)).or_insert(next);
+        if part == parts.len() {
+            parts.push(LogBatch {
+                compression,
+                overwrite,
+                ..LogBatch::default()
+            });
+        }
+        parts[part].items.get_mut().push(item);
+    }
+    parts
+}
+
+// Keep only indexes of entries in the batch, so that they aren't cached.
+fn drop_entries(batch: &mut LogBatch) {
+    batch.encoded.get_mut().take();
+    for item in batch.items.get_mut() {
+        if let Some(entries) = &mut item.entries {
+            entries.entries.clear();
+        }
+    }
+}
+
+// Bytes of entries in the batch.
+fn entries_bytes(batch: &LogBatch) -> u64 {
+    let mut bytes = 0;
+    for item in batch.items.borrow().iter() {
+        if let Some(entries) = &item.entries {
+            bytes += entries
+                .entries_index
+                .borrow()
+                .iter()
+                .map(|i| i.len)
+                .sum::<u64>();
+        }
+    }
+    bytes
+}
+
+// Regions cleaned by the batch.
+fn cleaned_regions(batch: &LogBatch) -> Vec<u64> {
+    let mut regions = vec![];
+    for item in batch.items.borrow().iter() {
+        if let Some(Command::Clean { region_id }) = &item.command {
+            regions.push(*region_id);
+        }
+    }
+    regions
+}
+
+// Has entries in inactive files, at the same time the total entries is less than
+// `compact_threshold`, compaction will not be triggered, so we need rewrite these
+// entries, so the old files can be dropped ASAP.
+fn needs_rewrite(
+    memtable: &dyn MemTableAccessor,
+    inactive_file_num: u64,
+    compact_threshold: usize,
+) -> bool {
+    memtable
+        .min_file_num()
+        .map_or(false, |n| n < inactive_file_num)
+        && memtable.entries_count() < compact_threshold
+}
+
+// The range of the log file to read for the entry, the whole batch if it's compressed.
+fn entry_read_range(entry_index: &EntryIndex) -> (u64, u64) {
+    match entry_index.compression_type {
+        CompressionType::None => (
+            entry_index.base_offset + entry_index.offset,
+            entry_index.len,
+        ),
+        // 8 bytes for len.
+        CompressionType::Lz4 | CompressionType::Lz4Dict => {
+            (entry_index.base_offset, entry_index.batch_len + 8)
+        }
+    }
+}
+
+// Extract the encoded entry from the content of `entry_read_range`.
+fn extract_entry_bytes(
+    entry_index: &EntryIndex,
+    content: &[u8],
+    dicts: &Dictionaries,
+) -> Result<Vec<u8>> {
+    let file_num = entry_index.file_num;
+    let base_offset = entry_index.base_offset;
+    let batch_len = entry_index.batch_len;
+    let offset = entry_index.offset;
+    let len = entry_index.len;
+
+    match entry_index.compression_type {
+        CompressionType::None => Ok(content.to_vec()),
+        compression_type => {
+            let mut reader = content;
+            let header = codec::decode_u64(&mut reader)?;
+            if header >> 8 != batch_len {
+                return Err(Error::Corruption(
+                    file_num,
+                    base_offset,
+                    format!(
+                        "batch length {} mismatches index {}",
+                        header >> 8,
+                        batch_len
+                    ),
+                ));
+            }
+
+            format::test_batch_checksum(reader)?;
+            let content = &reader[HEADER_LEN - 8..to_usize(batch_len)? - CHECKSUM_LEN];
+            let buf = log_batch::decompress(compression_type, content, dicts)?;
+            let start = to_usize(offset)? - HEADER_LEN;
+            let end = to_usize(offset + len)? - HEADER_LEN;
+            Ok(buf[start..end].to_vec())
+        }
+    }
+}
+
+fn decode_entry(entry_index: &EntryIndex, entry_content: &[u8]) -> Result<Entry> {
+    let e = entry_codec::decode_entry(entry_content)?;
+    if e.get_index() != entry_index.index {
+        return Err(Error::Corruption(
+            entry_index.file_num,
+            entry_index.base_offset + entry_index.offset,
+            format!(
+                "entry index {} mismatches {}",
+                e.get_index(),
+                entry_index.index
+            ),
+        ));
+    }
+    Ok(e)
+}
+
+/// Recompute checksums of all batches in the file content. The position of the
+/// first corrupted one is returned in `Error::Corruption`.
+pub(crate) fn verify_file(file_num: u64, content: &[u8], dicts: &Dictionaries) -> Result<()> {
+    let header_len = pipe_log::check_file_header(file_num, content)?;
+
+    let mut buf = &content[header_len..];
+    let mut offset = header_len as u64;
+    loop {
+        match LogBatch::from_bytes(&mut buf, file_num, offset, dicts) {
+            Ok(Some(_)) => offset = (content.len() - buf.len()) as u64,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(Error::Corruption(file_num, offset, e.to_string())),
+        }
+    }
+}
+
+const MAX_REWRITE_LEVEL: u64 = 4;
+
+// Raises the rewrite threshold step by step while files keep growing beyond
+// `total_size_limit`, and lowers it back once they are below the limit.
+#[derive(Default)]
+struct RewriteTuner {
+    level: u64,
+    last_total_size: u64,
+}
+
+impl RewriteTuner {
+    fn update(&mut self, total_size: u64, total_size_limit: u64) {
+        if total_size > total_size_limit {
+            if total_size >= self.last_total_size && self.level < MAX_REWRITE_LEVEL {
+                self.level += 1;
+            }
+        } else if self.level > 0 {
+            self.level -= 1;
+        }
+        self.last_total_size = total_size;
+    }
+
+    // Return the entries threshold of regions to rewrite, and the size of newest
+    // files regarded as active.
+    fn limits(&self, cfg: &Config) -> (usize, u64) {
+        // Without the entry cache, only the active file is regarded as active.
+        let cache_size_limit = cmp::max(cfg.cache_size_limit.0, cfg.target_file_size.0);
+        if cfg.max_compact_threshold == 0 || self.level == 0 {
+            return (cfg.compact_threshold, cache_size_limit);
+        }
+        let range = (cfg.max_compact_threshold - cfg.compact_threshold) as u64;
+        let threshold = cfg.compact_threshold + (range * self.level / MAX_REWRITE_LEVEL) as usize;
+        let inactive_size = cmp::max(cache_size_limit >> self.level, cfg.target_file_size.0);
+        (threshold, inactive_size)
+    }
+}
+
+#[derive(Default)]
+pub struct SharedCacheStats {
+    hit: AtomicUsize,
+    miss: AtomicUsize,
+    mem_size_change: AtomicIsize,
+    // Not reset by `flush_stats`.
+    total_hit: AtomicUsize,
+    total_miss: AtomicUsize,
+    // Cache bytes released by gc, clean commands and `FileEngine::evict_region_cache`.
+    total_reclaimed: AtomicU64,
+    // Cached entries are charged against it.
+    memory_limiter: Option<Arc<dyn MemoryLimiter>>,
+    memory_trace: Arc<MemoryTrace>,
+    metrics: Arc<EngineMetrics>,
+}
+
+impl SharedCacheStats {
+    pub fn new(
+        memory_limiter: Option<Arc<dyn MemoryLimiter>>,
+        metrics: Arc<EngineMetrics>,
+    ) -> Self {
+        SharedCacheStats {
+            memory_limiter,
+            metrics,
+            ..Default::default()
+        }
+    }
+    /// Record cache bytes of a region released at once, by gc for example.
+    pub fn reclaim(&self, bytes: u64) {
+        self.total_reclaimed.fetch_add(bytes, Ordering::Relaxed);
+    }
+    pub fn evict_entries(&self, count: usize) {
+        if count > 0 {
+            self.metrics.cache_evicted_entries.inc_by(count as f64);
+        }
+    }
+    pub fn sub_mem_change(&self, bytes: u64) {
+        self.mem_size_change
+            .fetch_sub(bytes as isize, Ordering::Relaxed);
+        self.memory_trace.free(MemoryComponent::EntryCache, bytes);
+        if let Some(limiter) = &self.memory_limiter {
+            limiter.release(bytes);
+        }
+    }
+    /// Like `add_mem_change`, but return false if the memory limiter refuses it.
+    pub fn try_add_mem_change(&self, bytes: u64) -> bool {
+        if let Some(limiter) = &self.memory_limiter {
+            if !limiter.try_acquire(bytes) {
+                return false;
+            }
+        }
+        self.add_mem_change(bytes);
+        true
+    }
+    pub fn add_mem_change(&self, bytes: u64) {
+        self.mem_size_change
+            .fetch_add(bytes as isize, Ordering::Relaxed);
+        self.memory_trace.alloc(MemoryComponent::EntryCache, bytes);
+    }
+    pub fn hit_cache(&self, count: usize) {
+        self.hit.fetch_add(count, Ordering::Relaxed);
+        self.total_hit.fetch_add(count, Ordering::Relaxed);
+    }
+    pub fn miss_cache(&self, count: usize) {
+        self.miss.fetch_add(count, Ordering::Relaxed);
+        self.total_miss.fetch_add(count, Ordering::Relaxed);
+    }
+    pub fn hit_times(&self) -> usize {
+        self.hit.load(Ordering::Relaxed)
+    }
+    pub fn miss_times(&self) -> usize {
+        self.miss.load(Ordering::Relaxed)
+    }
+    #[cfg(test)]
+    pub fn reset(&self) {
+        self.hit.store(0, Ordering::Relaxed);
+        self.miss.store(0, Ordering::Relaxed);
+        self.mem_size_change.store(0, Ordering::Relaxed);
+    }
+}
+
+/// What `FileEngine::gc_with_stats` reclaims.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+    /// Count of compacted entries.
+    pub entries: usize,
+    /// Size of compacted entries in log files, an estimate of reclaimable disk space.
+    pub bytes: u64,
+    /// Memory released from the entry cache.
+    pub cache_bytes: u64,
+}
+
+/// Identifies a write by `FileEngine::consume_deferred`. Tokens of later writes
+/// are greater, and a write is durable once a token no less than its own is
+/// received from `FileEngine::subscribe_durable`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WriteToken(pub u64);
+
+/// Totals of data in memtables over all regions, see `FileEngine::summary`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub regions: usize,
+    /// Count and size in log files of live entries.
+    pub entries: usize,
+    pub entries_size: u64,
+    /// Bytes of entries cached by memtables.
+    pub cache_size: u64,
+    pub kvs: usize,
+}
+
+/// Cumulative statistics of an engine since opened, see `FileEngine::get_statistics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Bytes of batches written, by users and by rewrites.
+    pub bytes_written: u64,
+    pub foreground_bytes_written: u64,
+    pub rewrite_bytes_written: u64,
+    /// All bytes written to disk, including file headers.
+    pub disk_bytes_written: u64,
+    pub bytes_read: u64,
+    pub batches_written: u64,
+    /// Count of regions rewritten to newer files.
+    pub rewrites: u64,
+    pub files_purged: u64,
+    pub cache_hit: u64,
+    pub cache_miss: u64,
+    /// Cache bytes released at once by gc, clean commands and `evict_region_cache`.
+    pub cache_reclaimed: u64,
+    /// Batches found by recovery or `catch_up` with sequences not after the ones
+    /// applied, e.g. written twice, which are skipped instead of applied again.
+    pub batches_skipped: u64,
+    /// The oldest and the active log file.
+    pub first_file_num: u64,
+    pub active_file_num: u64,
+}
+
+impl Statistics {
+    /// Ratio of bytes written to disk to bytes written by users, 0 if users
+    /// write nothing.
+    pub fn write_amplification(&self) -> f64 {
+        if self.foreground_bytes_written == 0 {
+            return 0.0;
+        }
+        self.disk_bytes_written as f64 / self.foreground_bytes_written as f64
+    }
+
+    /// Ratio of entries read from the cache, 0 if no entry is read.
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let total = self.cache_hit + self.cache_miss;
+        if total == 0 {
+            return 0.0;
+        }
+        self.cache_hit as f64 / total as f64
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "raft-engine.bytes-written: {}", self.bytes_written)?;
+        writeln!(
+            f,
+            "raft-engine.write-amplification: {:.4} ({} foreground, {} rewrite, {} disk)",
+            self.write_amplification(),
+            self.foreground_bytes_written,
+            self.rewrite_bytes_written,
+            self.disk_bytes_written
+        )?;
+        writeln!(f, "raft-engine.bytes-read: {}", self.bytes_read)?;
+        writeln!(f, "raft-engine.batches-written: {}", self.batches_written)?;
+        writeln!(f, "raft-engine.rewrites: {}", self.rewrites)?;
+        writeln!(f, "raft-engine.files-purged: {}", self.files_purged)?;
+        writeln!(
+            f,
+            "raft-engine.cache-hit-ratio: {:.4} ({} hit, {} miss)",
+            self.cache_hit_ratio(),
+            self.cache_hit,
+            self.cache_miss
+        )?;
+        writeln!(f, "raft-engine.cache-reclaimed: {}", self.cache_reclaimed)?;
+        writeln!(f, "raft-engine.batches-skipped: {}", self.batches_skipped)?;
+        write!(
+            f,
+            "raft-engine.files: {} - {}",
+            self.first_file_num, self.active_file_num
+        )
+    }
+}
+
+/// A region keeping a log file from being purged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PurgeBlocker {
+    pub file_num: u64,
+    pub region_id: u64,
+    /// Count and size of the region's entries in the file.
+    pub entries: usize,
+    pub entries_size: u64,
+    /// Count of the region's key value pairs in the file.
+    pub kvs: usize,
+}
+
+/// A piece of live data of a region, passed to the sink of `FileEngine::export_all`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExportItem {
+    Entry(Entry),
+    Kv(Vec<u8>, Vec<u8>),
+}
+
+/// Times the batches in a log file are written, see `FileEngine::file_time_ranges`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileTimeRange {
+    pub file_num: u64,
+    pub oldest: SystemTime,
+    pub newest: SystemTime,
+}
+
+/// An item of a region persisted in log files, see `FileEngine::dump_region`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpItem {
+    pub file_num: u64,
+    /// Offset of the batch containing the item in its file.
+    pub offset: u64,
+    pub sequence: u64,
+    /// When the batch containing the item is written.
+    pub timestamp: Timestamp,
+    pub content: DumpContent,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DumpContent {
+    /// Indexes of appended entries, pointing to their positions in files.
+    Entries(Vec<EntryIndex>),
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Clean,
+}
+
+impl fmt::Display for DumpItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "file {} offset {} sequence {} timestamp {}: ",
+            self.file_num, self.offset, self.sequence, self.timestamp.0
+        )?;
+        match &self.content {
+            DumpContent::Entries(indexes) => match (indexes.first(), indexes.last()) {
+                (Some(first), Some(last)) => write!(
+                    f,
+                    "entries [{}, {}] ({} bytes)",
+                    first.index,
+                    last.index,
+                    indexes.iter().map(|i| i.len).sum::<u64>()
+                ),
+                _ => write!(f, "no entries"),
+            },
+            DumpContent::Put(key, value) => {
+                write!(f, "put {:?} ({} bytes)", key, value.len())
+            }
+            DumpContent::Delete(key) => write!(f, "delete {:?}", key),
+            DumpContent::Clean => write!(f, "clean"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FileEngine {
+    inner: Arc<FileEngineInner>,
+}
+
+/// Log files kept from being purged, see `FileEngine::pin_files`.
+pub struct PinnedFiles {
+    inner: Arc<FileEngineInner>,
+    first_file_num: u64,
+    last_file_num: u64,
+}
+
+impl PinnedFiles {
+    /// Numbers of the first and the last pinned files.
+    pub fn files(&self) -> (u64, u64) {
+        (self.first_file_num, self.last_file_num)
+    }
+}
+
+impl Drop for PinnedFiles {
+    fn drop(&mut self) {
+        self.inner
+            .pipe_log
+            .unpin_files(self.first_file_num, self.last_file_num);
+    }
+}
+
+impl fmt::Debug for FileEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FileEngineInner dir: {}", self.inner.cfg.dir)
+    }
+}
+
+// Dependencies injected by the host application.
+#[derive(Clone, Default)]
+struct Extensions {
+    cold_storage: Option<Arc<dyn ObjectStorage>>,
+    memory_limiter: Option<Arc<dyn MemoryLimiter>>,
+    entry_cache: Option<Arc<dyn EntryCache>>,
+    memtable_factory: Option<Arc<dyn MemTableFactory>>,
+    clock: Option<Arc<dyn Clock>>,
+    recovery_observer: Option<Arc<dyn RecoveryObserver>>,
+    // Where recovery starts, (file_num, offset).
+    recover_from: Option<(u64, u64)>,
+}
+
+/// Builds a `FileEngine` with dependencies injected by the host application,
+/// created by `FileEngine::builder`. Any of them can be combined, and the ones
+/// not set fall back to what `FileEngine::new` uses.
+pub struct EngineBuilder {
+    cfg: Config,
+    ext: Extensions,
+}
+
+impl EngineBuilder {
+    /// Move old files to `cold_storage` when `offload_cold_files` is called, and
+    /// read them back from there on demand.
+    pub fn cold_storage(mut self, cold_storage: Arc<dyn ObjectStorage>) -> EngineBuilder {
+        self.ext.cold_storage = Some(cold_storage);
+        self
+    }
+
+    /// Charge the entry cache and recovery buffers against `memory_limiter`,
+    /// which is shared with the host application. Entries are not cached if the
+    /// quota is exceeded.
+    pub fn memory_limiter(mut self, memory_limiter: Arc<dyn MemoryLimiter>) -> EngineBuilder {
+        self.ext.memory_limiter = Some(memory_limiter);
+        self
+    }
+
+    /// Cache entries in `entry_cache` instead of memtables.
+    pub fn entry_cache(mut self, entry_cache: Arc<dyn EntryCache>) -> EngineBuilder {
+        self.ext.entry_cache = Some(entry_cache);
+        self
+    }
+
+    /// Index regions by memtables created by `factory`, e.g. to try another
+    /// `MemTableAccessor`.
+    pub fn memtable_factory(mut self, factory: Arc<dyn MemTableFactory>) -> EngineBuilder {
+        self.ext.memtable_factory = Some(factory);
+        self
+    }
+
+    /// Measure time by `clock`, which also runs the background tasks started by
+    /// the engine. Tests can move a `ManualClock` instead of sleeping.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> EngineBuilder {
+        self.ext.clock = Some(clock);
+        self
+    }
+
+    /// Report key/value changes and clean commands to `observer` as they are
+    /// recovered, see `RecoveryObserver`. Unlike `FileEngine::open_observer`,
+    /// the engine is a writer.
+    pub fn recovery_observer(mut self, observer: Arc<dyn RecoveryObserver>) -> EngineBuilder {
+        self.ext.recovery_observer = Some(observer);
+        self
+    }
+
+    /// Only recover batches from `offset` of file `file_num` on, e.g. for a warm
+    /// standby whose state, applied from the log or loaded from a snapshot,
+    /// already reflects the batches before. Earlier files aren't read, so the
+    /// items in them can't be read from the engine, and the files are purged as
+    /// if the items were compacted. `offset` must be where a batch starts, like
+    /// a position returned by `last_position`, or the end of the batches of the
+    /// file.
+    pub fn recover_from(mut self, file_num: u64, offset: u64) -> EngineBuilder {
+        self.ext.recover_from = Some((file_num, offset));
+        self
+    }
+
+    /// Open the engine, panicking like `FileEngine::new` if it fails.
+    pub fn build(self) -> FileEngine {
+        FileEngine::new_impl(self.cfg, self.ext)
+    }
+
+    /// Open the engine, or return an error like `FileEngine::open` if the files
+    /// can't be recovered.
+    pub fn open(self) -> Result<FileEngine> {
+        FileEngine::open_impl(self.cfg, self.ext)
+    }
+}
+
+impl FileEngine {
+    pub fn new(cfg: Config) -> FileEngine {
+        FileEngine::new_impl(cfg, Extensions::default())
+    }
+
+    /// Like `new`, but return an error rather than panic if the files can't be
+    /// recovered, e.g. written in another format version.
+    pub fn open(cfg: Config) -> Result<FileEngine> {
+        FileEngine::open_impl(cfg, Extensions::default())
+    }
+
+    /// Create an observer following files written by another engine in `cfg.dir`.
+    /// The observer never modifies the files, and all writes to it fail. New
+    /// batches from the writer are applied by `catch_up` or `start_tailing`.
+    pub fn new_observer(cfg: Config) -> FileEngine {
+        FileEngine::open_observer(cfg)
+            .unwrap_or_else(|e| panic!("Open raft log failed, error: {:?}", e))
+    }
+
+    /// Like `new_observer`, but return an error rather than panic if the files
+    /// can't be recovered.
+    pub fn open_observer(mut cfg: Config) -> Result<FileEngine> {
+        if cfg.name.is_empty() {
+            cfg.name = format!("{}-observer", cfg.dir);
+        }
+        let mut pipe_log = PipeLog::open_read_only(&cfg.dir, cfg.target_file_size.0, None)?;
+        pipe_log.set_name(&cfg.name);
+        pipe_log.set_mmap_sealed_files(cfg.mmap_sealed_files);
+        FileEngine::with_pipe_log(cfg, pipe_log, Extensions::default())
+    }
+
+    /// Start building an engine over `cfg` with dependencies injected by the
+    /// host application, see `EngineBuilder`.
+    pub fn builder(cfg: Config) -> EngineBuilder {
+        EngineBuilder {
+            cfg,
+            ext: Extensions::default(),
+        }
+    }
+
+    fn new_impl(cfg: Config, ext: Extensions) -> FileEngine {
+        FileEngine::open_impl(cfg, ext)
+            .unwrap_or_else(|e| panic!("Open raft log failed, error: {}", e))
+    }
+
+    fn open_impl(mut cfg: Config, ext: Extensions) -> Result<FileEngine> {
+        if cfg.name.is_empty() {
+            cfg.name = cfg.dir.clone();
+        }
+        let mut pipe_log = PipeLog::open_with_cold_storage(
+            &cfg.dir,
+            cfg.bytes_per_sync.0,
+            cfg.target_file_size.0,
+            ext.cold_storage.clone(),
+        )?;
+        pipe_log.set_name(&cfg.name);
+        pipe_log.set_compression(!cfg.disable_compression);
+        pipe_log.set_scan_bypass_cache(cfg.scan_bypass_page_cache);
+        pipe_log.set_mmap_sealed_files(cfg.mmap_sealed_files);
+        if !cfg.compression_dictionary.is_empty() {
+            let content = fs::read(&cfg.compression_dictionary)?;
+            pipe_log.set_dictionary(Some(Dictionary::new(content)))?;
+        }
+        if let Some(clock) = &ext.clock {
+            pipe_log.set_clock(clock.clone());
+        }
+        if !cfg.archive_dir.is_empty() {
+            pipe_log.set_archive(
+                &cfg.archive_dir,
+                cfg.archive_retention_size.0,
+                cfg.archive_retention_age.0,
+            )?;
+        }
+        FileEngine::with_pipe_log(cfg, pipe_log, ext)
+    }
+
+    fn with_pipe_log(cfg: Config, pipe_log: PipeLog, ext: Extensions) -> Result<FileEngine> {
+        let metrics = Arc::new(EngineMetrics::register(&cfg.name)?);
+        let cache_stats = Arc::new(SharedCacheStats::new(
+            ext.memory_limiter.clone(),
+            metrics.clone(),
+        ));
+        let prefetcher = Prefetcher::new(PREFETCH_CAPACITY, cache_stats.memory_trace.clone());
+        let mut memtables = Vec::with_capacity(SLOTS_COUNT);
+        for _ in 0..SLOTS_COUNT {
+            memtables.push(RwLock::new(HashMap::default()));
+        }
+        let mut engine = FileEngineInner {
+            cfg,
+            memtables,
+            pipe_log,
+            cache_stats,
+            subscribers: Subscribers::new(),
+            durable_subscribers: Subscribers::new(),
+            durable_notified: Mutex::new(WriteToken(0)),
+            tail_position: Mutex::new((0, 0)),
+            rewrites: AtomicU64::new(0),
+            rewrite_tuner: Mutex::new(RewriteTuner::default()),
+            foreground_bytes: AtomicU64::new(0),
+            rewrite_bytes: AtomicU64::new(0),
+            skipped_batches: AtomicU64::new(0),
+            memory_limiter: ext.memory_limiter,
+            entry_cache: ext.entry_cache,
+            memtable_factory: ext.memtable_factory,
+            recovery_observer: ext.recovery_observer,
+            hot_regions: Mutex::new(HotRegions::new(HOT_REGIONS_CAPACITY)),
+            recent_regions: RecentRegions::new(SLOTS_COUNT),
+            put_if_lock: Mutex::new(()),
+            cleaning: Mutex::new(HashMap::default()),
+            strict_appends: Mutex::new(HashMap::default()),
+            generations: Mutex::new(HashMap::default()),
+            snapshots: RwLock::new(vec![]),
+            pending_writes: Mutex::new(PendingWrites::default()),
+            writes_done: Condvar::new(),
+            applier: None,
+            write_buffer: None,
+            background_tasks: Mutex::new(vec![]),
+            prefetcher,
+            clock: ext.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            metrics,
+        };
+        let recovery_mode = engine.cfg.recovery_mode;
+        if engine.cfg.verify_on_recovery {
+            engine.verify_files(recovery_mode)?;
+        }
+        engine.recover(recovery_mode, ext.recover_from)?;
+        engine.recovery_observer = None;
+        if engine.cfg.async_apply {
+            engine.applier = Some(Applier::new());
+        }
+        if engine.cfg.write_buffer_size.0 > 0
+            && !engine.cfg.strict_append
+            && !engine.pipe_log.is_read_only()
+        {
+            engine.write_buffer = Some(WriteBuffer::new());
+        }
+
+        let inner = Arc::new(engine);
+        if let Some(applier) = &inner.applier {
+            applier.start(Arc::downgrade(&inner), inner.thread_name("apply"));
+        }
+        if let Some(buffer) = &inner.write_buffer {
+            buffer.start(
+                Arc::downgrade(&inner),
+                inner.clock.clone(),
+                inner.thread_name("write-buffer"),
+                inner.cfg.write_buffer_wait.0,
+            );
+        }
+        Ok(FileEngine { inner })
+    }
+
+    pub(crate) fn region_ids(&self) -> Vec<u64> {
+        let mut ids = vec![];
+        for memtables in &self.inner.memtables {
+            ids.extend(memtables.read().unwrap().keys());
+        }
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Count of regions with data.
+    pub fn regions(&self) -> usize {
+        self.inner
+            .memtables
+            .iter()
+            .map(|memtables| memtables.read().unwrap().len())
+            .sum()
+    }
+
+    /// Totals over all regions, for admission decisions like whether more regions
+    /// can be accepted. Each memtable is visited, but no file is read.
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        for memtables in &self.inner.memtables {
+            let memtables = memtables.read().unwrap();
+            summary.regions += memtables.len();
+            for memtable in memtables.values() {
+                summary.entries += memtable.entries_count();
+                summary.entries_size += memtable.entries_size();
+                summary.cache_size += memtable.cache_size();
+                summary.kvs += memtable.kvs_total_count();
+            }
+        }
+        summary
+    }
+
+    /// Return the first and last index of entries of the region.
+    pub(crate) fn entries_range(&self, region_id: u64) -> Option<(u64, u64)> {
+        let memtables = self.inner.memtables[region_id as usize % SLOTS_COUNT]
+            .read()
+            .unwrap();
+        let memtable = memtables.get(&region_id)?;
+        Some((memtable.first_index()?, memtable.last_index()?))
+    }
+
+    pub(crate) fn region_kvs(&self, region_id: u64) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut values = vec![];
+        let mut pins = vec![];
+        {
+            let memtables = self.inner.memtables[region_id as usize % SLOTS_COUNT]
+                .read()
+                .unwrap();
+            if let Some(memtable) = memtables.get(&region_id) {
+                memtable.fetch_all_kv_values(&mut values);
+            }
+            for (_, value) in &values {
+                if let KvValue::InFile(idx) = value {
+                    pins.push(self.inner.pipe_log.pin(idx.file_num)?);
+                }
+            }
+        }
+        values
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.inner.load_value(value)?)))
+            .collect()
+    }
+
+    /// Pass all live data to `sink`: regions in ascending order, and for each region
+    /// entries in index order followed by key value pairs in key order.
+    pub fn export_all<F: FnMut(u64, ExportItem)>(&self, mut sink: F) -> Result<()> {
+        let mut entries = Vec::new();
+        for region_id in self.region_ids() {
+            if let Some((first, last)) = self.entries_range(region_id) {
+                let mut begin = first;
+                while begin <= last {
+                    let end = cmp::min(begin + EXPORT_BATCH_ENTRIES, last + 1);
+                    self.fetch_entries_to(region_id, begin, end, None, &mut entries)?;
+                    for e in entries.drain(..) {
+                        sink(region_id, ExportItem::Entry(e));
+                    }
+                    begin = end;
+                }
+            }
+            let mut kvs = self.region_kvs(region_id)?;
+            kvs.sort_unstable();
+            for (key, value) in kvs {
+                sink(region_id, ExportItem::Kv(key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return all items of the region persisted in log files in log order,
+    /// including the ones already compacted or overwritten, for debugging.
+    pub fn dump_region(&self, region_id: u64) -> Result<Vec<DumpItem>> {
+        self.inner.dump_region(region_id)
+    }
+
+    /// Like `RaftEngine::gc`, but also report reclaimed space. Old files are purged
+    /// if the region was the one keeping the oldest file.
+    pub fn gc_with_stats(&self, raft_group_id: u64, to: u64) -> Result<GcStats> {
+        Ok(self.inner.compact_to(raft_group_id, to))
+    }
+
+    /// Rewrite all entries and key value pairs of the region to the active file
+    /// right now, so that it no longer keeps old files from being purged. Return
+    /// false if the region has nothing to rewrite.
+    pub fn rewrite_region(&self, region_id: u64) -> Result<bool> {
+        self.inner.rewrite_region(region_id)
+    }
+
+    /// Clean the region, rewrite other regions sharing files with it, and purge the
+    /// files at once. This is for reclaiming disk space in emergency, as it may
+    /// rewrite lots of data. Return the count of purged files.
+    pub fn unsafe_destroy_region(&self, region_id: u64) -> Result<u64> {
+        self.inner.unsafe_destroy_region(region_id)
+    }
+
+    /// Drop entries of the region after `index`, for unsafe recovery when the
+    /// quorum is lost. The raft state is adjusted not to point beyond `index`. The
+    /// entry at `index` is written again, which overwrites the ones after it like
+    /// a raft append after conflicts, so the region isn't rewritten. If no entry
+    /// is kept, the region is cleaned and only its key value pairs are written
+    /// again. Return the count of dropped entries.
+    pub fn unsafe_truncate_region(&self, region_id: u64, index: u64) -> Result<u64> {
+        let (first, last) = match self.entries_range(region_id) {
+            Some(range) if range.1 > index => range,
+            _ => return Ok(0),
+        };
+        let state: Option<RaftLocalState> = self.get_raft_state(region_id)?;
+
+        let mut log_batch = LogBatch::new();
+        log_batch.overwrite = true;
+        if index >= first {
+            let mut entries = vec![];
+            self.fetch_entries_to(region_id, index, index + 1, None, &mut entries)?;
+            log_batch.add_entries(region_id, entries);
+        } else {
+            log_batch.clean_region(region_id);
+            for (key, value) in self.region_kvs(region_id)? {
+                if key != RAFT_LOG_STATE_KEY {
+                    log_batch.put(region_id, &key, &value);
+                }
+            }
+        }
+        if let Some(mut state) = state {
+            state.set_last_index(cmp::min(state.get_last_index(), index));
+            let hard_state = state.mut_hard_state();
+            hard_state.set_commit(cmp::min(hard_state.get_commit(), index));
+            log_batch.put_msg(region_id, RAFT_LOG_STATE_KEY, &state)?;
+        }
+        self.inner.write(log_batch, true)?;
+        Ok(last - cmp::max(index, first.saturating_sub(1)))
+    }
+
+    /// Append `entries` and put `state` of the region in one batch, as a raft ready
+    /// usually writes both. Return written bytes.
+    pub fn append_with_state(
+        &self,
+        region_id: u64,
+        entries: Vec<Entry>,
+        state: &RaftLocalState,
+        sync: bool,
+    ) -> Result<usize> {
+        let batch = LogBatch::default();
+        if !entries.is_empty() {
+            batch.add_entries(region_id, entries);
+        }
+        batch.put_msg(region_id, RAFT_LOG_STATE_KEY, state)?;
+        self.inner.write(batch, sync)
+    }
+
+    /// Put several messages of the region in one batch, e.g. the apply state and the
+    /// region state persisted by a ready. Return written bytes.
+    pub fn put_msgs<M: protobuf::Message>(
+        &self,
+        region_id: u64,
+        msgs: &[(&[u8], &M)],
+        sync: bool,
+    ) -> Result<usize> {
+        let batch = LogBatch::default();
+        batch.put_msgs(region_id, msgs)?;
+        self.inner.write(batch, sync)
+    }
+
+    /// Put `value` to `key` of the region if its current value is `expected`, where
+    /// `None` means the key doesn't exist, and return whether it's put. Conditional
+    /// puts are serialized with each other, but not with plain puts to the key.
+    pub fn put_if(
+        &self,
+        region_id: u64,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<bool> {
+        let _guard = self.inner.put_if_lock.lock().unwrap();
+        if self.inner.get(None, region_id, key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let batch = LogBatch::default();
+        batch.put(region_id, key, value);
+        self.inner.write(batch, false)?;
+        Ok(true)
+    }
+
+    /// Return the encoded entry as stored, for callers forwarding it without
+    /// needing the decoded one.
+    pub fn fetch_entry_bytes(&self, region_id: u64, index: u64) -> Result<Option<Vec<u8>>> {
+        self.inner.get_entry_bytes(region_id, index)
+    }
+
+    /// Like `fetch_entries_to`, but return the total encoded size of fetched entries
+    /// alongside their count, which is what raft limits the size of a message by.
+    pub fn fetch_entries_with_size(
+        &self,
+        region_id: u64,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        to: &mut Vec<Entry>,
+    ) -> Result<(usize, usize)> {
+        self.inner
+            .fetch_entries_to(None, region_id, begin, end, max_size, to)
+    }
+
+    /// Split the region into `targets`: each target region gets the entries of the
+    /// region in its index range and a copy of all key value pairs, then the region is
+    /// cleaned. All changes are written in one batch, so they survive a crash together.
+    /// Writes to the regions should be stopped meanwhile.
+    pub fn split_region(&self, region_id: u64, targets: &[(u64, Range<u64>)]) -> Result<()> {
+        let kvs = self.region_kvs(region_id)?;
+        let batch = LogBatch::new();
+        for (i, (target, indexes)) in targets.iter().enumerate() {
+            if *target == region_id
+                || targets[..i].iter().any(|(t, _)| t == target)
+                || self.entries_range(*target).is_some()
+                || !self.region_kvs(*target)?.is_empty()
+            {
+                return Err(box_err!("Split target region {} exists", target));
+            }
+            if indexes.start < indexes.end {
+                let mut entries = vec![];
+                self.fetch_entries_to(region_id, indexes.start, indexes.end, None, &mut entries)?;
+                batch.add_entries(*target, entries);
+            }
+            for (key, value) in &kvs {
+                batch.put(*target, key, value);
+            }
+        }
+        batch.clean_region(region_id);
+        self.inner.write(batch, true)?;
+        Ok(())
+    }
+
+    /// Move entries and key value pairs of `sources` in order into `target`, and clean
+    /// the sources, in one batch. Entries of each source must continue the ones before
+    /// it, and a later value of a key overrides earlier ones. Writes to the regions
+    /// should be stopped meanwhile.
+    pub fn merge_regions(&self, sources: &[u64], target: u64) -> Result<()> {
+        if sources.contains(&target) {
+            return Err(box_err!("Merge target region {} is a source", target));
+        }
+        let batch = LogBatch::new();
+        let mut next_index = self.entries_range(target).map(|(_, last)| last + 1);
+        for source in sources {
+            if let Some((first, last)) = self.entries_range(*source) {
+                if next_index.map_or(false, |next| next != first) {
+                    return Err(box_err!(
+                        "Entries of region {} start at {}, expect {}",
+                        source,
+                        first,
+                        next_index.unwrap()
+                    ));
+                }
+                let mut entries = vec![];
+                self.fetch_entries_to(*source, first, last + 1, None, &mut entries)?;
+                batch.add_entries(target, entries);
+                next_index = Some(last + 1);
+            }
+            for (key, value) in self.region_kvs(*source)? {
+                batch.put(target, &key, &value);
+            }
+            batch.clean_region(*source);
+        }
+        self.inner.write(batch, true)?;
+        Ok(())
+    }
+
+    /// Rewrite regions with few entries in inactive files, and purge files no longer
+    /// referenced. Return whether anything is rewritten. If `max_compact_threshold`
+    /// is set, more regions are rewritten while files keep growing beyond
+    /// `total_size_limit`.
+    pub fn purge_expired_files(&self) -> Result<bool> {
+        let rewritten = self.inner.rewrite_inactive()?;
+        self.inner.purge_expired_files()?;
+        Ok(rewritten)
+    }
+
+    /// Return bytes of log files older than the inactive threshold, which
+    /// `purge_expired_files` rewrites live data out of and purges. An embedder
+    /// scheduling purges by itself can check it in its own tick, and compact raft
+    /// logs more aggressively while it keeps growing.
+    pub fn purge_pending_bytes(&self) -> u64 {
+        let inactive_file_num = self.inner.inactive_file_num();
+        let first_file_num = self.inner.pipe_log.first_file_num();
+        inactive_file_num.saturating_sub(first_file_num) * self.inner.cfg.target_file_size.0
+    }
+
+    /// Return the index the region should gc its raft logs to, i.e. entries before
+    /// it are suggested to be compacted, or `None` if it doesn't need to. Entries
+    /// are suggested to be compacted if their total size exceeds 2/3 of
+    /// `region_size`, or they are left in files old enough to be purged by
+    /// `total_size_limit`. The caller should limit it to the applied index.
+    pub fn suggest_compact_index(&self, region_id: u64) -> Option<u64> {
+        let gc_file_num = self.inner.gc_file_num();
+        let memtables = self.inner.memtables[region_id as usize % SLOTS_COUNT]
+            .read()
+            .unwrap();
+        let memtable = memtables.get(&region_id)?;
+        self.inner
+            .force_compact_index(memtable.as_ref(), gc_file_num)
+    }
+
+    /// Return (file_num, offset) of the newest batch persisting an item of the
+    /// region, including rewrites of it, or `None` if the region has no data, e.g.
+    /// it's cleaned. Tools tailing log files can resume from the batch.
+    pub fn last_position(&self, region_id: u64) -> Option<(u64, u64)> {
+        let memtables = self.inner.memtables[region_id as usize % SLOTS_COUNT]
+            .read()
+            .unwrap();
+        memtables.get(&region_id)?.last_position()
+    }
+
+    /// Return where the entry of the region is persisted: the file, offset, length
+    /// and compression of its batch, and its offset and length in the uncompressed
+    /// batch. `None` if the region has no such entry, e.g. it's compacted. Support
+    /// engineers can correlate an entry with bytes on disk this way when looking
+    /// into corruptions reported on reads.
+    pub fn locate_entry(&self, region_id: u64, index: u64) -> Option<EntryIndex> {
+        self.inner.with_memtable(None, region_id, |memtable| {
+            memtable?.entry_index(index).cloned()
+        })
+    }
+
+    /// Return whether `purge_expired_files` has anything to do: files to rewrite,
+    /// or files no longer referenced.
+    pub fn needs_purge(&self) -> bool {
+        let pipe_log = &self.inner.pipe_log;
+        self.purge_pending_bytes() > 0 || self.inner.purge_file_num() > pipe_log.first_file_num()
+    }
+
+    /// Return the regions referencing the oldest `files` log files, with how much data
+    /// they hold in each, ordered by file number and then region id.
+    pub fn purge_blockers(&self, files: usize) -> Vec<PurgeBlocker> {
+        let end_file_num = self.inner.pipe_log.first_file_num() + files as u64;
+        let mut blockers = vec![];
+        for memtables in &self.inner.memtables {
+            for memtable in memtables.read().unwrap().values() {
+                for (file_num, usage) in memtable.usage_before(end_file_num) {
+                    blockers.push(PurgeBlocker {
+                        file_num,
+                        region_id: memtable.region_id(),
+                        entries: usage.entries,
+                        entries_size: usage.entries_size,
+                        kvs: usage.kvs,
+                    });
+                }
+            }
+        }
+        blockers.sort_by_key(|b| (b.file_num, b.region_id));
+        blockers
+    }
+
+    /// Return once all writes accepted before the call are synced to disk and
+    /// applied to memtables, e.g. before a checkpoint or a backup of the directory.
+    pub fn flush_barrier(&self) -> Result<()> {
+        self.inner.flush_writes()?;
+        self.inner.wait_for_writes();
+        self.inner.sync_log()
+    }
+
+    /// Turn compression of large batches written since on or off, overriding
+    /// `Config::disable_compression`. Batches already written are still readable.
+    pub fn set_compression_enabled(&self, enabled: bool) {
+        self.inner.pipe_log.set_compression(enabled);
+    }
+
+    /// Compress small batches written since with `dict`, or stop using a dictionary
+    /// if `None`. The dictionary is saved in the directory, so batches compressed
+    /// with it can be read after restarts.
+    pub fn set_compression_dictionary(&self, dict: Option<Dictionary>) -> Result<()> {
+        self.inner.pipe_log.set_dictionary(dict)
+    }
+
+    /// Drop all cached entries of the region, from the entry cache given by the host
+    /// application too, and release their memory to the budget at once. Return the
+    /// released bytes of the engine's cache.
+    pub fn evict_region_cache(&self, region_id: u64) -> u64 {
+        self.inner.evict_region_cache(region_id)
+    }
+
+    /// Keep log files up to `up_to_file` from being purged until the returned guard
+    /// is dropped, e.g. while a backup or an export is copying them. As files are
+    /// purged oldest first, none is purged until then.
+    pub fn pin_files(&self, up_to_file: u64) -> Result<PinnedFiles> {
+        let (first_file_num, last_file_num) = self.inner.pipe_log.pin_files(up_to_file)?;
+        Ok(PinnedFiles {
+            inner: self.inner.clone(),
+            first_file_num,
+            last_file_num,
+        })
+    }
+
+    /// Return the `n` regions writing the most bytes, the hottest first. Counts are
+    /// halved by each update of metrics, so they favor recent writes.
+    pub fn hot_regions(&self, n: usize) -> Vec<RegionWrites> {
+        self.inner.hot_regions.lock().unwrap().top(n)
+    }
+
+    /// Return the `n` regions read or written least recently, the coldest first,
+    /// e.g. to pick ones to move off the store. Reads of snapshots don't count.
+    pub fn coldest_regions(&self, n: usize) -> Vec<u64> {
+        self.inner.coldest_regions(n)
+    }
+
+    /// Bytes of large buffers held by each component, which can be compared with
+    /// heap profiles of the host process.
+    pub fn memory_trace(&self) -> &MemoryTrace {
+        &self.inner.cache_stats.memory_trace
+    }
+
+    /// Return cumulative statistics since the engine is opened, for embedders to
+    /// log engine health without Prometheus.
+    pub fn get_statistics(&self) -> Statistics {
+        let inner = &self.inner;
+        Statistics {
+            bytes_written: inner.pipe_log.bytes_written(),
+            foreground_bytes_written: inner.foreground_bytes.load(Ordering::Relaxed),
+            rewrite_bytes_written: inner.rewrite_bytes.load(Ordering::Relaxed),
+            disk_bytes_written: inner.pipe_log.disk_bytes_written(),
+            bytes_read: inner.pipe_log.bytes_read(),
+            batches_written: inner.pipe_log.batches_written(),
+            rewrites: inner.rewrites.load(Ordering::Relaxed),
+            files_purged: inner.pipe_log.files_purged(),
+            cache_hit: inner.cache_stats.total_hit.load(Ordering::Relaxed) as u64,
+            cache_miss: inner.cache_stats.total_miss.load(Ordering::Relaxed) as u64,
+            cache_reclaimed: inner.cache_stats.total_reclaimed.load(Ordering::Relaxed),
+            batches_skipped: inner.skipped_batches.load(Ordering::Relaxed),
+            first_file_num: inner.pipe_log.first_file_num(),
+            active_file_num: inner.pipe_log.active_file_num(),
+        }
+    }
+
+    /// Return the sequence of the last written batch. Batches are numbered from 1 in
+    /// the order they are written, and batches out of order are ignored on recovery.
+    pub fn latest_sequence(&self) -> u64 {
+        self.inner.pipe_log.latest_sequence()
+    }
+
+    /// When the oldest batch in log files is written, e.g. how far back in time
+    /// the log reaches. Rewritten data counts as written when it's rewritten.
+    /// `None` if no batch has a known time, e.g. all are migrated from v1 files.
+    pub fn oldest_batch_time(&self) -> Option<SystemTime> {
+        let batch_times = self.inner.pipe_log.batch_times();
+        batch_times.iter().map(|t| t.1).min().map(|t| t.physical())
+    }
+
+    /// Times the batches in each log file are written, from the oldest file.
+    pub fn file_time_ranges(&self) -> Vec<FileTimeRange> {
+        let batch_times = self.inner.pipe_log.batch_times();
+        batch_times
+            .into_iter()
+            .map(|(file_num, oldest, newest)| FileTimeRange {
+                file_num,
+                oldest: oldest.physical(),
+                newest: newest.physical(),
+            })
+            .collect()
+    }
+
+    /// For an observer, apply batches appended by the writer since last time.
+    /// Return the count of applied batches.
+    pub fn catch_up(&self) -> Result<usize> {
+        self.inner.catch_up()
+    }
+
+    /// For an observer, start a background task calling `catch_up` every `interval`.
+    pub fn start_tailing(&self, interval: Duration) -> Worker {
+        let inner = self.inner.clone();
+        self.schedule("tail", interval, move || {
+            if let Err(e) = inner.catch_up() {
+                error!("[{}] Catch up raft log failed: {}", inner.cfg.name, e);
+            }
+        })
+    }
+
+    /// Subscribe summaries of all batches written afterwards. A summary is sent after
+    /// the batch is written to the log and applied, or queued to be applied with
+    /// `async_apply` and visible to reads all the same, so for a synced write it's
+    /// durable. Summaries of concurrent writes may arrive in any order, compare
+    /// `BatchSummary::file_num` if needed.
+    pub fn subscribe(&self) -> Receiver<BatchSummary> {
+        self.inner.subscribers.subscribe()
+    }
+
+    /// Write the batch without waiting for it to be synced, and return the token of
+    /// the write. The write is visible to reads once returned, and durable once a
+    /// token no less than it is sent by `subscribe_durable`, e.g. after `sync`, a
+    /// later synced write or the sync task started by `start_sync`. Raftstore can
+    /// advance persisted indexes this way instead of blocking each ready on fsync.
+    pub fn consume_deferred(&self, batch: &mut LogBatch) -> Result<WriteToken> {
+        self.inner.write_deferred(mem::take(batch))
+    }
+
+    /// Subscribe tokens of writes as they become durable. A token received means
+    /// the writes of all tokens up to it are durable, so tokens received keep
+    /// increasing, and not every token is sent.
+    pub fn subscribe_durable(&self) -> Receiver<WriteToken> {
+        self.inner.durable_subscribers.subscribe()
+    }
+
+    /// Whether the write of `token` is durable.
+    pub fn is_durable(&self, token: WriteToken) -> bool {
+        token.0 <= self.inner.pipe_log.durable_sequence()
+    }
+
+    /// Move files beyond `cold_file_threshold` to cold storage. Return the count of
+    /// moved files.
+    pub fn offload_cold_files(&self) -> Result<usize> {
+        let threshold = self.inner.cfg.cold_file_threshold.0;
+        if threshold == 0 {
+            return Ok(0);
+        }
+        let pipe_log = &self.inner.pipe_log;
+        pipe_log.offload_to(pipe_log.files_before(threshold))
+    }
+
+    /// Start a thread reading ahead entries following sequential fetches of regions
+    /// every `interval`, so that a reader like a follower catching up fetches them
+    /// from memory. Nothing is read ahead before it's started.
+    pub fn start_prefetcher(&self, interval: Duration) -> Worker {
+        self.inner.prefetcher.start();
+        let inner = self.inner.clone();
+        self.schedule("prefetch", interval, move || {
+            if let Err(e) = inner.prefetch() {
+                warn!("[{}] Prefetch raft entries failed: {}", inner.cfg.name, e);
+            }
+        })
+    }
+
+    /// Start a background task updating gauges of memory usage, cache usage, files
+    /// count, write amplification and regions count of each slot every `interval`.
+    pub fn start_metrics_updater(&self, interval: Duration) -> Worker {
+        let inner = self.inner.clone();
+        self.schedule("metrics", interval, move || inner.update_metrics())
+    }
+
+    /// Start a background task verifying one inactive log file every `interval`,
+    /// so corruption in cold data is found before anyone reads it. `listener` is
+    /// called with each corruption found. The task holds a reference to the engine
+    /// until the returned handle is dropped.
+    pub fn start_scrub<F>(&self, interval: Duration, listener: F) -> Worker
+    where
+        F: Fn(&Error) + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let mut next_file_num = 0;
+        self.schedule("scrub", interval, move || {
+            inner.scrub_next_file(&mut next_file_num, &listener)
+        })
+    }
+
+    /// Start a background task calling `purge_expired_files` every `interval`, for
+    /// embedders not scheduling purges by themselves.
+    pub fn start_purge(&self, interval: Duration) -> Worker {
+        let engine = self.clone();
+        self.schedule("purge", interval, move || {
+            if let Err(e) = engine.purge_expired_files() {
+                error!("[{}] Purge raft log failed: {}", engine.inner.cfg.name, e);
+            }
+        })
+    }
+
+    /// Start a background task syncing writes made without sync every `interval`,
+    /// bounding how much of them a power loss drops.
+    pub fn start_sync(&self, interval: Duration) -> Worker {
+        let inner = self.inner.clone();
+        self.schedule("sync", interval, move || {
+            if let Err(e) = inner.sync() {
+                error!("[{}] Sync raft log failed: {}", inner.cfg.name, e);
+            }
+        })
+    }
+
+    /// Start a background task preparing the log file following the active one
+    /// right away and after each rotation, so that writes filling up the active
+    /// file don't stall on creating the next one.
+    pub fn start_prepare_file(&self) -> Worker {
+        let (tx, rx) = mpsc::channel();
+        self.inner.pipe_log.set_rotation_listener(Some(tx));
+        let inner = Arc::downgrade(&self.inner);
+        let name = self.inner.thread_name("prepare-file");
+        // Like the applier, the thread only refers to the engine while it prepares,
+        // and stops once rotations aren't listened to.
+        let handle = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || loop {
+                match inner.upgrade() {
+                    Some(inner) => {
+                        if let Err(e) = inner.pipe_log.prepare_next_file() {
+                            warn!("[{}] Prepare raft log file failed: {}", inner.cfg.name, e);
+                        }
+                    }
+                    None => break,
+                }
+                if rx.recv().is_err() {
+                    break;
+                }
+            })
+            .unwrap_or_else(|e| panic!("Spawn thread {} failed, err {:?}", name, e));
+        let inner = Arc::downgrade(&self.inner);
+        Worker::with_cancel(move || {
+            if let Some(inner) = inner.upgrade() {
+                inner.pipe_log.set_rotation_listener(None);
+            }
+            if let Err(e) = handle.join() {
+                error!("Background thread {} panicked: {:?}", name, e);
+            }
+        })
+    }
+
+    /// Health of background tasks started and not stopped yet. A panic of a task
+    /// is caught and reported here and by a metric, and the task is run again
+    /// after a backoff instead of stopping for good.
+    pub fn background_health(&self) -> Vec<TaskHealth> {
+        let mut tasks = self.inner.background_tasks.lock().unwrap();
+        tasks.retain(|t| t.strong_count() > 0);
+        tasks
+            .iter()
+            .filter_map(|t| t.upgrade())
+            .map(|t| t.lock().unwrap().clone())
+            .collect()
+    }
+
+    // Schedule a background task named `task` watched by a `Watchdog`.
+    fn schedule<F>(&self, task: &str, interval: Duration, tick: F) -> Worker
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let health = Arc::new(Mutex::new(TaskHealth {
+            name: task.to_owned(),
+            ..Default::default()
+        }));
+        let mut tasks = self.inner.background_tasks.lock().unwrap();
+        tasks.retain(|t| t.strong_count() > 0);
+        tasks.push(Arc::downgrade(&health));
+        let mut watchdog = Watchdog::new(tick, health);
+        let (name, task) = (self.inner.cfg.name.clone(), task.to_owned());
+        let panics = self.inner.metrics.background_panics(&task);
+        self.inner.clock.schedule(
+            &self.inner.thread_name(&task),
+            interval,
+            Box::new(move || {
+                if let Some(msg) = watchdog.tick() {
+                    panics.inc();
+                    error!("[{}] Background task {} panicked: {}", name, task, msg);
+                }
+            }),
+        )
+    }
+}
+
+impl RaftEngine for FileEngine {
+    type LogBatch = LogBatch;
+
+    fn log_batch(&self, _capacity: usize) -> Self::LogBatch {
+        LogBatch::default()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn get_raft_state(&self, raft_group_id: u64) -> Result<Option<RaftLocalState>> {
+        self.inner.get_msg(None, raft_group_id, RAFT_LOG_STATE_KEY)
+    }
+
+    fn get_entry(&self, raft_group_id: u64, index: u64) -> Result<Option<Entry>> {
+        self.inner.get_entry(None, raft_group_id, index)
+    }
+
+    fn fetch_entries_to(
+        &self,
+        raft_group_id: u64,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        to: &mut Vec<Entry>,
+    ) -> Result<usize> {
+        self.inner
+            .fetch_entries_to(None, raft_group_id, begin, end, max_size, to)
+            .map(|(count, _)| count)
+    }
+
+    fn consume(&self, batch: &mut Self::LogBatch, sync: bool) -> Result<usize> {
+        self.inner.write(std::mem::take(batch), sync)
+    }
+
+    fn consume_and_shrink(
+        &self,
+        batch: &mut Self::LogBatch,
+        sync: bool,
+        _: usize,
+        _: usize,
+    ) -> Result<usize> {
+        self.consume(batch, sync)
+    }
+
+    fn clean(&self, raft_group_id: u64, _: &RaftLocalState, batch: &mut LogBatch) -> Result<()> {
+        batch.clean_region(raft_group_id);
+        Ok(())
+    }
+
+    fn append(&self, raft_group_id: u64, entries: Vec<Entry>) -> Result<usize> {
+        let batch = LogBatch::default();
+        batch.add_entries(raft_group_id, entries);
+        self.inner.write(batch, false)
+    }
+
+    fn put_raft_state(&self, raft_group_id: u64, state: &RaftLocalState) -> Result<()> {
+        self.inner.put_msg(raft_group_id, RAFT_LOG_STATE_KEY, state)
+    }
+
+    fn put_raft_states(&self, states: &[(u64, RaftLocalState)]) -> Result<()> {
+        if states.is_empty() {
+            return Ok(());
+        }
+        let batch = LogBatch::default();
+        for (raft_group_id, state) in states {
+            batch.put_msg(*raft_group_id, RAFT_LOG_STATE_KEY, state)?;
+        }
+        self.inner.write(batch, false).map(|_| ())
+    }
+
+    fn gc(&self, raft_group_id: u64, _from: u64, to: u64) -> Result<usize> {
+        Ok(self.gc_with_stats(raft_group_id, to)?.entries)
+    }
+
+    fn has_builtin_entry_cache(&self) -> bool {
+        true
+    }
+
+    fn gc_entry_cache(&self, raft_group_id: u64, to: u64) {
+        self.inner.compact_cache_to(raft_group_id, to)
+    }
+
+    fn flush_stats(&self) -> CacheStats {
+        let inner = &self.inner;
+        CacheStats {
+            hit: inner.cache_stats.hit.swap(0, Ordering::SeqCst),
+            miss: inner.cache_stats.miss.swap(0, Ordering::SeqCst),
+            mem_size_change: inner.cache_stats.mem_size_change.swap(0, Ordering::SeqCst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena;
+    use crate::clock::ManualClock;
+    use crate::cold_storage::LocalObjectStorage;
+    use crate::log_batch::ItemSummary;
+    use crate::memory::MemoryQuota;
+    use crate::util::{ReadableDuration, ReadableSize};
+    use protobuf::Message as PbMsg;
+    use std::path::Path;
+
+    #[test]
+    fn test_get_entry_from_file() {
+        let normal_batch_size = 10;
+        let compressed_batch_size = 5120;
+        for &entry_size in &[normal_batch_size, compressed_batch_size] {
+            let dir = tempfile::Builder::new()
+                .prefix("test_engine")
+                .tempdir()
+                .unwrap();
+
+            let mut cfg = Config::default();
+            cfg.dir = dir.path().to_str().unwrap().to_owned();
+
+            let engine = FileEngine::new(cfg.clone());
+            let mut entry = Entry::new();
+            entry.set_data(vec![b'x'; entry_size]);
+            for i in 10..20 {
+                entry.set_index(i);
+                engine.append(i, vec![entry.clone()]).unwrap();
+                entry.set_index(i + 1);
+                engine.append(i, vec![entry.clone()]).unwrap();
+            }
+
+            for i in 10..20 {
+                // Test get_entry from cache.
+                entry.set_index(i + 1);
+                assert_eq!(engine.get_entry(i, i + 1).unwrap(), Some(entry.clone()));
+
+                // Test get_entry from file.
+                entry.set_index(i);
+                assert_eq!(engine.get_entry(i, i).unwrap(), Some(entry.clone()));
+            }
+
+            drop(engine);
+
+            // Recover the engine.
+            let engine = FileEngine::new(cfg.clone());
+            for i in 10..20 {
+                entry.set_index(i + 1);
+                assert_eq!(engine.get_entry(i, i + 1).unwrap(), Some(entry.clone()));
+
+                entry.set_index(i);
+                assert_eq!(engine.get_entry(i, i).unwrap(), Some(entry.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression_dictionary() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_compression_dictionary")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let entry = |index| {
+            let mut e = Entry::new();
+            e.set_index(index);
+            let cmd = format!(
+                "put table_1/column_family_default/key_{:08} value_{:08}",
+                index, index
+            );
+            e.set_data(cmd.into_bytes());
+            e
+        };
+        let samples: Vec<_> = (1..=10)
+            .map(|i| entry(i).write_to_bytes().unwrap())
+            .collect();
+        let samples: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = Dictionary::train(&samples, 1024);
+        let dict_path = dir.path().join("dict");
+        fs::write(&dict_path, dict.content()).unwrap();
+        cfg.compression_dictionary = dict_path.to_str().unwrap().to_owned();
+
+        let engine = FileEngine::new(cfg.clone());
+        let compression_type = |engine: &FileEngine, index| {
+            let memtables = engine.inner.memtables[1].read().unwrap();
+            memtables[&1].entry_index(index).unwrap().compression_type
+        };
+        engine.append(1, vec![entry(11)]).unwrap();
+        assert_eq!(compression_type(&engine, 11), CompressionType::Lz4Dict);
+        engine.set_compression_dictionary(None).unwrap();
+        engine.append(1, vec![entry(12)]).unwrap();
+        assert_eq!(compression_type(&engine, 12), CompressionType::None);
+        let other = Dictionary::new(samples[0].to_vec());
+        let dict_ids = [dict.id(), other.id()];
+        engine.set_compression_dictionary(Some(other)).unwrap();
+        engine.append(1, vec![entry(13)]).unwrap();
+        assert_eq!(compression_type(&engine, 13), CompressionType::Lz4Dict);
+        drop(engine);
+
+        // Dictionaries are loaded from the directory, even if the configured one is
+        // gone.
+        assert!(crate::check::check_dir(&cfg.dir).unwrap().is_empty());
+        fs::remove_file(&dict_path).unwrap();
+        cfg.compression_dictionary.clear();
+        let archive_dir = dir.path().join("archive");
+        cfg.archive_dir = archive_dir.to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        let engine = FileEngine::new(cfg);
+        for i in 11..=13 {
+            assert_eq!(engine.get_entry(1, i).unwrap(), Some(entry(i)));
+        }
+
+        // Saved dictionaries are removed with files compressed with them, and kept
+        // along with archived files.
+        let dict_file = |dir: &Path, id| dir.join(crate::dictionary::file_name_of(id));
+        for i in 14..=64 {
+            engine.append(1, vec![entry(i)]).unwrap();
+        }
+        engine.gc(1, 0, 64).unwrap();
+        engine.purge_expired_files().unwrap();
+        assert!(engine.inner.pipe_log.first_file_num() > 1);
+        for id in dict_ids {
+            assert!(!dict_file(dir.path(), id).exists());
+            assert!(dict_file(&archive_dir, id).exists());
+        }
+        assert_eq!(engine.get_entry(1, 64).unwrap(), Some(entry(64)));
+    }
+
+    #[test]
+    fn test_disable_compression() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_disable_compression")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.disable_compression = true;
+        let engine = FileEngine::new(cfg.clone());
+        let compression_type = |index| {
+            let memtables = engine.inner.memtables[1].read().unwrap();
+            memtables[&1].entry_index(index).unwrap().compression_type
+        };
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 5120]);
+        entry.set_index(1);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        assert_eq!(compression_type(1), CompressionType::None);
+        engine.set_compression_enabled(true);
+        entry.set_index(2);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        assert_eq!(compression_type(2), CompressionType::Lz4);
+        engine.set_compression_enabled(false);
+        entry.set_index(3);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        assert_eq!(compression_type(3), CompressionType::None);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        for i in 1..=3 {
+            entry.set_index(i);
+            assert_eq!(engine.get_entry(1, i).unwrap(), Some(entry.clone()));
+        }
+    }
+
+    #[test]
+    fn test_verify_on_recovery() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_verify_on_recovery")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.verify_on_recovery = true;
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        assert!(engine.inner.pipe_log.active_file_num() > 2);
+        drop(engine);
+
+        // Clean files pass the verification.
+        let engine = FileEngine::new(cfg.clone());
+        assert!(engine
+            .inner
+            .verify_files(RecoveryMode::AbsoluteConsistency)
+            .is_ok());
+
+        // A partially written header of the active file is reported, unless the
+        // corrupted tail is tolerated.
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        let path = dir.path().join(format!("{:016}.raftlog", active_file_num));
+        let active = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &active[..FILE_HEADER_LEN / 2]).unwrap();
+        assert!(engine
+            .inner
+            .verify_files(RecoveryMode::AbsoluteConsistency)
+            .is_err());
+        assert!(engine
+            .inner
+            .verify_files(RecoveryMode::TolerateCorruptedTailRecords)
+            .is_ok());
+        std::fs::write(&path, &active).unwrap();
+        drop(engine);
+
+        // Corrupt the first batch of the first file.
+        let header_len = FILE_HEADER_LEN as u64;
+        let path = dir.path().join("0000000000000001.raftlog");
+        let mut content = std::fs::read(&path).unwrap();
+        let pos = header_len as usize + HEADER_LEN + 4;
+        content[pos] = !content[pos];
+        std::fs::write(&path, &content).unwrap();
+
+        match verify_file(1, &content, &Dictionaries::default()) {
+            Err(Error::Corruption(1, offset, _)) => assert_eq!(offset, header_len),
+            res => panic!("unexpected verify result: {:?}", res),
+        }
+        assert!(std::panic::catch_unwind(|| FileEngine::new(cfg.clone())).is_err());
+    }
+
+    #[test]
+    fn test_skip_recovery_checksum() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_skip_recovery_checksum")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        assert!(active_file_num > 2);
+        drop(engine);
+
+        // Damage the checksum of the first batch of a sealed file, and of the last
+        // batch of the active file.
+        let flip_checksum = |file_num: u64, last: bool| {
+            let path = dir.path().join(format!("{:016}.raftlog", file_num));
+            let mut content = std::fs::read(&path).unwrap();
+            let mut buf = &content[FILE_HEADER_LEN..];
+            let mut end = FILE_HEADER_LEN;
+            while LogBatch::from_bytes(&mut buf, file_num, 0, &Dictionaries::default())
+                .unwrap()
+                .is_some()
+            {
+                end = content.len() - buf.len();
+                if !last {
+                    break;
+                }
+            }
+            content[end - 1] = !content[end - 1];
+            std::fs::write(&path, &content).unwrap();
+        };
+        flip_checksum(1, false);
+        match FileEngine::open(cfg.clone()) {
+            Err(Error::Corruption(1, offset, _)) => assert_eq!(offset, FILE_HEADER_LEN as u64),
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        // Sealed files are trusted, but not the active one, whose damaged tail is
+        // dropped.
+        cfg.skip_recovery_checksum = true;
+        flip_checksum(active_file_num, true);
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.entries_range(1), Some((1, 18)));
+        for i in 1..19 {
+            entry.set_index(i);
+            assert_eq!(engine.get_entry(1, i).unwrap(), Some(entry.clone()));
+        }
+    }
+
+    #[test]
+    fn test_recover_from() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_recover_from")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        // Written until the active file is a new empty one.
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        let mut last_index = 0;
+        loop {
+            let active_file_num = engine.inner.pipe_log.active_file_num();
+            last_index += 1;
+            entry.set_index(last_index);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            if active_file_num >= 3 && engine.inner.pipe_log.active_file_num() > active_file_num {
+                break;
+            }
+        }
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        let latest_sequence = engine.latest_sequence();
+        drop(engine);
+
+        // Nothing is recovered, but new batches still follow the skipped ones.
+        let header_len = FILE_HEADER_LEN as u64;
+        let engine = FileEngine::builder(cfg.clone())
+            .recover_from(active_file_num, header_len)
+            .open()
+            .unwrap();
+        assert_eq!(engine.regions(), 0);
+        assert_eq!(engine.latest_sequence(), latest_sequence);
+        entry.set_index(1);
+        engine.append(2, vec![entry.clone()]).unwrap();
+        drop(engine);
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.entries_range(1), Some((1, last_index)));
+        assert_eq!(engine.entries_range(2), Some((1, 1)));
+        let (file_num, offset) = engine.last_position(2).unwrap();
+        assert_eq!((file_num, offset), (active_file_num, header_len));
+        entry.set_index(last_index + 1);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        let latest_sequence = engine.latest_sequence();
+        drop(engine);
+
+        // Recovered from the batch of region 2 on, without reading earlier files.
+        std::fs::write(pipe_log::log_file_path(&cfg.dir, 1), b"garbage").unwrap();
+        let engine = FileEngine::builder(cfg.clone())
+            .recover_from(file_num, offset)
+            .open()
+            .unwrap();
+        assert_eq!(
+            engine.entries_range(1),
+            Some((last_index + 1, last_index + 1))
+        );
+        entry.set_index(1);
+        assert_eq!(engine.get_entry(2, 1).unwrap(), Some(entry));
+        assert_eq!(engine.latest_sequence(), latest_sequence);
+        drop(engine);
+
+        // From the tail, with batches before it skipped.
+        let (_, tail) = {
+            let engine = FileEngine::builder(cfg.clone())
+                .recover_from(file_num, offset)
+                .open()
+                .unwrap();
+            let tail = *engine.inner.tail_position.lock().unwrap();
+            tail
+        };
+        let engine = FileEngine::builder(cfg.clone())
+            .recover_from(file_num, tail)
+            .open()
+            .unwrap();
+        assert_eq!(engine.regions(), 0);
+        assert_eq!(engine.latest_sequence(), latest_sequence);
+        drop(engine);
+
+        // Not where a batch starts, or out of the files.
+        assert!(FileEngine::builder(cfg.clone())
+            .recover_from(file_num, offset + 1)
+            .open()
+            .is_err());
+        assert!(FileEngine::builder(cfg.clone())
+            .recover_from(file_num, tail + 1)
+            .open()
+            .is_err());
+        assert!(FileEngine::builder(cfg.clone())
+            .recover_from(file_num + 1, 0)
+            .open()
+            .is_err());
+    }
+
+    #[test]
+    fn test_gc_with_stats() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_gc_with_stats")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        engine.append(2, vec![entry.clone()]).unwrap();
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+
+        // Region 1 still keeps the oldest file.
+        let stats = engine.gc_with_stats(1, 5).unwrap();
+        assert_eq!(stats.entries, 4);
+        assert!(stats.bytes >= 4 * 128);
+        assert!(stats.cache_bytes <= stats.bytes);
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num);
+
+        let stats = engine.gc_with_stats(1, 20).unwrap();
+        assert_eq!(stats.entries, 15);
+        assert!(engine.inner.pipe_log.first_file_num() > first_file_num);
+        assert_eq!(engine.gc_with_stats(1, 20).unwrap(), GcStats::default());
+    }
+
+    #[test]
+    fn test_adaptive_rewrite() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_adaptive_rewrite")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cache_size_limit = ReadableSize::kb(2);
+        cfg.total_size_limit = ReadableSize::kb(4);
+        cfg.compact_threshold = 2;
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 600]);
+        for &(max_compact_threshold, rewritten) in &[(0, false), (100, true)] {
+            let path = dir.path().join(max_compact_threshold.to_string());
+            cfg.dir = path.to_str().unwrap().to_owned();
+            cfg.max_compact_threshold = max_compact_threshold;
+            let engine = FileEngine::new(cfg.clone());
+            for i in 1..=10 {
+                entry.set_index(i);
+                engine.append(1, vec![entry.clone()]).unwrap();
+            }
+            let first_file_num = engine.inner.pipe_log.first_file_num();
+            assert_eq!(engine.purge_expired_files().unwrap(), rewritten);
+            let purged = engine.inner.pipe_log.first_file_num() > first_file_num;
+            assert_eq!(purged, rewritten);
+            let e = engine.get_entry(1, 1).unwrap().unwrap();
+            assert_eq!(e.get_data(), entry.get_data());
+        }
+    }
+
+    #[test]
+    fn test_rewrite_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_rewrite_region")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..5 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(2, vec![entry.clone()]).unwrap();
+        }
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+        let region_min_file_num = |region_id: u64| {
+            let memtables = engine.inner.memtables[region_id as usize % SLOTS_COUNT]
+                .read()
+                .unwrap();
+            memtables[&region_id].min_file_num().unwrap()
+        };
+        assert_eq!(region_min_file_num(1), first_file_num);
+
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        assert!(engine.rewrite_region(1).unwrap());
+        assert_eq!(region_min_file_num(1), active_file_num);
+        for i in 1..5 {
+            let e = engine.get_entry(1, i).unwrap().unwrap();
+            assert_eq!(e.get_data(), entry.get_data());
+        }
+        assert_eq!(engine.inner.get(None, 1, b"k").unwrap().unwrap(), b"v");
+        assert!(!engine.rewrite_region(3).unwrap());
+
+        // Writes of the region wait for its rewrite, but not writes of others.
+        let rewrite = engine.inner.start_rewrite(1);
+        let (tx, rx) = mpsc::channel();
+        let e = engine.clone();
+        let handle = thread::spawn(move || {
+            let mut batch = LogBatch::new();
+            batch.put(1, b"k", b"v2");
+            e.consume(&mut batch, false).unwrap();
+            tx.send(()).unwrap();
+        });
+        let mut batch = LogBatch::new();
+        batch.put(2, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(engine.inner.get(None, 1, b"k").unwrap().unwrap(), b"v");
+        drop(rewrite);
+        rx.recv().unwrap();
+        handle.join().unwrap();
+        assert_eq!(engine.inner.get(None, 1, b"k").unwrap().unwrap(), b"v2");
+    }
+
+    #[test]
+    fn test_append_with_state() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_append_with_state")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let mut state = RaftLocalState::new();
+        {
+            let engine = FileEngine::new(cfg.clone());
+            let mut entry = Entry::new();
+            for i in 1..=10 {
+                entry.set_index(i);
+                state.set_last_index(i);
+                let sequence = engine.latest_sequence();
+                engine
+                    .append_with_state(1, vec![entry.clone()], &state, i == 10)
+                    .unwrap();
+                // Entries and the state are written in one batch.
+                assert_eq!(engine.latest_sequence(), sequence + 1);
+            }
+            state.mut_hard_state().set_commit(10);
+            engine.append_with_state(1, vec![], &state, true).unwrap();
+        }
+
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), Some((1, 10)));
+        assert_eq!(engine.get_raft_state(1).unwrap().unwrap(), state);
+    }
+
+    #[test]
+    fn test_put_msgs() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_put_msgs")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let mut applied = RaftLocalState::new();
+        applied.set_last_index(5);
+        let mut region = RaftLocalState::new();
+        region.mut_hard_state().set_term(3);
+        {
+            let engine = FileEngine::new(cfg.clone());
+            let sequence = engine.latest_sequence();
+            engine
+                .put_msgs(1, &[(b"apply", &applied), (b"region", &region)], true)
+                .unwrap();
+            // All messages are written in one batch.
+            assert_eq!(engine.latest_sequence(), sequence + 1);
+            let batch = LogBatch::default();
+            batch.put_msgs::<RaftLocalState>(1, &[]).unwrap();
+            assert!(batch.is_empty());
+        }
+
+        let engine = FileEngine::new(cfg);
+        let get = |key: &[u8]| {
+            let m = engine.inner.get_msg::<RaftLocalState>(None, 1, key);
+            m.unwrap().unwrap()
+        };
+        assert_eq!(get(b"apply"), applied);
+        assert_eq!(get(b"region"), region);
+    }
+
+    #[test]
+    fn test_recovery_observer() {
+        #[derive(Default)]
+        struct Events(Mutex<Vec<String>>);
+        impl RecoveryObserver for Events {
+            fn on_put(&self, region_id: u64, key: &[u8], value: &[u8]) {
+                let event = format!("put {} {:?} {:?}", region_id, key, value);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_delete(&self, region_id: u64, key: &[u8]) {
+                let event = format!("delete {} {:?}", region_id, key);
+                self.0.lock().unwrap().push(event);
+            }
+            fn on_clean(&self, region_id: u64) {
+                self.0.lock().unwrap().push(format!("clean {}", region_id));
+            }
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("test_recovery_observer")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        {
+            let engine = FileEngine::new(cfg.clone());
+            let mut batch = LogBatch::default();
+            batch.put(1, b"k", b"v1");
+            batch.put(2, b"k", b"v2");
+            engine.consume(&mut batch, false).unwrap();
+            let mut entry = Entry::new();
+            entry.set_index(1);
+            engine.append(1, vec![entry]).unwrap();
+            batch.delete(1, b"k");
+            batch.clean_region(2);
+            engine.consume(&mut batch, false).unwrap();
+        }
+
+        let events = Arc::new(Events::default());
+        let engine = FileEngine::builder(cfg.clone())
+            .recovery_observer(events.clone())
+            .open()
+            .unwrap();
+        let expected = vec![
+            format!("put 1 {:?} {:?}", b"k", b"v1"),
+            format!("put 2 {:?} {:?}", b"k", b"v2"),
+            format!("delete 1 {:?}", b"k"),
+            "clean 2".to_owned(),
+        ];
+        assert_eq!(*events.0.lock().unwrap(), expected);
+        assert_eq!(engine.entries_range(1), Some((1, 1)));
+
+        // Writes after recovery aren't reported.
+        let mut batch = LogBatch::default();
+        batch.put(3, b"k", b"v3");
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(events.0.lock().unwrap().len(), expected.len());
+        let (file_num, offset) = engine.last_position(3).unwrap();
+        drop(engine);
+
+        // Combined with other extensions, only the batches recovered are reported.
+        let events = Arc::new(Events::default());
+        let quota = Arc::new(MemoryQuota::new(1 << 20));
+        let engine = FileEngine::builder(cfg)
+            .recovery_observer(events.clone())
+            .recover_from(file_num, offset)
+            .memory_limiter(quota.clone())
+            .open()
+            .unwrap();
+        let expected = vec![format!("put 3 {:?} {:?}", b"k", b"v3")];
+        assert_eq!(*events.0.lock().unwrap(), expected);
+        assert_eq!(
+            engine.region_kvs(3).unwrap(),
+            vec![(b"k".to_vec(), b"v3".to_vec())]
+        );
+        assert_eq!(engine.entries_range(1), None);
+        drop(engine);
+        assert_eq!(quota.used(), 0);
+    }
+
+    #[test]
+    fn test_put_if() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_put_if")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        {
+            let engine = FileEngine::new(cfg.clone());
+            assert!(!engine.put_if(1, b"owner", Some(b"a"), b"b").unwrap());
+            assert!(engine.put_if(1, b"owner", None, b"a").unwrap());
+            assert!(!engine.put_if(1, b"owner", None, b"b").unwrap());
+            assert!(!engine.put_if(1, b"owner", Some(b"b"), b"c").unwrap());
+            assert_eq!(
+                engine.region_kvs(1).unwrap(),
+                vec![(b"owner".to_vec(), b"a".to_vec())]
+            );
+
+            // Only one of the racing puts wins.
+            let handles: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let engine = engine.clone();
+                    std::thread::spawn(move || {
+                        engine.put_if(1, b"owner", Some(b"a"), &[i]).unwrap()
+                    })
+                })
+                .collect();
+            let wins = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .filter(|won| *won)
+                .count();
+            assert_eq!(wins, 1);
+        }
+
+        // Successful puts are persisted.
+        let engine = FileEngine::new(cfg);
+        let kvs = engine.region_kvs(1).unwrap();
+        assert_eq!(kvs.len(), 1);
+        assert_eq!(kvs[0].1.len(), 1);
+        assert!(kvs[0].1[0] < 4);
+    }
+
+    #[test]
+    fn test_split_merge_regions() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_split_merge_regions")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        for i in 1..=10 {
+            entry.set_index(i);
+            entry.set_term(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v1");
+        engine.consume(&mut batch, false).unwrap();
+
+        assert!(engine.split_region(1, &[(2, 1..5), (2, 5..11)]).is_err());
+        assert!(engine.split_region(1, &[(2, 0..5)]).is_err());
+        engine.split_region(1, &[(2, 1..5), (3, 5..11)]).unwrap();
+        assert_eq!(engine.region_ids(), vec![2, 3]);
+        assert_eq!(engine.entries_range(2), Some((1, 4)));
+        assert_eq!(engine.entries_range(3), Some((5, 10)));
+        assert_eq!(engine.get_entry(3, 7).unwrap().unwrap().get_term(), 7);
+        assert_eq!(
+            engine.region_kvs(3).unwrap(),
+            vec![(b"k".to_vec(), b"v1".to_vec())]
+        );
+
+        let mut batch = LogBatch::new();
+        batch.put(3, b"k", b"v3");
+        engine.consume(&mut batch, false).unwrap();
+        assert!(engine.merge_regions(&[3, 2], 4).is_err());
+        engine.merge_regions(&[2, 3], 4).unwrap();
+        drop(engine);
+
+        // Both operations are replayed from the log.
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.region_ids(), vec![4]);
+        assert_eq!(engine.entries_range(4), Some((1, 10)));
+        assert_eq!(engine.get_entry(4, 3).unwrap().unwrap().get_term(), 3);
+        assert_eq!(
+            engine.region_kvs(4).unwrap(),
+            vec![(b"k".to_vec(), b"v3".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_unsafe_destroy_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_unsafe_destroy_region")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            if i % 5 == 0 {
+                entry.set_index(i / 5);
+                engine.append(2, vec![entry.clone()]).unwrap();
+            }
+        }
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+
+        assert!(engine.unsafe_destroy_region(1).unwrap() > 0);
+        assert!(engine.inner.pipe_log.first_file_num() > first_file_num);
+        assert!(engine.get_entry(1, 19).unwrap().is_none());
+        for i in 1..=3 {
+            assert!(engine.get_entry(2, i).unwrap().is_some());
+        }
+        assert_eq!(engine.unsafe_destroy_region(1).unwrap(), 0);
+
+        // Writes of rewritten regions during the destroy aren't overwritten by
+        // their rewrites in recovery.
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(3, vec![entry.clone()]).unwrap();
+        }
+        let e = engine.clone();
+        let writer = thread::spawn(move || {
+            for i in 0..200u32 {
+                let mut batch = LogBatch::new();
+                batch.put(2, b"k", &i.to_le_bytes());
+                e.consume(&mut batch, false).unwrap();
+            }
+        });
+        engine.unsafe_destroy_region(3).unwrap();
+        writer.join().unwrap();
+        drop(engine);
+
+        // The region is still destroyed after restart.
+        let engine = FileEngine::new(cfg);
+        assert!(engine.get_entry(1, 19).unwrap().is_none());
+        assert!(engine.get_entry(3, 19).unwrap().is_none());
+        assert!(engine.get_entry(2, 3).unwrap().is_some());
+        assert_eq!(
+            engine.inner.get(None, 2, b"k").unwrap().unwrap(),
+            199u32.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn test_unsafe_truncate_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_unsafe_truncate_region")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..=20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let mut state = RaftLocalState::default();
+        state.set_last_index(20);
+        state.mut_hard_state().set_commit(18);
+        engine.put_raft_state(1, &state).unwrap();
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+
+        assert_eq!(engine.unsafe_truncate_region(1, 15).unwrap(), 5);
+        assert_eq!(engine.unsafe_truncate_region(1, 15).unwrap(), 0);
+        assert_eq!(engine.unsafe_truncate_region(2, 15).unwrap(), 0);
+        drop(engine);
+
+        // The truncation survives restart.
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.entries_range(1), Some((1, 15)));
+        assert!(engine.get_entry(1, 16).unwrap().is_none());
+        let state = engine.get_raft_state(1).unwrap().unwrap();
+        assert_eq!(state.get_last_index(), 15);
+        assert_eq!(state.get_hard_state().get_commit(), 15);
+        assert_eq!(
+            engine.inner.get(None, 1, b"k").unwrap(),
+            Some(b"v".to_vec())
+        );
+
+        // Drop all entries.
+        assert_eq!(engine.unsafe_truncate_region(1, 0).unwrap(), 15);
+        assert_eq!(engine.entries_range(1), None);
+        assert_eq!(
+            engine.get_raft_state(1).unwrap().unwrap().get_last_index(),
+            0
+        );
+        drop(engine);
+
+        // Entries from index 0, which overwrite checks don't refuse.
+        cfg.strict_append = true;
+        let engine = FileEngine::new(cfg.clone());
+        for i in 0..=5 {
+            entry.set_index(i);
+            engine.append(2, vec![entry.clone()]).unwrap();
+        }
+        assert_eq!(engine.unsafe_truncate_region(2, 2).unwrap(), 3);
+        assert_eq!(engine.entries_range(2), Some((0, 2)));
+        entry.set_index(3);
+        engine.append(2, vec![entry.clone()]).unwrap();
+        drop(engine);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(2), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_put_raft_states() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_put_raft_states")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        let states: Vec<_> = (1..=1000)
+            .map(|region_id| {
+                let mut state = RaftLocalState::default();
+                state.set_last_index(region_id * 10);
+                (region_id, state)
+            })
+            .collect();
+        let sequence = engine.latest_sequence();
+        engine.put_raft_states(&states).unwrap();
+        // Written by one batch.
+        assert_eq!(engine.latest_sequence(), sequence + 1);
+        engine.put_raft_states(&[]).unwrap();
+        assert_eq!(engine.latest_sequence(), sequence + 1);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        for (region_id, state) in &states {
+            assert_eq!(
+                engine.get_raft_state(*region_id).unwrap().as_ref(),
+                Some(state)
+            );
+        }
+    }
+
+    #[test]
+    fn test_needs_purge() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_needs_purge")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cache_size_limit = ReadableSize::kb(2);
+        cfg.compact_threshold = 10;
+        let engine = FileEngine::new(cfg);
+        assert!(!engine.needs_purge());
+
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..=40 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        assert!(engine.needs_purge());
+        let pending = engine.purge_pending_bytes();
+        assert!(pending >= 1024, "{}", pending);
+
+        // Files no longer referenced are purged by gc.
+        engine.gc(1, 0, 41).unwrap();
+        assert_eq!(engine.purge_pending_bytes(), 0);
+        assert!(!engine.needs_purge());
+        assert!(!engine.purge_expired_files().unwrap());
+    }
+
+    #[test]
+    fn test_purge_blockers() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_purge_blockers")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..3 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let mut batch = LogBatch::new();
+        batch.put(2, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        for i in 3..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        engine.gc(1, 0, 3).unwrap();
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+
+        let blockers = engine.purge_blockers(1);
+        assert_eq!(blockers.len(), 2);
+        assert_eq!(
+            blockers[1],
+            PurgeBlocker {
+                file_num: first_file_num,
+                region_id: 2,
+                entries: 0,
+                entries_size: 0,
+                kvs: 1,
+            }
+        );
+        assert_eq!(blockers[0].region_id, 1);
+        assert!(blockers[0].entries > 0);
+
+        let blockers = engine.purge_blockers(2);
+        assert!(blockers.iter().all(|b| b.file_num < first_file_num + 2));
+        assert!(blockers.iter().any(|b| b.file_num == first_file_num + 1));
+    }
+
+    #[test]
+    fn test_skip_rewrite_of_cleaning_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_skip_rewrite_of_cleaning_region")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_index(1);
+        engine.append(1, vec![entry]).unwrap();
+
+        // As if a clean command of the region is being written.
+        engine.inner.cleaning.lock().unwrap().insert(1, 1);
+        engine.rewrite_region(1).unwrap();
+        assert_eq!(engine.get_statistics().rewrites, 0);
+        engine.inner.finish_cleaning(&[1]);
+        assert!(engine.inner.cleaning.lock().unwrap().is_empty());
+        engine.rewrite_region(1).unwrap();
+        assert_eq!(engine.get_statistics().rewrites, 1);
+    }
+
+    #[test]
+    fn test_flush_barrier() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_flush_barrier")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        engine.flush_barrier().unwrap();
+
+        // Block the write of region 1 after it's appended to the file.
+        let slot = engine.inner.memtables[1].write().unwrap();
+        let e = engine.clone();
+        let writer = std::thread::spawn(move || {
+            let mut entry = Entry::new();
+            entry.set_index(1);
+            e.append(1, vec![entry]).unwrap();
+        });
+        while engine.latest_sequence() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let (tx, rx) = mpsc::channel();
+        let e = engine.clone();
+        let barrier = std::thread::spawn(move || {
+            e.flush_barrier().unwrap();
+            tx.send(e.entries_range(1)).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(slot);
+        assert_eq!(rx.recv().unwrap(), Some((1, 1)));
+        writer.join().unwrap();
+        barrier.join().unwrap();
+    }
+
+    #[test]
+    fn test_suggest_compact_index() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_suggest_compact_index")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cache_size_limit = ReadableSize(0);
+        let append = |engine: &FileEngine| {
+            let mut entry = Entry::new();
+            entry.set_data(vec![b'x'; 1000]);
+            for i in 1..=10 {
+                entry.set_index(i);
+                engine.append(1, vec![entry.clone()]).unwrap();
+            }
+        };
+        {
+            let engine = FileEngine::new(cfg.clone());
+            append(&engine);
+            assert_eq!(engine.suggest_compact_index(1), None);
+            assert_eq!(engine.suggest_compact_index(2), None);
+        }
+
+        // The latest entries within 2/3 of the region size are kept.
+        let dir = tempfile::Builder::new()
+            .prefix("test_suggest_compact_index")
+            .tempdir()
+            .unwrap();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize::kb(3);
+        let engine = FileEngine::new(cfg.clone());
+        append(&engine);
+        assert_eq!(engine.suggest_compact_index(1), Some(9));
+        engine.gc(1, 0, 9).unwrap();
+        assert_eq!(engine.suggest_compact_index(1), None);
+
+        // Entries in files to be purged by the total size limit are compacted.
+        let dir = tempfile::Builder::new()
+            .prefix("test_suggest_compact_index")
+            .tempdir()
+            .unwrap();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize(0);
+        cfg.total_size_limit = ReadableSize::kb(4);
+        let engine = FileEngine::new(cfg);
+        append(&engine);
+        let gc_file_num = engine.inner.gc_file_num();
+        assert!(gc_file_num > engine.inner.pipe_log.first_file_num());
+        let index = engine.suggest_compact_index(1).unwrap();
+        let file_num = |index| {
+            let memtables = engine.inner.memtables[1].read().unwrap();
+            memtables[&1].entry_index(index).unwrap().file_num
+        };
+        assert!(index > 1 && index <= 10);
+        assert!(file_num(index - 1) < gc_file_num);
+        assert!(file_num(index) >= gc_file_num);
+    }
+
+    #[test]
+    fn test_batch_sequence() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_batch_sequence")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.latest_sequence(), 0);
+        for i in 1..=3 {
+            let mut entry = Entry::new();
+            entry.set_index(i);
+            engine.append(1, vec![entry]).unwrap();
+            assert_eq!(engine.latest_sequence(), i);
+        }
+        drop(engine);
+
+        // A batch with a stale sequence is ignored on recovery.
+        let batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        let mut content = batch.encode_to_bytes().unwrap();
+        format::set_sequence(&mut content, 2);
+        let path = dir.path().join("0000000000000001.raftlog");
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, &content).unwrap();
+        drop(file);
+
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.latest_sequence(), 3);
+        assert_eq!(engine.get_statistics().batches_skipped, 1);
+        assert!(engine.inner.get(None, 1, b"k").unwrap().is_none());
+        assert!(engine.get_entry(1, 3).unwrap().is_some());
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.latest_sequence(), 4);
+    }
+
+    #[test]
+    fn test_duplicate_batches() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_duplicate_batches")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let path = dir.path().join("0000000000000001.raftlog");
+        let file_len = || std::fs::metadata(&path).unwrap().len() as usize;
+        let append = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+        let entries = |range: std::ops::Range<u64>, data: &str| -> Vec<Entry> {
+            range
+                .map(|i| {
+                    let mut e = Entry::new();
+                    e.set_index(i);
+                    e.set_data(data.as_bytes().to_vec());
+                    e
+                })
+                .collect()
+        };
+
+        let engine = FileEngine::new(cfg.clone());
+        let start = file_len();
+        engine.append(1, entries(1..4, "a")).unwrap();
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v1");
+        engine.consume(&mut batch, false).unwrap();
+        let retry_start = file_len();
+        engine.append(1, entries(3..5, "b")).unwrap();
+        let retry_end = file_len();
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v2");
+        engine.consume(&mut batch, false).unwrap();
+        drop(engine);
+        let content = std::fs::read(&path).unwrap();
+
+        let check = |engine: &FileEngine| {
+            assert_eq!(engine.entries_range(1), Some((1, 4)));
+            let mut fetched = vec![];
+            engine
+                .fetch_entries_to(1, 1, 5, None, &mut fetched)
+                .unwrap();
+            let mut expected = entries(1..3, "a");
+            expected.extend(entries(3..5, "b"));
+            assert_eq!(fetched, expected);
+            assert_eq!(engine.inner.get(None, 1, b"k").unwrap().unwrap(), b"v2");
+        };
+
+        // All batches written again are detected by their sequences and skipped,
+        // though an old value of a key is among them.
+        append(&content[start..]);
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.latest_sequence(), 4);
+        assert_eq!(engine.get_statistics().batches_skipped, 4);
+        check(&engine);
+        drop(engine);
+
+        // A retry of appending entries with a new sequence overwrites the same
+        // entries, instead of appending them twice.
+        let mut retried = content[retry_start..retry_end].to_vec();
+        format::set_sequence(&mut retried, 5);
+        append(&retried);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.latest_sequence(), 5);
+        assert_eq!(engine.get_statistics().batches_skipped, 4);
+        check(&engine);
+    }
+
+    #[test]
+    fn test_unwritten_tail() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_unwritten_tail")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.recovery_mode = RecoveryMode::AbsoluteConsistency;
+        cfg.verify_on_recovery = true;
+
+        let engine = FileEngine::new(cfg.clone());
+        for i in 1..=3 {
+            let mut entry = Entry::new();
+            entry.set_index(i);
+            engine.append(1, vec![entry]).unwrap();
+        }
+        drop(engine);
+        let path = dir.path().join("0000000000000001.raftlog");
+        let tail = std::fs::metadata(&path).unwrap().len();
+        let append = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+
+        // Zeros after the last batch aren't corruption, even in absolute
+        // consistency mode.
+        append(&[0; 4096]);
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.entries_range(1), Some((1, 3)));
+        drop(engine);
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(len, tail + format::TAIL_BARRIER.len() as u64);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(tail)
+            .unwrap();
+
+        // But anything else written after them is.
+        let mut garbage = vec![0; 4096];
+        garbage[4000] = 1;
+        append(&garbage);
+        assert!(FileEngine::open(cfg.clone()).is_err());
+        cfg.recovery_mode = RecoveryMode::TolerateCorruptedTailRecords;
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_locate_entry() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_locate_entry")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let entries: Vec<_> = (1..=3)
+            .map(|i| {
+                let mut entry = Entry::new();
+                entry.set_index(i);
+                entry.set_data(vec![b'x'; i as usize]);
+                entry
+            })
+            .collect();
+        engine.append(1, entries.clone()).unwrap();
+
+        let idx = engine.locate_entry(1, 2).unwrap();
+        assert_eq!(idx.file_num, engine.inner.pipe_log.active_file_num());
+        let content = engine.inner.pipe_log.read_file(idx.file_num).unwrap();
+        let batch = &content[idx.base_offset as usize..];
+        assert_eq!(
+            format::decode_batch_header(batch).unwrap(),
+            (idx.batch_len, idx.compression_type)
+        );
+        assert_eq!(idx.compression_type, CompressionType::None);
+        let mut entry = Entry::new();
+        entry
+            .merge_from_bytes(&batch[idx.offset as usize..(idx.offset + idx.len) as usize])
+            .unwrap();
+        assert_eq!(entry, entries[1]);
+
+        assert_eq!(engine.locate_entry(1, 4), None);
+        assert_eq!(engine.locate_entry(2, 1), None);
+        engine.gc(1, 0, 3).unwrap();
+        assert_eq!(engine.locate_entry(1, 2), None);
+        assert!(engine.locate_entry(1, 3).is_some());
+    }
+
+    #[test]
+    fn test_last_position() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_last_position")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+
+        // The batch at the position has an item of the region.
+        let check = |engine: &FileEngine, region_id: u64| {
+            let (file_num, offset) = engine.last_position(region_id).unwrap();
+            let content = engine.inner.pipe_log.read_file(file_num).unwrap();
+            let batch = LogBatch::from_bytes(
+                &mut &content[offset as usize..],
+                file_num,
+                offset,
+                engine.inner.pipe_log.dictionaries(),
+            )
+            .unwrap()
+            .unwrap();
+            assert!(batch
+                .items
+                .borrow()
+                .iter()
+                .any(|item| item_region_id(item) == region_id));
+            (file_num, offset)
+        };
+
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.last_position(1), None);
+        let mut entry = Entry::new();
+        entry.set_index(1);
+        engine.append(1, vec![entry]).unwrap();
+        let first = check(&engine, 1);
+        let mut batch = LogBatch::new();
+        batch.put(2, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(check(&engine, 1), first);
+        let second = check(&engine, 2);
+        assert!(second > first);
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        let third = check(&engine, 1);
+        assert!(third > second);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        assert_eq!(check(&engine, 1), third);
+        assert_eq!(check(&engine, 2), second);
+        assert!(engine.rewrite_region(2).unwrap());
+        assert!(check(&engine, 2) > third);
+        let mut batch = LogBatch::new();
+        batch.clean_region(1);
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.last_position(1), None);
+    }
+
+    #[test]
+    fn test_tail_barrier() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_tail_barrier")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+
+        let engine = FileEngine::new(cfg.clone());
+        for i in 1..=3 {
+            let mut entry = Entry::new();
+            entry.set_index(i);
+            engine.append(1, vec![entry]).unwrap();
+        }
+        drop(engine);
+        let path = dir.path().join("0000000000000001.raftlog");
+        let tail = std::fs::metadata(&path).unwrap().len();
+
+        // A valid batch, as left by a write of another file reusing the space.
+        let batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        let mut stale = batch.encode_to_bytes().unwrap();
+        format::set_sequence(&mut stale, 10);
+        let append = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+
+        // A torn batch is truncated, leaving a barrier after the tail.
+        append(&stale[..stale.len() / 2]);
+        drop(FileEngine::new(cfg.clone()));
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(
+            content.len() as u64,
+            tail + format::TAIL_BARRIER.len() as u64
+        );
+        assert!(format::is_tail_barrier(&content[tail as usize..]));
+
+        // Bytes beyond the barrier are never parsed, even in absolute consistency
+        // mode, and are dropped by recovery.
+        append(&stale);
+        cfg.recovery_mode = RecoveryMode::AbsoluteConsistency;
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.latest_sequence(), 3);
+        assert!(engine.inner.get(None, 1, b"k").unwrap().is_none());
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            tail + format::TAIL_BARRIER.len() as u64
+        );
+
+        // The barrier is overwritten by the next batch.
+        let mut entry = Entry::new();
+        entry.set_index(4);
+        engine.append(1, vec![entry]).unwrap();
+        drop(engine);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.latest_sequence(), 4);
+        assert_eq!(engine.entries_range(1), Some((1, 4)));
+    }
+
+    #[test]
+    fn test_multi_region_batch_atomicity() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_multi_region_batch_atomicity")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+
+        let entries = |indexes: Range<u64>| -> Vec<Entry> {
+            indexes
+                .map(|i| {
+                    let mut entry = Entry::new();
+                    entry.set_index(i);
+                    entry.set_data(vec![b'x'; 16]);
+                    entry
+                })
+                .collect()
+        };
+        let engine = FileEngine::new(cfg.clone());
+        let mut batch = LogBatch::new();
+        batch.add_entries(1, entries(1..3));
+        batch.add_entries(2, entries(1..3));
+        engine.consume(&mut batch, true).unwrap();
+        drop(engine);
+        let path = dir.path().join("0000000000000001.raftlog");
+        let tail = std::fs::metadata(&path).unwrap().len();
+
+        // A batch of several regions, written after the first one.
+        let batch = LogBatch::new();
+        batch.add_entries(1, entries(3..5));
+        batch.add_entries(2, entries(3..5));
+        batch.add_entries(3, entries(1..3));
+        batch.put(2, b"k", b"v");
+        let mut encoded = batch.encode_to_bytes().unwrap();
+        format::set_sequence(&mut encoded, 10);
+        let rewrite_tail = |content: &[u8]| {
+            let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(tail).unwrap();
+            std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(tail)).unwrap();
+            std::io::Write::write_all(&mut file, content).unwrap();
+        };
+
+        // Torn anywhere, none of its items is recovered.
+        for len in (1..encoded.len()).step_by(7) {
+            rewrite_tail(&encoded[..len]);
+            let engine = FileEngine::new(cfg.clone());
+            assert_eq!(engine.latest_sequence(), 1);
+            assert_eq!(engine.entries_range(1), Some((1, 2)));
+            assert_eq!(engine.entries_range(2), Some((1, 2)));
+            assert_eq!(engine.entries_range(3), None);
+            assert!(engine.inner.get(None, 2, b"k").unwrap().is_none());
+        }
+
+        // Once complete, all of them are.
+        rewrite_tail(&encoded);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.latest_sequence(), 10);
+        assert_eq!(engine.entries_range(1), Some((1, 4)));
+        assert_eq!(engine.entries_range(2), Some((1, 4)));
+        assert_eq!(engine.entries_range(3), Some((1, 2)));
+        assert_eq!(engine.inner.get(None, 2, b"k").unwrap().unwrap(), b"v");
+    }
+
+    #[test]
+    fn test_max_batch_regions() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_max_batch_regions")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.name = "test_max_batch_regions".to_owned();
+        cfg.max_batch_regions = 2;
+        let engine = FileEngine::new(cfg.clone());
+        let entry = |index| {
+            let mut entry = Entry::new();
+            entry.set_index(index);
+            entry
+        };
+        let mut batch = LogBatch::new();
+        for region_id in 1..=5 {
+            batch.add_entries(region_id, vec![entry(1), entry(2)]);
+        }
+        // Items of a region stay together in order.
+        batch.put(1, b"k", b"v");
+        batch.delete(1, b"k");
+        batch.add_entries(1, vec![entry(3)]);
+        engine.consume(&mut batch, true).unwrap();
+        assert_eq!(engine.latest_sequence(), 3);
+        let regions = &engine.inner.metrics.batch_regions_count;
+        assert_eq!(regions.get_sample_count(), 3);
+        assert_eq!(regions.get_sample_sum(), 5.0);
+        assert_eq!(
+            engine.inner.metrics.batch_entries_count.get_sample_sum(),
+            11.0
+        );
+
+        let mut batch = LogBatch::new();
+        batch.put(6, b"k", b"v");
+        batch.put(7, b"k", b"v");
+        engine.consume(&mut batch, true).unwrap();
+        assert_eq!(engine.latest_sequence(), 4);
+        drop(engine);
+
+        cfg.strict_append = true;
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), Some((1, 3)));
+        assert!(engine.inner.get(None, 1, b"k").unwrap().is_none());
+        for region_id in 2..=5 {
+            assert_eq!(engine.entries_range(region_id), Some((1, 2)));
+        }
+        assert!(engine.inner.get(None, 7, b"k").unwrap().is_some());
+
+        // Parts written before a failed one stay written.
+        let mut batch = LogBatch::new();
+        batch.add_entries(8, vec![entry(1)]);
+        batch.add_entries(9, vec![entry(1)]);
+        batch.add_entries(2, vec![entry(5)]);
+        match engine.consume(&mut batch, false) {
+            Err(Error::PartiallyWritten(1, 2, e)) => match *e {
+                Error::AppendConflict(2, 3, 5) => {}
+                e => panic!("unexpected error {:?}", e),
+            },
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(engine.entries_range(8), Some((1, 1)));
+        assert_eq!(engine.entries_range(9), Some((1, 1)));
+        assert_eq!(engine.entries_range(2), Some((1, 2)));
+    }
+
+    #[test]
+    fn test_max_memtable_value_size() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_max_memtable_value_size")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.max_memtable_value_size = ReadableSize::kb(1);
+        let engine = FileEngine::new(cfg.clone());
+        let in_file = |engine: &FileEngine, region_id: u64, key: &[u8]| {
+            let memtables = engine.inner.memtables[region_id as usize % SLOTS_COUNT]
+                .read()
+                .unwrap();
+            match memtables[&region_id].get_value(key) {
+                Some(KvValue::InFile(_)) => true,
+                Some(KvValue::InMemory(_)) => false,
+                None => panic!("key not found"),
+            }
+        };
+
+        // Large values are left in files, compressed or not.
+        let large: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+        let compressible = vec![b'x'; 64 * 1024];
+        let mut batch = LogBatch::new();
+        batch.put(1, b"small", b"v");
+        batch.put(1, b"large", &large);
+        batch.put(2, b"large", &compressible);
+        engine.consume(&mut batch, true).unwrap();
+        let check = |engine: &FileEngine| {
+            assert!(!in_file(engine, 1, b"small"));
+            assert!(in_file(engine, 1, b"large"));
+            assert!(in_file(engine, 2, b"large"));
+            assert_eq!(
+                engine.inner.get(None, 1, b"small").unwrap(),
+                Some(b"v".to_vec())
+            );
+            assert_eq!(
+                engine.inner.get(None, 1, b"large").unwrap(),
+                Some(large.clone())
+            );
+            assert_eq!(
+                engine.inner.get(None, 2, b"large").unwrap(),
+                Some(compressible.clone())
+            );
+        };
+        check(&engine);
+        drop(engine);
+
+        // And after recovery.
+        let engine = FileEngine::new(cfg.clone());
+        check(&engine);
+
+        // Rewritten values are left in the new copy.
+        assert!(engine.rewrite_region(1).unwrap());
+        assert!(engine.rewrite_region(2).unwrap());
+        check(&engine);
+        assert_eq!(
+            engine.region_kvs(2).unwrap(),
+            vec![(b"large".to_vec(), compressible.clone())]
+        );
+
+        // Small enough values are kept in memory once the limit is raised.
+        drop(engine);
+        cfg.max_memtable_value_size = ReadableSize::mb(1);
+        let engine = FileEngine::new(cfg);
+        assert!(!in_file(&engine, 1, b"large"));
+        assert_eq!(engine.inner.get(None, 1, b"large").unwrap(), Some(large));
+    }
+
+    #[test]
+    fn test_cold_storage() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_cold_storage")
+            .tempdir()
+            .unwrap();
+        let cold_dir = dir.path().join("cold");
+        let storage = Arc::new(LocalObjectStorage::new(cold_dir.to_str().unwrap()).unwrap());
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().join("raft").to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cold_file_threshold = ReadableSize::kb(2);
+
+        let engine = FileEngine::builder(cfg.clone())
+            .cold_storage(storage.clone())
+            .build();
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..40 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let offloaded = engine.offload_cold_files().unwrap();
+        assert!(offloaded > 0);
+        assert_eq!(storage.list().unwrap().len(), offloaded);
+        assert!(!Path::new(&cfg.dir)
+            .join("0000000000000001.raftlog")
+            .exists());
+
+        // Entries in cold files can still be read.
+        engine.gc_entry_cache(1, 39);
+        entry.set_index(1);
+        assert_eq!(engine.get_entry(1, 1).unwrap(), Some(entry.clone()));
+        drop(engine);
+
+        // Each cold file is fetched once while it's cached.
+        let storage = Arc::new(CountingStorage {
+            storage: Arc::try_unwrap(storage).ok().unwrap(),
+            gets: AtomicUsize::new(0),
+        });
+        let engine = FileEngine::builder(cfg)
+            .cold_storage(storage.clone())
+            .build();
+        // Fetched by recovery as well.
+        storage.gets.store(0, Ordering::Relaxed);
+        for _ in 0..2 {
+            for i in 1..40 {
+                entry.set_index(i);
+                assert_eq!(engine.get_entry(1, i).unwrap(), Some(entry.clone()));
+            }
+        }
+        assert!(offloaded <= 4);
+        assert_eq!(storage.gets.load(Ordering::Relaxed), offloaded);
+    }
+
+    struct CountingStorage {
+        storage: LocalObjectStorage,
+        gets: AtomicUsize,
+    }
+
+    impl ObjectStorage for CountingStorage {
+        fn put(&self, name: &str, content: &[u8]) -> Result<()> {
+            self.storage.put(name, content)
+        }
+
+        fn get(&self, name: &str) -> Result<Vec<u8>> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            self.storage.get(name)
+        }
+
+        fn delete(&self, name: &str) -> Result<()> {
+            self.storage.delete(name)
+        }
+
+        fn list(&self) -> Result<Vec<String>> {
+            self.storage.list()
+        }
+    }
+
+    #[test]
+    fn test_durable_tokens() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_durable_tokens")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(4);
+        cfg.disable_compression = true;
+        let engine = FileEngine::new(cfg);
+        let rx = engine.subscribe_durable();
+        let batch = |index: u64, len: usize| {
+            let mut entry = Entry::new();
+            entry.set_index(index);
+            entry.set_data(vec![b'x'; len]);
+            let batch = LogBatch::new();
+            batch.add_entries(1, vec![entry]);
+            batch
+        };
+
+        // Visible once written, but durable only once synced.
+        let t1 = engine.consume_deferred(&mut batch(1, 16)).unwrap();
+        let t2 = engine.consume_deferred(&mut batch(2, 16)).unwrap();
+        assert!(t1 < t2);
+        assert_eq!(engine.entries_range(1), Some((1, 2)));
+        assert!(!engine.is_durable(t1));
+        assert!(rx.try_recv().is_err());
+        engine.sync().unwrap();
+        assert_eq!(rx.try_recv().unwrap(), t2);
+        assert!(engine.is_durable(t1) && engine.is_durable(t2));
+
+        // Synced along with a later synced write, or when the file is rotated.
+        let t3 = engine.consume_deferred(&mut batch(3, 16)).unwrap();
+        engine.consume(&mut batch(4, 16), true).unwrap();
+        assert!(rx.try_recv().unwrap() > t3);
+        let t5 = engine.consume_deferred(&mut batch(5, 4096)).unwrap();
+        assert_eq!(engine.inner.pipe_log.active_file_num(), 2);
+        assert_eq!(rx.try_recv().unwrap(), t5);
+        assert!(rx.try_recv().is_err());
+
+        // Nothing is written for an empty batch.
+        assert_eq!(engine.consume_deferred(&mut LogBatch::new()).unwrap(), t5);
+
+        // Dropped receivers are removed.
+        drop(rx);
+        engine.consume_deferred(&mut batch(6, 16)).unwrap();
+        engine.sync().unwrap();
+        assert!(engine.inner.durable_subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_subscribe")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let rx = engine.subscribe();
+
+        let mut entries = vec![Entry::new(); 3];
+        for (i, e) in entries.iter_mut().enumerate() {
+            e.set_index(10 + i as u64);
+        }
+        let mut batch = LogBatch::new();
+        batch.add_entries(1, entries);
+        batch.put(1, b"k", b"v");
+        batch.delete(2, b"k");
+        batch.clean_region(3);
+        engine.consume(&mut batch, true).unwrap();
+
+        let summary = rx.try_recv().unwrap();
+        assert_eq!(summary.file_num, 1);
+        assert_eq!(
+            summary.items,
+            vec![
+                ItemSummary::Entries {
+                    region_id: 1,
+                    first_index: 10,
+                    last_index: 12,
+                },
+                ItemSummary::Put {
+                    region_id: 1,
+                    key: b"k".to_vec(),
+                    value: b"v".to_vec(),
+                },
+                ItemSummary::Delete {
+                    region_id: 2,
+                    key: b"k".to_vec(),
+                },
+                ItemSummary::Clean { region_id: 3 },
+            ]
+        );
+
+        // Dropped receivers are removed.
+        drop(rx);
+        engine.append(4, vec![Entry::new()]).unwrap();
+        assert!(engine.inner.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_export_all() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_export_all")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+
+        let mut expected = vec![];
+        for region_id in (1..4).rev() {
+            let mut entries = vec![Entry::new(); 3];
+            for (i, e) in entries.iter_mut().enumerate() {
+                e.set_index(5 + i as u64);
+            }
+            let mut batch = LogBatch::new();
+            batch.add_entries(region_id, entries.clone());
+            batch.put(region_id, b"k2", b"v2");
+            batch.put(region_id, b"k1", b"v1");
+            engine.consume(&mut batch, true).unwrap();
+
+            let mut items: Vec<_> = entries
+                .into_iter()
+                .map(|e| (region_id, ExportItem::Entry(e)))
+                .collect();
+            items.push((region_id, ExportItem::Kv(b"k1".to_vec(), b"v1".to_vec())));
+            items.push((region_id, ExportItem::Kv(b"k2".to_vec(), b"v2".to_vec())));
+            expected.splice(0..0, items);
+        }
+        engine.gc(2, 0, 6).unwrap();
+        expected.retain(|(region_id, item)| match item {
+            ExportItem::Entry(e) => *region_id != 2 || e.get_index() >= 6,
+            _ => true,
+        });
+
+        let mut exported = vec![];
+        engine
+            .export_all(|region_id, item| exported.push((region_id, item)))
+            .unwrap();
+        assert_eq!(exported, expected);
+    }
+
+    #[test]
+    fn test_observer() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_observer")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..5 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+
+        let observer = FileEngine::new_observer(cfg);
+        assert_eq!(observer.get_entry(1, 4).unwrap().unwrap(), entry);
+        assert!(observer.append(1, vec![entry.clone()]).is_err());
+
+        // Batches written afterwards, across several files, are followed.
+        let active = engine.inner.pipe_log.active_file_num();
+        for i in 5..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        assert!(engine.inner.pipe_log.active_file_num() > active + 1);
+        assert!(observer.get_entry(1, 19).unwrap().is_none());
+        assert_eq!(observer.catch_up().unwrap(), 15);
+        assert_eq!(observer.get_entry(1, 19).unwrap().unwrap(), entry);
+        assert_eq!(observer.catch_up().unwrap(), 0);
+
+        // Purges of the writer are followed, dropping entries in purged files.
+        engine.gc_with_stats(1, 15).unwrap();
+        engine.purge_expired_files().unwrap();
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+        assert!(first_file_num > 1);
+        assert_eq!(observer.catch_up().unwrap(), 0);
+        assert_eq!(observer.inner.pipe_log.first_file_num(), first_file_num);
+        assert!(observer.get_entry(1, 1).unwrap().is_none());
+        assert_eq!(observer.get_entry(1, 19).unwrap().unwrap(), entry);
+        let memtables = observer.inner.memtables[1].read().unwrap();
+        assert!(memtables[&1].min_file_num().unwrap() >= first_file_num);
+        drop(memtables);
+
+        // A writer can't catch up.
+        assert!(engine.catch_up().is_err());
+    }
+
+    #[test]
+    fn test_fetch_entries_with_size() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_fetch_entries_with_size")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 100]);
+        for i in 1..=10 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let entry_size = entry.compute_size() as usize;
+
+        let mut entries = vec![];
+        let res = engine.fetch_entries_with_size(1, 1, 11, None, &mut entries);
+        assert_eq!(res.unwrap(), (10, entry_size * 10));
+        // Exactly fits.
+        entries.clear();
+        let res = engine.fetch_entries_with_size(1, 1, 11, Some(entry_size * 3), &mut entries);
+        assert_eq!(res.unwrap(), (3, entry_size * 3));
+        entries.clear();
+        let res = engine.fetch_entries_with_size(1, 1, 11, Some(entry_size * 3 - 1), &mut entries);
+        assert_eq!(res.unwrap(), (2, entry_size * 2));
+        // At least one entry.
+        entries.clear();
+        let res = engine.fetch_entries_with_size(1, 5, 11, Some(0), &mut entries);
+        assert_eq!(res.unwrap(), (1, entry_size));
+        assert_eq!(entries[0].get_index(), 5);
+        assert_eq!(
+            engine
+                .fetch_entries_to(1, 1, 11, Some(0), &mut entries)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_fetch_entry_bytes() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_fetch_entry_bytes")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.region_size = ReadableSize::kb(1);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for i in 1..=20 {
+            entry.set_index(i);
+            entry.set_data(vec![b'x'; i as usize * 10]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+
+        // Early entries are evicted from the cache.
+        for i in &[1, 20] {
+            let e = engine.get_entry(1, *i).unwrap().unwrap();
+            let bytes = engine.fetch_entry_bytes(1, *i).unwrap().unwrap();
+            assert_eq!(bytes, e.write_to_bytes().unwrap());
+        }
+        assert!(engine.fetch_entry_bytes(1, 21).unwrap().is_none());
+        assert!(engine.fetch_entry_bytes(2, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_entry_cache_plugin() {
+        #[derive(Default)]
+        struct MapCache(Mutex<HashMap<u64, Vec<Entry>>>);
+        impl EntryCache for MapCache {
+            fn insert(&self, region_id: u64, entries: &[Entry]) {
+                let mut map = self.0.lock().unwrap();
+                let cached = map.entry(region_id).or_default();
+                cached.retain(|e| e.get_index() < entries[0].get_index());
+                cached.extend_from_slice(entries);
+            }
+            fn get(&self, region_id: u64, index: u64) -> Option<Entry> {
+                let map = self.0.lock().unwrap();
+                let cached = map.get(&region_id)?;
+                cached.iter().find(|e| e.get_index() == index).cloned()
+            }
+            fn evict(&self, region_id: u64, index: u64) {
+                if let Some(cached) = self.0.lock().unwrap().get_mut(&region_id) {
+                    cached.retain(|e| e.get_index() >= index);
+                }
+            }
+            fn remove_region(&self, region_id: u64) {
+                self.0.lock().unwrap().remove(&region_id);
+            }
+            fn size(&self) -> u64 {
+                let map = self.0.lock().unwrap();
+                map.values()
+                    .flatten()
+                    .map(|e| e.compute_size() as u64)
+                    .sum()
+            }
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("test_entry_cache_plugin")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let cache = Arc::new(MapCache::default());
+        let engine = FileEngine::builder(cfg).entry_cache(cache.clone()).build();
+        let mut entry = Entry::new();
+        for i in 1..=10 {
+            entry.set_index(i);
+            entry.set_data(vec![b'x'; 16]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        assert!(cache.size() > 0);
+        assert_eq!(
+            engine
+                .inner
+                .cache_stats
+                .memory_trace
+                .bytes(MemoryComponent::EntryCache),
+            0
+        );
+
+        // Served by the plugin without reading files.
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(1, 1, 11, None, &mut entries)
+            .unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(engine.get_entry(1, 5).unwrap().unwrap().get_index(), 5);
+        assert_eq!(engine.get_statistics().bytes_read, 0);
+
+        // Misses are read from files in order.
+        cache
+            .0
+            .lock()
+            .unwrap()
+            .get_mut(&1)
+            .unwrap()
+            .retain(|e| e.get_index() % 2 == 0);
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(1, 1, 11, None, &mut entries)
+            .unwrap();
+        let indexes: Vec<_> = entries.iter().map(|e| e.get_index()).collect();
+        assert_eq!(indexes, (1..=10).collect::<Vec<_>>());
+        assert!(engine.get_statistics().bytes_read > 0);
+
+        engine.gc(1, 0, 8).unwrap();
+        assert!(cache.get(1, 6).is_none());
+        assert!(cache.get(1, 8).is_some());
+        let mut batch = LogBatch::default();
+        engine
+            .clean(1, &RaftLocalState::default(), &mut batch)
+            .unwrap();
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_memtable_factory() {
+        #[derive(Default)]
+        struct CountingFactory(AtomicU64);
+        impl MemTableFactory for CountingFactory {
+            fn new_memtable(
+                &self,
+                region_id: u64,
+                cache_stats: Arc<SharedCacheStats>,
+            ) -> Box<dyn MemTableAccessor> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Box::new(MemTable::without_cache(region_id, cache_stats))
+            }
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("test_memtable_factory")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let factory = Arc::new(CountingFactory::default());
+        let engine = FileEngine::builder(cfg)
+            .memtable_factory(factory.clone())
+            .build();
+        let mut entry = Entry::new();
+        for region_id in 1..=3 {
+            entry.set_index(1);
+            engine.append(region_id, vec![entry.clone()]).unwrap();
+        }
+        assert_eq!(factory.0.load(Ordering::Relaxed), 3);
+        assert_eq!(engine.get_entry(2, 1).unwrap(), Some(entry));
+    }
+
+    #[test]
+    fn test_dump_region() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_dump_region")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(128);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for i in 1..=4 {
+            entry.set_index(i);
+            entry.set_data(vec![b'x'; 64]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            engine.append(2, vec![entry.clone()]).unwrap();
+        }
+        let mut batch = LogBatch::default();
+        batch.put(1, b"k", b"v");
+        batch.delete(1, b"k2");
+        batch.put(2, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        engine
+            .clean(1, &RaftLocalState::default(), &mut batch)
+            .unwrap();
+        engine.consume(&mut batch, false).unwrap();
+
+        let items = engine.dump_region(1).unwrap();
+        assert_eq!(items.len(), 7);
+        for (i, item) in items[..4].iter().enumerate() {
+            match &item.content {
+                DumpContent::Entries(indexes) => {
+                    assert_eq!(indexes.len(), 1);
+                    assert_eq!(indexes[0].index, i as u64 + 1);
+                    assert_eq!(indexes[0].file_num, item.file_num);
+                }
+                c => panic!("unexpected {:?}", c),
+            }
+        }
+        assert_eq!(
+            items[4].content,
+            DumpContent::Put(b"k".to_vec(), b"v".to_vec())
+        );
+        assert_eq!(items[5].content, DumpContent::Delete(b"k2".to_vec()));
+        assert_eq!(items[5].sequence, items[4].sequence);
+        assert_eq!(items[6].content, DumpContent::Clean);
+        assert!(items.windows(2).all(|w| w[0].sequence <= w[1].sequence));
+        assert!(items[0].file_num < items[6].file_num);
+        assert!(engine.dump_region(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_prefetch")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.cache_size_limit = ReadableSize(0);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for i in 1..=40 {
+            entry.set_index(i);
+            entry.set_data(vec![b'x'; i as usize]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+
+        engine.inner.prefetcher.start();
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(1, 1, 11, None, &mut entries)
+            .unwrap();
+        engine
+            .fetch_entries_to(1, 11, 21, None, &mut entries)
+            .unwrap();
+        engine.inner.prefetch().unwrap();
+        assert!(engine.inner.prefetcher.size() > 0);
+
+        // [21, 31) are read ahead.
+        let bytes_read = engine.get_statistics().bytes_read;
+        engine
+            .fetch_entries_to(1, 21, 31, None, &mut entries)
+            .unwrap();
+        assert_eq!(engine.get_statistics().bytes_read, bytes_read);
+        assert_eq!(engine.inner.prefetcher.size(), 0);
+        engine
+            .fetch_entries_to(1, 31, 41, None, &mut entries)
+            .unwrap();
+        assert!(engine.get_statistics().bytes_read > bytes_read);
+        for (i, e) in entries.iter().enumerate() {
+            assert_eq!(e.get_index(), i as u64 + 1);
+            assert_eq!(e.get_data().len(), i + 1);
+        }
+    }
+
+    #[test]
+    fn test_entry_cache_disabled() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_entry_cache_disabled")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.cache_size_limit = ReadableSize(0);
+        cfg.region_size = ReadableSize::mb(1);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for i in 1..=10 {
+            entry.set_index(i);
+            entry.set_data(vec![b'x'; i as usize]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+
+        let e = engine.get_entry(1, 3).unwrap().unwrap();
+        assert_eq!(e.get_data(), vec![b'x'; 3].as_slice());
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(1, 1, 11, None, &mut entries)
+            .unwrap();
+        assert_eq!(entries.len(), 10);
+        {
+            let memtables = engine.inner.memtables[1].read().unwrap();
+            assert_eq!(memtables[&1].cache_size(), 0);
+        }
+        let stats = engine.flush_stats();
+        assert_eq!((stats.hit, stats.miss, stats.mem_size_change), (0, 0, 0));
+        assert!(engine.get_statistics().bytes_read > 0);
+    }
+
+    #[test]
+    fn test_cache_encoded_entries() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_cache_encoded_entries")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize::mb(1);
+        cfg.cache_encoded_entries = true;
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for i in 1..=10 {
+            entry.set_index(i);
+            entry.set_term(2);
+            entry.set_data(vec![b'x'; i as usize]);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        // A large batch is compressed, but entries are cached uncompressed.
+        let large: Vec<_> = (11..=20)
+            .map(|i| {
+                entry.set_index(i);
+                entry.set_data(vec![b'y'; 1024]);
+                entry.clone()
+            })
+            .collect();
+        let mut batch = LogBatch::default();
+        batch.add_entries(1, large.clone());
+        batch.put(1, b"key", b"value");
+        engine.consume(&mut batch, false).unwrap();
+
+        let e = engine.get_entry(1, 3).unwrap().unwrap();
+        assert_eq!((e.get_index(), e.get_term()), (3, 2));
+        assert_eq!(e.get_data(), vec![b'x'; 3].as_slice());
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(1, 1, 21, None, &mut entries)
+            .unwrap();
+        assert_eq!(entries.len(), 20);
+        assert_eq!(&entries[10..], large.as_slice());
+        let value = engine.inner.get(None, 1, b"key").unwrap();
+        assert_eq!(value, Some(b"value".to_vec()));
+        assert_eq!(engine.get_statistics().bytes_read, 0);
+
+        // Entries of a batch are dropped once written if they aren't cached, but
+        // kept while it's only encoded, so that it can be written again.
+        let mut batch = LogBatch::default();
+        batch.add_entries(1, large);
+        batch.retention = EntryRetention::None;
+        assert!(batch.encode_to_bytes_with_compression(true, None).is_some());
+        assert!(batch.encoded.borrow().is_none());
+        assert!(!batch.items.borrow()[0]
+            .entries
+            .as_ref()
+            .unwrap()
+            .entries
+            .is_empty());
+        batch.drop_written_entries();
+        let items = batch.items.borrow();
+        assert!(items[0].entries.as_ref().unwrap().entries.is_empty());
+        assert_eq!(batch.summary(1).items.len(), 1);
+    }
+
+    #[test]
+    fn test_memory_limiter() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_memory_limiter")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize::mb(1);
+        // Chunks of the cache arena are charged, not only the entries in them.
+        let quota = Arc::new(MemoryQuota::new(4000));
+        let engine = FileEngine::builder(cfg.clone())
+            .memory_limiter(quota.clone())
+            .build();
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 300]);
+        for i in 1..=10 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            assert!(quota.used() <= 4000);
+            let memtables = engine.inner.memtables[1].read().unwrap();
+            let cache = &memtables[&1];
+            assert!(quota.used() >= cache.cache_size());
+            assert_eq!(quota.used(), cache.cache_memory());
+        }
+        assert!(quota.used() > 0);
+        let trace = engine.memory_trace();
+        assert_eq!(trace.bytes(MemoryComponent::EntryCache), quota.used());
+        // Entries beyond the quota aren't cached.
+        for i in 11..=20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            assert!(quota.used() <= 4000);
+            assert_eq!(trace.bytes(MemoryComponent::EntryCache), quota.used());
+        }
+        for i in 1..=20 {
+            let e = engine.get_entry(1, i).unwrap().unwrap();
+            assert_eq!(e.get_data(), entry.get_data());
+        }
+        drop(engine);
+        assert_eq!(quota.used(), 0);
+
+        // Files can't be recovered within the quota.
+        let quota = Arc::new(MemoryQuota::new(1000));
+        assert!(FileEngine::builder(cfg.clone())
+            .memory_limiter(quota.clone())
+            .open()
+            .is_err());
+        assert_eq!(quota.used(), 0);
+        let quota = Arc::new(MemoryQuota::new(1 << 20));
+        let engine = FileEngine::builder(cfg)
+            .memory_limiter(quota.clone())
+            .build();
+        assert_eq!(engine.entries_range(1), Some((1, 20)));
+        let trace = engine.memory_trace();
+        assert_eq!(trace.bytes(MemoryComponent::RecoveryBuffer), 0);
+        assert!(engine.rewrite_region(1).unwrap());
+        assert_eq!(trace.bytes(MemoryComponent::RewriteBuffer), 0);
+    }
+
+    #[test]
+    fn test_summary() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_summary")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize::mb(1);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.regions(), 0);
+        assert_eq!(engine.summary(), Summary::default());
+
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 100]);
+        for region_id in 1..=2 {
+            for i in 1..=10 {
+                entry.set_index(i);
+                engine.append(region_id, vec![entry.clone()]).unwrap();
+            }
+        }
+        let mut batch = LogBatch::new();
+        batch.put(3, b"k1", b"v");
+        batch.put(3, b"k2", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.regions(), 3);
+        let summary = engine.summary();
+        assert_eq!(summary.regions, 3);
+        assert_eq!(summary.entries, 20);
+        assert!(summary.entries_size >= 20 * 100);
+        assert!(summary.cache_size > 0);
+        assert_eq!(summary.kvs, 2);
+
+        engine.gc_with_stats(1, 6).unwrap();
+        engine.evict_region_cache(2);
+        let mut batch = LogBatch::new();
+        batch.clean_region(3);
+        engine.consume(&mut batch, false).unwrap();
+        let after = engine.summary();
+        assert_eq!(after.regions, 2);
+        assert_eq!(after.entries, 15);
+        assert!(after.entries_size < summary.entries_size);
+        assert!(after.cache_size < summary.cache_size / 2);
+        assert_eq!(after.kvs, 0);
+    }
+
+    #[test]
+    fn test_reclaim_region_cache() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_reclaim_region_cache")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.region_size = ReadableSize::mb(1);
+        let quota = Arc::new(MemoryQuota::new(1 << 20));
+        let engine = FileEngine::builder(cfg)
+            .memory_limiter(quota.clone())
+            .build();
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 100]);
+        for region_id in 1..=3 {
+            for i in 1..=10 {
+                entry.set_index(i);
+                engine.append(region_id, vec![entry.clone()]).unwrap();
+            }
+        }
+        let region_cache = quota.used() / 3;
+        assert!(region_cache > 0);
+
+        // Compacted, evicted and cleaned regions release the quota at once.
+        engine.gc_entry_cache(1, 6);
+        let reclaimed = engine.get_statistics().cache_reclaimed;
+        assert!(reclaimed > 0);
+        assert_eq!(quota.used(), region_cache * 3 - reclaimed);
+        assert_eq!(engine.evict_region_cache(2), region_cache);
+        assert_eq!(engine.evict_region_cache(2), 0);
+        let mut batch = LogBatch::new();
+        batch.clean_region(3);
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(quota.used(), region_cache - reclaimed);
+        assert_eq!(
+            engine.get_statistics().cache_reclaimed,
+            region_cache * 2 + reclaimed
+        );
 
-        // Read from file
-        let entry = self.read_entry_from_file(&entry_idx).unwrap_or_else(|e| {
-            panic!(
-                "Read entry from file for region {} index {} failed, err {:?}",
-                region_id, log_idx, e
-            )
-        });
-        Ok(Some(entry))
+        // Evicted entries are read from files.
+        assert_eq!(engine.get_entry(2, 10).unwrap(), Some(entry));
     }
 
-    fn read_entry_from_file(&self, entry_index: &EntryIndex) -> Result<Entry> {
-        let file_num = entry_index.file_num;
-        let base_offset = entry_index.base_offset;
-        let batch_len = entry_index.batch_len;
-        let offset = entry_index.offset;
-        let len = entry_index.len;
-
-        let entry_content = match entry_index.compression_type {
-            CompressionType::None => {
-                let offset = base_offset + offset;
-                self.pipe_log.fread(file_num, offset, len)?
+    #[test]
+    fn test_bounded_recovery_cache() {
+        // Accepts all charges, and records the peak.
+        #[derive(Default)]
+        struct PeakLimiter {
+            // Used and peak bytes.
+            bytes: Mutex<(u64, u64)>,
+        }
+        impl MemoryLimiter for PeakLimiter {
+            fn try_acquire(&self, bytes: u64) -> bool {
+                let mut b = self.bytes.lock().unwrap();
+                b.0 += bytes;
+                b.1 = cmp::max(b.0, b.1);
+                true
+            }
+            fn release(&self, bytes: u64) {
+                self.bytes.lock().unwrap().0 -= bytes;
             }
-            CompressionType::Lz4 => {
-                let read_len = batch_len + 8; // 8 bytes for header.
-                let compressed = self.pipe_log.fread(file_num, base_offset, read_len)?;
-                let mut reader = compressed.as_ref();
-                let header = codec::decode_u64(&mut reader)?;
-                assert_eq!(header >> 8, batch_len);
+        }
 
-                log_batch::test_batch_checksum(reader)?;
-                let buf = log_batch::decompress(&reader[..batch_len as usize - CHECKSUM_LEN]);
-                let start = offset as usize - HEADER_LEN;
-                let end = (offset + len) as usize - HEADER_LEN;
-                buf[start..end].to_vec()
+        let dir = tempfile::Builder::new()
+            .prefix("test_bounded_recovery_cache")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(4);
+        cfg.cache_size_limit = ReadableSize::kb(4);
+        cfg.region_size = ReadableSize::mb(1);
+        {
+            let engine = FileEngine::new(cfg.clone());
+            let mut entry = Entry::new();
+            entry.set_data(vec![b'x'; 100]);
+            for i in 1..=50 {
+                entry.set_index(i);
+                for region_id in 1..=4 {
+                    engine.append(region_id, vec![entry.clone()]).unwrap();
+                }
             }
-        };
+            // A batch larger than the whole cache.
+            let entries: Vec<_> = (1..=50)
+                .map(|i| {
+                    entry.set_index(i);
+                    entry.clone()
+                })
+                .collect();
+            engine.append(5, entries).unwrap();
+        }
+        let max_file_len = pipe_log::list_log_files(dir.path())
+            .unwrap()
+            .iter()
+            .map(|(_, path)| std::fs::metadata(path).unwrap().len())
+            .max()
+            .unwrap();
 
-        let mut e = Entry::new();
-        e.merge_from_bytes(&entry_content)?;
-        assert_eq!(e.get_index(), entry_index.index);
-        Ok(e)
+        // Charged for cached entries, the buffer of the file being recovered, and a
+        // chunk of the arena being allocated before entries are evicted from it.
+        let limiter = Arc::new(PeakLimiter::default());
+        let engine = FileEngine::builder(cfg.clone())
+            .memory_limiter(limiter.clone())
+            .build();
+        let peak = limiter.bytes.lock().unwrap().1;
+        let bound = cfg.cache_size_limit.0 + max_file_len + arena::MAX_CHUNK_SIZE as u64;
+        assert!(peak <= bound, "{}", peak);
+        let trace = engine.memory_trace();
+        assert!(trace.bytes(MemoryComponent::EntryCache) > 0);
+        assert!(trace.bytes(MemoryComponent::EntryCache) <= cfg.cache_size_limit.0);
+        for region_id in 1..=5 {
+            assert_eq!(engine.entries_range(region_id), Some((1, 50)));
+        }
+        let memtables = engine.inner.memtables[5].read().unwrap();
+        assert_eq!(memtables[&5].cache_size(), 0);
     }
 
-    pub fn fetch_entries_to(
-        &self,
-        region_id: u64,
-        begin: u64,
-        end: u64,
-        max_size: Option<usize>,
-        vec: &mut Vec<Entry>,
-    ) -> Result<usize> {
-        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
-            .read()
+    #[test]
+    fn test_get_statistics() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_get_statistics")
+            .tempdir()
             .unwrap();
-        if let Some(memtable) = memtables.get(&region_id) {
-            let mut entries = Vec::with_capacity((end - begin) as usize);
-            let mut entries_idx = Vec::with_capacity((end - begin) as usize);
-            memtable.fetch_entries_to(begin, end, max_size, &mut entries, &mut entries_idx)?;
-            let count = entries.len() + entries_idx.len();
-            for idx in &entries_idx {
-                let e = self.read_entry_from_file(idx)?;
-                vec.push(e);
-            }
-            vec.extend(entries.into_iter());
-            return Ok(count);
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..=20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
         }
-        Ok(0)
+        engine.get_entry(1, 5).unwrap().unwrap();
+        engine.gc_with_stats(1, 11).unwrap();
+        assert!(engine.rewrite_region(1).unwrap());
+
+        let stats = engine.get_statistics();
+        assert_eq!(stats.batches_written, 21);
+        assert!(stats.bytes_written > 20 * 128);
+        assert!(stats.bytes_read >= 128);
+        assert_eq!(stats.rewrites, 1);
+        assert!(stats.files_purged > 0);
+        assert!(stats.cache_hit + stats.cache_miss > 0);
+        assert_eq!(stats.first_file_num, engine.inner.pipe_log.first_file_num());
+        assert!(stats.to_string().contains("raft-engine.rewrites: 1"));
+        assert_eq!(
+            stats.bytes_written,
+            stats.foreground_bytes_written + stats.rewrite_bytes_written
+        );
+        assert!(stats.disk_bytes_written > stats.bytes_written);
+        assert!(stats.write_amplification() > 1.0);
     }
 
-    fn post_append_to_file(&self, log_batch: LogBatch, file_num: u64) {
-        // 0 means write nothing.
-        if file_num == 0 {
-            return;
+    #[test]
+    fn test_batch_times() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_batch_times")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(1);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = ManualClock::new(start);
+        let mut entry = Entry::new();
+        {
+            let engine = FileEngine::builder(cfg.clone())
+                .clock(Arc::new(clock.clone()))
+                .build();
+            assert_eq!(engine.oldest_batch_time(), None);
+            // One batch in each file.
+            for i in 1..=3 {
+                entry.set_index(i);
+                engine.append(1, vec![entry.clone()]).unwrap();
+                clock.advance(Duration::from_secs(10));
+            }
+            let ranges = engine.file_time_ranges();
+            assert_eq!(ranges.len(), 3);
+            assert_eq!((ranges[0].oldest, ranges[0].newest), (start, start));
+            assert_eq!(ranges[2].newest, start + Duration::from_secs(20));
+            assert_eq!(engine.oldest_batch_time(), Some(start));
+
+            let items = engine.dump_region(1).unwrap();
+            assert_eq!(items[1].timestamp.physical(), ranges[1].oldest);
         }
-        self.apply_to_memtable(log_batch, file_num);
-    }
-}
 
-#[derive(Default)]
-pub struct SharedCacheStats {
-    hit: AtomicUsize,
-    miss: AtomicUsize,
-    mem_size_change: AtomicIsize,
-}
+        // Times are recovered, and later batches are later even if the clock goes
+        // back.
+        let clock = ManualClock::new(start);
+        let engine = FileEngine::builder(cfg).clock(Arc::new(clock)).build();
+        assert_eq!(engine.file_time_ranges().len(), 3);
+        entry.set_index(4);
+        engine.append(1, vec![entry]).unwrap();
+        let items = engine.dump_region(1).unwrap();
+        assert!(items[3].timestamp > items[2].timestamp);
+        assert_eq!(
+            items[3].timestamp.physical(),
+            start + Duration::from_secs(20)
+        );
 
-impl SharedCacheStats {
-    pub fn sub_mem_change(&self, bytes: u64) {
-        self.mem_size_change
-            .fetch_sub(bytes as isize, Ordering::Relaxed);
+        engine.gc(1, 0, 3).unwrap();
+        engine.purge_expired_files().unwrap();
+        let ranges = engine.file_time_ranges();
+        assert_eq!(ranges[0].file_num, engine.inner.pipe_log.first_file_num());
+        assert_eq!(engine.oldest_batch_time(), Some(ranges[0].oldest));
+        assert!(engine.oldest_batch_time() > Some(start));
     }
-    pub fn add_mem_change(&self, bytes: u64) {
-        self.mem_size_change
-            .fetch_add(bytes as isize, Ordering::Relaxed);
-    }
-    pub fn hit_cache(&self, count: usize) {
-        self.hit.fetch_add(count, Ordering::Relaxed);
-    }
-    pub fn miss_cache(&self, count: usize) {
-        self.miss.fetch_add(count, Ordering::Relaxed);
-    }
-    pub fn hit_times(&self) -> usize {
-        self.hit.load(Ordering::Relaxed)
-    }
-    pub fn miss_times(&self) -> usize {
-        self.miss.load(Ordering::Relaxed)
-    }
-    #[cfg(test)]
-    pub fn reset(&self) {
-        self.hit.store(0, Ordering::Relaxed);
-        self.miss.store(0, Ordering::Relaxed);
-        self.mem_size_change.store(0, Ordering::Relaxed);
-    }
-}
 
-#[derive(Clone)]
-pub struct FileEngine {
-    inner: Arc<FileEngineInner>,
-}
+    #[test]
+    fn test_retention() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_retention")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(1);
+        cfg.retention_min_age = ReadableDuration::hours(1);
+        cfg.retention_min_entries = 2;
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let engine = FileEngine::builder(cfg)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let mut entry = Entry::new();
+        // One batch in each file.
+        for i in 1..=5 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let first_file_num = engine.inner.pipe_log.first_file_num();
 
-impl fmt::Debug for FileEngine {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "FileEngineInner dir: {}", self.inner.cfg.dir)
-    }
-}
+        // The latest 2 entries are kept.
+        assert_eq!(engine.gc(1, 0, 6).unwrap(), 3);
+        assert!(engine.get_entry(1, 3).unwrap().is_none());
+        assert!(engine.get_entry(1, 4).unwrap().is_some());
+        assert_eq!(engine.gc(1, 0, 6).unwrap(), 0);
 
-impl FileEngine {
-    pub fn new(cfg: Config) -> FileEngine {
-        let cache_stats = Arc::new(SharedCacheStats::default());
+        // Files are kept until they are old enough.
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num);
+        assert_eq!(engine.punch_holes().unwrap(), 0);
+        clock.advance(Duration::from_secs(2 * 3600));
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num + 3);
+        assert_eq!(engine.file_time_ranges().len(), 2);
+    }
 
-        let pipe_log = PipeLog::open(
-            &cfg.dir,
-            cfg.bytes_per_sync.0 as usize,
-            cfg.target_file_size.0 as usize,
-        )
-        .unwrap_or_else(|e| panic!("Open raft log failed, error: {:?}", e));
-        let mut memtables = Vec::with_capacity(SLOTS_COUNT);
-        for _ in 0..SLOTS_COUNT {
-            memtables.push(RwLock::new(HashMap::default()));
-        }
-        let mut engine = FileEngineInner {
-            cfg,
-            memtables,
-            pipe_log,
-            cache_stats,
+    #[test]
+    fn test_prepare_file() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_prepare_file")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(1);
+        let engine = FileEngine::new(cfg);
+        let prepared = |file_num| {
+            dir.path()
+                .join(format!("{:016}.raftlog.prepared.tmp", file_num))
+                .exists()
+        };
+        let wait_prepared = |file_num| {
+            for _ in 0..1000 {
+                if prepared(file_num) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("file {} isn't prepared", file_num);
         };
-        let recovery_mode = RecoveryMode::from(engine.cfg.recovery_mode);
-        engine
-            .recover(recovery_mode)
-            .unwrap_or_else(|e| panic!("Recover raft log failed, error: {:?}", e));
 
-        FileEngine {
-            inner: Arc::new(engine),
+        let mut worker = engine.start_prepare_file();
+        let mut entry = Entry::new();
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        wait_prepared(active_file_num + 1);
+        // Prepared again after each rotation.
+        for i in 1..=3 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            let active_file_num = engine.inner.pipe_log.active_file_num();
+            assert!(!prepared(active_file_num));
+            wait_prepared(active_file_num + 1);
         }
+        worker.stop();
+        entry.set_index(4);
+        engine.append(1, vec![entry]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!prepared(engine.inner.pipe_log.active_file_num() + 1));
     }
-}
 
-impl RaftEngine for FileEngine {
-    type LogBatch = LogBatch;
+    #[test]
+    fn test_pin_files() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_pin_files")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(1);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        // One batch in each file.
+        for i in 1..=5 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+        let active_file_num = engine.inner.pipe_log.active_file_num();
+        assert!(engine.pin_files(first_file_num - 1).is_err());
+        assert!(engine.pin_files(active_file_num + 1).is_err());
 
-    fn log_batch(&self, _capacity: usize) -> Self::LogBatch {
-        LogBatch::default()
-    }
+        let pinned = engine.pin_files(first_file_num + 1).unwrap();
+        assert_eq!(pinned.files(), (first_file_num, first_file_num + 1));
+        let other = engine.pin_files(first_file_num).unwrap();
+        engine.gc(1, 0, 4).unwrap();
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num);
+        // Pinned files aren't punched either.
+        let path = |file_num| pipe_log::log_file_path(&engine.inner.cfg.dir, file_num);
+        let contents: Vec<_> = (first_file_num..=first_file_num + 1)
+            .map(|file_num| std::fs::read(path(file_num)).unwrap())
+            .collect();
+        engine.punch_holes().unwrap();
+        for (i, content) in contents.iter().enumerate() {
+            assert_eq!(
+                &std::fs::read(path(first_file_num + i as u64)).unwrap(),
+                content
+            );
+        }
+        assert!(engine
+            .inner
+            .pipe_log
+            .punch_hole(
+                first_file_num + 1,
+                FILE_HEADER_LEN as u64,
+                holes::MIN_HOLE_SIZE,
+            )
+            .is_err());
+        drop(pinned);
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num);
 
-    fn sync(&self) -> Result<()> {
-        self.inner.sync()
+        // Purged once all pins are dropped.
+        drop(other);
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), first_file_num + 3);
+        assert!(!pipe_log::log_file_path(&engine.inner.cfg.dir, first_file_num).exists());
     }
 
-    fn get_raft_state(&self, raft_group_id: u64) -> Result<Option<RaftLocalState>> {
-        self.inner.get_msg(raft_group_id, RAFT_LOG_STATE_KEY)
-    }
+    #[test]
+    fn test_engine_name() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_engine_name")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().join("raft").to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        assert_eq!(engine.inner.cfg.name, cfg.dir);
+        // An observer of the same directory is named apart from the writer.
+        let observer = FileEngine::new_observer(cfg.clone());
+        assert_eq!(observer.inner.cfg.name, format!("{}-observer", cfg.dir));
+        assert!(FileEngine::open_observer(cfg.clone()).is_err());
+        drop(observer);
+        FileEngine::new_observer(cfg.clone());
 
-    fn get_entry(&self, raft_group_id: u64, index: u64) -> Result<Option<Entry>> {
-        self.inner.get_entry(raft_group_id, index)
+        // Metrics of engines with the same name would be mixed.
+        let mut other = cfg.clone();
+        other.dir = dir.path().join("other").to_str().unwrap().to_owned();
+        other.name = cfg.dir.clone();
+        assert!(FileEngine::open(other.clone()).is_err());
+        drop(engine);
+        FileEngine::new(other);
     }
 
-    fn fetch_entries_to(
-        &self,
-        raft_group_id: u64,
-        begin: u64,
-        end: u64,
-        max_size: Option<usize>,
-        to: &mut Vec<Entry>,
-    ) -> Result<usize> {
-        self.inner
-            .fetch_entries_to(raft_group_id, begin, end, max_size, to)
-    }
+    #[test]
+    fn test_metrics_updater() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_metrics_updater")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().join("raft").to_str().unwrap().to_owned();
+        cfg.name = "test_metrics_updater".to_owned();
+        let clock = ManualClock::default();
+        let engine = FileEngine::builder(cfg.clone())
+            .clock(Arc::new(clock.clone()))
+            .build();
+        // Another engine in the process reports its own metrics.
+        cfg.dir = dir.path().join("other").to_str().unwrap().to_owned();
+        cfg.name = "test_metrics_updater_other".to_owned();
+        let other = FileEngine::builder(cfg)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for region_id in (1..=3).map(|i| i * SLOTS_COUNT as u64 + 77) {
+            entry.set_index(1);
+            engine.append(region_id, vec![entry.clone()]).unwrap();
+        }
 
-    fn consume(&self, batch: &mut Self::LogBatch, sync: bool) -> Result<usize> {
-        self.inner.write(std::mem::take(batch), sync)
-    }
+        let worker = engine.start_metrics_updater(Duration::from_secs(10));
+        let _other_worker = other.start_metrics_updater(Duration::from_secs(10));
+        let slot = engine.inner.metrics.slot_regions_count(77);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(slot.get(), 3.0);
+        assert_eq!(other.inner.metrics.slot_regions_count(77).get(), 0.0);
+        assert!(engine.inner.metrics.pipe_files_count.get() >= 1.0);
 
-    fn consume_and_shrink(
-        &self,
-        batch: &mut Self::LogBatch,
-        sync: bool,
-        _: usize,
-        _: usize,
-    ) -> Result<usize> {
-        self.consume(batch, sync)
+        // Stopped workers are no longer run by the clock.
+        drop(worker);
+        let region_id = 4 * SLOTS_COUNT as u64 + 77;
+        engine.append(region_id, vec![entry.clone()]).unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(slot.get(), 3.0);
     }
 
-    fn clean(&self, raft_group_id: u64, _: &RaftLocalState, batch: &mut LogBatch) -> Result<()> {
-        batch.clean_region(raft_group_id);
-        Ok(())
-    }
+    #[test]
+    fn test_backlog_metrics() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_backlog_metrics")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cache_size_limit = ReadableSize::kb(2);
+        cfg.region_size = ReadableSize::kb(1);
+        cfg.compact_threshold = 10;
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.inner.rewrite_backlog(), (0, 0));
 
-    fn append(&self, raft_group_id: u64, entries: Vec<Entry>) -> Result<usize> {
-        let batch = LogBatch::default();
-        batch.add_entries(raft_group_id, entries);
-        self.inner.write(batch, false)
-    }
+        // Region 1 only writes to the first file, and region 2 keeps compacting.
+        let evicted = engine.inner.metrics.cache_evicted_entries.get();
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..=2 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        for i in 1..=40 {
+            entry.set_index(i);
+            engine.append(2, vec![entry.clone()]).unwrap();
+        }
+        // Caches of regions are limited to half of the region size.
+        assert!(engine.inner.metrics.cache_evicted_entries.get() - evicted >= 36.0);
+        engine.gc(2, 0, 41).unwrap();
 
-    fn put_raft_state(&self, raft_group_id: u64, state: &RaftLocalState) -> Result<()> {
-        self.inner.put_msg(raft_group_id, RAFT_LOG_STATE_KEY, state)
-    }
+        let (backlog_bytes, blocked_files) = engine.inner.rewrite_backlog();
+        assert_eq!(
+            backlog_bytes,
+            engine.inner.memtables[1].read().unwrap()[&1].entries_size()
+        );
+        assert!(blocked_files >= 2);
 
-    fn gc(&self, raft_group_id: u64, _from: u64, to: u64) -> Result<usize> {
-        let entries = self.inner.compact_to(raft_group_id, to) as usize;
-        Ok(entries)
+        // Rewritten and purged.
+        assert!(engine.purge_expired_files().unwrap());
+        assert_eq!(engine.inner.rewrite_backlog(), (0, 0));
     }
 
-    fn has_builtin_entry_cache(&self) -> bool {
-        true
-    }
+    #[test]
+    fn test_coldest_regions() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_coldest_regions")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
+        cfg.cache_size_limit = ReadableSize::kb(2);
+        cfg.region_size = ReadableSize::kb(1);
+        cfg.compact_threshold = 10;
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for region_id in 1..=3 {
+            for i in 1..=2 {
+                entry.set_index(i);
+                engine.append(region_id, vec![entry.clone()]).unwrap();
+            }
+        }
+        engine.get_entry(1, 1).unwrap().unwrap();
+        // Reads of missing regions and of snapshots don't count.
+        let snapshot = engine.snapshot();
+        snapshot.get_entry(2, 1).unwrap().unwrap();
+        assert!(engine.get_entry(5, 1).unwrap().is_none());
+        assert_eq!(engine.coldest_regions(2), vec![2, 3]);
+        assert_eq!(engine.coldest_regions(10), vec![2, 3, 1]);
+        drop(snapshot);
 
-    fn gc_entry_cache(&self, raft_group_id: u64, to: u64) {
-        self.inner.compact_cache_to(raft_group_id, to)
+        // Region 4 pushes others to inactive files, which are rewritten coldest first.
+        for i in 1..=40 {
+            entry.set_index(i);
+            engine.append(4, vec![entry.clone()]).unwrap();
+        }
+        engine.gc(4, 0, 41).unwrap();
+        assert!(engine.purge_expired_files().unwrap());
+        let positions: Vec<_> = [2, 3, 1]
+            .iter()
+            .map(|id| engine.last_position(*id).unwrap())
+            .collect();
+        assert!(positions[0] < positions[1] && positions[1] < positions[2]);
+        // Rewrites don't count as accesses.
+        assert_eq!(engine.coldest_regions(10), vec![2, 3, 1, 4]);
+
+        let mut batch = LogBatch::new();
+        batch.clean_region(3);
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.coldest_regions(10), vec![2, 1, 4]);
+
+        // Recovered in the order they are last written, and region 4 isn't written
+        // since the rewrites.
+        drop(engine);
+        let engine = FileEngine::open(cfg).unwrap();
+        assert_eq!(engine.coldest_regions(10), vec![4, 2, 1]);
     }
 
-    fn flush_stats(&self) -> CacheStats {
-        let inner = &self.inner;
-        CacheStats {
-            hit: inner.cache_stats.hit.swap(0, Ordering::SeqCst),
-            miss: inner.cache_stats.miss.swap(0, Ordering::SeqCst),
-            mem_size_change: inner.cache_stats.mem_size_change.swap(0, Ordering::SeqCst),
+    #[test]
+    fn test_hot_regions() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_hot_regions")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        for region_id in 1..=3 {
+            entry.set_data(vec![b'x'; region_id as usize * 100]);
+            for i in 1..=10 {
+                entry.set_index(i);
+                engine.append(region_id, vec![entry.clone()]).unwrap();
+            }
         }
-    }
-}
+        let mut batch = LogBatch::new();
+        batch.put(4, b"k", b"v");
+        batch.put(4, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let hot = engine.hot_regions(2);
+        assert_eq!(hot.len(), 2);
+        assert_eq!((hot[0].region_id, hot[0].ops), (3, 10));
+        assert!(hot[0].bytes >= 3000);
+        assert_eq!(hot[1].region_id, 2);
+        let coldest = engine.hot_regions(4)[3];
+        assert_eq!((coldest.region_id, coldest.bytes, coldest.ops), (4, 4, 1));
+
+        engine.inner.update_metrics();
+        assert_eq!(engine.hot_regions(1)[0].ops, 5);
+    }
 
     #[test]
-    fn test_get_entry_from_file() {
-        let normal_batch_size = 10;
-        let compressed_batch_size = 5120;
-        for &entry_size in &[normal_batch_size, compressed_batch_size] {
-            let dir = tempfile::Builder::new()
-                .prefix("test_engine")
-                .tempdir()
-                .unwrap();
+    fn test_background_health() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_background_health")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.name = "test_background_health".to_owned();
+        let clock = ManualClock::default();
+        let engine = FileEngine::builder(cfg)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let _purge = engine.start_purge(Duration::from_secs(1));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let r = runs.clone();
+        let failing = engine.schedule("failing", Duration::from_secs(1), move || {
+            r.fetch_add(1, Ordering::SeqCst);
+            panic!("failing task");
+        });
 
-            let mut cfg = Config::default();
-            cfg.dir = dir.path().to_str().unwrap().to_owned();
+        // Run again after skipping 1 tick, and then 2.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        let health = engine.background_health();
+        assert_eq!(health.len(), 2);
+        assert_eq!(health[0].name, "purge");
+        assert_eq!(health[0].panics, 0);
+        assert_eq!(health[1].panics, 2);
+        assert_eq!(health[1].last_panic.as_deref(), Some("failing task"));
+        assert_eq!(health[1].backoff_ticks, 0);
+        assert_eq!(engine.inner.metrics.background_panics("failing").get(), 2.0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
 
-            let engine = FileEngine::new(cfg.clone());
-            let mut entry = Entry::new();
-            entry.set_data(vec![b'x'; entry_size]);
-            for i in 10..20 {
-                entry.set_index(i);
-                engine.append(i, vec![entry.clone()]).unwrap();
-                entry.set_index(i + 1);
-                engine.append(i, vec![entry.clone()]).unwrap();
-            }
+        drop(failing);
+        assert_eq!(engine.background_health().len(), 1);
+    }
 
-            for i in 10..20 {
-                // Test get_entry from cache.
-                entry.set_index(i + 1);
-                assert_eq!(engine.get_entry(i, i + 1).unwrap(), Some(entry.clone()));
+    #[test]
+    fn test_scrub() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_scrub")
+            .tempdir()
+            .unwrap();
 
-                // Test get_entry from file.
-                entry.set_index(i);
-                assert_eq!(engine.get_entry(i, i).unwrap(), Some(entry.clone()));
-            }
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(1);
 
-            drop(engine);
+        let engine = FileEngine::new(cfg);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 128]);
+        for i in 1..20 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
 
-            // Recover the engine.
-            let engine = FileEngine::new(cfg.clone());
-            for i in 10..20 {
-                entry.set_index(i + 1);
-                assert_eq!(engine.get_entry(i, i + 1).unwrap(), Some(entry.clone()));
+        // Corrupt the first batch of the second file.
+        let path = dir.path().join("0000000000000002.raftlog");
+        let mut content = std::fs::read(&path).unwrap();
+        let pos = FILE_HEADER_LEN + HEADER_LEN + 4;
+        content[pos] = !content[pos];
+        std::fs::write(&path, &content).unwrap();
 
-                entry.set_index(i);
-                assert_eq!(engine.get_entry(i, i).unwrap(), Some(entry.clone()));
-            }
-        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        let scrubber = engine.start_scrub(Duration::from_millis(10), move |e| {
+            tx.send(e.to_string()).unwrap();
+        });
+        let report = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            report.contains("Raft log file 2 is corrupted"),
+            "{}",
+            report
+        );
+        drop(scrubber);
     }
 }
@@ -0,0 +1,281 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Ordering of writes applied to memtables, by writers themselves or by the
+//! applier thread if `async_apply` is set.
+
+use std::collections::BTreeSet;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use super::{item_region_id, FileEngineInner};
+use crate::apply_queue::ApplyQueue;
+use crate::log_batch::LogBatch;
+use crate::util::HashSet;
+use crate::worker::panic_message;
+
+// Tickets of writes in progress, taken in order when writes start.
+#[derive(Default)]
+pub(super) struct PendingWrites {
+    next_ticket: u64,
+    tickets: BTreeSet<u64>,
+    // Regions being rewritten, whose writes don't start until the rewrite is done.
+    pub(super) rewriting: HashSet<u64>,
+}
+
+// Keeps writes of the region from starting until dropped, see
+// `FileEngineInner::start_rewrite`.
+pub(super) struct RewriteGuard<'a> {
+    inner: &'a FileEngineInner,
+    region_id: u64,
+}
+
+impl Drop for RewriteGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.inner.pending_writes.lock().unwrap();
+        pending.rewriting.remove(&self.region_id);
+        self.inner.writes_done.notify_all();
+    }
+}
+
+// Marks a write in progress until dropped.
+pub(super) struct PendingWrite<'a> {
+    inner: &'a FileEngineInner,
+    ticket: u64,
+}
+
+impl PendingWrite<'_> {
+    // Leave the write in progress after dropped, until its ticket is finished by
+    // `FileEngineInner::finish_write`.
+    pub(super) fn detach(self) -> u64 {
+        let ticket = self.ticket;
+        mem::forget(self);
+        ticket
+    }
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        self.inner.finish_write(self.ticket);
+    }
+}
+
+// A written batch to be applied by the applier.
+pub(super) struct PendingApply {
+    batch: LogBatch,
+    file_num: u64,
+    // Of the write, finished once applied.
+    ticket: u64,
+    // Regions cleaned by the batch, see `FileEngineInner::cleaning`.
+    cleaned: Vec<u64>,
+}
+
+// Applies written batches to memtables in order in a dedicated thread, so that
+// writers don't wait for locks of memtables. Reads wait for batches written
+// before them to be applied, see `FileEngineInner::wait_applied`. A panic while
+// applying aborts the process, as memtables no longer match the log, and reads
+// and writes would wait for the batch forever. Recovery rebuilds them.
+pub(super) struct Applier {
+    queue: Arc<ApplyQueue<PendingApply>>,
+}
+
+impl Applier {
+    pub(super) fn new() -> Applier {
+        Applier {
+            queue: Arc::new(ApplyQueue::new()),
+        }
+    }
+
+    // The thread only refers to the engine while it applies a batch, and stops
+    // once the engine is dropped.
+    pub(super) fn start(&self, inner: Weak<FileEngineInner>, name: String) {
+        let queue = self.queue.clone();
+        thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                while let Some(pending) = queue.pop() {
+                    let inner = match inner.upgrade() {
+                        Some(inner) => inner,
+                        None => break,
+                    };
+                    let res =
+                        panic::catch_unwind(AssertUnwindSafe(|| inner.apply_pending(pending)));
+                    if let Err(payload) = res {
+                        error!(
+                            "[{}] Apply written batch panicked: {}, abort",
+                            inner.cfg.name,
+                            panic_message(payload.as_ref())
+                        );
+                        process::abort();
+                    }
+                    // Released before waiters are woken, which may drop the engine.
+                    drop(inner);
+                    queue.finish();
+                }
+            })
+            .unwrap_or_else(|e| panic!("Spawn thread {} failed, err {:?}", name, e));
+    }
+}
+
+impl Drop for Applier {
+    fn drop(&mut self) {
+        self.queue.stop();
+    }
+}
+
+impl FileEngineInner {
+    pub(super) fn finish_write(&self, ticket: u64) {
+        let mut pending = self.pending_writes.lock().unwrap();
+        pending.tickets.remove(&ticket);
+        self.writes_done.notify_all();
+    }
+
+    // Wait until batches written before are applied to memtables, so that writes
+    // returned are visible to reads.
+    pub(super) fn wait_applied(&self) {
+        if let Some(applier) = &self.applier {
+            applier.queue.wait();
+        }
+    }
+
+    pub(super) fn apply_pending(&self, pending: PendingApply) {
+        let sequence = pending.batch.sequence;
+        self.post_append_to_file(pending.batch, pending.file_num);
+        self.finish_strict_append(sequence);
+        self.finish_cleaning(&pending.cleaned);
+        self.finish_write(pending.ticket);
+    }
+
+    pub(super) fn start_write(&self, log_batch: &LogBatch) -> PendingWrite<'_> {
+        let mut pending = self.pending_writes.lock().unwrap();
+        while !pending.rewriting.is_empty()
+            && log_batch
+                .items
+                .borrow()
+                .iter()
+                .any(|item| pending.rewriting.contains(&item_region_id(item)))
+        {
+            pending = self.writes_done.wait(pending).unwrap();
+        }
+        let ticket = pending.next_ticket;
+        pending.next_ticket += 1;
+        pending.tickets.insert(ticket);
+        PendingWrite {
+            inner: self,
+            ticket,
+        }
+    }
+
+    // Keep writes of the region from starting, and wait until ones started before
+    // are applied, so that what's rewritten from the memtable isn't overwritten
+    // by a write appended before the rewrite but applied after it. Writes must be
+    // flushed from the buffer first, and no slot may be locked.
+    pub(super) fn start_rewrite(&self, region_id: u64) -> RewriteGuard<'_> {
+        let mut pending = self.pending_writes.lock().unwrap();
+        while pending.rewriting.contains(&region_id) {
+            pending = self.writes_done.wait(pending).unwrap();
+        }
+        pending.rewriting.insert(region_id);
+        let end = pending.next_ticket;
+        while pending.tickets.iter().next().map_or(false, |t| *t < end) {
+            pending = self.writes_done.wait(pending).unwrap();
+        }
+        RewriteGuard {
+            inner: self,
+            region_id,
+        }
+    }
+
+    // Wait until writes started before are applied to memtables.
+    pub(super) fn wait_for_writes(&self) {
+        let mut pending = self.pending_writes.lock().unwrap();
+        let end = pending.next_ticket;
+        while pending.tickets.iter().next().map_or(false, |t| *t < end) {
+            pending = self.writes_done.wait(pending).unwrap();
+        }
+    }
+
+    // Apply the written batch to memtables, or queue it to the applier if any,
+    // which finishes the write once it's applied.
+    pub(super) fn apply_written(
+        &self,
+        pending: PendingWrite<'_>,
+        batch: LogBatch,
+        file_num: u64,
+        cleaned: Vec<u64>,
+    ) {
+        match &self.applier {
+            Some(applier) if file_num != 0 => applier.queue.push(PendingApply {
+                batch,
+                file_num,
+                ticket: pending.detach(),
+                cleaned,
+            }),
+            _ => {
+                let sequence = batch.sequence;
+                self.post_append_to_file(batch, file_num);
+                self.finish_strict_append(sequence);
+                self.finish_cleaning(&cleaned);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_async_apply() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_async_apply")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.async_apply = true;
+        cfg.strict_append = true;
+        let engine = FileEngine::new(cfg.clone());
+        let handles: Vec<_> = (1..=4)
+            .map(|region_id| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    let mut entry = Entry::new();
+                    for i in 1..=100 {
+                        entry.set_index(i);
+                        entry.set_data(vec![b'x'; i as usize]);
+                        engine.append(region_id, vec![entry.clone()]).unwrap();
+                        // Writes are visible once returned.
+                        let e = engine.get_entry(region_id, i).unwrap().unwrap();
+                        assert_eq!(e, entry);
+                        let mut state = RaftLocalState::new();
+                        state.set_last_index(i);
+                        engine.put_raft_state(region_id, &state).unwrap();
+                        let got = engine.get_raft_state(region_id).unwrap();
+                        assert_eq!(got, Some(state));
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(engine.gc(1, 0, 51).unwrap(), 50);
+        let snapshot = engine.snapshot();
+        let mut batch = LogBatch::default();
+        batch.clean_region(2);
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.get_entry(2, 1).unwrap(), None);
+        assert!(snapshot.get_entry(2, 1).unwrap().is_some());
+        drop(snapshot);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        assert!(engine.get_entry(1, 51).unwrap().is_some());
+        assert_eq!(engine.get_entry(2, 1).unwrap(), None);
+        assert!(engine.get_entry(4, 100).unwrap().is_some());
+    }
+}
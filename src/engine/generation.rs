@@ -0,0 +1,223 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Generations of regions: a region starts a new one with each clean command,
+//! and items written in an earlier one, e.g. by a rewrite that read the region
+//! before the clean, are stale and dropped when applied.
+
+use std::cmp;
+
+use super::{FileEngineInner, SLOTS_COUNT};
+use crate::log_batch::LogBatch;
+use crate::Result;
+
+// Where the latest clean command of a region is written. It's forgotten once the
+// file is purged, unless stale batches dropped because of it are still in later
+// files, which the clean is rewritten for, or they would be recovered.
+#[derive(Clone, Copy, Default)]
+pub(super) struct Generation {
+    // Sequence of the batch with the command.
+    sequence: u64,
+    file_num: u64,
+    // The last file with stale batches of the region, 0 if none.
+    stale_file_num: u64,
+}
+
+impl FileEngineInner {
+    // Sequence of the batch with the latest clean command of the region, 0 if
+    // none is known.
+    pub(super) fn generation(&self, region_id: u64) -> u64 {
+        self.generations
+            .lock()
+            .unwrap()
+            .get(&region_id)
+            .map_or(0, |g| g.sequence)
+    }
+
+    pub(super) fn record_clean(&self, region_id: u64, sequence: u64, file_num: u64) {
+        self.generations.lock().unwrap().insert(
+            region_id,
+            Generation {
+                sequence,
+                file_num,
+                stale_file_num: 0,
+            },
+        );
+    }
+
+    // Regions whose items in the batch, written in file `file_num`, are stale. The
+    // file is recorded as holding stale batches of them.
+    pub(super) fn stale_regions(&self, log_batch: &LogBatch, file_num: u64) -> Vec<u64> {
+        let mut generations = self.generations.lock().unwrap();
+        let stale = log_batch
+            .stale_regions(|region_id| generations.get(&region_id).map_or(0, |g| g.sequence));
+        for region_id in &stale {
+            let g = generations.get_mut(region_id).unwrap();
+            g.stale_file_num = cmp::max(g.stale_file_num, file_num);
+        }
+        stale
+    }
+
+    // Files before `file_num` are to be purged. Rewrite clean commands in them
+    // that hide stale batches in later files, and return the file to purge up to
+    // instead, before the clean commands failing to be rewritten.
+    pub(super) fn keep_clean_commands(&self, mut file_num: u64) -> u64 {
+        let needed: Vec<u64> = self
+            .generations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, g)| g.file_num < file_num && g.stale_file_num >= file_num)
+            .map(|(region_id, _)| *region_id)
+            .collect();
+        for region_id in needed {
+            if let Err(e) = self.rewrite_clean(region_id) {
+                warn!(
+                    "[{}] Rewrite clean command of region {} failed: {}",
+                    self.cfg.name, region_id, e
+                );
+            }
+            // Kept if the clean isn't rewritten.
+            let generations = self.generations.lock().unwrap();
+            let g = generations[&region_id];
+            if g.stale_file_num >= file_num {
+                file_num = cmp::min(file_num, g.file_num);
+            }
+        }
+        file_num
+    }
+
+    // Forget clean commands in files before `first_file_num`, which are purged.
+    pub(super) fn forget_generations(&self, first_file_num: u64) {
+        self.generations
+            .lock()
+            .unwrap()
+            .retain(|_, g| g.file_num >= first_file_num);
+    }
+
+    // Write the latest clean command of the region again, along with data of the
+    // region written since if any.
+    fn rewrite_clean(&self, region_id: u64) -> Result<()> {
+        {
+            let mut memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                .write()
+                .unwrap();
+            if let Some(memtable) = memtables.get_mut(&region_id) {
+                return self.rewrite_memtable(memtable.as_mut(), true);
+            }
+        }
+        let batch = LogBatch::new();
+        batch.clean_region(region_id);
+        self.write_batch(batch, false)?;
+        self.wait_applied();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::util::ReadableSize;
+
+    #[test]
+    fn test_purge_clean_with_stale_batches() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_purge_clean_with_stale_batches")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(64);
+        cfg.disable_compression = true;
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 1024]);
+        let fill = |region_id: u64, count: u64| {
+            let mut entry = Entry::new();
+            entry.set_data(vec![b'x'; 1024]);
+            for i in 1..=count {
+                entry.set_index(i);
+                engine.append(region_id, vec![entry.clone()]).unwrap();
+            }
+        };
+        // Region 1 is cleaned in the first file, and a rewrite read before the clean
+        // is written in the second one.
+        entry.set_index(1);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        let generation = engine.latest_sequence();
+        let mut batch = LogBatch::new();
+        batch.clean_region(1);
+        engine.consume(&mut batch, false).unwrap();
+        fill(2, 70);
+        assert_eq!(engine.inner.pipe_log.active_file_num(), 2);
+        let mut batch = LogBatch::new();
+        batch.add_command(Command::Fence {
+            region_id: 1,
+            generation,
+        });
+        batch.add_entries(1, vec![entry.clone()]);
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.entries_range(1), None);
+        fill(3, 70);
+
+        // The first file is purged, but the clean isn't lost.
+        engine.gc(2, 0, 71).unwrap();
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.pipe_log.first_file_num(), 2);
+        drop(engine);
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), None);
+        assert_eq!(engine.entries_range(3), Some((1, 70)));
+    }
+
+    #[test]
+    fn test_drop_stale_rewrite() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_drop_stale_rewrite")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_index(1);
+        engine.append(1, vec![entry.clone()]).unwrap();
+        let mut batch = LogBatch::new();
+        batch.clean_region(1);
+        engine.consume(&mut batch, false).unwrap();
+
+        // A rewrite of the region read before the clean command, written after it.
+        let mut stale = LogBatch::new();
+        stale.add_command(Command::Fence {
+            region_id: 1,
+            generation: 0,
+        });
+        stale.add_entries(1, vec![entry.clone()]);
+        stale.put(1, b"key", b"value");
+        let mut file_num = 0;
+        engine
+            .inner
+            .pipe_log
+            .append_log_batch(&mut stale, false, &mut file_num)
+            .unwrap();
+        engine.inner.apply_to_memtable(stale, file_num);
+        assert_eq!(engine.entries_range(1), None);
+
+        // Rewrites of the region written again are kept.
+        entry.set_index(2);
+        engine.append(1, vec![entry]).unwrap();
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        engine.rewrite_region(1).unwrap();
+        assert_eq!(engine.get_statistics().rewrites, 1);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), Some((2, 2)));
+        assert_eq!(
+            engine.region_kvs(1).unwrap(),
+            vec![(b"k".to_vec(), b"v".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use super::{cleaned_regions, item_region_id, FileEngine, FileEngineInner, SLOTS_COUNT};
+use crate::log_batch::{LogBatch, LogItemType, OpType};
+use crate::pipe_log;
+use crate::util::HashMap;
+use crate::{Error, Result};
+
+// Smaller holes hardly release any disk space.
+pub(super) const MIN_HOLE_SIZE: u64 = 4096;
+
+// Regions whose data written before the batch is hidden by it, i.e. cleaned or
+// with keys deleted. The batch must be replayed as long as the data may be.
+fn hiding_regions(batch: &LogBatch) -> Vec<u64> {
+    let mut regions = cleaned_regions(batch);
+    for item in batch.items.borrow().iter() {
+        if let Some(kv) = &item.kv {
+            if kv.op_type == OpType::Del {
+                regions.push(kv.region_id);
+            }
+        }
+    }
+    regions
+}
+
+impl FileEngine {
+    /// Punch holes over dead batches in inactive files that are mostly dead, to
+    /// release disk space before the files can be purged. Return punched bytes,
+    /// including holes punched before and merged into new ones. Nothing is punched
+    /// while snapshots are alive, as they may read batches dead to the engine.
+    pub fn punch_holes(&self) -> Result<u64> {
+        if !self.inner.snapshots.read().unwrap().is_empty() {
+            return Ok(0);
+        }
+        let (first, active) = (
+            self.inner.pipe_log.first_file_num(),
+            self.inner.pipe_log.active_file_num(),
+        );
+        let retained = self.inner.retained_file_num();
+        let mut live_size = vec![0; (active - first) as usize];
+        for memtables in &self.inner.memtables {
+            for memtable in memtables.read().unwrap().values() {
+                for (file_num, usage) in memtable.usage_before(active) {
+                    if file_num >= first {
+                        live_size[(file_num - first) as usize] += usage.entries_size;
+                    }
+                }
+            }
+        }
+
+        let mut punched = 0;
+        for (i, size) in live_size.into_iter().enumerate() {
+            let file_num = first + i as u64;
+            if size * 2 < self.inner.cfg.target_file_size.0
+                && file_num < retained
+                && !self.inner.pipe_log.is_pinned(file_num)
+            {
+                punched += self.inner.punch_holes_in(file_num)?;
+            }
+        }
+        Ok(punched)
+    }
+}
+
+impl FileEngineInner {
+    // Whether the batch read from the file contains entries or key value pairs still
+    // in use. Clean commands are checked by `punch_holes_in`.
+    fn is_batch_live(&self, batch: &LogBatch, file_num: u64) -> bool {
+        for item in batch.items.borrow().iter() {
+            let region_id = match item.item_type {
+                LogItemType::CMD => continue,
+                LogItemType::Entries => item.entries.as_ref().unwrap().region_id,
+                LogItemType::KV => item.kv.as_ref().unwrap().region_id,
+            };
+            let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                .read()
+                .unwrap();
+            let memtable = match memtables.get(&region_id) {
+                Some(memtable) => memtable,
+                None => continue,
+            };
+            let live = match item.item_type {
+                LogItemType::Entries => {
+                    let entries = item.entries.as_ref().unwrap();
+                    entries.entries_index.borrow().iter().any(|idx| {
+                        memtable.entry_index(idx.index).map_or(false, |i| {
+                            i.file_num == file_num && i.base_offset == idx.base_offset
+                        })
+                    })
+                }
+                _ => memtable.kv_file_num(&item.kv.as_ref().unwrap().key) == Some(file_num),
+            };
+            if live {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Punch holes over runs of dead batches in an inactive file. Return punched bytes.
+    pub(super) fn punch_holes_in(&self, file_num: u64) -> Result<u64> {
+        let content = self.pipe_log.scan_file(file_num)?;
+        let header_len = pipe_log::check_file_header(file_num, &content)?;
+
+        // A clean command or a deleted key hides data of the region written before
+        // it. It's dropped only if there is no older file, and the earlier batches of
+        // the region in this file are dropped together with it, i.e. in the same run.
+        let is_first_file = file_num == self.pipe_log.first_file_num();
+        // Region id -> offset of the first batch of the region.
+        let mut first_batches: HashMap<u64, u64> = HashMap::default();
+
+        // Each run starts after a live batch, so it may cover an existing hole.
+        let mut runs = vec![];
+        let mut run_start = None;
+        let mut buf = &content[header_len..];
+        loop {
+            let start = (content.len() - buf.len()) as u64;
+            let batch =
+                match LogBatch::from_bytes(&mut buf, file_num, start, self.pipe_log.dictionaries())
+                {
+                    Ok(Some(batch)) => batch,
+                    Ok(None) => break,
+                    Err(e) => return Err(Error::Corruption(file_num, start, e.to_string())),
+                };
+            let hides_data = hiding_regions(&batch).into_iter().any(|region_id| {
+                !is_first_file
+                    || match (first_batches.get(&region_id), run_start) {
+                        (Some(&offset), Some(run_start)) => offset < run_start,
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    }
+            });
+            for item in batch.items.borrow().iter() {
+                first_batches.entry(item_region_id(item)).or_insert(start);
+            }
+            if !hides_data && !self.is_batch_live(&batch, file_num) {
+                run_start.get_or_insert(start);
+            } else if let Some(run_start) = run_start.take() {
+                runs.push((run_start, start));
+            }
+        }
+        if let Some(run_start) = run_start {
+            runs.push((run_start, content.len() as u64));
+        }
+
+        let mut punched = 0;
+        for (start, end) in runs {
+            if end - start >= MIN_HOLE_SIZE {
+                self.pipe_log.punch_hole(file_num, start, end - start)?;
+                punched += end - start;
+            }
+        }
+        Ok(punched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::util::ReadableSize;
+
+    #[test]
+    fn test_punch_holes() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_punch_holes")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(64);
+        cfg.verify_on_recovery = true;
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 1024]);
+        for i in 1..100 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            if i % 20 == 0 {
+                entry.set_index(i / 20);
+                engine.append(2, vec![entry.clone()]).unwrap();
+            }
+        }
+        assert!(engine.inner.pipe_log.active_file_num() > 1);
+        engine.gc(1, 0, 100).unwrap();
+        let path = dir.path().join("0000000000000001.raftlog");
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        assert!(engine.punch_holes().unwrap() > 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        for i in 1..5 {
+            assert_eq!(engine.get_entry(2, i).unwrap().unwrap().get_index(), i);
+        }
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        for i in 1..5 {
+            assert_eq!(engine.get_entry(2, i).unwrap().unwrap().get_index(), i);
+        }
+        assert!(engine.get_entry(1, 50).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_punch_deleted_keys() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_punch_deleted_keys")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(64);
+        cfg.disable_compression = true;
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 1024]);
+        // Put in the first file, and deleted in the second one among dead entries.
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k", b"v");
+        engine.consume(&mut batch, false).unwrap();
+        for i in 1..=70 {
+            entry.set_index(i);
+            engine.append(2, vec![entry.clone()]).unwrap();
+        }
+        assert_eq!(engine.inner.pipe_log.active_file_num(), 2);
+        for i in 1..=20 {
+            entry.set_index(i);
+            engine.append(3, vec![entry.clone()]).unwrap();
+            if i == 10 {
+                let mut batch = LogBatch::new();
+                batch.delete(1, b"k");
+                engine.consume(&mut batch, false).unwrap();
+            }
+        }
+        for i in 1..=70 {
+            entry.set_index(i);
+            engine.append(4, vec![entry.clone()]).unwrap();
+        }
+        assert_eq!(engine.inner.pipe_log.active_file_num(), 3);
+        engine.gc(3, 0, 21).unwrap();
+        engine.gc(4, 0, 71).unwrap();
+
+        assert!(engine.punch_holes().unwrap() > 0);
+        assert!(engine.inner.get(None, 1, b"k").unwrap().is_none());
+        drop(engine);
+        let engine = FileEngine::new(cfg);
+        assert!(engine.inner.get(None, 1, b"k").unwrap().is_none());
+        assert_eq!(engine.entries_range(3), None);
+    }
+
+    #[test]
+    fn test_punch_clean_commands() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_punch_clean_commands")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize::kb(64);
+
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 1024]);
+        for i in 1..=10 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            engine.append(3, vec![entry.clone()]).unwrap();
+        }
+        // Region 1 is cleaned in the first file, after all its data.
+        let mut batch = LogBatch::new();
+        batch.clean_region(1);
+        engine.consume(&mut batch, false).unwrap();
+        entry.set_index(1);
+        engine.append(2, vec![entry.clone()]).unwrap();
+        // Region 3 is cleaned in the second file.
+        for i in 1..=70 {
+            entry.set_index(i);
+            engine.append(4, vec![entry.clone()]).unwrap();
+        }
+        assert_eq!(engine.inner.pipe_log.active_file_num(), 2);
+        let mut batch = LogBatch::new();
+        batch.clean_region(3);
+        engine.consume(&mut batch, false).unwrap();
+        for i in 71..=140 {
+            entry.set_index(i);
+            engine.append(4, vec![entry.clone()]).unwrap();
+        }
+        engine.gc(4, 0, 141).unwrap();
+
+        assert!(engine.punch_holes().unwrap() > 0);
+        assert_eq!(engine.inner.pipe_log.first_file_num(), 1);
+        // Nothing older hides behind the clean command of region 1.
+        assert!(engine.dump_region(1).unwrap().is_empty());
+        // Data of region 3 in the first file may be read on recovery.
+        let items = engine.dump_region(3).unwrap();
+        assert_eq!(items.last().unwrap().content, DumpContent::Clean);
+        drop(engine);
+
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.entries_range(1), None);
+        assert_eq!(engine.entries_range(2), Some((1, 1)));
+        assert_eq!(engine.entries_range(3), None);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use raft::eraftpb::Entry;
+
+use super::{FileEngine, FileEngineInner};
+use crate::memtable::MemTableAccessor;
+use crate::util::HashMap;
+use crate::Result;
+
+// A read view of the engine as of a sequence. Regions are frozen when they are
+// first changed by a later batch, others are read from memtables.
+pub(super) struct SnapshotState {
+    pub(super) sequence: u64,
+    // Files before it may be purged, later ones are kept for reads of the snapshot.
+    pub(super) first_file_num: u64,
+    // Region id -> the region as of the snapshot, `None` if it didn't exist.
+    pub(super) regions: Mutex<HashMap<u64, Option<Box<dyn MemTableAccessor>>>>,
+}
+
+/// A read view of a `FileEngine` as of the time it's taken, see
+/// `FileEngine::snapshot`. Writes after it, including gc and clean commands, are
+/// not observed by its reads. Log files needed by the view are not purged until
+/// it's dropped.
+pub struct Snapshot {
+    inner: Arc<FileEngineInner>,
+    state: Arc<SnapshotState>,
+}
+
+impl Snapshot {
+    /// The sequence of the last batch observed by the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.state.sequence
+    }
+
+    pub fn get(&self, region_id: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(Some(&self.state), region_id, key)
+    }
+
+    pub fn get_msg<M: protobuf::Message>(&self, region_id: u64, key: &[u8]) -> Result<Option<M>> {
+        self.inner.get_msg(Some(&self.state), region_id, key)
+    }
+
+    pub fn get_entry(&self, region_id: u64, log_idx: u64) -> Result<Option<Entry>> {
+        self.inner.get_entry(Some(&self.state), region_id, log_idx)
+    }
+
+    /// Fetch entries in [begin, end) as `RaftEngine::fetch_entries_to`, return the
+    /// count of fetched entries.
+    pub fn fetch_entries_to(
+        &self,
+        region_id: u64,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        to: &mut Vec<Entry>,
+    ) -> Result<usize> {
+        self.inner
+            .fetch_entries_to(Some(&self.state), region_id, begin, end, max_size, to)
+            .map(|(count, _)| count)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner
+            .snapshots
+            .write()
+            .unwrap()
+            .retain(|s| !Arc::ptr_eq(s, &self.state));
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Snapshot sequence: {}", self.state.sequence)
+    }
+}
+
+impl FileEngine {
+    /// Take a read view of the current data, which is cheap until regions are
+    /// changed: a region is copied when it's first written or gc'ed after the
+    /// snapshot, and its entries are read from files since.
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.wait_written();
+        // Batches up to the sequence are applied to memtables without freezing them
+        // for the snapshot, so those written but not applied yet are waited for
+        // first, e.g. of writes without the applier still applying them.
+        self.inner.wait_for_writes();
+        let state = {
+            let mut snapshots = self.inner.snapshots.write().unwrap();
+            // Batches after the sequence are applied with the lock held, so all of
+            // them find the snapshot.
+            let state = Arc::new(SnapshotState {
+                sequence: self.inner.pipe_log.latest_sequence(),
+                first_file_num: self.inner.pipe_log.first_file_num(),
+                regions: Mutex::new(HashMap::default()),
+            });
+            snapshots.push(state.clone());
+            state
+        };
+        // Writes started in between, with sequences up to the snapshot's, are
+        // visible to it once applied.
+        self.inner.wait_for_writes();
+        Snapshot {
+            inner: self.inner.clone(),
+            state,
+        }
+    }
+}
+
+impl FileEngineInner {
+    // Keep the region as of snapshots before it's changed by the batch with
+    // `sequence`, or by a gc if `None`. The slot of the region must be locked for
+    // writing.
+    pub(super) fn freeze_for_snapshots(
+        &self,
+        region_id: u64,
+        memtable: Option<&dyn MemTableAccessor>,
+        sequence: Option<u64>,
+    ) {
+        for snapshot in self.snapshots.read().unwrap().iter() {
+            if sequence.map_or(false, |s| s <= snapshot.sequence) {
+                continue;
+            }
+            snapshot
+                .regions
+                .lock()
+                .unwrap()
+                .entry(region_id)
+                .or_insert_with(|| memtable.map(|m| m.freeze()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::util::ReadableSize;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_snapshot")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.target_file_size = ReadableSize(1);
+        let engine = FileEngine::new(cfg);
+        let entry = |index, data: &[u8]| {
+            let mut e = Entry::new();
+            e.set_index(index);
+            e.set_data(data.to_vec());
+            e
+        };
+        let append = |region_id, entries| {
+            let mut batch = LogBatch::new();
+            batch.add_entries(region_id, entries);
+            engine.consume(&mut batch, false).unwrap();
+        };
+        append(1, vec![entry(1, b"a"), entry(2, b"b")]);
+        let mut batch = LogBatch::new();
+        batch.put(1, b"k1", b"v1");
+        batch.put(2, b"k2", b"v2");
+        engine.consume(&mut batch, false).unwrap();
+
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.sequence(), engine.latest_sequence());
+        // Overwrite, append, gc and clean after the snapshot.
+        append(1, vec![entry(2, b"c"), entry(3, b"d")]);
+        engine.gc(1, 0, 2).unwrap();
+        let mut batch = LogBatch::new();
+        batch.delete(1, b"k1");
+        batch.clean_region(2);
+        batch.put(3, b"k3", b"v3");
+        engine.consume(&mut batch, false).unwrap();
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.punch_holes().unwrap(), 0);
+
+        assert_eq!(engine.get_entry(1, 1).unwrap(), None);
+        assert_eq!(engine.get_entry(1, 2).unwrap(), Some(entry(2, b"c")));
+        assert_eq!(engine.inner.get(None, 1, b"k1").unwrap(), None);
+        assert_eq!(engine.inner.get(None, 2, b"k2").unwrap(), None);
+        assert_eq!(
+            engine.inner.get(None, 3, b"k3").unwrap(),
+            Some(b"v3".to_vec())
+        );
+
+        assert_eq!(snapshot.get_entry(1, 1).unwrap(), Some(entry(1, b"a")));
+        assert_eq!(snapshot.get_entry(1, 2).unwrap(), Some(entry(2, b"b")));
+        assert_eq!(snapshot.get_entry(1, 3).unwrap(), None);
+        let mut entries = vec![];
+        assert_eq!(
+            snapshot
+                .fetch_entries_to(1, 1, 3, None, &mut entries)
+                .unwrap(),
+            2
+        );
+        assert_eq!(entries, vec![entry(1, b"a"), entry(2, b"b")]);
+        assert_eq!(snapshot.get(1, b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(snapshot.get(2, b"k2").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(snapshot.get(3, b"k3").unwrap(), None);
+
+        // Files are purged once the snapshot is dropped.
+        let first_file_num = engine.inner.pipe_log.first_file_num();
+        drop(snapshot);
+        engine.purge_expired_files().unwrap();
+        assert!(engine.inner.pipe_log.first_file_num() > first_file_num);
+    }
+}
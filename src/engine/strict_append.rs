@@ -0,0 +1,144 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use super::{FileEngineInner, SLOTS_COUNT};
+use crate::log_batch::{Command, LogBatch, LogItemType};
+use crate::util::HashMap;
+use crate::{Error, Result};
+
+impl FileEngineInner {
+    // Entries must follow the last entry of their region without gap or overlap,
+    // unless the batch overwrites them. Regions' last entries are in `appends` if
+    // appended but not applied yet, or in memtables. Return the last indexes of
+    // regions after the batch.
+    pub(super) fn check_append(
+        &self,
+        log_batch: &LogBatch,
+        appends: &HashMap<u64, (u64, Option<u64>)>,
+    ) -> Result<HashMap<u64, Option<u64>>> {
+        let mut last_indexes: HashMap<u64, Option<u64>> = HashMap::default();
+        for item in log_batch.items.borrow().iter() {
+            match item.item_type {
+                LogItemType::Entries => {
+                    let entries = item.entries.as_ref().unwrap();
+                    let region_id = entries.region_id;
+                    let last_index = last_indexes.entry(region_id).or_insert_with(|| {
+                        if let Some((_, last_index)) = appends.get(&region_id) {
+                            return *last_index;
+                        }
+                        let memtables = self.memtables[region_id as usize % SLOTS_COUNT]
+                            .read()
+                            .unwrap();
+                        memtables.get(&region_id).and_then(|m| m.last_index())
+                    });
+                    for e in &entries.entries {
+                        match *last_index {
+                            Some(last) if !log_batch.overwrite && e.get_index() != last + 1 => {
+                                return Err(Error::AppendConflict(
+                                    region_id,
+                                    last + 1,
+                                    e.get_index(),
+                                ));
+                            }
+                            _ => *last_index = Some(e.get_index()),
+                        }
+                    }
+                }
+                LogItemType::CMD => match *item.command.as_ref().unwrap() {
+                    Command::Clean { region_id } => {
+                        last_indexes.insert(region_id, None);
+                    }
+                    Command::Fence { .. } => {}
+                },
+                LogItemType::KV => {}
+            }
+        }
+        Ok(last_indexes)
+    }
+
+    // Forget last indexes appended by the batch of `sequence`, now in memtables.
+    pub(super) fn finish_strict_append(&self, sequence: u64) {
+        if self.cfg.strict_append {
+            let mut appends = self.strict_appends.lock().unwrap();
+            appends.retain(|_, (s, _)| *s != sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_strict_append() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_strict_append")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.strict_append = true;
+        let engine = FileEngine::new(cfg.clone());
+        let entries = |indexes: &[u64]| -> Vec<Entry> {
+            indexes
+                .iter()
+                .map(|&i| {
+                    let mut e = Entry::new();
+                    e.set_index(i);
+                    e
+                })
+                .collect()
+        };
+
+        engine.append(1, entries(&[5, 6, 7])).unwrap();
+        let sequence = engine.latest_sequence();
+        for (indexes, expected, got) in &[
+            (&[9, 10][..], 8, 9),
+            (&[7, 8][..], 8, 7),
+            (&[8, 10][..], 9, 10),
+            (&[8, 9, 8][..], 10, 8),
+        ] {
+            match engine.append(1, entries(indexes)) {
+                Err(Error::AppendConflict(1, e, g)) => assert_eq!((e, g), (*expected, *got)),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+        // Nothing is written.
+        assert_eq!(engine.latest_sequence(), sequence);
+        assert_eq!(engine.entries_range(1), Some((5, 7)));
+
+        engine.append(1, entries(&[8, 9])).unwrap();
+        engine.append(2, entries(&[100])).unwrap();
+        let mut batch = LogBatch::new();
+        batch.clean_region(2);
+        batch.add_entries(2, entries(&[1, 2]));
+        engine.consume(&mut batch, false).unwrap();
+        assert_eq!(engine.entries_range(1), Some((5, 9)));
+        assert_eq!(engine.entries_range(2), Some((1, 2)));
+        drop(engine);
+
+        // Of concurrent appends of the same index, only one is written, even if
+        // batches are applied later.
+        cfg.async_apply = true;
+        let engine = FileEngine::new(cfg);
+        for index in 10..60 {
+            let barrier = Arc::new(std::sync::Barrier::new(4));
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let (engine, barrier) = (engine.clone(), barrier.clone());
+                    let entry = entries(&[index]);
+                    thread::spawn(move || {
+                        barrier.wait();
+                        engine.append(1, entry).is_ok()
+                    })
+                })
+                .collect();
+            let written = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .filter(|ok| *ok)
+                .count();
+            assert_eq!(written, 1);
+        }
+        assert_eq!(engine.entries_range(1), Some((5, 59)));
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+use std::{cmp, mem, thread};
+
+use super::FileEngineInner;
+use crate::clock::Clock;
+use crate::log_batch::{BatchCompression, LogBatch};
+use crate::Result;
+
+#[derive(Default)]
+struct BufferedWrites {
+    batch: LogBatch,
+    bytes: usize,
+    // When the oldest buffered write is accepted, by the engine's clock.
+    since: Option<SystemTime>,
+    stopped: bool,
+}
+
+struct WriteBufferState {
+    buffered: Mutex<BufferedWrites>,
+    // Notified when the buffer turns non-empty or is stopped.
+    accepted: Condvar,
+    // Held by flushes, so that buffered writes are written in the order they're
+    // taken from the buffer.
+    flushing: Mutex<()>,
+}
+
+// Writes without sync merged into one batch, which is written with sync before
+// the buffer overflows `write_buffer_size`, by the next write with sync or
+// read, or by a dedicated thread once it has waited `write_buffer_wait`. Writes
+// failing to be written stay buffered, as they have been accepted.
+pub(super) struct WriteBuffer {
+    state: Arc<WriteBufferState>,
+}
+
+impl WriteBuffer {
+    pub(super) fn new() -> WriteBuffer {
+        WriteBuffer {
+            state: Arc::new(WriteBufferState {
+                buffered: Mutex::new(BufferedWrites::default()),
+                accepted: Condvar::new(),
+                flushing: Mutex::new(()),
+            }),
+        }
+    }
+
+    // Like the applier, the thread only refers to the engine while it flushes. It
+    // checks the clock at least every `wait`, so a clock moved by hand is followed
+    // as well.
+    pub(super) fn start(
+        &self,
+        inner: Weak<FileEngineInner>,
+        clock: Arc<dyn Clock>,
+        name: String,
+        wait: Duration,
+    ) {
+        let state = self.state.clone();
+        thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || loop {
+                {
+                    let mut buffered = state.buffered.lock().unwrap();
+                    let deadline = loop {
+                        if buffered.stopped {
+                            return;
+                        }
+                        match buffered.since {
+                            Some(since) => break since + wait,
+                            None => buffered = state.accepted.wait(buffered).unwrap(),
+                        }
+                    };
+                    if let Ok(left) = deadline.duration_since(clock.now()) {
+                        if left > Duration::from_secs(0) {
+                            // Woken earlier if the buffer is flushed and refilled.
+                            let timeout = cmp::min(left, wait);
+                            let _ = state.accepted.wait_timeout(buffered, timeout);
+                            continue;
+                        }
+                    }
+                }
+                match inner.upgrade() {
+                    Some(inner) => inner.flush_in_background(),
+                    None => return,
+                }
+            })
+            .unwrap_or_else(|e| panic!("Spawn thread {} failed, err {:?}", name, e));
+    }
+}
+
+impl Drop for WriteBuffer {
+    fn drop(&mut self) {
+        self.state.buffered.lock().unwrap().stopped = true;
+        self.state.accepted.notify_all();
+    }
+}
+
+impl FileEngineInner {
+    pub(super) fn write(&self, log_batch: LogBatch, sync: bool) -> Result<usize> {
+        let buffer = match &self.write_buffer {
+            Some(buffer) => &buffer.state,
+            None => return self.write_batch(log_batch, sync),
+        };
+        // Batches with sync or their own compression are written on their own,
+        // after buffered ones.
+        let bytes = log_batch.approximate_size();
+        let limit = self.cfg.write_buffer_size.0 as usize;
+        if sync || log_batch.compression != BatchCompression::Auto || bytes >= limit {
+            self.flush_writes()?;
+            return self.write_batch(log_batch, sync);
+        }
+        loop {
+            {
+                let mut buffered = buffer.buffered.lock().unwrap();
+                if buffered.bytes + bytes <= limit {
+                    buffered
+                        .batch
+                        .items
+                        .borrow_mut()
+                        .extend(log_batch.items.into_inner());
+                    buffered.bytes += bytes;
+                    if buffered.since.is_none() {
+                        buffered.since = Some(self.clock.now());
+                        buffer.accepted.notify_all();
+                    }
+                    // Written with the buffer later.
+                    return Ok(bytes);
+                }
+            }
+            // The write isn't accepted if buffered ones can't be written.
+            self.flush_writes()?;
+        }
+    }
+
+    // Write buffered writes as one batch with sync. Those failing to be written
+    // are kept in the buffer, ahead of writes buffered in the meantime.
+    pub(super) fn flush_writes(&self) -> Result<()> {
+        let buffer = match &self.write_buffer {
+            Some(buffer) => &buffer.state,
+            None => return Ok(()),
+        };
+        let _flushing = buffer.flushing.lock().unwrap();
+        let batch = {
+            let mut buffered = buffer.buffered.lock().unwrap();
+            buffered.bytes = 0;
+            buffered.since = None;
+            mem::take(&mut buffered.batch)
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if let Err((e, unwritten)) = self.try_write_batch(batch, true) {
+            let mut buffered = buffer.buffered.lock().unwrap();
+            let mut items = unwritten.items.into_inner();
+            items.extend(buffered.batch.items.take());
+            *buffered.batch.items.borrow_mut() = items;
+            buffered.bytes = buffered.batch.approximate_size();
+            // Retried in the background after waiting again.
+            buffered.since = Some(self.clock.now());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Flush buffered writes where errors can't be returned. Those failing to be
+    // written are left to the next flush.
+    pub(super) fn flush_in_background(&self) {
+        if let Err(e) = self.flush_writes() {
+            error!("[{}] Write buffered writes failed: {}", self.cfg.name, e);
+        }
+    }
+
+    // Write buffered writes and wait until writes returned before are applied, so
+    // that they're visible.
+    pub(super) fn wait_written(&self) {
+        if self.write_buffer.is_some() {
+            self.flush_in_background();
+        }
+        self.wait_applied();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::clock::ManualClock;
+    use crate::fault_fs::{Fault, FaultyDir};
+    use crate::util::{ReadableDuration, ReadableSize};
+
+    #[test]
+    fn test_write_buffer() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_write_buffer")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        cfg.write_buffer_size = ReadableSize::kb(1);
+        cfg.write_buffer_wait = ReadableDuration::hours(1);
+        let mut entry = Entry::new();
+        entry.set_data(vec![b'x'; 400]);
+        {
+            let engine = FileEngine::new(cfg.clone());
+            // Written as one batch before the buffer overflows. Buffered writes
+            // return the bytes they will be written in.
+            for i in 1..=3 {
+                assert_eq!(engine.latest_sequence(), 0);
+                entry.set_index(i);
+                assert!(engine.append(1, vec![entry.clone()]).unwrap() > 400);
+            }
+            assert_eq!(engine.latest_sequence(), 1);
+            assert!(engine.get_entry(1, 3).is_ok());
+            assert_eq!(engine.latest_sequence(), 2);
+
+            // Reads see buffered writes, written with sync.
+            entry.set_index(4);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            assert_eq!(engine.latest_sequence(), 2);
+            assert_eq!(engine.get_entry(1, 4).unwrap(), Some(entry.clone()));
+            assert_eq!(engine.latest_sequence(), 3);
+            assert_eq!(engine.inner.pipe_log.durable_sequence(), 3);
+
+            // Writes with sync are written after buffered ones.
+            entry.set_index(5);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            let mut batch = LogBatch::default();
+            entry.set_index(6);
+            batch.add_entries(1, vec![entry.clone()]);
+            engine.consume(&mut batch, true).unwrap();
+            assert_eq!(engine.latest_sequence(), 5);
+            assert_eq!(engine.inner.pipe_log.durable_sequence(), 5);
+
+            // Buffered writes failing to be written are kept.
+            entry.set_index(7);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            let faulty = FaultyDir::new(dir.path());
+            faulty.inject(
+                "write",
+                Fault {
+                    error_rate: 1.0,
+                    ..Default::default()
+                },
+            );
+            assert!(engine.get_entry(1, 7).unwrap().is_none());
+            drop(faulty);
+            assert_eq!(engine.latest_sequence(), 5);
+
+            // Buffered writes are written when the engine is dropped.
+            entry.set_index(8);
+            engine.append(1, vec![entry.clone()]).unwrap();
+            assert_eq!(engine.latest_sequence(), 5);
+        }
+
+        cfg.write_buffer_wait = ReadableDuration::millis(10);
+        let clock = ManualClock::default();
+        let engine = FileEngine::builder(cfg)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        assert_eq!(engine.latest_sequence(), 6);
+        assert!(engine.get_entry(1, 7).unwrap().is_some());
+        assert!(engine.get_entry(1, 8).unwrap().is_some());
+        // Or once they have waited long enough by the clock.
+        entry.set_index(9);
+        engine.append(1, vec![entry]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(engine.latest_sequence(), 6);
+        clock.advance(Duration::from_millis(10));
+        let start = Instant::now();
+        while engine.latest_sequence() == 6 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
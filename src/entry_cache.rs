@@ -0,0 +1,25 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use raft::eraftpb::Entry;
+
+/// A cache of raft entries provided by the host application, e.g. one shared by
+/// the whole process. If an engine is given one, it's used instead of the cache
+/// built in memtables, while entries are still indexed by memtables. The cache
+/// decides what to keep within its own memory budget.
+pub trait EntryCache: Send + Sync {
+    /// Cache entries of the region, which are contiguous. Cached entries of the
+    /// region from the first one on are overwritten by them, and the ones after them
+    /// are dropped, as raft truncates conflicting entries.
+    fn insert(&self, region_id: u64, entries: &[Entry]);
+
+    fn get(&self, region_id: u64, index: u64) -> Option<Entry>;
+
+    /// Drop entries of the region before `index`.
+    fn evict(&self, region_id: u64, index: u64);
+
+    /// Drop all entries of the region.
+    fn remove_region(&self, region_id: u64);
+
+    /// Bytes of cached entries.
+    fn size(&self) -> u64;
+}
@@ -0,0 +1,167 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Encoding of raft entries in log batches. Entries are stored as encoded
+//! `eraftpb::Entry` messages, and are only encoded and decoded here, so that a
+//! raft upgrade changing the message is dealt with in one place, and entries
+//! written before stay readable.
+//!
+//! The layouts of `Entry` written so far, by field numbers:
+//!
+//! ```text
+//! v1 = { entry_type = 1 | term = 2 | index = 3 | data = 4 | sync_log = 5 | context = 6 }
+//! ```
+//!
+//! Fields added later are ignored by older readers, and fields unknown to the
+//! current message, e.g. written by a newer raft, are kept in it and encoded
+//! again as they are. A field removed or moved by a raft upgrade is translated
+//! by an `Upgrade` from where older layouts put it, which is added to
+//! `UPGRADES` along with the layout above.
+//!
+//! Entries carry no version of their own: layout `n + 1` is the one written
+//! after the `n` upgrades so far, see `LAYOUT_VERSION`. As an entry may have
+//! been written by any earlier layout, every upgrade is applied to every entry
+//! decoded, and must leave an entry already in its target layout as it is.
+//! Encoded entries handed out as they are, e.g. by `get_entry_bytes`, are
+//! passed through `upgrade_encoded` for the same reason.
+
+use protobuf::Message;
+use raft::eraftpb::Entry;
+
+use crate::Result;
+
+/// Translates an entry decoded from an older layout to the current message,
+/// e.g. moving a field it finds in unknown fields to where it is now.
+pub type Upgrade = fn(&mut Entry) -> Result<()>;
+
+/// Applied in order to every entry decoded. Empty as the layout is unchanged
+/// since v1.
+const UPGRADES: &[Upgrade] = &[];
+
+/// The layout of entries encoded by this version, derived from the upgrades
+/// added since v1.
+pub const LAYOUT_VERSION: u32 = UPGRADES.len() as u32 + 1;
+
+pub fn encode_entry(entry: &Entry) -> Result<Vec<u8>> {
+    Ok(entry.write_to_bytes()?)
+}
+
+/// Decode an entry written by any version.
+pub fn decode_entry(buf: &[u8]) -> Result<Entry> {
+    decode_entry_with(buf, UPGRADES)
+}
+
+/// Translate an encoded entry written by any version to the current layout.
+/// The bytes are returned as they are if no upgrade has ever been added.
+pub fn upgrade_encoded(buf: Vec<u8>) -> Result<Vec<u8>> {
+    upgrade_encoded_with(buf, UPGRADES)
+}
+
+fn upgrade_encoded_with(buf: Vec<u8>, upgrades: &[Upgrade]) -> Result<Vec<u8>> {
+    if upgrades.is_empty() {
+        return Ok(buf);
+    }
+    encode_entry(&decode_entry_with(&buf, upgrades)?)
+}
+
+fn decode_entry_with(buf: &[u8], upgrades: &[Upgrade]) -> Result<Entry> {
+    let mut entry = Entry::new();
+    entry.merge_from_bytes(buf)?;
+    for upgrade in upgrades {
+        upgrade(&mut entry)?;
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use protobuf::UnknownFields;
+    use raft::eraftpb::EntryType;
+
+    fn entry(
+        entry_type: EntryType,
+        term: u64,
+        index: u64,
+        data: &[u8],
+        context: &[u8],
+        sync_log: bool,
+    ) -> Entry {
+        let mut e = Entry::new();
+        e.set_entry_type(entry_type);
+        e.set_term(term);
+        e.set_index(index);
+        e.set_data(data.to_vec());
+        e.set_context(context.to_vec());
+        e.set_sync_log(sync_log);
+        e
+    }
+
+    #[test]
+    fn test_decode_v1_fixtures() {
+        let fixtures: Vec<(&[u8], Entry)> = vec![
+            // An empty entry, e.g. of a new leader.
+            (b"", Entry::new()),
+            (
+                b"\x10\x02\x18\x05\x22\x03abc",
+                entry(EntryType::EntryNormal, 2, 5, b"abc", b"", false),
+            ),
+            // With all fields, including the deprecated `sync_log`.
+            (
+                b"\x08\x01\x10\x03\x18\x87\x01\x22\x02cc\x28\x01\x32\x03ctx",
+                entry(EntryType::EntryConfChange, 3, 135, b"cc", b"ctx", true),
+            ),
+            // Fields out of order, as other encoders may write them.
+            (
+                b"\x32\x01c\x22\x01d\x18\x09\x10\x01",
+                entry(EntryType::EntryNormal, 1, 9, b"d", b"c", false),
+            ),
+        ];
+        for (bytes, expected) in fixtures {
+            let e = decode_entry(bytes).unwrap();
+            assert_eq!(e, expected);
+            assert_eq!(decode_entry(&encode_entry(&e).unwrap()).unwrap(), expected);
+        }
+        // Truncated.
+        assert!(decode_entry(b"\x10\x02\x18\x05\x22\x03ab").is_err());
+    }
+
+    #[test]
+    fn test_decode_newer_entry() {
+        // Written by a newer raft with a field 15 unknown here.
+        let bytes = b"\x10\x02\x18\x05\x22\x01x\x78\x2a";
+        let e = decode_entry(bytes).unwrap();
+        assert_eq!(e.get_index(), 5);
+        assert_eq!(e.get_data(), b"x");
+        assert_eq!(encode_entry(&e).unwrap(), bytes.to_vec());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        // Suppose `context` was field 9 in an older layout. Entries of it are
+        // translated.
+        fn move_context(e: &mut Entry) -> Result<()> {
+            let context = e
+                .get_unknown_fields()
+                .get(9)
+                .and_then(|v| v.length_delimited.last().cloned());
+            if let Some(context) = context {
+                e.set_context(context);
+                *e.mut_unknown_fields() = UnknownFields::new();
+            }
+            Ok(())
+        }
+        let e = decode_entry_with(b"\x18\x05\x4a\x03ctx", &[move_context]).unwrap();
+        assert_eq!(e, entry(EntryType::EntryNormal, 0, 5, b"", b"ctx", false));
+        // The current layout is left as is.
+        let e = decode_entry_with(b"\x18\x05\x32\x03ctx", &[move_context]).unwrap();
+        assert_eq!(e, entry(EntryType::EntryNormal, 0, 5, b"", b"ctx", false));
+
+        // Encoded entries handed out are translated too.
+        let bytes = upgrade_encoded_with(b"\x18\x05\x4a\x03ctx".to_vec(), &[move_context]).unwrap();
+        assert_eq!(bytes, b"\x18\x05\x32\x03ctx");
+        let bytes = upgrade_encoded_with(b"\x18\x05\x4a\x03ctx".to_vec(), &[]).unwrap();
+        assert_eq!(bytes, b"\x18\x05\x4a\x03ctx");
+        assert_eq!(LAYOUT_VERSION, 1);
+    }
+}
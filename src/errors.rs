@@ -1,7 +1,10 @@
 use std::error;
+use std::fmt;
 use std::io::Error as IoError;
+use std::path::PathBuf;
 
 use crate::codec::Error as CodecError;
+use crate::dir_lock::LockOwner;
 
 use raft::StorageError;
 
@@ -41,6 +44,49 @@ quick_error! {
         TooShort {
             description("content too short")
         }
+        FileIo(ctx: FileIoContext, err: IoError) {
+            cause(err)
+            description(err.description())
+            display("{}: {}", ctx, err)
+        }
+        Corruption(file_num: u64, offset: u64, reason: String) {
+            description("Raft log file is corrupted")
+            display("Raft log file {} is corrupted at offset {}: {}", file_num, offset, reason)
+        }
+        UnsupportedVersion(file_num: u64, version: String, newer: bool) {
+            description("Raft log file of unsupported format version")
+            display(
+                "Raft log file {} is written by {} raft-engine (format {}, expected {}){}",
+                file_num,
+                if *newer { "a newer" } else { "an older" },
+                version,
+                String::from_utf8_lossy(crate::pipe_log::VERSION),
+                if *newer { "" } else { ", open it writable to upgrade" }
+            )
+        }
+        AppendConflict(raft_group_id: u64, expected: u64, got: u64) {
+            description("Appended entries conflict with existing ones")
+            display(
+                "Raft group {} expects entry {} to be appended, but got {}",
+                raft_group_id,
+                expected,
+                got
+            )
+        }
+        Locked(dir: String, owner: LockOwner, alive: bool) {
+            description("Raft log directory is locked by another process")
+            display(
+                "Raft log directory {} is locked by {}{}",
+                dir,
+                owner,
+                if *alive { "" } else { ", which has exited, force unlock to recover" }
+            )
+        }
+        PartiallyWritten(written: usize, total: usize, err: Box<Error>) {
+            cause(err.as_ref())
+            description("Only some parts of a split batch are written")
+            display("Only {} of {} parts of the split batch are written: {}", written, total, err)
+        }
         RaftNotFound(raft_group_id: u64) {
             description("Raft group not found")
             display("Raft group not found: {}", raft_group_id)
@@ -66,3 +112,42 @@ impl From<Error> for raft::Error {
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Where an IO error on a raft log file happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileIoContext {
+    /// The failed operation, like "read" or "sync".
+    pub op: &'static str,
+    pub file_num: u64,
+    pub path: PathBuf,
+    /// Position of a read or write, `None` for operations on the whole file.
+    pub offset: Option<u64>,
+}
+
+impl fmt::Display for FileIoContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to {} raft log file {} ({})",
+            self.op,
+            self.file_num,
+            self.path.display()
+        )?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns IO errors on raft log files into `Error::FileIo`. The context is only
+/// built on errors.
+pub(crate) trait FileIoResultExt<T> {
+    fn file_context<F: FnOnce() -> FileIoContext>(self, ctx: F) -> Result<T>;
+}
+
+impl<T> FileIoResultExt<T> for ::std::result::Result<T, IoError> {
+    fn file_context<F: FnOnce() -> FileIoContext>(self, ctx: F) -> Result<T> {
+        self.map_err(|e| Error::FileIo(ctx(), e))
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Simulated slow or flaky disks. Faults are injected into operations on log
+//! files of a directory: each operation of a kind, like "write" or "sync", is
+//! delayed, and fails at random with an IO error. The pipe log calls `on_io`
+//! before each operation, so that it fails as if the file system failed it.
+//!
+//! Only built for unit tests, or with the `fault-injection` feature for tests
+//! outside the crate.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::util::HashMap;
+
+/// Faults of operations of a kind.
+#[derive(Clone, Debug)]
+pub struct Fault {
+    /// Added to each operation.
+    pub delay: Duration,
+    /// Probability of failing an operation, from 0 to 1.
+    pub error_rate: f64,
+    /// Error number of failed operations.
+    pub errno: i32,
+}
+
+impl Default for Fault {
+    fn default() -> Fault {
+        Fault {
+            delay: Duration::from_secs(0),
+            error_rate: 0.0,
+            errno: libc::EIO,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DirFaults {
+    // Kind of operations -> faults.
+    faults: HashMap<&'static str, Fault>,
+    // Kind of operations -> count of them, failed or not.
+    ops: HashMap<&'static str, usize>,
+    errors: usize,
+}
+
+lazy_static! {
+    static ref FAULTS: Mutex<HashMap<PathBuf, DirFaults>> = Mutex::new(HashMap::default());
+}
+
+/// Faults injected into a directory until dropped.
+pub struct FaultyDir {
+    dir: PathBuf,
+}
+
+impl FaultyDir {
+    pub fn new(dir: &Path) -> FaultyDir {
+        FAULTS
+            .lock()
+            .unwrap()
+            .insert(dir.to_owned(), DirFaults::default());
+        FaultyDir {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Inject `fault` into operations of kind `op`, replacing the one before.
+    pub fn inject(&self, op: &'static str, fault: Fault) {
+        let mut faults = FAULTS.lock().unwrap();
+        faults.get_mut(&self.dir).unwrap().faults.insert(op, fault);
+    }
+
+    pub fn clear(&self, op: &'static str) {
+        let mut faults = FAULTS.lock().unwrap();
+        faults.get_mut(&self.dir).unwrap().faults.remove(op);
+    }
+
+    /// Operations failed so far.
+    pub fn errors(&self) -> usize {
+        FAULTS.lock().unwrap()[&self.dir].errors
+    }
+
+    /// Operations of kind `op` so far, with faults injected or not.
+    pub fn ops(&self, op: &str) -> usize {
+        FAULTS.lock().unwrap()[&self.dir]
+            .ops
+            .get(op)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for FaultyDir {
+    fn drop(&mut self) {
+        FAULTS.lock().unwrap().remove(&self.dir);
+    }
+}
+
+pub(crate) fn on_io(dir: &Path, op: &'static str) -> io::Result<()> {
+    let (delay, errno) = {
+        let mut faults = FAULTS.lock().unwrap();
+        let dir_faults = match faults.get_mut(dir) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        *dir_faults.ops.entry(op).or_insert(0) += 1;
+        let fault = match dir_faults.faults.get(op) {
+            Some(fault) => fault.clone(),
+            None => return Ok(()),
+        };
+        let failed = fault.error_rate > 0.0 && rand::thread_rng().gen_bool(fault.error_rate);
+        if failed {
+            dir_faults.errors += 1;
+        }
+        (fault.delay, Some(fault.errno).filter(|_| failed))
+    };
+    thread::sleep(delay);
+    match errno {
+        Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use raft::eraftpb::Entry;
+
+    use crate::engine::FileEngine;
+    use crate::{Config, Error, LogBatch, RaftEngine};
+
+    fn entry(index: u64) -> Entry {
+        let mut entry = Entry::new();
+        entry.set_index(index);
+        entry.set_data(vec![b'x'; 64]);
+        entry
+    }
+
+    #[test]
+    fn test_slow_disk() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_slow_disk")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        let faulty = FaultyDir::new(dir.path());
+        let delay = Duration::from_millis(50);
+        faulty.inject(
+            "sync",
+            Fault {
+                delay,
+                ..Default::default()
+            },
+        );
+
+        // Writes with sync stall on the disk, but others don't sync at all. Only
+        // the lower bound of a stall is checked, as a loaded machine only makes it
+        // longer.
+        engine.append(1, vec![entry(1)]).unwrap();
+        assert_eq!(faulty.ops("sync"), 0);
+        let start = Instant::now();
+        engine.sync().unwrap();
+        assert!(start.elapsed() >= delay);
+        assert_eq!(faulty.ops("sync"), 1);
+        assert_eq!(faulty.errors(), 0);
+    }
+
+    #[test]
+    fn test_flaky_disk() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_flaky_disk")
+            .tempdir()
+            .unwrap();
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        let faulty = FaultyDir::new(dir.path());
+
+        // Failed writes are reported, and leave the engine writable once the disk
+        // is back.
+        faulty.inject(
+            "write",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        match engine.append(1, vec![entry(1)]) {
+            Err(Error::FileIo(ctx, e)) => {
+                assert_eq!(ctx.op, "write");
+                assert_eq!(e.raw_os_error(), Some(libc::EIO));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(engine.entries_range(1), None);
+
+        // Failed writes retried by the caller are all persisted.
+        faulty.inject(
+            "write",
+            Fault {
+                error_rate: 0.3,
+                ..Default::default()
+            },
+        );
+        for index in 1..=50 {
+            loop {
+                let mut batch = LogBatch::new();
+                batch.add_entries(1, vec![entry(index)]);
+                if engine.consume(&mut batch, true).is_ok() {
+                    break;
+                }
+            }
+        }
+        assert!(faulty.errors() > 1);
+        faulty.clear("write");
+
+        // But a failed sync can't be retried, the data may be lost already. The
+        // engine refuses writes until it's reopened.
+        faulty.inject(
+            "sync",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        let mut batch = LogBatch::new();
+        batch.add_entries(1, vec![entry(51)]);
+        assert!(engine.consume(&mut batch, true).is_err());
+        faulty.clear("sync");
+        assert!(engine.append(1, vec![entry(51)]).is_err());
+        assert!(engine.sync().is_err());
+        drop(engine);
+
+        // Reads fail as well, but not the engine.
+        let engine = FileEngine::new(cfg);
+        // The write failed to sync may be recovered, here from the page cache.
+        assert_eq!(engine.entries_range(1), Some((1, 51)));
+        engine.append(1, vec![entry(52)]).unwrap();
+        engine.evict_region_cache(1);
+        faulty.inject(
+            "read",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(engine.get_entry(1, 1).is_err());
+        faulty.clear("read");
+        assert_eq!(engine.get_entry(1, 1).unwrap(), Some(entry(1)));
+    }
+}
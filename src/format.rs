@@ -0,0 +1,291 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Layout of log batches in log files, for tools that build or inspect them
+//! outside of the engine. A log file starts with a header, `FILE_MAGIC_HEADER`
+//! followed by `VERSION` and the little-endian crc32 of both (see `pipe_log`),
+//! and then batches, holes and barriers:
+//!
+//! ```text
+//! batch   = { 8 bytes header | 8 bytes sequence | 8 bytes timestamp | content | 4 bytes checksum }
+//! hole    = { 8 bytes header | any bytes }
+//! barrier = { 8 bytes header }
+//! ```
+//!
+//! Headers are big-endian u64s, `len << 8 | type`. The length of a batch counts
+//! the bytes after its header, and its type is a `CompressionType`. The content
+//! is compressed as the type says, and the checksum is the little-endian crc32
+//! of the sequence, the timestamp and the content. The sequence and the
+//! timestamp, a `Timestamp`, are big-endian. The length of a hole doesn't count its
+//! header, and its type is `HOLE_TYPE`. Holes are punched over dead batches and
+//! skipped by readers. They're tracked only by their headers in the file, not in
+//! a separate manifest, see `PipeLog::punch_hole`. A `TAIL_BARRIER` ends the batches of the active file.
+//!
+//! The uncompressed content is a var-int count of items followed by the items,
+//! e.g. of several regions. A batch is atomic: readers apply it only once the
+//! checksum matches and exactly as many items as counted are decoded with no
+//! bytes left, so a batch torn by a crash at the tail is dropped whole, and
+//! never applied to some of its regions but not the others. No other marker of
+//! a complete batch is written, as the checksum is one already: it's written
+//! last, where the header says the batch ends, and covers the count and every
+//! item, so a batch cut anywhere, or whose header is torn to another length,
+//! either runs past the end of the file or fails the checksum. A marker would be
+//! covered by the same checksum and catch nothing more, and the count only
+//! guards against a corruption matching the checksum by chance. Writers must not
+//! split a batch into several ones, as callers such as raftstore rely on this
+//! to persist writes of many regions together, unless told to by
+//! `max_batch_regions` of `Config`.
+
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crc32fast::Hasher;
+
+use crate::{Error, Result};
+
+/// Length of the header, the sequence and the timestamp of a batch.
+pub const HEADER_LEN: usize = 24;
+pub const SEQUENCE_LEN: usize = 8;
+pub const TIMESTAMP_LEN: usize = 8;
+pub const CHECKSUM_LEN: usize = 4;
+pub const BATCH_MIN_SIZE: usize = HEADER_LEN + CHECKSUM_LEN;
+
+/// The low byte of the header of a hole, which takes the place of compression type.
+pub const HOLE_TYPE: u8 = 0xff;
+pub const HOLE_HEADER_LEN: usize = 8;
+
+/// The low byte of the header of a tail barrier.
+pub const BARRIER_TYPE: u8 = 0xfe;
+/// Written right after the tail of the active file when it's truncated, and
+/// overwritten by the next batch. Readers stop at it, so that bytes left beyond
+/// the tail, e.g. of a torn write, are never parsed as batches even if they look
+/// valid.
+pub const TAIL_BARRIER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, BARRIER_TYPE];
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompressionType {
+    None = 0,
+    Lz4 = 1,
+    /// Compressed with a `Dictionary`, for small batches. The content starts
+    /// with the little-endian u32 id of the dictionary, followed by a lz4 block.
+    Lz4Dict = 2,
+}
+
+impl CompressionType {
+    pub fn from_byte(t: u8) -> Result<CompressionType> {
+        match t {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Lz4Dict),
+            _ => Err(box_err!("Invalid compression type: {}", t)),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// Bits of the logical counter of a timestamp.
+const LOGICAL_BITS: u32 = 16;
+
+/// A hybrid logical clock reading of when a batch is written: milliseconds since
+/// the Unix epoch in the high 48 bits, and a counter in the low 16 bits ordering
+/// batches written in the same millisecond, so that timestamps of batches still
+/// increase with their sequences if the clock goes back. 0 is unknown, e.g. for
+/// batches migrated from older formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    /// The timestamp following `self` at wall time `now`.
+    pub fn next(self, now: SystemTime) -> Timestamp {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        cmp::max(Timestamp(millis << LOGICAL_BITS), Timestamp(self.0 + 1))
+    }
+
+    /// The wall time part, to the millisecond.
+    pub fn physical(self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.0 >> LOGICAL_BITS)
+    }
+}
+
+/// The checksum of batches.
+#[inline]
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Header of a batch of `len` bytes after the header.
+pub fn encode_batch_header(len: u64, compression_type: CompressionType) -> [u8; 8] {
+    (len << 8 | u64::from(compression_type.to_byte())).to_be_bytes()
+}
+
+/// Decode the header at the start of `buf` into the length of the batch after
+/// the header and its compression type.
+pub fn decode_batch_header(buf: &[u8]) -> Result<(u64, CompressionType)> {
+    if buf.len() < 8 {
+        return Err(Error::TooShort);
+    }
+    let header = BigEndian::read_u64(buf);
+    Ok((header >> 8, CompressionType::from_byte(header as u8)?))
+}
+
+/// Header of a hole of `len` bytes including the header. Readers of batches skip
+/// the hole.
+pub fn encode_hole_header(len: u64) -> [u8; HOLE_HEADER_LEN] {
+    assert!(len >= HOLE_HEADER_LEN as u64);
+    let header = (len - HOLE_HEADER_LEN as u64) << 8 | u64::from(HOLE_TYPE);
+    header.to_be_bytes()
+}
+
+/// Return the length of the hole including its header if `buf` starts with one.
+pub fn decode_hole_header(buf: &[u8]) -> Option<u64> {
+    if buf.len() < HOLE_HEADER_LEN || buf[HOLE_HEADER_LEN - 1] != HOLE_TYPE {
+        return None;
+    }
+    Some((BigEndian::read_u64(buf) >> 8) + HOLE_HEADER_LEN as u64)
+}
+
+pub fn is_tail_barrier(buf: &[u8]) -> bool {
+    buf.starts_with(&TAIL_BARRIER)
+}
+
+/// Whether `buf` is all zeros, as space allocated to a file but never written is
+/// read. It can't be mistaken for data, as no batch, hole or barrier starts with
+/// a zero header.
+pub fn is_unwritten(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+/// Fill the sequence of a whole encoded batch and update its checksum. The
+/// timestamp is left as is.
+pub fn set_sequence(content: &mut [u8], sequence: u64) {
+    let checksum = content_checksum(content);
+    let timestamp = Timestamp(BigEndian::read_u64(&content[8 + SEQUENCE_LEN..]));
+    set_sequence_with(content, sequence, timestamp, &checksum);
+}
+
+/// The partial checksum of the content of a whole encoded batch, i.e. without
+/// the sequence and the timestamp. It's computed before they're assigned, so
+/// that only they are hashed by `set_sequence_with` while writes are serialized.
+pub fn content_checksum(content: &[u8]) -> Hasher {
+    let mut hasher = Hasher::new();
+    hasher.update(&content[HEADER_LEN..content.len() - CHECKSUM_LEN]);
+    hasher
+}
+
+/// Fill the sequence and the timestamp of a whole encoded batch and update its
+/// checksum, given the `content_checksum` of the batch.
+pub fn set_sequence_with(
+    content: &mut [u8],
+    sequence: u64,
+    timestamp: Timestamp,
+    checksum: &Hasher,
+) {
+    let len = content.len();
+    BigEndian::write_u64(&mut content[8..8 + SEQUENCE_LEN], sequence);
+    BigEndian::write_u64(&mut content[8 + SEQUENCE_LEN..HEADER_LEN], timestamp.0);
+    let mut hasher = Hasher::new();
+    hasher.update(&content[8..HEADER_LEN]);
+    hasher.combine(checksum);
+    LittleEndian::write_u32(&mut content[len - CHECKSUM_LEN..], hasher.finalize());
+}
+
+/// The timestamp in the header of a whole encoded batch.
+pub fn batch_timestamp(content: &[u8]) -> Result<Timestamp> {
+    if content.len() < HEADER_LEN {
+        return Err(Error::TooShort);
+    }
+    Ok(Timestamp(BigEndian::read_u64(&content[8 + SEQUENCE_LEN..])))
+}
+
+/// Verify the checksum of a batch without its header, i.e. `buf` is the
+/// sequence, the content and the checksum.
+pub fn test_batch_checksum(buf: &[u8]) -> Result<()> {
+    if buf.len() <= CHECKSUM_LEN {
+        return Err(Error::TooShort);
+    }
+
+    let batch_len = buf.len();
+    let expected = LittleEndian::read_u32(&buf[batch_len - CHECKSUM_LEN..]);
+    let got = crc32(&buf[..batch_len - CHECKSUM_LEN]);
+    if got != expected {
+        return Err(Error::IncorrectChecksum(expected, got));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_000_000);
+        let ts = Timestamp::default().next(now);
+        assert_eq!(ts.physical(), now);
+        // Batches in the same millisecond, or after the clock goes back.
+        let next = ts.next(now);
+        assert!(next > ts);
+        assert_eq!(next.physical(), now);
+        let back = next.next(now - Duration::from_secs(1));
+        assert!(back > next);
+        assert_eq!(back.physical(), now);
+        let later = back.next(now + Duration::from_millis(1));
+        assert_eq!(later.physical(), now + Duration::from_millis(1));
+        assert_eq!(later.0 & 0xffff, 0);
+    }
+
+    #[test]
+    fn test_batch_layout() {
+        let content = b"content";
+        let mut batch = encode_batch_header(
+            (SEQUENCE_LEN + TIMESTAMP_LEN + content.len() + CHECKSUM_LEN) as u64,
+            CompressionType::None,
+        )
+        .to_vec();
+        batch.extend_from_slice(&[0; SEQUENCE_LEN + TIMESTAMP_LEN]);
+        batch.extend_from_slice(content);
+        batch.extend_from_slice(&[0; CHECKSUM_LEN]);
+        assert!(test_batch_checksum(&batch[8..]).is_err());
+        set_sequence(&mut batch, 7);
+        test_batch_checksum(&batch[8..]).unwrap();
+        assert_eq!(BigEndian::read_u64(&batch[8..]), 7);
+        assert_eq!(batch_timestamp(&batch).unwrap(), Timestamp(0));
+        let checksum = content_checksum(&batch);
+        let mut other = batch.clone();
+        set_sequence_with(&mut other, 8, Timestamp(9), &checksum);
+        test_batch_checksum(&other[8..]).unwrap();
+        assert_eq!(batch_timestamp(&other).unwrap(), Timestamp(9));
+        // The timestamp is kept.
+        set_sequence(&mut other, 7);
+        test_batch_checksum(&other[8..]).unwrap();
+        assert_eq!(batch_timestamp(&other).unwrap(), Timestamp(9));
+        assert!(batch_timestamp(&batch[..8]).is_err());
+
+        let (len, compression_type) = decode_batch_header(&batch).unwrap();
+        assert_eq!(len as usize, batch.len() - 8);
+        assert_eq!(compression_type, CompressionType::None);
+        assert_eq!(decode_hole_header(&batch), None);
+        assert!(decode_batch_header(&batch[..4]).is_err());
+
+        let hole = encode_hole_header(100);
+        assert_eq!(decode_hole_header(&hole), Some(100));
+        assert!(decode_batch_header(&hole).is_err());
+
+        assert!(is_tail_barrier(&TAIL_BARRIER));
+        assert!(!is_tail_barrier(&batch));
+        assert!(!is_tail_barrier(&hole));
+        assert!(decode_batch_header(&TAIL_BARRIER).is_err());
+
+        assert!(is_unwritten(&[0; 100]));
+        assert!(!is_unwritten(&TAIL_BARRIER));
+        assert!(decode_batch_header(&[0; 8]).unwrap().0 < (SEQUENCE_LEN + CHECKSUM_LEN) as u64);
+    }
+}
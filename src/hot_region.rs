@@ -0,0 +1,164 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::util::HashMap;
+
+/// Writes of a region tracked by `HotRegions`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegionWrites {
+    pub region_id: u64,
+    pub bytes: u64,
+    pub ops: u64,
+}
+
+/// Tracks the regions writing the most bytes with at most `capacity` counters
+/// (the Space-Saving sketch). When it's full, a new region takes over the counter
+/// with the fewest bytes and inherits its counts, so counts of a region can be
+/// overestimated but a region hotter than any untracked one is never missed.
+pub struct HotRegions {
+    capacity: usize,
+    counters: HashMap<u64, RegionWrites>,
+}
+
+impl HotRegions {
+    pub fn new(capacity: usize) -> HotRegions {
+        assert!(capacity > 0);
+        HotRegions {
+            capacity,
+            counters: HashMap::default(),
+        }
+    }
+
+    pub fn record(&mut self, region_id: u64, bytes: u64) {
+        if !self.counters.contains_key(&region_id) && self.counters.len() >= self.capacity {
+            let coldest = *self.counters.values().min_by_key(|w| w.bytes).unwrap();
+            self.counters.remove(&coldest.region_id);
+            self.counters.insert(
+                region_id,
+                RegionWrites {
+                    region_id,
+                    ..coldest
+                },
+            );
+        }
+        let writes = self.counters.entry(region_id).or_insert(RegionWrites {
+            region_id,
+            bytes: 0,
+            ops: 0,
+        });
+        writes.bytes += bytes;
+        writes.ops += 1;
+    }
+
+    /// The `n` regions writing the most bytes, the hottest first.
+    pub fn top(&self, n: usize) -> Vec<RegionWrites> {
+        let mut writes: Vec<_> = self.counters.values().copied().collect();
+        writes.sort_by(|a, b| (b.bytes, a.region_id).cmp(&(a.bytes, b.region_id)));
+        writes.truncate(n);
+        writes
+    }
+
+    /// Halve all counts so that recent writes weigh more, and forget regions
+    /// without writes.
+    pub fn decay(&mut self) {
+        self.counters.retain(|_, w| {
+            w.bytes /= 2;
+            w.ops /= 2;
+            w.ops > 0
+        });
+    }
+}
+
+/// Approximate order of regions by their last access. An access stamps the
+/// region with a tick, and regions are sorted by their stamps only when asked, so
+/// recording one is as cheap as updating a hash map. Regions are split into
+/// shards by id, so that accesses to regions of different shards don't contend.
+pub struct RecentRegions {
+    tick: AtomicU64,
+    shards: Vec<Mutex<HashMap<u64, u64>>>,
+}
+
+impl RecentRegions {
+    pub fn new(shards: usize) -> RecentRegions {
+        assert!(shards > 0);
+        RecentRegions {
+            tick: AtomicU64::new(0),
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, region_id: u64) -> &Mutex<HashMap<u64, u64>> {
+        &self.shards[region_id as usize % self.shards.len()]
+    }
+
+    pub fn touch(&self, region_id: u64) {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+        self.shard(region_id)
+            .lock()
+            .unwrap()
+            .insert(region_id, tick);
+    }
+
+    pub fn remove(&self, region_id: u64) {
+        self.shard(region_id).lock().unwrap().remove(&region_id);
+    }
+
+    /// The `n` regions accessed least recently, the coldest first.
+    pub fn coldest(&self, n: usize) -> Vec<u64> {
+        let mut regions = vec![];
+        for shard in &self.shards {
+            regions.extend(shard.lock().unwrap().iter().map(|(id, t)| (*t, *id)));
+        }
+        regions.sort_unstable();
+        regions.into_iter().take(n).map(|(_, id)| id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_regions() {
+        let mut hot = HotRegions::new(3);
+        for _ in 0..10 {
+            hot.record(1, 100);
+        }
+        for _ in 0..4 {
+            hot.record(2, 100);
+        }
+        hot.record(3, 50);
+        // Region 4 takes over the counter of region 3.
+        hot.record(4, 10);
+        let top = hot.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].region_id, top[0].bytes, top[0].ops), (1, 1000, 10));
+        assert_eq!((top[1].region_id, top[1].bytes, top[1].ops), (2, 400, 4));
+        let all = hot.top(10);
+        assert_eq!(all.len(), 3);
+        assert_eq!((all[2].region_id, all[2].bytes, all[2].ops), (4, 60, 2));
+
+        hot.decay();
+        assert_eq!(hot.top(1)[0].bytes, 500);
+        hot.decay();
+        // Region 4 has no writes left.
+        let ids: Vec<_> = hot.top(10).iter().map(|w| w.region_id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_recent_regions() {
+        let recent = RecentRegions::new(2);
+        for region_id in 1..=4 {
+            recent.touch(region_id);
+        }
+        recent.touch(2);
+        recent.touch(1);
+        assert_eq!(recent.coldest(2), vec![3, 4]);
+        recent.remove(3);
+        assert_eq!(recent.coldest(10), vec![4, 2, 1]);
+        assert!(recent.coldest(0).is_empty());
+    }
+}
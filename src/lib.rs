@@ -23,19 +23,43 @@ macro_rules! box_err {
     });
 }
 
+mod apply_queue;
+mod arena;
+pub mod check;
+pub mod clock;
 pub mod codec;
+pub mod cold_storage;
+pub mod compare;
 pub mod config;
+#[cfg(test)]
+mod crash_test;
+pub mod dictionary;
+pub mod dir_lock;
 pub mod engine;
+pub mod entry_cache;
+pub mod entry_codec;
 mod errors;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault_fs;
+pub mod format;
+pub mod hot_region;
 pub mod log_batch;
+pub mod memory;
 pub mod memtable;
 pub mod metrics;
+pub mod migrate;
 pub mod pipe_log;
+mod prefetch;
+pub mod recovery_observer;
+pub mod replay;
+#[cfg(feature = "rocksdb-import")]
+mod rocksdb_import;
 pub mod util;
+pub mod worker;
 
 pub use self::config::Config;
-pub use self::engine::FileEngine;
-pub use self::errors::{Error, Result};
+pub use self::engine::{EngineBuilder, FileEngine};
+pub use self::errors::{Error, FileIoContext, Result};
 pub use self::log_batch::LogBatch;
 
 use kvproto::raft_serverpb::RaftLocalState;
@@ -53,7 +77,9 @@ pub trait RaftEngine: Clone + Sync + Send + 'static {
 
     fn get_entry(&self, raft_group_id: u64, index: u64) -> Result<Option<Entry>>;
 
-    /// Return count of fetched entries.
+    /// Fetch entries in [begin, end) and return count of fetched entries. With
+    /// `max_size`, fetching stops before the total encoded size of entries exceeds
+    /// it, but at least one entry is fetched.
     fn fetch_entries_to(
         &self,
         raft_group_id: u64,
@@ -97,6 +123,15 @@ pub trait RaftEngine: Clone + Sync + Send + 'static {
 
     fn put_raft_state(&self, raft_group_id: u64, state: &RaftLocalState) -> Result<()>;
 
+    /// Put raft states of many raft groups, e.g. when a store bootstraps or a region
+    /// is split into many. An engine can write them at once.
+    fn put_raft_states(&self, states: &[(u64, RaftLocalState)]) -> Result<()> {
+        for (raft_group_id, state) in states {
+            self.put_raft_state(*raft_group_id, state)?;
+        }
+        Ok(())
+    }
+
     /// Like `cut_logs` but the range could be very large. Return the deleted count.
     /// Generally, `from` can be passed in `0`.
     fn gc(&self, raft_group_id: u64, from: u64, to: u64) -> Result<usize>;
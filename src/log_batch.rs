@@ -1,88 +1,251 @@
 use std::borrow::{Borrow, Cow};
 use std::cell::RefCell;
 use std::io::BufRead;
-use std::{mem, u64};
+use std::mem;
+use std::u64;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use crc32fast::Hasher;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use protobuf::Message as PbMsg;
 use raft::eraftpb::Entry;
 
 use crate::codec::{self, NumberEncoder};
-use crate::memtable::EntryIndex;
-use crate::util::RAFT_LOG_STATE_KEY;
+use crate::dictionary::{Dictionaries, Dictionary};
+use crate::entry_codec;
+use crate::format::{self, crc32, Timestamp};
+// Layout of batches, kept here for paths used before `format` was split out.
+pub use crate::format::{
+    encode_hole_header, set_sequence, test_batch_checksum, CompressionType, BATCH_MIN_SIZE,
+    CHECKSUM_LEN, HEADER_LEN, HOLE_HEADER_LEN, SEQUENCE_LEN,
+};
+use crate::memtable::{EntryIndex, EntryIndexes};
+use crate::util::{to_usize, RAFT_LOG_STATE_KEY};
 use crate::{Error, RaftLocalState, RaftLogBatch, Result};
 
-pub const BATCH_MIN_SIZE: usize = HEADER_LEN + CHECKSUM_LEN;
-pub const HEADER_LEN: usize = 8;
-pub const CHECKSUM_LEN: usize = 4;
-
 const TYPE_ENTRIES: u8 = 0x01;
 const TYPE_COMMAND: u8 = 0x02;
 const TYPE_KV: u8 = 0x3;
 
 const CMD_CLEAN: u8 = 0x01;
+const CMD_FENCE: u8 = 0x02;
 
 const COMPRESSION_SIZE: usize = 4096;
-
-#[inline]
-fn crc32(data: &[u8]) -> u32 {
-    let mut hasher = Hasher::new();
-    hasher.update(data);
-    hasher.finalize()
-}
+// Batches compressed with a dictionary start with the id of it.
+const DICT_ID_LEN: usize = 4;
 
 mod lz4 {
-    use std::{i32, ptr};
-
-    // TODO: use in place compression instead.
-    #[inline]
-    pub fn encode_block(src: &[u8]) -> Vec<u8> {
-        unsafe {
-            let bound = lz4_sys::LZ4_compressBound(src.len() as i32);
-            if bound > 0 && src.len() <= i32::MAX as usize {
-                let mut output = Vec::<u8>::with_capacity(bound as usize + 4);
-                let le_len = src.len().to_le_bytes();
-                ptr::copy_nonoverlapping(le_len.as_ptr(), output.as_mut_ptr(), 4);
-                let size = lz4_sys::LZ4_compress_default(
-                    src.as_ptr() as _,
-                    output.as_mut_ptr().add(4) as _,
-                    src.len() as i32,
-                    bound,
+    // Framing shared by all backends: { 4 bytes little-endian raw length | lz4 block }.
+
+    #[cfg(not(any(feature = "lz4-c", feature = "lz4-pure")))]
+    compile_error!("one of the `lz4-c` or `lz4-pure` features must be enabled");
+
+    use crate::Result;
+
+    // A byte of lz4 block expands to at most 255 bytes, so a larger raw length is
+    // corrupted, and must not be allocated.
+    const MAX_RATIO: usize = 255;
+
+    fn check_raw_len(src: &[u8], len: usize) -> Result<()> {
+        if len / MAX_RATIO > src.len() {
+            return Err(box_err!(
+                "raw length {} is too large for {} compressed bytes",
+                len,
+                src.len()
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "lz4-c", not(feature = "lz4-pure")))]
+    pub use self::c::{decode_block, decode_block_with_dict, encode_block, encode_block_with_dict};
+    #[cfg(feature = "lz4-pure")]
+    pub use self::pure::{
+        decode_block, decode_block_with_dict, encode_block, encode_block_with_dict,
+    };
+
+    #[cfg(feature = "lz4-c")]
+    #[cfg_attr(feature = "lz4-pure", allow(dead_code))]
+    mod c {
+        use std::os::raw::{c_char, c_int};
+        use std::{i32, ptr};
+
+        use crate::Result;
+
+        // TODO: use in place compression instead.
+        #[inline]
+        pub fn encode_block(src: &[u8]) -> Vec<u8> {
+            encode_block_by(src, |src, dst, src_size, bound| unsafe {
+                lz4_sys::LZ4_compress_default(src, dst, src_size, bound)
+            })
+        }
+
+        pub fn encode_block_with_dict(src: &[u8], dict: &[u8]) -> Vec<u8> {
+            if dict.is_empty() {
+                return encode_block(src);
+            }
+            unsafe {
+                let stream = lz4_sys::LZ4_createStream();
+                assert!(!stream.is_null());
+                // lz4-sys doesn't bind `LZ4_loadDict`, prime the stream by
+                // compressing the dictionary as the previous block instead.
+                let bound = lz4_sys::LZ4_compressBound(dict.len() as i32);
+                assert!(bound > 0, "dictionary is too large: {}", dict.len());
+                let mut scratch = Vec::<u8>::with_capacity(bound as usize);
+                let size = lz4_sys::LZ4_compress_continue(
+                    stream,
+                    dict.as_ptr() as _,
+                    scratch.as_mut_ptr() as _,
+                    dict.len() as i32,
                 );
-                if size > 0 {
-                    output.set_len(size as usize + 4);
-                    return output;
+                if size <= 0 {
+                    lz4_sys::LZ4_freeStream(stream);
+                    panic!("load dictionary fail: {}", size);
                 }
-                panic!("compression fail: {}", size);
+                let output = encode_block_by(src, |src, dst, src_size, _| {
+                    lz4_sys::LZ4_compress_continue(stream, src as _, dst as _, src_size)
+                });
+                lz4_sys::LZ4_freeStream(stream);
+                output
             }
-            panic!("input size is too large: {}", src.len());
         }
-    }
 
-    #[inline]
-    pub fn decode_block(src: &[u8]) -> Vec<u8> {
-        unsafe {
-            if src.len() > 4 {
+        fn encode_block_by<F>(src: &[u8], compress: F) -> Vec<u8>
+        where
+            F: FnOnce(*const c_char, *mut c_char, c_int, c_int) -> c_int,
+        {
+            unsafe {
+                let bound = lz4_sys::LZ4_compressBound(src.len() as i32);
+                if bound > 0 && src.len() <= i32::MAX as usize {
+                    let mut output = Vec::<u8>::with_capacity(bound as usize + 4);
+                    let le_len = src.len().to_le_bytes();
+                    ptr::copy_nonoverlapping(le_len.as_ptr(), output.as_mut_ptr(), 4);
+                    let size = compress(
+                        src.as_ptr() as _,
+                        output.as_mut_ptr().add(4) as _,
+                        src.len() as i32,
+                        bound,
+                    );
+                    if size > 0 {
+                        output.set_len(size as usize + 4);
+                        return output;
+                    }
+                    panic!("compression fail: {}", size);
+                }
+                panic!("input size is too large: {}", src.len());
+            }
+        }
+
+        #[inline]
+        pub fn decode_block(src: &[u8]) -> Result<Vec<u8>> {
+            decode_block_by(src, |src, dst, src_size, len| unsafe {
+                lz4_sys::LZ4_decompress_safe(src, dst, src_size, len)
+            })
+        }
+
+        pub fn decode_block_with_dict(src: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+            if dict.is_empty() {
+                return decode_block(src);
+            }
+            unsafe {
+                let stream = lz4_sys::LZ4_createStreamDecode();
+                assert!(!stream.is_null());
+                if lz4_sys::LZ4_setStreamDecode(stream, dict.as_ptr() as _, dict.len() as i32) != 1
+                {
+                    lz4_sys::LZ4_freeStreamDecode(stream);
+                    return Err(box_err!("load dictionary failed: {}", dict.len()));
+                }
+                let output = decode_block_by(src, |src, dst, src_size, len| {
+                    lz4_sys::LZ4_decompress_safe_continue(stream, src as _, dst as _, src_size, len)
+                });
+                lz4_sys::LZ4_freeStreamDecode(stream);
+                output
+            }
+        }
+
+        fn decode_block_by<F>(src: &[u8], decompress: F) -> Result<Vec<u8>>
+        where
+            F: FnOnce(*const c_char, *mut c_char, c_int, c_int) -> c_int,
+        {
+            if src.len() <= 4 || src.len() > i32::MAX as usize {
+                return Err(box_err!("invalid lz4 block size: {}", src.len()));
+            }
+            unsafe {
                 let len = u32::from_le(ptr::read_unaligned(src.as_ptr() as *const u32));
+                super::check_raw_len(src, len as usize)?;
                 let mut dst = Vec::with_capacity(len as usize);
-                let l = lz4_sys::LZ4_decompress_safe(
+                let l = decompress(
                     src.as_ptr().add(4) as _,
                     dst.as_mut_ptr() as _,
                     src.len() as i32 - 4,
-                    dst.capacity() as i32,
+                    len as i32,
                 );
-                if l == len as i32 {
+                if l >= 0 && l as u32 == len {
                     dst.set_len(l as usize);
-                    return dst;
+                    return Ok(dst);
                 }
                 if l < 0 {
-                    panic!("decompress failed: {}", l);
+                    Err(box_err!("decompress failed: {}", l))
                 } else {
-                    panic!("length of decompress result not match {} != {}", len, l);
+                    Err(box_err!(
+                        "length of decompress result not match {} != {}",
+                        len,
+                        l
+                    ))
                 }
             }
-            panic!("data is too short: {} <= 4", src.len());
+        }
+    }
+
+    #[cfg(feature = "lz4-pure")]
+    mod pure {
+        use std::convert::TryInto;
+
+        use crate::Result;
+
+        #[inline]
+        pub fn encode_block(src: &[u8]) -> Vec<u8> {
+            encode_block_with_dict(src, &[])
+        }
+
+        pub fn encode_block_with_dict(src: &[u8], dict: &[u8]) -> Vec<u8> {
+            if src.len() > u32::MAX as usize {
+                panic!("input size is too large: {}", src.len());
+            }
+            let compressed = if dict.is_empty() {
+                lz4_flex::block::compress(src)
+            } else {
+                lz4_flex::block::compress_with_dict(src, dict)
+            };
+            let mut output = Vec::with_capacity(compressed.len() + 4);
+            output.extend_from_slice(&(src.len() as u32).to_le_bytes());
+            output.extend_from_slice(&compressed);
+            output
+        }
+
+        #[inline]
+        pub fn decode_block(src: &[u8]) -> Result<Vec<u8>> {
+            decode_block_with_dict(src, &[])
+        }
+
+        pub fn decode_block_with_dict(src: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+            if src.len() <= 4 {
+                return Err(box_err!("invalid lz4 block size: {}", src.len()));
+            }
+            let len = u32::from_le_bytes(src[..4].try_into().unwrap()) as usize;
+            super::check_raw_len(src, len)?;
+            let res = if dict.is_empty() {
+                lz4_flex::block::decompress(&src[4..], len)
+            } else {
+                lz4_flex::block::decompress_with_dict(&src[4..], len, dict)
+            };
+            match res {
+                Ok(dst) if dst.len() == len => Ok(dst),
+                Ok(dst) => Err(box_err!(
+                    "length of decompress result not match {} != {}",
+                    len,
+                    dst.len()
+                )),
+                Err(e) => Err(box_err!("decompress failed: {:?}", e)),
+            }
         }
     }
 
@@ -94,27 +257,40 @@ mod lz4 {
             for d in data {
                 let compressed = super::encode_block(d);
                 assert!(compressed.len() > 4);
-                let res = super::decode_block(&compressed);
+                let res = super::decode_block(&compressed).unwrap();
                 assert_eq!(res, d);
             }
         }
-    }
-}
-
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum CompressionType {
-    None = 0,
-    Lz4 = 1,
-}
 
-impl CompressionType {
-    pub fn from_byte(t: u8) -> CompressionType {
-        unsafe { mem::transmute(t) }
-    }
+        #[test]
+        fn test_dict() {
+            let dict = b"put table_1/column_family_default/key_00000001 value_00000001";
+            let data = b"put table_1/column_family_default/key_00000002 value_00000002";
+            let compressed = super::encode_block_with_dict(data, dict);
+            assert!(compressed.len() < super::encode_block(data).len());
+            assert_eq!(
+                super::decode_block_with_dict(&compressed, dict).unwrap(),
+                data
+            );
+        }
 
-    pub fn to_byte(&self) -> u8 {
-        *self as u8
+        #[cfg(all(feature = "lz4-c", feature = "lz4-pure"))]
+        #[test]
+        fn test_backend_compatible() {
+            let data: Vec<&'static [u8]> = vec![b"", b"123", &[b'x'; 8192]];
+            for d in data {
+                let c = super::c::encode_block(d);
+                let pure = super::pure::encode_block(d);
+                assert_eq!(super::pure::decode_block(&c).unwrap(), d);
+                assert_eq!(super::c::decode_block(&pure).unwrap(), d);
+
+                let dict = b"123456789";
+                let c = super::c::encode_block_with_dict(d, dict);
+                let pure = super::pure::encode_block_with_dict(d, dict);
+                assert_eq!(super::pure::decode_block_with_dict(&c, dict).unwrap(), d);
+                assert_eq!(super::c::decode_block_with_dict(&pure, dict).unwrap(), d);
+            }
+        }
     }
 }
 
@@ -128,16 +304,16 @@ pub enum LogItemType {
 }
 
 impl LogItemType {
-    pub fn from_byte(t: u8) -> LogItemType {
+    pub fn from_byte(t: u8) -> Result<LogItemType> {
         // likely
         if t == TYPE_ENTRIES {
-            LogItemType::Entries
+            Ok(LogItemType::Entries)
         } else if t == TYPE_COMMAND {
-            LogItemType::CMD
+            Ok(LogItemType::CMD)
         } else if t == TYPE_KV {
-            LogItemType::KV
+            Ok(LogItemType::KV)
         } else {
-            panic!("Invalid item type: {}", t)
+            Err(box_err!("Invalid item type: {}", t))
         }
     }
 
@@ -159,14 +335,14 @@ pub struct Entries {
     pub region_id: u64,
     pub entries: Vec<Entry>,
     // EntryIndex may be update after write to file.
-    pub entries_index: RefCell<Vec<EntryIndex>>,
+    pub entries_index: RefCell<EntryIndexes>,
 }
 
 impl Entries {
     pub fn new(
         region_id: u64,
         entries: Vec<Entry>,
-        entries_index: Option<Vec<EntryIndex>>,
+        entries_index: Option<EntryIndexes>,
     ) -> Entries {
         let len = entries.len();
         Entries {
@@ -174,7 +350,7 @@ impl Entries {
             entries,
             entries_index: match entries_index {
                 Some(index) => RefCell::new(index),
-                None => RefCell::new(vec![EntryIndex::default(); len]),
+                None => RefCell::new(EntryIndexes::from_elem(EntryIndex::default(), len)),
             },
         }
     }
@@ -189,12 +365,18 @@ impl Entries {
         let region_id = codec::decode_var_u64(buf)?;
         let mut count = codec::decode_var_u64(buf)? as usize;
 
+        // Each entry takes at least a byte for its length.
+        if count > buf.len() {
+            return Err(Error::TooShort);
+        }
         let mut entries = Vec::with_capacity(count);
-        let mut entries_index = Vec::with_capacity(count);
+        let mut entries_index = EntryIndexes::with_capacity(count);
         while count > 0 {
             let len = codec::decode_var_u64(buf)? as usize;
-            let mut e = Entry::new();
-            e.merge_from_bytes(&buf[..len])?;
+            if len > buf.len() {
+                return Err(Error::TooShort);
+            }
+            let e = entry_codec::decode_entry(&buf[..len])?;
 
             let mut entry_index = EntryIndex::default();
             entry_index.index = e.get_index();
@@ -213,17 +395,13 @@ impl Entries {
     }
 
     pub fn encode_to(&self, vec: &mut Vec<u8>) -> Result<()> {
-        if self.entries.is_empty() {
-            return Ok(());
-        }
-
         // layout = { region_id | entries count | multiple entries }
         // entries layout = { entry layout | ... | entry layout }
         // entry layout = { len | entry content }
         vec.encode_var_u64(self.region_id)?;
         vec.encode_var_u64(self.entries.len() as u64)?;
         for (i, e) in self.entries.iter().enumerate() {
-            let content = e.write_to_bytes()?;
+            let content = entry_codec::encode_entry(e)?;
             vec.encode_var_u64(content.len() as u64)?;
 
             // file_num = 0 means entry index is not initialized.
@@ -260,7 +438,16 @@ impl Entries {
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    Clean { region_id: u64 },
+    Clean {
+        region_id: u64,
+    },
+    /// Written before the rewritten items of a region. `generation` is the sequence
+    /// of the batch with the latest clean command of the region when the items were
+    /// read, 0 if none, so items read before a later clean can be told stale.
+    Fence {
+        region_id: u64,
+        generation: u64,
+    },
 }
 
 impl Command {
@@ -270,16 +457,33 @@ impl Command {
                 vec.push(CMD_CLEAN);
                 vec.encode_var_u64(region_id).unwrap();
             }
+            Command::Fence {
+                region_id,
+                generation,
+            } => {
+                vec.push(CMD_FENCE);
+                vec.encode_var_u64(region_id).unwrap();
+                vec.encode_var_u64(generation).unwrap();
+            }
         }
     }
 
     pub fn from_bytes(buf: &mut SliceReader<'_>) -> Result<Command> {
         let command_type = codec::read_u8(buf)?;
-        if command_type == CMD_CLEAN {
-            let region_id = codec::decode_var_u64(buf)?;
-            Ok(Command::Clean { region_id })
-        } else {
-            panic!("Unsupported command type: {:?}", command_type)
+        match command_type {
+            CMD_CLEAN => {
+                let region_id = codec::decode_var_u64(buf)?;
+                Ok(Command::Clean { region_id })
+            }
+            CMD_FENCE => {
+                let region_id = codec::decode_var_u64(buf)?;
+                let generation = codec::decode_var_u64(buf)?;
+                Ok(Command::Fence {
+                    region_id,
+                    generation,
+                })
+            }
+            _ => Err(box_err!("Unsupported command type: {:?}", command_type)),
         }
     }
 }
@@ -296,17 +500,34 @@ impl OpType {
     }
 
     pub fn from_bytes(buf: &mut SliceReader<'_>) -> Result<OpType> {
-        let op_type = buf.read_u8()?;
-        unsafe { Ok(mem::transmute(op_type)) }
+        match buf.read_u8()? {
+            0x01 => Ok(OpType::Put),
+            0x02 => Ok(OpType::Del),
+            t => Err(box_err!("Invalid op type: {}", t)),
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct KeyValue {
     pub op_type: OpType,
     pub region_id: u64,
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
+    // Where the value of a put is in files, located like an entry, for values
+    // too large to be kept in memtables. Updated after written to file like
+    // indexes of entries.
+    pub value_index: RefCell<Option<EntryIndex>>,
+}
+
+// Pairs are equal wherever they're written.
+impl PartialEq for KeyValue {
+    fn eq(&self, other: &KeyValue) -> bool {
+        self.op_type == other.op_type
+            && self.region_id == other.region_id
+            && self.key == other.key
+            && self.value == other.value
+    }
 }
 
 impl KeyValue {
@@ -316,21 +537,43 @@ impl KeyValue {
             region_id,
             key: key.to_vec(),
             value: value.map(|v| v.to_vec()),
+            value_index: RefCell::new(None),
         }
     }
 
-    pub fn from_bytes(buf: &mut SliceReader<'_>) -> Result<KeyValue> {
+    pub fn from_bytes(
+        buf: &mut SliceReader<'_>,
+        file_num: u64,
+        base_offset: u64,  // Offset of the batch from its log file.
+        batch_offset: u64, // Offset of the item from in its batch.
+    ) -> Result<KeyValue> {
+        let content_len = buf.len() as u64;
         let op_type = OpType::from_bytes(buf)?;
         let region_id = codec::decode_var_u64(buf)?;
         let k_len = codec::decode_var_u64(buf)? as usize;
+        if k_len > buf.len() {
+            return Err(Error::TooShort);
+        }
         let key = &buf[..k_len];
         buf.consume(k_len);
         match op_type {
             OpType::Put => {
                 let v_len = codec::decode_var_u64(buf)? as usize;
+                if v_len > buf.len() {
+                    return Err(Error::TooShort);
+                }
+                let value_index = EntryIndex {
+                    file_num,
+                    base_offset,
+                    offset: batch_offset + content_len - buf.len() as u64,
+                    len: v_len as u64,
+                    ..Default::default()
+                };
                 let value = &buf[..v_len];
                 buf.consume(v_len);
-                Ok(KeyValue::new(OpType::Put, region_id, key, Some(value)))
+                let kv = KeyValue::new(OpType::Put, region_id, key, Some(value));
+                *kv.value_index.borrow_mut() = Some(value_index);
+                Ok(kv)
             }
             OpType::Del => Ok(KeyValue::new(OpType::Del, region_id, key, None)),
         }
@@ -344,13 +587,41 @@ impl KeyValue {
         vec.extend_from_slice(self.key.as_slice());
         match self.op_type {
             OpType::Put => {
-                vec.encode_var_u64(self.value.as_ref().unwrap().len() as u64)?;
-                vec.extend_from_slice(self.value.as_ref().unwrap().as_slice());
+                let value = self.value.as_ref().unwrap();
+                vec.encode_var_u64(value.len() as u64)?;
+                let mut value_index = self.value_index.borrow_mut();
+                // File_num = 0 means the index is not initialized.
+                if value_index.as_ref().map_or(true, |idx| idx.file_num == 0) {
+                    *value_index = Some(EntryIndex {
+                        // This offset doesn't count the header.
+                        offset: vec.len() as u64,
+                        len: value.len() as u64,
+                        ..Default::default()
+                    });
+                }
+                vec.extend_from_slice(value);
             }
             OpType::Del => {}
         }
         Ok(())
     }
+
+    pub fn update_offset_when_needed(&self, file_num: u64, base: u64) {
+        if let Some(idx) = self.value_index.borrow_mut().as_mut() {
+            if idx.file_num == 0 {
+                debug_assert_eq!(idx.base_offset, 0);
+                idx.file_num = file_num;
+                idx.base_offset = base;
+            }
+        }
+    }
+
+    pub fn update_compression_type(&self, compression_type: CompressionType, batch_len: u64) {
+        if let Some(idx) = self.value_index.borrow_mut().as_mut() {
+            idx.compression_type = compression_type;
+            idx.batch_len = batch_len;
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -421,7 +692,7 @@ impl LogItem {
         base_offset: u64,      // Offset of the batch from its log file.
         mut batch_offset: u64, // Offset of the item from in its batch.
     ) -> Result<LogItem> {
-        let item_type = LogItemType::from_byte(buf.read_u8()?);
+        let item_type = LogItemType::from_byte(buf.read_u8()?)?;
         let mut item = LogItem::new(item_type);
 
         batch_offset += 1;
@@ -435,7 +706,7 @@ impl LogItem {
                 item.command = Some(command);
             }
             LogItemType::KV => {
-                let kv = KeyValue::from_bytes(buf)?;
+                let kv = KeyValue::from_bytes(buf, file_num, base_offset, batch_offset)?;
                 item.kv = Some(kv);
             }
         }
@@ -443,15 +714,97 @@ impl LogItem {
     }
 }
 
+/// A brief description of a written `LogItem`, for subscribers of the write stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItemSummary {
+    /// Entries in [`first_index`, `last_index`] are appended.
+    Entries {
+        region_id: u64,
+        first_index: u64,
+        last_index: u64,
+    },
+    Clean {
+        region_id: u64,
+    },
+    Put {
+        region_id: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        region_id: u64,
+        key: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchSummary {
+    /// The log file the batch is written to.
+    pub file_num: u64,
+    pub items: Vec<ItemSummary>,
+}
+
+/// How a batch is compressed when it's written. The compression type actually
+/// used is recorded in the header of the batch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchCompression {
+    /// Follow the engine: compress large batches, and small ones with the
+    /// dictionary if it helps, unless compression is disabled.
+    Auto,
+    /// Never compress the batch, e.g. if its content is compressed already.
+    Never,
+    /// Compress the batch whatever its size, even if compression is disabled for
+    /// the engine, e.g. for highly compressible bulk loads.
+    Always,
+}
+
+/// What's kept of entries of a batch once it's encoded to be written, for the
+/// memtables they're applied to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryRetention {
+    /// Keep the entries, to be cached as they are.
+    Decoded,
+    /// Drop the entries but keep the uncompressed encoded batch in `encoded`, so
+    /// that they're cached as encoded without encoding them again.
+    Encoded,
+    /// Drop the entries, as they aren't cached.
+    None,
+}
+
+/// Items written together, of one or several regions. A batch is written as one
+/// unit and recovered all or nothing, see `format`.
 #[derive(Debug, PartialEq)]
 pub struct LogBatch {
     pub items: RefCell<Vec<LogItem>>,
+    // Assigned when the batch is written, 0 for a batch never written.
+    pub sequence: u64,
+    // Assigned when the batch is written, like the sequence.
+    pub timestamp: Timestamp,
+    // Offset of the batch in the file it's written to or decoded from.
+    pub offset: u64,
+    /// Not decoded from files, a decoded batch is `Auto`.
+    pub compression: BatchCompression,
+    /// Set by the engine before the batch is written.
+    pub retention: EntryRetention,
+    /// The uncompressed encoded batch, kept once it's written if retention is
+    /// `Encoded`. Offsets of entry indexes are relative to its start.
+    pub encoded: RefCell<Option<Vec<u8>>>,
+    /// Entries may overwrite later ones of their regions even if `strict_append`
+    /// is set, e.g. to truncate a region. Not decoded from files.
+    pub overwrite: bool,
 }
 
 impl Default for LogBatch {
     fn default() -> Self {
         Self {
             items: RefCell::new(Vec::with_capacity(16)),
+            sequence: 0,
+            timestamp: Timestamp::default(),
+            offset: 0,
+            compression: BatchCompression::Auto,
+            retention: EntryRetention::Decoded,
+            encoded: RefCell::new(None),
+            overwrite: false,
         }
     }
 }
@@ -464,6 +817,13 @@ impl LogBatch {
     pub fn with_capacity(cap: usize) -> Self {
         Self {
             items: RefCell::new(Vec::with_capacity(cap)),
+            sequence: 0,
+            timestamp: Timestamp::default(),
+            offset: 0,
+            compression: BatchCompression::Auto,
+            retention: EntryRetention::Decoded,
+            encoded: RefCell::new(None),
+            overwrite: false,
         }
     }
 
@@ -497,103 +857,327 @@ impl LogBatch {
         Ok(())
     }
 
+    /// Put several messages of the region, serialized straight into the values of
+    /// the items. Nothing is put if any of them fails to serialize.
+    pub fn put_msgs<M: protobuf::Message>(
+        &self,
+        region_id: u64,
+        msgs: &[(&[u8], &M)],
+    ) -> Result<()> {
+        let mut items = Vec::with_capacity(msgs.len());
+        for (key, m) in msgs {
+            items.push(LogItem {
+                item_type: LogItemType::KV,
+                entries: None,
+                command: None,
+                kv: Some(KeyValue {
+                    op_type: OpType::Put,
+                    region_id,
+                    key: key.to_vec(),
+                    value: Some(m.write_to_bytes()?),
+                    value_index: RefCell::new(None),
+                }),
+            });
+        }
+        self.items.borrow_mut().extend(items);
+        Ok(())
+    }
+
     pub fn is_empty(&self) -> bool {
         self.items.borrow().is_empty()
     }
 
+    /// Bytes of the items once encoded without compression, roughly.
+    pub fn approximate_size(&self) -> usize {
+        let items = self.items.borrow();
+        let items_size: usize = items
+            .iter()
+            .map(|item| match item.item_type {
+                LogItemType::Entries => {
+                    let entries = &item.entries.as_ref().unwrap().entries;
+                    entries.iter().map(|e| e.compute_size() as usize).sum()
+                }
+                LogItemType::CMD => 0,
+                LogItemType::KV => {
+                    let kv = item.kv.as_ref().unwrap();
+                    kv.key.len() + kv.value.as_ref().map_or(0, |v| v.len())
+                }
+            })
+            .sum();
+        // Headers of items, counts and lengths take a few bytes each.
+        items_size + items.len() * 16
+    }
+
+    /// Regions with fenced items in the batch read before their latest clean, given
+    /// by `last_clean` as the sequence of the batch with the command.
+    pub fn stale_regions<F: Fn(u64) -> u64>(&self, last_clean: F) -> Vec<u64> {
+        let mut regions = vec![];
+        for item in self.items.borrow().iter() {
+            if let Some(Command::Fence {
+                region_id,
+                generation,
+            }) = item.command
+            {
+                if generation < last_clean(region_id) {
+                    regions.push(region_id);
+                }
+            }
+        }
+        regions
+    }
+
+    pub fn summary(&self, file_num: u64) -> BatchSummary {
+        let items = self
+            .items
+            .borrow()
+            .iter()
+            .filter_map(|item| match item.item_type {
+                LogItemType::Entries => {
+                    // Entries may be dropped once written, but not their indexes.
+                    let entries = item.entries.as_ref().unwrap();
+                    let entries_index = entries.entries_index.borrow();
+                    Some(ItemSummary::Entries {
+                        region_id: entries.region_id,
+                        first_index: entries_index.first()?.index,
+                        last_index: entries_index.last().unwrap().index,
+                    })
+                }
+                LogItemType::CMD => match *item.command.as_ref().unwrap() {
+                    Command::Clean { region_id } => Some(ItemSummary::Clean { region_id }),
+                    Command::Fence { .. } => None,
+                },
+                LogItemType::KV => {
+                    let kv = item.kv.as_ref().unwrap();
+                    Some(match kv.op_type {
+                        OpType::Put => ItemSummary::Put {
+                            region_id: kv.region_id,
+                            key: kv.key.clone(),
+                            value: kv.value.clone().unwrap(),
+                        },
+                        OpType::Del => ItemSummary::Delete {
+                            region_id: kv.region_id,
+                            key: kv.key.clone(),
+                        },
+                    })
+                }
+            })
+            .collect();
+        BatchSummary { file_num, items }
+    }
+
+    /// Decode the batch at the start of `buf`, with `dicts` for batches
+    /// compressed with a dictionary.
     pub fn from_bytes(
         buf: &mut SliceReader<'_>,
         file_num: u64,
         // The offset of the batch from its log file.
         base_offset: u64,
+        dicts: &Dictionaries,
+    ) -> Result<Option<LogBatch>> {
+        LogBatch::decode(buf, file_num, base_offset, dicts, true)
+    }
+
+    /// Like `from_bytes`, but don't verify the checksum of the batch, for files
+    /// known to be intact. The batch is still parsed and checked as a whole.
+    pub(crate) fn from_trusted_bytes(
+        buf: &mut SliceReader<'_>,
+        file_num: u64,
+        base_offset: u64,
+        dicts: &Dictionaries,
+    ) -> Result<Option<LogBatch>> {
+        LogBatch::decode(buf, file_num, base_offset, dicts, false)
+    }
+
+    fn decode(
+        buf: &mut SliceReader<'_>,
+        file_num: u64,
+        base_offset: u64,
+        dicts: &Dictionaries,
+        verify_checksum: bool,
     ) -> Result<Option<LogBatch>> {
-        if buf.is_empty() {
+        let mut base_offset = base_offset;
+        // Skip holes punched over dead batches.
+        while let Some(len) = format::decode_hole_header(buf) {
+            let len = to_usize(len)?;
+            if len > buf.len() {
+                return Err(Error::TooShort);
+            }
+            buf.consume(len);
+            base_offset += len as u64;
+        }
+        // Nothing after a barrier is written by the current writer.
+        if buf.is_empty() || format::is_tail_barrier(buf) {
             return Ok(None);
         }
         if buf.len() < BATCH_MIN_SIZE {
             return Err(Error::TooShort);
         }
 
-        let header = codec::decode_u64(buf)? as usize;
-        let batch_len = header >> 8;
-        let batch_type = CompressionType::from_byte(header as u8);
-        test_batch_checksum(&buf[..batch_len])?;
+        let (batch_len, batch_type) = format::decode_batch_header(buf)?;
+        let batch_len = to_usize(batch_len)?;
+        buf.consume(8);
+        if batch_len > buf.len() || batch_len < HEADER_LEN - 8 + CHECKSUM_LEN {
+            return Err(Error::TooShort);
+        }
+        if verify_checksum {
+            test_batch_checksum(&buf[..batch_len])?;
+        }
+        let sequence = (&buf[..SEQUENCE_LEN]).read_u64::<BigEndian>()?;
+        let timestamp = (&buf[SEQUENCE_LEN..]).read_u64::<BigEndian>()?;
 
+        let content = &buf[HEADER_LEN - 8..batch_len - CHECKSUM_LEN];
         let decompressed = match batch_type {
-            CompressionType::None => Cow::Borrowed(&buf[..(batch_len - CHECKSUM_LEN)]),
-            CompressionType::Lz4 => Cow::Owned(decompress(&buf[..batch_len - CHECKSUM_LEN])),
+            CompressionType::None => Cow::Borrowed(content),
+            t => Cow::Owned(decompress(t, content, dicts)?),
         };
 
         let mut reader: SliceReader = decompressed.borrow();
         let content_len = reader.len() + HEADER_LEN; // For its header.
 
+        // The batch is decoded whole before any item is applied, and the count
+        // must match the items exactly, so that it's applied all or nothing.
         let mut items_count = codec::decode_var_u64(&mut reader)? as usize;
-        assert!(items_count > 0 && !reader.is_empty());
-        let log_batch = LogBatch::with_capacity(items_count);
+        // Each item takes at least a byte for its type.
+        if items_count == 0 || items_count > reader.len() {
+            return Err(box_err!("Invalid item count: {}", items_count));
+        }
+        let mut log_batch = LogBatch::with_capacity(items_count);
+        log_batch.sequence = sequence;
+        log_batch.timestamp = Timestamp(timestamp);
+        log_batch.offset = base_offset;
         while items_count > 0 {
             let content_offset = (content_len - reader.len()) as u64;
             let item = LogItem::from_bytes(&mut reader, file_num, base_offset, content_offset)?;
             log_batch.items.borrow_mut().push(item);
             items_count -= 1;
         }
-        assert!(reader.is_empty());
+        if !reader.is_empty() {
+            return Err(box_err!("{} bytes left after items", reader.len()));
+        }
         buf.consume(batch_len);
 
         for item in log_batch.items.borrow_mut().iter_mut() {
-            if item.item_type == LogItemType::Entries {
-                item.entries
+            match item.item_type {
+                LogItemType::Entries => item
+                    .entries
                     .as_mut()
                     .unwrap()
-                    .update_compression_type(batch_type, batch_len as u64);
+                    .update_compression_type(batch_type, batch_len as u64),
+                LogItemType::KV => item
+                    .kv
+                    .as_ref()
+                    .unwrap()
+                    .update_compression_type(batch_type, batch_len as u64),
+                LogItemType::CMD => {}
             }
         }
 
         Ok(Some(log_batch))
     }
 
-    // TODO: avoid to write a large batch into one compressed chunk.
     pub fn encode_to_bytes(&self) -> Option<Vec<u8>> {
+        self.encode_to_bytes_with_compression(true, None)
+    }
+
+    /// Like `encode_to_bytes`, but never compress the batch if `compress` is false,
+    /// and compress small batches with `dictionary` if it's given and helps. Both
+    /// are overridden by `compression` of the batch unless it's `Auto`.
+    // TODO: avoid to write a large batch into one compressed chunk, but keep it
+    // one batch in files, which is recovered atomically.
+    pub fn encode_to_bytes_with_compression(
+        &self,
+        compress: bool,
+        dictionary: Option<&Dictionary>,
+    ) -> Option<Vec<u8>> {
         if self.items.borrow().is_empty() {
             return None;
         }
 
-        // layout = { 8 bytes len | item count | multiple items | 4 bytes checksum }
+        // layout = { 8 bytes len | 8 bytes sequence | 8 bytes timestamp | item count | multiple items | 4 bytes checksum }
+        // The sequence and the timestamp are filled by `set_sequence_with` when the
+        // batch is written.
         let mut vec = Vec::with_capacity(4096);
         vec.encode_u64(0).unwrap();
+        vec.encode_u64(0).unwrap();
+        vec.encode_u64(0).unwrap();
         vec.encode_var_u64(self.items.borrow().len() as u64)
             .unwrap();
-        for item in self.items.borrow_mut().iter_mut() {
+        for item in self.items.borrow().iter() {
             item.encode_to(&mut vec).unwrap();
         }
 
-        let compression_type = if vec.len() > COMPRESSION_SIZE {
-            let dst = lz4::encode_block(&vec[8..]);
-            vec.truncate(8);
-            vec.extend_from_slice(&dst);
-            CompressionType::Lz4
-        } else {
-            CompressionType::None
+        let (compress, force) = match self.compression {
+            BatchCompression::Auto => (compress, false),
+            BatchCompression::Never => (false, false),
+            BatchCompression::Always => (true, true),
         };
+        let mut compression_type = CompressionType::None;
+        if compress && (vec.len() > COMPRESSION_SIZE || (force && dictionary.is_none())) {
+            let dst = lz4::encode_block(&vec[HEADER_LEN..]);
+            self.truncate_to_header(&mut vec, dst.len());
+            vec.extend_from_slice(&dst);
+            compression_type = CompressionType::Lz4;
+        } else if let (true, Some(dict)) = (compress, dictionary) {
+            // { 4 bytes little-endian dictionary id | lz4 block }
+            let dst = lz4::encode_block_with_dict(&vec[HEADER_LEN..], dict.content());
+            if force || DICT_ID_LEN + dst.len() < vec.len() - HEADER_LEN {
+                self.truncate_to_header(&mut vec, DICT_ID_LEN + dst.len());
+                vec.extend_from_slice(&dict.id().to_le_bytes());
+                vec.extend_from_slice(&dst);
+                compression_type = CompressionType::Lz4Dict;
+            }
+        }
 
         let checksum = crc32(&vec[8..]);
         vec.encode_u32_le(checksum).unwrap();
         let len = vec.len() as u64 - 8;
-        let mut header = len << 8;
-        header |= u64::from(compression_type.to_byte());
-        vec.as_mut_slice().write_u64::<BigEndian>(header).unwrap();
+        vec[..8].copy_from_slice(&format::encode_batch_header(len, compression_type));
 
         let batch_len = (vec.len() - 8) as u64;
         for item in self.items.borrow_mut().iter_mut() {
-            if item.item_type == LogItemType::Entries {
-                item.entries
+            match item.item_type {
+                LogItemType::Entries => item
+                    .entries
                     .as_mut()
                     .unwrap()
-                    .update_compression_type(compression_type, batch_len);
+                    .update_compression_type(compression_type, batch_len),
+                LogItemType::KV => item
+                    .kv
+                    .as_ref()
+                    .unwrap()
+                    .update_compression_type(compression_type, batch_len),
+                LogItemType::CMD => {}
             }
         }
 
         Some(vec)
     }
+
+    /// Drop entries of the written batch unless they're kept decoded, as they're
+    /// read from files or `encoded` instead. They're kept until the batch is
+    /// written, so that a batch failing to be written can be written again.
+    pub fn drop_written_entries(&self) {
+        if self.retention == EntryRetention::Decoded {
+            return;
+        }
+        for item in self.items.borrow_mut().iter_mut() {
+            if item.item_type == LogItemType::Entries {
+                item.entries.as_mut().unwrap().entries = Vec::new();
+            }
+        }
+    }
+
+    // Leave only the header in `vec` to be followed by `len` bytes of compressed
+    // content, keeping the uncompressed batch if entries are to be cached encoded.
+    fn truncate_to_header(&self, vec: &mut Vec<u8>, len: usize) {
+        if self.retention == EntryRetention::Encoded {
+            let mut header = Vec::with_capacity(HEADER_LEN + len + CHECKSUM_LEN);
+            header.extend_from_slice(&vec[..HEADER_LEN]);
+            *self.encoded.borrow_mut() = Some(mem::replace(vec, header));
+        } else {
+            vec.truncate(HEADER_LEN);
+        }
+    }
 }
 
 impl RaftLogBatch for LogBatch {
@@ -615,30 +1199,36 @@ impl RaftLogBatch for LogBatch {
     }
 }
 
-pub fn test_batch_checksum(buf: &[u8]) -> Result<()> {
-    if buf.len() <= CHECKSUM_LEN {
-        return Err(Error::TooShort);
-    }
-
-    let batch_len = buf.len();
-    let mut s = &buf[(batch_len - CHECKSUM_LEN)..batch_len];
-    let expected = codec::decode_u32_le(&mut s)?;
-    let got = crc32(&buf[..(batch_len - CHECKSUM_LEN)]);
-    if got != expected {
-        return Err(Error::IncorrectChecksum(expected, got));
-    }
-    Ok(())
-}
-
 // NOTE: lz4::decode_block will truncate the output buffer first.
-pub fn decompress(buf: &[u8]) -> Vec<u8> {
-    self::lz4::decode_block(buf)
+pub fn decompress(
+    compression_type: CompressionType,
+    buf: &[u8],
+    dicts: &Dictionaries,
+) -> Result<Vec<u8>> {
+    match compression_type {
+        CompressionType::None => Ok(buf.to_vec()),
+        CompressionType::Lz4 => self::lz4::decode_block(buf),
+        CompressionType::Lz4Dict => {
+            if buf.len() < DICT_ID_LEN {
+                return Err(box_err!("invalid lz4 block size: {}", buf.len()));
+            }
+            let id = (&buf[..DICT_ID_LEN]).read_u32::<LittleEndian>()?;
+            let dict = match dicts.get(id) {
+                Some(dict) => dict,
+                None => return Err(box_err!("Unknown compression dictionary {:08x}", id)),
+            };
+            self::lz4::decode_block_with_dict(&buf[DICT_ID_LEN..], dict.content())
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use byteorder::WriteBytesExt;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     use raft::eraftpb::Entry;
 
     #[test]
@@ -648,7 +1238,10 @@ mod tests {
 
         for (pos, item_type) in item_types.iter().enumerate() {
             assert_eq!(item_type.to_byte(), item_types_byte[pos]);
-            assert_eq!(&LogItemType::from_byte(item_types_byte[pos]), item_type);
+            assert_eq!(
+                &LogItemType::from_byte(item_types_byte[pos]).unwrap(),
+                item_type
+            );
 
             let mut vec = vec![];
             item_type.encode_to(&mut vec);
@@ -674,17 +1267,34 @@ mod tests {
         assert_eq!(entries.region_id, decode_entries.region_id);
         assert_eq!(entries.entries, decode_entries.entries);
         assert_eq!(entries.entries_index, decode_entries.entries_index);
+
+        // Indexes of a few entries are kept inline.
+        let entries = Entries::new(region_id, vec![Entry::new(); 4], None);
+        let mut encoded = vec![];
+        entries.encode_to(&mut encoded).unwrap();
+        let mut s = encoded.as_slice();
+        let decode_entries = Entries::from_bytes(&mut s, file_num, 0, 0).unwrap();
+        assert!(!entries.entries_index.borrow().spilled());
+        assert!(!decode_entries.entries_index.borrow().spilled());
     }
 
     #[test]
     fn test_command_enc_dec() {
-        let cmd = Command::Clean { region_id: 8 };
-        let mut encoded = vec![];
-        cmd.encode_to(&mut encoded);
-        let mut bytes_slice = encoded.as_slice();
-        let decoded_cmd = Command::from_bytes(&mut bytes_slice).unwrap();
-        assert_eq!(bytes_slice.len(), 0);
-        assert_eq!(cmd, decoded_cmd);
+        let cmds = vec![
+            Command::Clean { region_id: 8 },
+            Command::Fence {
+                region_id: 8,
+                generation: 300,
+            },
+        ];
+        for cmd in cmds {
+            let mut encoded = vec![];
+            cmd.encode_to(&mut encoded);
+            let mut bytes_slice = encoded.as_slice();
+            let decoded_cmd = Command::from_bytes(&mut bytes_slice).unwrap();
+            assert_eq!(bytes_slice.len(), 0);
+            assert_eq!(cmd, decoded_cmd);
+        }
     }
 
     #[test]
@@ -693,9 +1303,14 @@ mod tests {
         let mut encoded = vec![];
         kv.encode_to(&mut encoded).unwrap();
         let mut bytes_slice = encoded.as_slice();
-        let decoded_kv = KeyValue::from_bytes(&mut bytes_slice).unwrap();
+        let decoded_kv = KeyValue::from_bytes(&mut bytes_slice, 0, 0, 0).unwrap();
         assert_eq!(bytes_slice.len(), 0);
         assert_eq!(kv, decoded_kv);
+        // The value is located as it's written.
+        let idx = decoded_kv.value_index.borrow().clone().unwrap();
+        assert_eq!(kv.value_index.borrow().as_ref(), Some(&idx));
+        let start = idx.offset as usize;
+        assert_eq!(&encoded[start..start + idx.len as usize], b"value");
     }
 
     #[test]
@@ -739,7 +1354,9 @@ mod tests {
 
         let encoded = batch.encode_to_bytes().unwrap();
         let mut s = encoded.as_slice();
-        let decoded_batch = LogBatch::from_bytes(&mut s, file_num, 0).unwrap().unwrap();
+        let decoded_batch = LogBatch::from_bytes(&mut s, file_num, 0, &Dictionaries::default())
+            .unwrap()
+            .unwrap();
         assert_eq!(s.len(), 0);
 
         for item in batch.items.borrow_mut().iter_mut() {
@@ -753,4 +1370,281 @@ mod tests {
 
         assert_eq!(batch, decoded_batch);
     }
+
+    #[test]
+    fn test_log_batch_atomicity() {
+        let batch = LogBatch::new();
+        batch.add_entries(1, vec![Entry::new(); 3]);
+        batch.add_entries(2, vec![Entry::new(); 3]);
+        batch.put(3, b"key", b"value");
+        let encoded = batch.encode_to_bytes().unwrap();
+
+        // A batch is never decoded in part.
+        for len in 1..encoded.len() {
+            assert!(
+                LogBatch::from_bytes(&mut &encoded[..len], 1, 0, &Dictionaries::default()).is_err()
+            );
+        }
+
+        // Nor with a count not matching its items, even if the checksum does.
+        assert_eq!(encoded[HEADER_LEN], 3);
+        for count in [2, 4] {
+            let mut miscounted = encoded.clone();
+            miscounted[HEADER_LEN] = count;
+            set_sequence(&mut miscounted, 1);
+            assert!(LogBatch::from_bytes(
+                &mut miscounted.as_slice(),
+                1,
+                0,
+                &Dictionaries::default()
+            )
+            .is_err());
+        }
+
+        // Nor with a header torn to another length, as the checksum is where the
+        // header says the batch ends.
+        let header = (&encoded[..8]).read_u64::<BigEndian>().unwrap();
+        for len in [(header >> 8) - 1, (header >> 8) + 1] {
+            let mut torn = encoded.clone();
+            torn.as_mut_slice()
+                .write_u64::<BigEndian>(len << 8 | (header & 0xff))
+                .unwrap();
+            torn.push(0);
+            assert!(
+                LogBatch::from_bytes(&mut torn.as_slice(), 1, 0, &Dictionaries::default()).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_batch_compression() {
+        let dict = Dictionary::new(b"put table_1/column_family_default/key_".to_vec());
+        let dicts = Dictionaries::default();
+        dicts.register(std::sync::Arc::new(dict.clone()));
+        let cases = vec![
+            // (compression, batch size, compress, dictionary, expected)
+            (
+                BatchCompression::Auto,
+                100,
+                true,
+                false,
+                CompressionType::None,
+            ),
+            (
+                BatchCompression::Auto,
+                8192,
+                true,
+                false,
+                CompressionType::Lz4,
+            ),
+            (
+                BatchCompression::Auto,
+                8192,
+                false,
+                false,
+                CompressionType::None,
+            ),
+            (
+                BatchCompression::Auto,
+                100,
+                true,
+                true,
+                CompressionType::Lz4Dict,
+            ),
+            (
+                BatchCompression::Auto,
+                100,
+                false,
+                true,
+                CompressionType::None,
+            ),
+            (
+                BatchCompression::Never,
+                8192,
+                true,
+                true,
+                CompressionType::None,
+            ),
+            (
+                BatchCompression::Never,
+                100,
+                true,
+                true,
+                CompressionType::None,
+            ),
+            (
+                BatchCompression::Always,
+                100,
+                false,
+                false,
+                CompressionType::Lz4,
+            ),
+            (
+                BatchCompression::Always,
+                100,
+                false,
+                true,
+                CompressionType::Lz4Dict,
+            ),
+            (
+                BatchCompression::Always,
+                8192,
+                false,
+                true,
+                CompressionType::Lz4,
+            ),
+        ];
+        for (compression, size, compress, use_dict, expected) in cases {
+            let mut batch = LogBatch::new();
+            let value = "put table_1/column_family_default/key_".repeat(size / 38);
+            batch.put(1, b"key", value.as_bytes());
+            batch.compression = compression;
+            let dict = if use_dict { Some(&dict) } else { None };
+            let encoded = batch
+                .encode_to_bytes_with_compression(compress, dict)
+                .unwrap();
+            assert_eq!(
+                CompressionType::from_byte(encoded[7]).unwrap(),
+                expected,
+                "{:?} {} {} {}",
+                compression,
+                size,
+                compress,
+                use_dict
+            );
+            let decoded = LogBatch::from_bytes(&mut encoded.as_slice(), 1, 0, &dicts)
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded.compression, BatchCompression::Auto);
+            batch.compression = BatchCompression::Auto;
+            assert_eq!(batch, decoded);
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    enum TestItem {
+        Entries(u64, Vec<(u64, u64, Vec<u8>)>),
+        Clean(u64),
+        Fence(u64, u64),
+        Put(u64, Vec<u8>, Vec<u8>),
+        Delete(u64, Vec<u8>),
+    }
+
+    fn test_item() -> impl Strategy<Value = TestItem> {
+        let bytes = || vec(any::<u8>(), 0..64);
+        prop_oneof![
+            (
+                any::<u64>(),
+                vec((any::<u64>(), any::<u64>(), bytes()), 0..8)
+            )
+                .prop_map(|(r, e)| TestItem::Entries(r, e)),
+            any::<u64>().prop_map(TestItem::Clean),
+            (any::<u64>(), any::<u64>()).prop_map(|(r, g)| TestItem::Fence(r, g)),
+            (any::<u64>(), bytes(), bytes()).prop_map(|(r, k, v)| TestItem::Put(r, k, v)),
+            (any::<u64>(), bytes()).prop_map(|(r, k)| TestItem::Delete(r, k)),
+        ]
+    }
+
+    fn build_batch(items: &[TestItem], padding: usize) -> LogBatch {
+        let batch = LogBatch::new();
+        for item in items {
+            match item.clone() {
+                TestItem::Entries(region_id, entries) => {
+                    let entries = entries
+                        .into_iter()
+                        .map(|(index, term, data)| {
+                            let mut e = Entry::new();
+                            e.set_index(index);
+                            e.set_term(term);
+                            e.set_data(data);
+                            e
+                        })
+                        .collect();
+                    batch.add_entries(region_id, entries);
+                }
+                TestItem::Clean(region_id) => batch.clean_region(region_id),
+                TestItem::Fence(region_id, generation) => batch.add_command(Command::Fence {
+                    region_id,
+                    generation,
+                }),
+                TestItem::Put(region_id, key, value) => batch.put(region_id, &key, &value),
+                TestItem::Delete(region_id, key) => batch.delete(region_id, &key),
+            }
+        }
+        if padding > 0 {
+            batch.put(0, b"padding", &vec![b'x'; padding]);
+        }
+        batch
+    }
+
+    // Wrap `payload` as the content of a batch with a valid header and checksum.
+    fn seal_batch(payload: &[u8], compression_type: u8) -> Vec<u8> {
+        let mut vec = vec![0; HEADER_LEN];
+        vec.extend_from_slice(payload);
+        vec.extend_from_slice(&[0; CHECKSUM_LEN]);
+        let header = ((vec.len() as u64 - 8) << 8) | u64::from(compression_type);
+        vec.as_mut_slice().write_u64::<BigEndian>(header).unwrap();
+        set_sequence(&mut vec, 1);
+        vec
+    }
+
+    proptest! {
+        #[test]
+        fn test_log_batch_round_trip(
+            items in vec(test_item(), 1..16),
+            compress in any::<bool>(),
+            sequence in any::<u64>(),
+        ) {
+            let file_num = 1;
+            // Batches larger than `COMPRESSION_SIZE` are compressed.
+            let mut batch = build_batch(&items, if compress { COMPRESSION_SIZE } else { 0 });
+            let mut encoded = batch.encode_to_bytes().unwrap();
+            set_sequence(&mut encoded, sequence);
+            let mut s = encoded.as_slice();
+            let decoded = LogBatch::from_bytes(&mut s, file_num, 0, &Dictionaries::default()).unwrap().unwrap();
+            prop_assert!(s.is_empty());
+
+            for item in batch.items.borrow().iter() {
+                if let Some(entries) = &item.entries {
+                    entries.update_offset_when_needed(file_num, 0);
+                    let idx = entries.entries_index.borrow();
+                    let expected = if compress { CompressionType::Lz4 } else { CompressionType::None };
+                    prop_assert!(idx.iter().all(|i| i.compression_type == expected));
+                }
+            }
+            batch.sequence = sequence;
+            prop_assert_eq!(batch, decoded);
+        }
+
+        #[test]
+        fn test_log_batch_corruption(
+            items in vec(test_item(), 1..16),
+            compress in any::<bool>(),
+            pos in any::<usize>(),
+            flip in 1..=255u8,
+        ) {
+            let batch = build_batch(&items, if compress { COMPRESSION_SIZE } else { 0 });
+            let mut encoded = batch.encode_to_bytes().unwrap();
+            // Bytes after the length are covered by the checksum.
+            let pos = 8 + pos % (encoded.len() - 8);
+            encoded[pos] ^= flip;
+            prop_assert!(LogBatch::from_bytes(&mut encoded.as_slice(), 1, 0, &Dictionaries::default()).is_err());
+
+            // Corrupted lengths must not panic either.
+            let mut encoded = batch.encode_to_bytes().unwrap();
+            encoded[pos % 8] ^= flip;
+            let _ = LogBatch::from_bytes(&mut encoded.as_slice(), 1, 0, &Dictionaries::default());
+        }
+
+        #[test]
+        fn test_log_batch_arbitrary_bytes(
+            bytes in vec(any::<u8>(), 0..256),
+            compression_type in 0..3u8,
+        ) {
+            let _ = LogBatch::from_bytes(&mut bytes.as_slice(), 1, 0, &Dictionaries::default());
+            // Get past the checksum to decode items.
+            let sealed = seal_batch(&bytes, compression_type);
+            let _ = LogBatch::from_bytes(&mut sealed.as_slice(), 1, 0, &Dictionaries::default());
+        }
+    }
 }
@@ -0,0 +1,271 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A memory quota shared with the host application. The entry cache and buffers
+/// of recovery are charged against it.
+pub trait MemoryLimiter: Send + Sync {
+    /// Charge `bytes`, return false without charging if the quota is exceeded.
+    fn try_acquire(&self, bytes: u64) -> bool;
+
+    /// Return `bytes` charged by `try_acquire` before.
+    fn release(&self, bytes: u64);
+}
+
+/// A `MemoryLimiter` allowing at most `limit` bytes in use.
+pub struct MemoryQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl MemoryQuota {
+    pub fn new(limit: u64) -> MemoryQuota {
+        MemoryQuota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+impl MemoryLimiter for MemoryQuota {
+    fn try_acquire(&self, bytes: u64) -> bool {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            if used + bytes > self.limit {
+                return false;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used + bytes,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(cur) => used = cur,
+            }
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+// Bytes charged against a limiter, released when dropped.
+pub(crate) struct MemoryCharge {
+    limiter: Arc<dyn MemoryLimiter>,
+    bytes: u64,
+}
+
+impl MemoryCharge {
+    pub(crate) fn try_new(limiter: &Arc<dyn MemoryLimiter>, bytes: u64) -> Option<MemoryCharge> {
+        if !limiter.try_acquire(bytes) {
+            return None;
+        }
+        Some(MemoryCharge {
+            limiter: limiter.clone(),
+            bytes,
+        })
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.limiter.release(self.bytes);
+    }
+}
+
+/// Subsystems of raft-engine holding large allocations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryComponent {
+    EntryCache,
+    RecoveryBuffer,
+    RewriteBuffer,
+    PrefetchBuffer,
+}
+
+const COMPONENTS: [MemoryComponent; 4] = [
+    MemoryComponent::EntryCache,
+    MemoryComponent::RecoveryBuffer,
+    MemoryComponent::RewriteBuffer,
+    MemoryComponent::PrefetchBuffer,
+];
+
+impl MemoryComponent {
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryComponent::EntryCache => "entry_cache",
+            MemoryComponent::RecoveryBuffer => "recovery_buffer",
+            MemoryComponent::RewriteBuffer => "rewrite_buffer",
+            MemoryComponent::PrefetchBuffer => "prefetch_buffer",
+        }
+    }
+}
+
+/// Notified when a component allocates or frees a large buffer, so that heap
+/// profiles of the host process (e.g. by jemalloc) can be attributed to
+/// subsystems of raft-engine. Installed on the trace of an engine, see
+/// `FileEngine::memory_trace`.
+pub trait AllocationHook: Send + Sync {
+    fn on_alloc(&self, component: MemoryComponent, bytes: u64);
+    fn on_free(&self, component: MemoryComponent, bytes: u64);
+}
+
+/// Bytes held by each component of an engine.
+#[derive(Default)]
+pub struct MemoryTrace {
+    bytes: [AtomicU64; 4],
+    hook: RwLock<Option<Arc<dyn AllocationHook>>>,
+}
+
+impl MemoryTrace {
+    /// Install an allocation hook of the engine, or remove it with `None`. Bytes
+    /// held already are reported as freed to the old hook and allocated to the
+    /// new one, so that each hook sees balanced changes.
+    pub fn set_allocation_hook(&self, hook: Option<Arc<dyn AllocationHook>>) {
+        let mut current = self.hook.write().unwrap();
+        for (component, bytes) in self.snapshot() {
+            if bytes == 0 {
+                continue;
+            }
+            if let Some(old) = &*current {
+                old.on_free(component, bytes);
+            }
+            if let Some(new) = &hook {
+                new.on_alloc(component, bytes);
+            }
+        }
+        *current = hook;
+    }
+
+    pub fn bytes(&self, component: MemoryComponent) -> u64 {
+        self.bytes[component as usize].load(Ordering::Relaxed)
+    }
+
+    /// All components with the bytes they hold.
+    pub fn snapshot(&self) -> Vec<(MemoryComponent, u64)> {
+        COMPONENTS.iter().map(|&c| (c, self.bytes(c))).collect()
+    }
+
+    // Changed under the lock of the hook, so that a new hook never misses or
+    // double counts a change.
+    pub(crate) fn alloc(&self, component: MemoryComponent, bytes: u64) {
+        let hook = self.hook.read().unwrap();
+        self.bytes[component as usize].fetch_add(bytes, Ordering::Relaxed);
+        if let Some(hook) = &*hook {
+            hook.on_alloc(component, bytes);
+        }
+    }
+
+    pub(crate) fn free(&self, component: MemoryComponent, bytes: u64) {
+        let hook = self.hook.read().unwrap();
+        self.bytes[component as usize].fetch_sub(bytes, Ordering::Relaxed);
+        if let Some(hook) = &*hook {
+            hook.on_free(component, bytes);
+        }
+    }
+}
+
+// A traced buffer, freed from the trace when dropped.
+pub(crate) struct TracedBuffer {
+    trace: Arc<MemoryTrace>,
+    component: MemoryComponent,
+    bytes: u64,
+}
+
+impl TracedBuffer {
+    pub(crate) fn new(
+        trace: &Arc<MemoryTrace>,
+        component: MemoryComponent,
+        bytes: u64,
+    ) -> TracedBuffer {
+        trace.alloc(component, bytes);
+        TracedBuffer {
+            trace: trace.clone(),
+            component,
+            bytes,
+        }
+    }
+}
+
+impl Drop for TracedBuffer {
+    fn drop(&mut self) {
+        self.trace.free(self.component, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_quota() {
+        let quota: Arc<dyn MemoryLimiter> = Arc::new(MemoryQuota::new(100));
+        let charge = MemoryCharge::try_new(&quota, 60).unwrap();
+        assert!(MemoryCharge::try_new(&quota, 50).is_none());
+        assert!(quota.try_acquire(40));
+        assert!(!quota.try_acquire(1));
+        drop(charge);
+        assert!(quota.try_acquire(60));
+        quota.release(100);
+        assert!(quota.try_acquire(100));
+    }
+
+    #[test]
+    fn test_memory_trace() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(MemoryComponent, i64)>>);
+        impl AllocationHook for Recorder {
+            fn on_alloc(&self, component: MemoryComponent, bytes: u64) {
+                self.0.lock().unwrap().push((component, bytes as i64));
+            }
+            fn on_free(&self, component: MemoryComponent, bytes: u64) {
+                self.0.lock().unwrap().push((component, -(bytes as i64)));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let trace = Arc::new(MemoryTrace::default());
+        trace.alloc(MemoryComponent::PrefetchBuffer, 1000);
+        trace.set_allocation_hook(Some(recorder.clone()));
+        trace.alloc(MemoryComponent::EntryCache, 1001);
+        {
+            let _buf = TracedBuffer::new(&trace, MemoryComponent::RewriteBuffer, 1002);
+            assert_eq!(trace.bytes(MemoryComponent::RewriteBuffer), 1002);
+        }
+        trace.free(MemoryComponent::EntryCache, 1001);
+        trace.set_allocation_hook(None);
+        trace.alloc(MemoryComponent::RecoveryBuffer, 1003);
+        trace.free(MemoryComponent::PrefetchBuffer, 1000);
+        // Traces of other engines don't report to the hook.
+        MemoryTrace::default().alloc(MemoryComponent::EntryCache, 1004);
+        assert_eq!(
+            trace.snapshot(),
+            vec![
+                (MemoryComponent::EntryCache, 0),
+                (MemoryComponent::RecoveryBuffer, 1003),
+                (MemoryComponent::RewriteBuffer, 0),
+                (MemoryComponent::PrefetchBuffer, 0),
+            ]
+        );
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                (MemoryComponent::PrefetchBuffer, 1000),
+                (MemoryComponent::EntryCache, 1001),
+                (MemoryComponent::RewriteBuffer, 1002),
+                (MemoryComponent::RewriteBuffer, -1002),
+                (MemoryComponent::EntryCache, -1001),
+                (MemoryComponent::PrefetchBuffer, -1000),
+            ]
+        );
+    }
+}
@@ -1,15 +1,21 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 use std::{cmp, u64};
 
-use raft::{eraftpb::Entry, StorageError};
+use raft::eraftpb::{Entry, EntryType};
+use raft::StorageError;
+use smallvec::SmallVec;
 
+use crate::arena::{Arena, ArenaSlice};
 use crate::engine::SharedCacheStats;
-use crate::log_batch::CompressionType;
-use crate::util::{slices_in_range, HashMap};
+use crate::entry_codec;
+use crate::format::CompressionType;
+use crate::util::slices_in_range;
 use crate::{Error, Result};
 
 const SHRINK_CACHE_CAPACITY: usize = 64;
+// Garbage of key value pairs of a region is reclaimed only if it's more than this.
+const KVS_MIN_GARBAGE: usize = 1024;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct EntryIndex {
@@ -26,6 +32,10 @@ pub struct EntryIndex {
     pub len: u64,
 }
 
+/// Indexes of entries of a region in a batch. Most batches have a handful of
+/// entries, whose indexes are kept inline without allocations.
+pub type EntryIndexes = SmallVec<[EntryIndex; 4]>;
+
 impl Default for EntryIndex {
     fn default() -> EntryIndex {
         EntryIndex {
@@ -40,6 +50,329 @@ impl Default for EntryIndex {
     }
 }
 
+/// The value of a key value pair in a memtable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvValue {
+    InMemory(Vec<u8>),
+    /// Too large to be kept in memory, see `max_memtable_value_size` of `Config`.
+    /// It's read from the file on demand, located like an entry.
+    InFile(EntryIndex),
+}
+
+/// Data of a region in a log file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileUsage {
+    pub entries: usize,
+    pub entries_size: u64,
+    pub kvs: usize,
+}
+
+/// Index of entries and key value pairs of a region in memory. `MemTable` is the
+/// default implementation, another one can be selected by `Config::memtable_type`,
+/// or provided by a `MemTableFactory`.
+pub trait MemTableAccessor: Send + Sync {
+    /// Append entries with their indexes. `entries` may be empty if they aren't
+    /// to be cached, e.g. they're dropped after written for a memtable without
+    /// cache.
+    fn append(&mut self, entries: Vec<Entry>, entries_index: EntryIndexes);
+
+    /// Append entries with their indexes, caching them as they're encoded in
+    /// `encoded`, the uncompressed content of the batch they're written with.
+    fn append_encoded(&mut self, encoded: &[u8], entries_index: EntryIndexes);
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>, file_num: u64);
+
+    /// Put a key value pair whose value is left in the file at `value_index`.
+    fn put_in_file(&mut self, key: Vec<u8>, value_index: EntryIndex);
+
+    fn delete(&mut self, key: &[u8]);
+
+    /// The value of the key if it's kept in memory. Values left in files aren't
+    /// got, see `get_value`.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.get_value(key)? {
+            KvValue::InMemory(value) => Some(value),
+            KvValue::InFile(_) => None,
+        }
+    }
+
+    fn get_value(&self, key: &[u8]) -> Option<KvValue>;
+
+    /// The file the key value pair is written to.
+    fn kv_file_num(&self, key: &[u8]) -> Option<u64>;
+
+    fn entry_index(&self, index: u64) -> Option<&EntryIndex>;
+
+    /// # Panics
+    ///
+    /// This method will panic if `idx` is greater than `last_idx + 1`.
+    fn compact_to(&mut self, idx: u64) -> u64;
+
+    /// # Panics
+    ///
+    /// This method will panic if `idx` is greater than `last_idx + 1`.
+    fn compact_cache_to(&mut self, idx: u64);
+
+    /// If entry exist in cache, return (Entry, None).
+    /// If entry exist but not in cache, return (None, EntryIndex).
+    /// If entry not exist, return (None, None).
+    fn get_entry(&self, index: u64) -> (Option<Entry>, Option<EntryIndex>);
+
+    /// Fetch entries in [begin, end) but stop before the total encoded size exceeds
+    /// `max_size`, though at least one entry is fetched. Return the total size.
+    fn fetch_entries_to(
+        &self,
+        begin: u64,
+        end: u64,
+        max_size: Option<usize>,
+        vec: &mut Vec<Entry>,
+        vec_idx: &mut Vec<EntryIndex>,
+    ) -> Result<u64>;
+
+    /// Indexes of entries in [begin, end) not in the cache, without counting cache
+    /// statistics. Entries out of the memtable are skipped.
+    fn fetch_uncached_indexes(&self, begin: u64, end: u64, vec_idx: &mut Vec<EntryIndex>);
+
+    fn fetch_all(&self, vec: &mut Vec<Entry>, vec_idx: &mut Vec<EntryIndex>);
+
+    /// Key value pairs whose values are kept in memory. Those left in files
+    /// aren't fetched, see `fetch_all_kv_values`.
+    fn fetch_all_kvs(&self, vec: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+        let mut values = vec![];
+        self.fetch_all_kv_values(&mut values);
+        vec.extend(values.into_iter().filter_map(|(key, value)| match value {
+            KvValue::InMemory(value) => Some((key, value)),
+            KvValue::InFile(_) => None,
+        }));
+    }
+
+    fn fetch_all_kv_values(&self, vec: &mut Vec<(Vec<u8>, KvValue)>);
+
+    fn first_index(&self) -> Option<u64>;
+
+    fn last_index(&self) -> Option<u64>;
+
+    fn min_file_num(&self) -> Option<u64>;
+
+    fn max_file_num(&self) -> Option<u64>;
+
+    /// Data of the region in files before `end_file_num`.
+    fn usage_before(&self, end_file_num: u64) -> BTreeMap<u64, FileUsage>;
+
+    fn kvs_total_count(&self) -> usize;
+
+    fn entries_count(&self) -> usize;
+
+    fn entries_size(&self) -> u64;
+
+    fn cache_size(&self) -> u64;
+
+    /// Memory held by cached entries, which is charged to `SharedCacheStats`.
+    fn cache_memory(&self) -> u64 {
+        self.cache_size()
+    }
+
+    /// Evict entries before `boundary_file_num` from cache.
+    fn evict_old_from_cache(&mut self, boundary_file_num: u64);
+
+    fn region_id(&self) -> u64;
+
+    /// Record that the batch at `offset` of the file has an item of the region.
+    fn update_position(&mut self, file_num: u64, offset: u64);
+
+    /// (file_num, offset) of the newest batch with an item of the region.
+    fn last_position(&self) -> Option<(u64, u64)>;
+
+    /// A copy of the region as of now, reading all entries from files, for reads
+    /// of snapshots.
+    fn freeze(&self) -> Box<dyn MemTableAccessor>;
+}
+
+/// Creates memtables of regions for an engine built with
+/// `EngineBuilder::memtable_factory`, instead of the one selected by
+/// `Config::memtable_type`.
+pub trait MemTableFactory: Send + Sync {
+    /// An empty memtable of the region. Sizes of entries it caches are counted in
+    /// `cache_stats`.
+    fn new_memtable(
+        &self,
+        region_id: u64,
+        cache_stats: Arc<SharedCacheStats>,
+    ) -> Box<dyn MemTableAccessor>;
+}
+
+// A cached entry, whose bytes are in the arena of its memtable.
+struct CachedEntry {
+    index: u64,
+    content: CachedContent,
+}
+
+enum CachedContent {
+    Decoded {
+        term: u64,
+        entry_type: EntryType,
+        sync_log: bool,
+        data: ArenaSlice,
+        context: ArenaSlice,
+    },
+    // The entry as encoded in the batch it's written with, decoded when read.
+    Encoded(ArenaSlice),
+}
+
+impl CachedEntry {
+    fn decoded(arena: &mut Arena, entry: &Entry) -> CachedEntry {
+        CachedEntry {
+            index: entry.get_index(),
+            content: CachedContent::Decoded {
+                term: entry.get_term(),
+                entry_type: entry.get_entry_type(),
+                sync_log: entry.get_sync_log(),
+                data: arena.alloc(entry.get_data()),
+                context: arena.alloc(entry.get_context()),
+            },
+        }
+    }
+
+    fn encoded(arena: &mut Arena, index: u64, bytes: &[u8]) -> CachedEntry {
+        CachedEntry {
+            index,
+            content: CachedContent::Encoded(arena.alloc(bytes)),
+        }
+    }
+
+    // The first piece of the entry allocated in the arena.
+    fn first_piece(&self) -> ArenaSlice {
+        match self.content {
+            CachedContent::Decoded { data, .. } => data,
+            CachedContent::Encoded(bytes) => bytes,
+        }
+    }
+
+    fn to_entry(&self, arena: &Arena) -> Result<Entry> {
+        match self.content {
+            CachedContent::Decoded {
+                term,
+                entry_type,
+                sync_log,
+                data,
+                context,
+            } => {
+                let mut entry = Entry::new();
+                entry.set_index(self.index);
+                entry.set_term(term);
+                entry.set_entry_type(entry_type);
+                entry.set_sync_log(sync_log);
+                entry.set_data(arena.get(data).to_vec());
+                entry.set_context(arena.get(context).to_vec());
+                Ok(entry)
+            }
+            CachedContent::Encoded(bytes) => entry_codec::decode_entry(arena.get(bytes)),
+        }
+    }
+}
+
+// A value of a key value pair, which is in the arena of `RegionKvs`.
+#[derive(Clone)]
+struct KvSlot {
+    // Empty if the value is left in the file.
+    value: ArenaSlice,
+    // Boxed as few values are left in files.
+    value_index: Option<Box<EntryIndex>>,
+    file_num: u64,
+}
+
+// Key value pairs of a region, sorted by keys. A region has a few keys usually,
+// which are overwritten again and again, so a value is overwritten in place if
+// it's not longer than the old one. Space of deleted or moved values is
+// reclaimed by copying live ones to a new arena once it's more than them.
+#[derive(Clone, Default)]
+struct RegionKvs {
+    arena: Arena,
+    slots: BTreeMap<Vec<u8>, KvSlot>,
+    live_bytes: usize,
+    garbage_bytes: usize,
+}
+
+impl RegionKvs {
+    fn put(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        value_index: Option<Box<EntryIndex>>,
+        file_num: u64,
+    ) {
+        match self.slots.get_mut(key) {
+            Some(slot) => {
+                let old = slot.value;
+                if value.len() <= old.len() {
+                    slot.value = self.arena.overwrite(old, value);
+                    self.garbage_bytes += old.len() - value.len();
+                } else {
+                    slot.value = self.arena.alloc(value);
+                    self.garbage_bytes += old.len();
+                }
+                slot.value_index = value_index;
+                slot.file_num = file_num;
+                self.live_bytes = self.live_bytes + value.len() - old.len();
+            }
+            None => {
+                let slot = KvSlot {
+                    value: self.arena.alloc(value),
+                    value_index,
+                    file_num,
+                };
+                self.slots.insert(key.to_vec(), slot);
+                self.live_bytes += value.len();
+            }
+        }
+        self.maybe_reclaim();
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        if let Some(slot) = self.slots.remove(key) {
+            let len = slot.value.len();
+            self.live_bytes -= len;
+            self.garbage_bytes += len;
+            self.maybe_reclaim();
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&KvSlot> {
+        self.slots.get(key)
+    }
+
+    fn value(&self, slot: &KvSlot) -> KvValue {
+        match &slot.value_index {
+            Some(value_index) => KvValue::InFile(value_index.as_ref().clone()),
+            None => KvValue::InMemory(self.arena.get(slot.value).to_vec()),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &KvSlot)> {
+        self.slots.iter().map(|(k, s)| (k.as_slice(), s))
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    fn maybe_reclaim(&mut self) {
+        if self.garbage_bytes <= cmp::max(self.live_bytes, KVS_MIN_GARBAGE) {
+            return;
+        }
+        let mut arena = Arena::default();
+        for slot in self.slots.values_mut() {
+            slot.value = arena.alloc(self.arena.get(slot.value));
+        }
+        self.arena = arena;
+        self.garbage_bytes = 0;
+    }
+}
+
 /*
  * Each region has an individual `MemTable` to cache latest entries and all entries indices.
  * `MemTable` also have a map to store all key value pairs for this region.
@@ -58,19 +391,25 @@ pub struct MemTable {
     region_id: u64,
 
     // latest N entries
-    entries_cache: VecDeque<Entry>,
+    entries_cache: VecDeque<CachedEntry>,
+    // Data of cached entries, allocated in the order of them.
+    cache_arena: Arena,
 
     // All entries index
     entries_index: VecDeque<EntryIndex>,
 
     // Region scope key/value pairs
-    // key -> (value, file_num)
-    kvs: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    kvs: RegionKvs,
 
     total_size: u64,
     cache_size: u64,
     cache_limit: u64,
     cache_stats: Arc<SharedCacheStats>,
+    // Capacity of `cache_arena` charged to `cache_stats`.
+    cache_charged: u64,
+    // False if the entry cache is disabled.
+    track_cache_stats: bool,
+    last_position: Option<(u64, u64)>,
 }
 
 impl MemTable {
@@ -86,6 +425,58 @@ impl MemTable {
     }
 
     // Remove all cached entries with index greater than or equal to the given.
+    // Append indexes of entries, caching the entries by `cache` if the memtable
+    // has room. Without `cache`, the entries aren't cached, and neither are ones
+    // before them any more, as cached entries must be the latest ones.
+    fn append_impl<F>(&mut self, entries_index: EntryIndexes, cache: Option<F>)
+    where
+        F: FnMut(&mut Arena, &EntryIndex) -> CachedEntry,
+    {
+        if entries_index.is_empty() {
+            return;
+        }
+
+        let first_index_to_add = entries_index[0].index;
+        self.cut_entries_cache(first_index_to_add);
+        self.cut_entries_index(first_index_to_add);
+
+        let delta_size = entries_index.iter().fold(0, |acc, i| acc + i.len);
+        let start = self.entries_index.len();
+        self.entries_index.extend(entries_index);
+        self.total_size += delta_size;
+        if self.cache_limit > 0 {
+            let cached = match cache {
+                Some(mut cache) => {
+                    for idx in self.entries_index.range(start..) {
+                        let cached = cache(&mut self.cache_arena, idx);
+                        self.entries_cache.push_back(cached);
+                    }
+                    self.cache_size += delta_size;
+                    self.charge_cache_arena()
+                }
+                None => false,
+            };
+            if !cached {
+                // Not cached or out of memory quota.
+                self.cache_stats.evict_entries(self.entries_cache.len());
+                self.clear_cache();
+            }
+        }
+
+        // Evict front entries from cache when reaching cache size limitation.
+        let mut evicted = 0;
+        while self.cache_size > self.cache_limit && !self.entries_cache.is_empty() {
+            let distance = self.cache_distance();
+            self.entries_cache.pop_front().unwrap();
+            self.cache_size -= self.entries_index[distance].len;
+            evicted += 1;
+        }
+        if evicted > 0 {
+            self.free_evicted_cache();
+        }
+        self.cache_stats.evict_entries(evicted);
+    }
+
     fn cut_entries_cache(&mut self, index: u64) {
         if self.entries_cache.is_empty() {
             return;
@@ -105,12 +496,48 @@ impl MemTable {
 
         let distance = self.cache_distance();
         for offset in conflict..self.entries_cache.len() {
-            let delta = self.entries_index[distance + offset].len;
-            self.cache_size -= delta;
-            self.cache_stats.sub_mem_change(delta);
+            self.cache_size -= self.entries_index[distance + offset].len;
         }
 
+        self.cache_arena
+            .truncate(self.entries_cache[conflict].first_piece());
         self.entries_cache.truncate(conflict);
+        self.charge_cache_arena();
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache_size = 0;
+        self.entries_cache.clear();
+        self.cache_arena.clear();
+        self.charge_cache_arena();
+    }
+
+    // Free data of entries removed from the front of the cache.
+    fn free_evicted_cache(&mut self) {
+        match self.entries_cache.front() {
+            Some(e) => self.cache_arena.free_before(e.first_piece()),
+            None => self.cache_arena.clear(),
+        }
+        self.charge_cache_arena();
+    }
+
+    // Charge chunks allocated by the cache arena since last time to `cache_stats`,
+    // or release freed ones. Return false if the memory limiter refuses the charge.
+    fn charge_cache_arena(&mut self) -> bool {
+        let capacity = self.cache_arena.capacity() as u64;
+        if capacity > self.cache_charged {
+            if !self
+                .cache_stats
+                .try_add_mem_change(capacity - self.cache_charged)
+            {
+                return false;
+            }
+        } else if capacity < self.cache_charged {
+            self.cache_stats
+                .sub_mem_change(self.cache_charged - capacity);
+        }
+        self.cache_charged = capacity;
+        true
     }
 
     // Remove all entry indexes with index greater than or equal to the given.
@@ -137,61 +564,143 @@ impl MemTable {
         MemTable {
             region_id,
             entries_cache: VecDeque::with_capacity(SHRINK_CACHE_CAPACITY),
+            cache_arena: Arena::default(),
             entries_index: VecDeque::with_capacity(SHRINK_CACHE_CAPACITY),
-            kvs: HashMap::default(),
+            kvs: RegionKvs::default(),
 
             total_size: 0,
             cache_size: 0,
+            cache_charged: 0,
             cache_limit,
             cache_stats: cache_stats,
+            track_cache_stats: true,
+            last_position: None,
         }
     }
 
-    pub fn append(&mut self, entries: Vec<Entry>, entries_index: Vec<EntryIndex>) {
-        assert_eq!(entries.len(), entries_index.len());
-        if entries.is_empty() {
-            return;
+    /// A memtable keeping no entries but only their indexes, and not tracking
+    /// cache statistics.
+    pub fn without_cache(region_id: u64, cache_stats: Arc<SharedCacheStats>) -> MemTable {
+        let mut memtable = MemTable::new(region_id, 0, cache_stats);
+        memtable.track_cache_stats = false;
+        memtable
+    }
+
+    fn kvs_min_file_num(&self) -> Option<u64> {
+        if self.kvs.is_empty() {
+            return None;
         }
+        Some(
+            self.kvs
+                .iter()
+                .fold(u64::MAX, |min, kv| cmp::min(min, kv.1.file_num)),
+        )
+    }
 
-        let first_index_to_add = entries[0].index;
-        self.cut_entries_cache(first_index_to_add);
-        self.cut_entries_index(first_index_to_add);
+    fn kvs_max_file_num(&self) -> Option<u64> {
+        if self.kvs.is_empty() {
+            return None;
+        }
+        Some(
+            self.kvs
+                .iter()
+                .fold(0, |max, kv| cmp::max(max, kv.1.file_num)),
+        )
+    }
 
-        let delta_size = entries_index.iter().fold(0, |acc, i| acc + i.len);
-        self.entries_index.extend(entries_index);
-        self.total_size += delta_size;
-        if self.cache_limit > 0 {
-            self.entries_cache.extend(entries);
-            self.cache_size += delta_size;
-            self.cache_stats.add_mem_change(delta_size);
+    fn fetch_cached(&self, low: usize, high: usize, vec: &mut Vec<Entry>) -> Result<()> {
+        let (first, second) = slices_in_range(&self.entries_cache, low, high);
+        for e in first.iter().chain(second) {
+            vec.push(e.to_entry(&self.cache_arena)?);
         }
+        Ok(())
+    }
 
-        // Evict front entries from cache when reaching cache size limitation.
-        while self.cache_size > self.cache_limit && !self.entries_cache.is_empty() {
-            let distance = self.cache_distance();
-            self.entries_cache.pop_front().unwrap();
-            let delta = self.entries_index[distance].len;
-            self.cache_size -= delta;
-            self.cache_stats.sub_mem_change(delta);
+    // Return the count and the total size of entries from `start_idx` within `max_size`.
+    fn size_limit(
+        &self,
+        start_idx: usize,
+        end_idx: usize,
+        max_size: Option<usize>,
+    ) -> (usize, u64) {
+        assert!(start_idx < end_idx);
+        let (first, second) = slices_in_range(&self.entries_index, start_idx, end_idx);
+
+        let mut count = 0;
+        let mut total_size = 0;
+        for i in first.iter().chain(second) {
+            // No matter max_size's value, fetch one entry at lease.
+            if count > 0 && matches!(max_size, Some(m) if total_size + i.len > m as u64) {
+                break;
+            }
+            count += 1;
+            total_size += i.len;
         }
+        (count, total_size)
     }
+}
 
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>, file_num: u64) {
-        self.kvs.insert(key, (value, file_num));
+impl MemTableAccessor for MemTable {
+    fn append(&mut self, entries: Vec<Entry>, entries_index: EntryIndexes) {
+        if entries.is_empty() {
+            self.append_impl(
+                entries_index,
+                None::<fn(&mut Arena, &EntryIndex) -> CachedEntry>,
+            );
+            return;
+        }
+        assert_eq!(entries.len(), entries_index.len());
+        let mut entries = entries.iter();
+        self.append_impl(
+            entries_index,
+            Some(|arena: &mut Arena, _: &EntryIndex| {
+                CachedEntry::decoded(arena, entries.next().unwrap())
+            }),
+        );
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
-        self.kvs.remove(key);
+    fn append_encoded(&mut self, encoded: &[u8], entries_index: EntryIndexes) {
+        self.append_impl(
+            entries_index,
+            Some(|arena: &mut Arena, idx: &EntryIndex| {
+                let start = idx.offset as usize;
+                let bytes = &encoded[start..start + idx.len as usize];
+                CachedEntry::encoded(arena, idx.index, bytes)
+            }),
+        );
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.kvs.get(key).map(|v| v.0.clone())
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>, file_num: u64) {
+        self.kvs.put(&key, &value, None, file_num);
     }
 
-    /// # Panics
-    ///
-    /// This method will panic if `idx` is greater than `last_idx + 1`.
-    pub fn compact_to(&mut self, idx: u64) -> u64 {
+    fn put_in_file(&mut self, key: Vec<u8>, value_index: EntryIndex) {
+        let file_num = value_index.file_num;
+        self.kvs
+            .put(&key, &[], Some(Box::new(value_index)), file_num);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.kvs.delete(key);
+    }
+
+    fn get_value(&self, key: &[u8]) -> Option<KvValue> {
+        self.kvs.get(key).map(|slot| self.kvs.value(slot))
+    }
+
+    fn kv_file_num(&self, key: &[u8]) -> Option<u64> {
+        self.kvs.get(key).map(|slot| slot.file_num)
+    }
+
+    fn entry_index(&self, index: u64) -> Option<&EntryIndex> {
+        let first_index = self.entries_index.front()?.index;
+        if index < first_index {
+            return None;
+        }
+        self.entries_index.get((index - first_index) as usize)
+    }
+
+    fn compact_to(&mut self, idx: u64) -> u64 {
         self.compact_cache_to(idx);
 
         let first_idx = match self.entries_index.front() {
@@ -209,10 +718,7 @@ impl MemTable {
         drain_end as u64
     }
 
-    /// # Panics
-    ///
-    /// This method will panic if `idx` is greater than `last_idx + 1`.
-    pub fn compact_cache_to(&mut self, idx: u64) {
+    fn compact_cache_to(&mut self, idx: u64) {
         let first_idx = match self.entries_cache.front() {
             Some(e) if e.index < idx => e.index,
             _ => return,
@@ -223,18 +729,14 @@ impl MemTable {
         let distance = self.cache_distance();
         let drain_end = (idx - first_idx) as usize;
         self.entries_cache.drain(0..drain_end);
+        self.free_evicted_cache();
 
         for i in 0..drain_end {
-            let delta = self.entries_index[distance + i].len;
-            self.cache_size -= delta;
-            self.cache_stats.sub_mem_change(delta);
+            self.cache_size -= self.entries_index[distance + i].len;
         }
     }
 
-    // If entry exist in cache, return (Entry, None).
-    // If entry exist but not in cache, return (None, EntryIndex).
-    // If entry not exist, return (None, None).
-    pub fn get_entry(&self, index: u64) -> (Option<Entry>, Option<EntryIndex>) {
+    fn get_entry(&self, index: u64) -> (Option<Entry>, Option<EntryIndex>) {
         if self.entries_index.is_empty() {
             return (None, None);
         }
@@ -248,25 +750,39 @@ impl MemTable {
         let ioffset = (index - first_index) as usize;
         let cache_distance = self.cache_distance();
         if ioffset < cache_distance {
-            self.cache_stats.miss_cache(1);
+            if self.track_cache_stats {
+                self.cache_stats.miss_cache(1);
+            }
             let entry_index = self.entries_index[ioffset].clone();
             (None, Some(entry_index))
         } else {
-            self.cache_stats.hit_cache(1);
             let coffset = ioffset - cache_distance;
-            let entry = self.entries_cache[coffset].clone();
-            (Some(entry), None)
+            match self.entries_cache[coffset].to_entry(&self.cache_arena) {
+                Ok(entry) => {
+                    self.cache_stats.hit_cache(1);
+                    (Some(entry), None)
+                }
+                Err(e) => {
+                    // The entry is read from its file instead, which reports the error
+                    // to the caller.
+                    warn!(
+                        "decode cached entry {} of region {} failed: {}",
+                        index, self.region_id, e
+                    );
+                    (None, Some(self.entries_index[ioffset].clone()))
+                }
+            }
         }
     }
 
-    pub(crate) fn fetch_entries_to(
+    fn fetch_entries_to(
         &self,
         begin: u64,
         end: u64,
         max_size: Option<usize>,
         vec: &mut Vec<Entry>,
         vec_idx: &mut Vec<EntryIndex>,
-    ) -> Result<()> {
+    ) -> Result<u64> {
         assert!(end > begin, "fetch_entries_to({}, {})", begin, end);
         let (vec_len, vec_idx_len) = (vec.len(), vec_idx.len());
 
@@ -283,30 +799,22 @@ impl MemTable {
         }
 
         let start_pos = (begin - first_index) as usize;
-        let mut end_pos = (end - begin) as usize + start_pos;
+        let end_pos = (end - begin) as usize + start_pos;
+        let (count, size) = self.size_limit(start_pos, end_pos, max_size);
+        let end_pos = start_pos + count;
 
-        // Check max size limitation.
-        if let Some(max_size) = max_size {
-            let count_limit = self.count_limit(start_pos, end_pos, max_size);
-            end_pos = start_pos + count_limit;
-        }
-
-        let cache_first_index = self.entries_cache.front().unwrap().get_index();
-        let cache_offset = (cache_first_index - first_index) as usize;
+        // The cache may be empty, e.g. evicted during recovery.
+        let cache_offset = self.cache_distance();
         if cache_offset < end_pos {
             if start_pos >= cache_offset {
                 // All needed entries are in cache.
                 let low = start_pos - cache_offset;
                 let high = end_pos - cache_offset;
-                let (first, second) = slices_in_range(&self.entries_cache, low, high);
-                vec.extend_from_slice(first);
-                vec.extend_from_slice(second);
+                self.fetch_cached(low, high, vec)?;
             } else {
                 // Partial needed entries are in cache.
                 let high = end_pos - cache_offset;
-                let (first, second) = slices_in_range(&self.entries_cache, 0, high);
-                vec.extend_from_slice(first);
-                vec.extend_from_slice(second);
+                self.fetch_cached(0, high, vec)?;
 
                 // Entries that not in cache should return their indices.
                 let (first, second) = slices_in_range(&self.entries_index, start_pos, cache_offset);
@@ -319,29 +827,68 @@ impl MemTable {
             vec_idx.extend_from_slice(first);
             vec_idx.extend_from_slice(second);
         }
-        self.cache_stats.hit_cache(vec.len() - vec_len);
-        self.cache_stats.miss_cache(vec_idx.len() - vec_idx_len);
-        Ok(())
+        if self.track_cache_stats {
+            self.cache_stats.hit_cache(vec.len() - vec_len);
+            self.cache_stats.miss_cache(vec_idx.len() - vec_idx_len);
+        }
+        Ok(size)
     }
 
-    pub fn fetch_all(&self, vec: &mut Vec<Entry>, vec_idx: &mut Vec<EntryIndex>) {
+    fn fetch_uncached_indexes(&self, begin: u64, end: u64, vec_idx: &mut Vec<EntryIndex>) {
+        let (first_index, last_index) =
+            match (self.entries_index.front(), self.entries_index.back()) {
+                (Some(first), Some(last)) => (first.index, last.index),
+                _ => return,
+            };
+        let begin = cmp::max(begin, first_index);
+        let end = cmp::min(end, last_index + 1);
+        if begin >= end {
+            return;
+        }
+        let start_pos = (begin - first_index) as usize;
+        let end_pos = cmp::min((end - first_index) as usize, self.cache_distance());
+        if start_pos < end_pos {
+            let (first, second) = slices_in_range(&self.entries_index, start_pos, end_pos);
+            vec_idx.extend_from_slice(first);
+            vec_idx.extend_from_slice(second);
+        }
+    }
+
+    fn fetch_all(&self, vec: &mut Vec<Entry>, vec_idx: &mut Vec<EntryIndex>) {
         if self.entries_index.is_empty() {
             return;
         }
 
         let begin = self.entries_index.front().unwrap().index;
         let end = self.entries_index.back().unwrap().index + 1;
-        self.fetch_entries_to(begin, end, None, vec, vec_idx)
-            .unwrap();
+        let (vec_len, vec_idx_len) = (vec.len(), vec_idx.len());
+        if let Err(e) = self.fetch_entries_to(begin, end, None, vec, vec_idx) {
+            // Only decoding a cached entry can fail, so all entries are read from
+            // files instead, which reports the error to the caller.
+            warn!("fetch entries of region {} failed: {}", self.region_id, e);
+            vec.truncate(vec_len);
+            vec_idx.truncate(vec_idx_len);
+            let (first, second) = slices_in_range(&self.entries_index, 0, self.entries_index.len());
+            vec_idx.extend_from_slice(first);
+            vec_idx.extend_from_slice(second);
+        }
     }
 
-    pub fn fetch_all_kvs(&self, vec: &mut Vec<(Vec<u8>, Vec<u8>)>) {
-        for (key, value) in &self.kvs {
-            vec.push((key.clone(), value.0.clone()));
+    fn fetch_all_kv_values(&self, vec: &mut Vec<(Vec<u8>, KvValue)>) {
+        for (key, slot) in self.kvs.iter() {
+            vec.push((key.to_vec(), self.kvs.value(slot)));
         }
     }
 
-    pub fn min_file_num(&self) -> Option<u64> {
+    fn first_index(&self) -> Option<u64> {
+        self.entries_index.front().map(|e| e.index)
+    }
+
+    fn last_index(&self) -> Option<u64> {
+        self.entries_index.back().map(|e| e.index)
+    }
+
+    fn min_file_num(&self) -> Option<u64> {
         let ents_min = self.entries_index.front().map(|idx| idx.file_num);
         let kvs_min = self.kvs_min_file_num();
         match (ents_min, kvs_min) {
@@ -352,7 +899,7 @@ impl MemTable {
         }
     }
 
-    pub fn max_file_num(&self) -> Option<u64> {
+    fn max_file_num(&self) -> Option<u64> {
         let ents_max = self.entries_index.back().map(|idx| idx.file_num);
         let kvs_max = self.kvs_max_file_num();
         match (ents_max, kvs_max) {
@@ -363,83 +910,88 @@ impl MemTable {
         }
     }
 
-    pub fn kvs_total_count(&self) -> usize {
+    fn usage_before(&self, end_file_num: u64) -> BTreeMap<u64, FileUsage> {
+        let mut usage = BTreeMap::<u64, FileUsage>::new();
+        for idx in self.entries_index.iter() {
+            if idx.file_num >= end_file_num {
+                break;
+            }
+            let u = usage.entry(idx.file_num).or_default();
+            u.entries += 1;
+            u.entries_size += idx.len;
+        }
+        for (_, slot) in self.kvs.iter() {
+            if slot.file_num < end_file_num {
+                usage.entry(slot.file_num).or_default().kvs += 1;
+            }
+        }
+        usage
+    }
+
+    fn kvs_total_count(&self) -> usize {
         self.kvs.len()
     }
 
-    pub fn entries_count(&self) -> usize {
+    fn entries_count(&self) -> usize {
         self.entries_index.len()
     }
 
-    pub fn entries_size(&self) -> u64 {
+    fn entries_size(&self) -> u64 {
         self.total_size
     }
 
-    pub fn cache_size(&self) -> u64 {
+    fn cache_size(&self) -> u64 {
         self.cache_size
     }
 
-    // Evict entries before `boundary_file_num` from cache.
-    pub fn evict_old_from_cache(&mut self, boundary_file_num: u64) {
+    fn cache_memory(&self) -> u64 {
+        self.cache_charged
+    }
+
+    fn evict_old_from_cache(&mut self, boundary_file_num: u64) {
         if self.entries_cache.is_empty() {
             return;
         }
 
+        let cached = self.entries_cache.len();
+        let mut index = self.entries_index.back().unwrap().index + 1;
         for i in self.cache_distance()..self.entries_index.len() {
             if self.entries_index[i].file_num >= boundary_file_num {
-                let index = self.entries_index[i].index;
-                self.compact_cache_to(index);
-                return;
+                index = self.entries_index[i].index;
+                break;
             }
         }
-        let index = self.entries_index.back().unwrap().index;
-        self.compact_cache_to(index + 1);
+        self.compact_cache_to(index);
+        let evicted = cached - self.entries_cache.len();
+        self.cache_stats.evict_entries(evicted);
     }
 
-    pub fn region_id(&self) -> u64 {
+    fn region_id(&self) -> u64 {
         self.region_id
     }
 
-    fn kvs_min_file_num(&self) -> Option<u64> {
-        if self.kvs.is_empty() {
-            return None;
-        }
-        Some(
-            self.kvs
-                .values()
-                .fold(u64::MAX, |min, v| cmp::min(min, v.1)),
-        )
+    fn update_position(&mut self, file_num: u64, offset: u64) {
+        // Batches written concurrently may be applied out of order.
+        self.last_position = cmp::max(self.last_position, Some((file_num, offset)));
     }
 
-    fn kvs_max_file_num(&self) -> Option<u64> {
-        if self.kvs.is_empty() {
-            return None;
-        }
-        Some(self.kvs.values().fold(0, |max, v| cmp::max(max, v.1)))
+    fn last_position(&self) -> Option<(u64, u64)> {
+        self.last_position
     }
 
-    fn count_limit(&self, start_idx: usize, end_idx: usize, max_size: usize) -> usize {
-        assert!(start_idx < end_idx);
-        let (first, second) = slices_in_range(&self.entries_index, start_idx, end_idx);
+    fn freeze(&self) -> Box<dyn MemTableAccessor> {
+        let mut frozen = MemTable::without_cache(self.region_id, self.cache_stats.clone());
+        frozen.entries_index = self.entries_index.clone();
+        frozen.kvs = self.kvs.clone();
+        frozen.total_size = self.total_size;
+        frozen.last_position = self.last_position;
+        Box::new(frozen)
+    }
+}
 
-        let mut count = 0;
-        let mut total_size = 0;
-        for i in first {
-            count += 1;
-            total_size += i.len;
-            if total_size as usize > max_size {
-                // No matter max_size's value, fetch one entry at lease.
-                return if count > 1 { count - 1 } else { count };
-            }
-        }
-        for i in second {
-            count += 1;
-            total_size += i.len;
-            if total_size as usize > max_size {
-                return if count > 1 { count - 1 } else { count };
-            }
-        }
-        count
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        self.clear_cache();
     }
 }
 
@@ -475,8 +1027,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 20);
         assert_eq!(memtable.entries_cache.len(), 15);
         assert_eq!(memtable.entries_index.len(), 20);
-        assert_eq!(memtable.entries_cache[0].get_index(), 15);
-        assert_eq!(memtable.entries_cache[14].get_index(), 29);
+        assert_eq!(memtable.entries_cache[0].index, 15);
+        assert_eq!(memtable.entries_cache[14].index, 29);
         assert_eq!(memtable.entries_index[0].index, 10);
         assert_eq!(memtable.entries_index[19].index, 29);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -494,8 +1046,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 25);
         assert_eq!(memtable.entries_cache.len(), 15);
         assert_eq!(memtable.entries_index.len(), 25);
-        assert_eq!(memtable.entries_cache[0].get_index(), 20);
-        assert_eq!(memtable.entries_cache[14].get_index(), 34);
+        assert_eq!(memtable.entries_cache[0].index, 20);
+        assert_eq!(memtable.entries_cache[14].index, 34);
         assert_eq!(memtable.entries_index[0].index, 10);
         assert_eq!(memtable.entries_index[24].index, 34);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -512,8 +1064,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 30);
         assert_eq!(memtable.entries_cache.len(), 15);
         assert_eq!(memtable.entries_index.len(), 30);
-        assert_eq!(memtable.entries_cache[0].get_index(), 25);
-        assert_eq!(memtable.entries_cache[14].get_index(), 39);
+        assert_eq!(memtable.entries_cache[0].index, 25);
+        assert_eq!(memtable.entries_cache[14].index, 39);
         assert_eq!(memtable.entries_index[0].index, 10);
         assert_eq!(memtable.entries_index[29].index, 39);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -529,8 +1081,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 20);
         assert_eq!(memtable.entries_cache.len(), 15);
         assert_eq!(memtable.entries_index.len(), 20);
-        assert_eq!(memtable.entries_cache[0].get_index(), 15);
-        assert_eq!(memtable.entries_cache[14].get_index(), 29);
+        assert_eq!(memtable.entries_cache[0].index, 15);
+        assert_eq!(memtable.entries_cache[14].index, 29);
         assert_eq!(memtable.entries_index[0].index, 10);
         assert_eq!(memtable.entries_index[19].index, 29);
         assert_eq!(memtable.min_file_num().unwrap(), 5);
@@ -567,8 +1119,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 25);
         assert_eq!(memtable.entries_cache.len(), 10);
         assert_eq!(memtable.entries_index.len(), 25);
-        assert_eq!(memtable.entries_cache[0].get_index(), 15);
-        assert_eq!(memtable.entries_cache[9].get_index(), 24);
+        assert_eq!(memtable.entries_cache[0].index, 15);
+        assert_eq!(memtable.entries_cache[9].index, 24);
         assert_eq!(memtable.entries_index[0].index, 0);
         assert_eq!(memtable.entries_index[24].index, 24);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -581,8 +1133,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 20);
         assert_eq!(memtable.entries_cache.len(), 10);
         assert_eq!(memtable.entries_index.len(), 20);
-        assert_eq!(memtable.entries_cache[0].get_index(), 15);
-        assert_eq!(memtable.entries_cache[9].get_index(), 24);
+        assert_eq!(memtable.entries_cache[0].index, 15);
+        assert_eq!(memtable.entries_cache[9].index, 24);
         assert_eq!(memtable.entries_index[0].index, 5);
         assert_eq!(memtable.entries_index[19].index, 24);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -595,8 +1147,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 5);
         assert_eq!(memtable.entries_cache.len(), 5);
         assert_eq!(memtable.entries_index.len(), 5);
-        assert_eq!(memtable.entries_cache[0].get_index(), 20);
-        assert_eq!(memtable.entries_cache[4].get_index(), 24);
+        assert_eq!(memtable.entries_cache[0].index, 20);
+        assert_eq!(memtable.entries_cache[4].index, 24);
         assert_eq!(memtable.entries_index[0].index, 20);
         assert_eq!(memtable.entries_index[4].index, 24);
         assert_eq!(memtable.min_file_num().unwrap(), 3);
@@ -742,9 +1294,10 @@ mod tests {
         ents_idx.clear();
         let max_size = Some(10);
         stats.reset();
-        memtable
+        let size = memtable
             .fetch_entries_to(10, 25, max_size, &mut ents, &mut ents_idx)
             .unwrap();
+        assert_eq!(size, 10);
         assert_eq!(ents.len(), 5);
         assert_eq!(ents[0].get_index(), 15);
         assert_eq!(ents[4].get_index(), 19);
@@ -758,9 +1311,10 @@ mod tests {
         ents.clear();
         ents_idx.clear();
         stats.reset();
-        memtable
+        let size = memtable
             .fetch_entries_to(20, 25, Some(0), &mut ents, &mut ents_idx)
             .unwrap();
+        assert_eq!(size, 1);
         assert_eq!(ents.len(), 1);
         assert_eq!(ents[0].get_index(), 20);
         assert!(ents_idx.is_empty());
@@ -785,6 +1339,82 @@ mod tests {
 
         memtable.delete(k5.as_ref());
         assert_eq!(memtable.get(k5.as_ref()), None);
+
+        // A large value is left in the file, and an overwrite brings it back.
+        let value_index = EntryIndex {
+            file_num: 7,
+            offset: 100,
+            len: 4096,
+            ..Default::default()
+        };
+        memtable.put_in_file(k1.to_vec(), value_index.clone());
+        assert_eq!(
+            memtable.get_value(k1.as_ref()),
+            Some(KvValue::InFile(value_index))
+        );
+        assert_eq!(memtable.get(k1.as_ref()), None);
+        let mut kvs = vec![];
+        memtable.fetch_all_kvs(&mut kvs);
+        assert!(kvs.is_empty());
+        assert_eq!(memtable.kv_file_num(k1.as_ref()), Some(7));
+        assert_eq!(memtable.max_file_num().unwrap(), 7);
+        memtable.put(k1.to_vec(), v1.to_vec(), 8);
+        assert_eq!(
+            memtable.get_value(k1.as_ref()),
+            Some(KvValue::InMemory(v1.to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_memtable_arena() {
+        let stats = Arc::new(SharedCacheStats::default());
+        let mut memtable = MemTable::new(8, 1024 * 1024, stats);
+
+        // Entries are read back as appended.
+        let mut ents = generate_ents(1, 101);
+        for e in &mut ents {
+            e.set_term(3);
+            e.set_entry_type(EntryType::EntryConfChange);
+            e.set_data(vec![e.get_index() as u8; 100]);
+            e.set_context(b"context".to_vec());
+        }
+        memtable.append(ents.clone(), generate_ents_index(1, 101, 1));
+        let (mut fetched, mut fetched_idx) = (vec![], vec![]);
+        memtable.fetch_all(&mut fetched, &mut fetched_idx);
+        assert_eq!(fetched, ents);
+        assert_eq!(memtable.get_entry(50).0.unwrap(), ents[49]);
+
+        // Space of compacted and overwritten entries is freed.
+        let capacity = memtable.cache_arena.capacity();
+        memtable.compact_to(80);
+        assert!(memtable.cache_arena.capacity() < capacity);
+        let capacity = memtable.cache_arena.capacity();
+        memtable.append(generate_ents(90, 101), generate_ents_index(90, 101, 2));
+        assert!(memtable.cache_arena.capacity() <= capacity);
+        assert_eq!(memtable.get_entry(89).0.unwrap(), ents[88]);
+        assert_eq!(memtable.get_entry(90).0.unwrap().get_data(), b"");
+        memtable.compact_to(101);
+        assert_eq!(memtable.cache_arena.capacity(), 0);
+
+        // Garbage of key value pairs is bounded.
+        for i in 0..1000u64 {
+            let value = vec![b'v'; i as usize % 100];
+            memtable.put(b"k1".to_vec(), value.clone(), i);
+            memtable.put(format!("k{}", i % 10).into_bytes(), value, i);
+        }
+        assert!(memtable.kvs.arena.capacity() < 16 * 1024);
+        assert_eq!(memtable.get(b"k1"), Some(vec![b'v'; 99]));
+        assert_eq!(memtable.kv_file_num(b"k9"), Some(999));
+        assert_eq!(memtable.min_file_num(), Some(990));
+        memtable.delete(b"k1");
+        let mut kvs = vec![];
+        memtable.fetch_all_kvs(&mut kvs);
+        let keys: Vec<_> = kvs.into_iter().map(|(k, _)| k).collect();
+        let expected: Vec<_> = (0..10)
+            .filter(|i| *i != 1)
+            .map(|i| format!("k{}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
     }
 
     #[test]
@@ -804,8 +1434,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 30);
         assert_eq!(memtable.entries_cache.len(), 30);
         assert_eq!(memtable.entries_index.len(), 30);
-        assert_eq!(memtable.entries_cache[0].get_index(), 0);
-        assert_eq!(memtable.entries_cache[29].get_index(), 29);
+        assert_eq!(memtable.entries_cache[0].index, 0);
+        assert_eq!(memtable.entries_cache[29].index, 29);
         assert_eq!(memtable.entries_index[0].index, 0);
         assert_eq!(memtable.entries_index[29].index, 29);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -817,8 +1447,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 30);
         assert_eq!(memtable.entries_cache.len(), 20);
         assert_eq!(memtable.entries_index.len(), 30);
-        assert_eq!(memtable.entries_cache[0].get_index(), 10);
-        assert_eq!(memtable.entries_cache[19].get_index(), 29);
+        assert_eq!(memtable.entries_cache[0].index, 10);
+        assert_eq!(memtable.entries_cache[19].index, 29);
         assert_eq!(memtable.entries_index[0].index, 0);
         assert_eq!(memtable.entries_index[29].index, 29);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -830,8 +1460,8 @@ mod tests {
         assert_eq!(memtable.entries_size(), 30);
         assert_eq!(memtable.entries_cache.len(), 10);
         assert_eq!(memtable.entries_index.len(), 30);
-        assert_eq!(memtable.entries_cache[0].get_index(), 20);
-        assert_eq!(memtable.entries_cache[9].get_index(), 29);
+        assert_eq!(memtable.entries_cache[0].index, 20);
+        assert_eq!(memtable.entries_cache[9].index, 29);
         assert_eq!(memtable.entries_index[0].index, 0);
         assert_eq!(memtable.entries_index[29].index, 29);
         assert_eq!(memtable.min_file_num().unwrap(), 1);
@@ -872,6 +1502,21 @@ mod tests {
         // Not in cache.
         let (_, entry_idx) = memtable.get_entry(5);
         assert_eq!(entry_idx.unwrap().index, 5);
+
+        // A cached entry failed to decode is read from its file instead.
+        let mut encoded = vec![0; 20];
+        encoded.push(0xff);
+        memtable.append_encoded(&encoded, generate_ents_index(20, 21, 3));
+        let (entry, entry_idx) = memtable.get_entry(20);
+        assert!(entry.is_none());
+        assert_eq!(entry_idx.unwrap().file_num, 3);
+        let (mut ents, mut ents_idx) = (vec![], vec![]);
+        assert!(memtable
+            .fetch_entries_to(20, 21, None, &mut ents, &mut ents_idx)
+            .is_err());
+        memtable.fetch_all(&mut ents, &mut ents_idx);
+        assert!(ents.is_empty());
+        assert_eq!(ents_idx.len(), memtable.entries_count());
     }
 
     fn generate_ents(begin_idx: u64, end_idx: u64) -> Vec<Entry> {
@@ -885,9 +1530,9 @@ mod tests {
         ents
     }
 
-    fn generate_ents_index(begin_idx: u64, end_idx: u64, file_num: u64) -> Vec<EntryIndex> {
+    fn generate_ents_index(begin_idx: u64, end_idx: u64, file_num: u64) -> EntryIndexes {
         assert!(end_idx >= begin_idx);
-        let mut ents_idx = vec![];
+        let mut ents_idx = EntryIndexes::new();
         for idx in begin_idx..end_idx {
             let mut ent_idx = EntryIndex::default();
             ent_idx.index = idx;
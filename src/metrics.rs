@@ -11,40 +11,256 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::{exponential_buckets, Counter, Gauge, Histogram};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use prometheus::{
+    exponential_buckets, Counter, CounterVec, Gauge, GaugeVec, Histogram, HistogramVec,
+};
+
+use crate::hot_region::RegionWrites;
+use crate::memory::MemoryComponent;
+use crate::Result;
 
 lazy_static! {
-    pub static ref RAFTENGINE_MEMORY_USAGE_GAUGE: Gauge = register_gauge!(
+    pub static ref RAFTENGINE_MEMORY_USAGE_GAUGE: GaugeVec = register_gauge_vec!(
         "tikv_raftengine_memory_usage_bytes",
-        "Total bytes of all memtables."
+        "Total bytes of all memtables.",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref RAFTENGINE_CACHE_USAGE_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_cache_usage_bytes",
+        "Total bytes of entries cached in memtables.",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref MEMORY_TRACE_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_memory_trace_bytes",
+        "Bytes of large buffers held by each component.",
+        &["engine", "component"]
+    )
+    .unwrap();
+    pub static ref HOT_REGION_WRITE_BYTES_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_hot_region_write_bytes",
+        "Recent write bytes of the hottest regions.",
+        &["engine", "region"]
+    )
+    .unwrap();
+    pub static ref SLOT_REGIONS_COUNT_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_slot_regions_count",
+        "Number of regions in each memtable slot.",
+        &["engine", "slot"]
+    )
+    .unwrap();
+    pub static ref WRITE_AMPLIFICATION_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_write_amplification",
+        "Ratio of bytes written to disk to bytes written by users.",
+        &["engine"]
     )
     .unwrap();
-    pub static ref REWRITE_ENTRIES_COUNT_HISTOGRAM: Histogram = register_histogram!(
+    pub static ref REWRITE_THRESHOLD_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_rewrite_threshold",
+        "Regions with fewer entries than this are rewritten.",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref REWRITE_ENTRIES_COUNT_HISTOGRAM: HistogramVec = register_histogram_vec!(
         "tikv_raftengine_rewrite_entries_count",
         "Bucketed histogram of rewrite entries count.",
+        &["engine"],
         exponential_buckets(1.0, 2.0, 8).unwrap()
     )
     .unwrap();
-    pub static ref REWRITE_COUNTER: Counter = register_counter!(
+    pub static ref REWRITE_COUNTER: CounterVec = register_counter_vec!(
         "tikv_raftengine_rewrite_counter",
-        "Total number of rewriting happens"
+        "Total number of rewriting happens",
+        &["engine"]
     )
     .unwrap();
-    pub static ref NEED_COMPACT_REGIONS_HISTOGRAM: Histogram = register_histogram!(
+    pub static ref NEED_COMPACT_REGIONS_HISTOGRAM: HistogramVec = register_histogram_vec!(
         "tikv_raftengine_need_compact_regions_count",
         "Bucketed histogram of regions count need compact.",
+        &["engine"],
         exponential_buckets(1.0, 2.0, 20).unwrap()
     )
     .unwrap();
-    pub static ref EXPIRED_FILES_PURGED_HISTOGRAM: Histogram = register_histogram!(
+    pub static ref EXPIRED_FILES_PURGED_HISTOGRAM: HistogramVec = register_histogram_vec!(
         "tikv_raftengine_expired_files_purged_count",
         "Bucketed histogram of expired files purged count.",
+        &["engine"],
         exponential_buckets(1.0, 2.0, 8).unwrap()
     )
     .unwrap();
-    pub static ref PIPE_FILES_COUNT_GAUGE: Gauge = register_gauge!(
+    pub static ref PIPE_FILES_COUNT_GAUGE: GaugeVec = register_gauge_vec!(
         "tikv_raftengine_total_pipe_files_count",
-        "Total number of current pipe log files."
+        "Total number of current pipe log files.",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref SCRUB_FILES_COUNTER: CounterVec = register_counter_vec!(
+        "tikv_raftengine_scrub_files_counter",
+        "Total number of log files verified by background scrub",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref SCRUB_CORRUPTION_COUNTER: CounterVec = register_counter_vec!(
+        "tikv_raftengine_scrub_corruption_counter",
+        "Total number of corrupted log files found by background scrub",
+        &["engine"]
     )
     .unwrap();
+    pub static ref CACHE_EVICTED_ENTRIES_COUNTER: CounterVec = register_counter_vec!(
+        "tikv_raftengine_cache_evicted_entries_counter",
+        "Total number of entries evicted from memtable caches",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref REWRITE_BACKLOG_BYTES_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_rewrite_backlog_bytes",
+        "Bytes of live entries in files older than the inactive threshold.",
+        &["engine"]
+    )
+    .unwrap();
+    pub static ref BACKGROUND_PANICS_COUNTER: CounterVec = register_counter_vec!(
+        "tikv_raftengine_background_panics_counter",
+        "Total number of panics caught in background tasks",
+        &["engine", "task"]
+    )
+    .unwrap();
+    pub static ref BATCH_REGIONS_COUNT_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "tikv_raftengine_batch_regions_count",
+        "Bucketed histogram of regions count of written batches.",
+        &["engine"],
+        exponential_buckets(1.0, 2.0, 16).unwrap()
+    )
+    .unwrap();
+    pub static ref BATCH_ENTRIES_COUNT_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "tikv_raftengine_batch_entries_count",
+        "Bucketed histogram of entries count of written batches.",
+        &["engine"],
+        exponential_buckets(1.0, 2.0, 20).unwrap()
+    )
+    .unwrap();
+    pub static ref PURGE_BLOCKED_FILES_GAUGE: GaugeVec = register_gauge_vec!(
+        "tikv_raftengine_purge_blocked_files_count",
+        "Number of files older than the inactive threshold kept by live data.",
+        &["engine"]
+    )
+    .unwrap();
+    // Names of engines alive in the process, see `EngineMetrics::register`.
+    static ref ENGINE_NAMES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Metrics of one engine, labeled with its name so that engines in one process
+/// are told apart.
+pub struct EngineMetrics {
+    name: String,
+    pub memory_usage: Gauge,
+    pub cache_usage: Gauge,
+    pub write_amplification: Gauge,
+    pub rewrite_threshold: Gauge,
+    pub rewrite_entries_count: Histogram,
+    pub rewrites: Counter,
+    pub need_compact_regions: Histogram,
+    pub expired_files_purged: Histogram,
+    pub pipe_files_count: Gauge,
+    pub scrub_files: Counter,
+    pub scrub_corruptions: Counter,
+    pub cache_evicted_entries: Counter,
+    pub rewrite_backlog_bytes: Gauge,
+    pub purge_blocked_files: Gauge,
+    pub batch_regions_count: Histogram,
+    pub batch_entries_count: Histogram,
+    // Regions last reported as hot.
+    hot_regions: Mutex<Vec<String>>,
+    // Whether the name is released when dropped.
+    registered: bool,
+}
+
+impl EngineMetrics {
+    pub fn new(name: &str) -> EngineMetrics {
+        let labels = &[name];
+        EngineMetrics {
+            name: name.to_owned(),
+            memory_usage: RAFTENGINE_MEMORY_USAGE_GAUGE.with_label_values(labels),
+            cache_usage: RAFTENGINE_CACHE_USAGE_GAUGE.with_label_values(labels),
+            write_amplification: WRITE_AMPLIFICATION_GAUGE.with_label_values(labels),
+            rewrite_threshold: REWRITE_THRESHOLD_GAUGE.with_label_values(labels),
+            rewrite_entries_count: REWRITE_ENTRIES_COUNT_HISTOGRAM.with_label_values(labels),
+            rewrites: REWRITE_COUNTER.with_label_values(labels),
+            need_compact_regions: NEED_COMPACT_REGIONS_HISTOGRAM.with_label_values(labels),
+            expired_files_purged: EXPIRED_FILES_PURGED_HISTOGRAM.with_label_values(labels),
+            pipe_files_count: PIPE_FILES_COUNT_GAUGE.with_label_values(labels),
+            scrub_files: SCRUB_FILES_COUNTER.with_label_values(labels),
+            scrub_corruptions: SCRUB_CORRUPTION_COUNTER.with_label_values(labels),
+            cache_evicted_entries: CACHE_EVICTED_ENTRIES_COUNTER.with_label_values(labels),
+            rewrite_backlog_bytes: REWRITE_BACKLOG_BYTES_GAUGE.with_label_values(labels),
+            purge_blocked_files: PURGE_BLOCKED_FILES_GAUGE.with_label_values(labels),
+            batch_regions_count: BATCH_REGIONS_COUNT_HISTOGRAM.with_label_values(labels),
+            batch_entries_count: BATCH_ENTRIES_COUNT_HISTOGRAM.with_label_values(labels),
+            hot_regions: Mutex::new(vec![]),
+            registered: false,
+        }
+    }
+
+    /// Like `new`, but fail if another engine alive in the process registered the
+    /// name, whose metrics would be mixed with these. The name is released when
+    /// the metrics are dropped.
+    pub fn register(name: &str) -> Result<EngineMetrics> {
+        if !ENGINE_NAMES.lock().unwrap().insert(name.to_owned()) {
+            return Err(box_err!(
+                "Engine name {} is used by another engine in the process",
+                name
+            ));
+        }
+        let mut metrics = EngineMetrics::new(name);
+        metrics.registered = true;
+        Ok(metrics)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn memory_trace(&self, component: MemoryComponent) -> Gauge {
+        MEMORY_TRACE_GAUGE.with_label_values(&[&self.name, component.name()])
+    }
+
+    pub fn background_panics(&self, task: &str) -> Counter {
+        BACKGROUND_PANICS_COUNTER.with_label_values(&[&self.name, task])
+    }
+
+    pub fn slot_regions_count(&self, slot: usize) -> Gauge {
+        SLOT_REGIONS_COUNT_GAUGE.with_label_values(&[&self.name, &slot.to_string()])
+    }
+
+    /// Report the write bytes of hot regions, replacing the ones reported before.
+    pub fn set_hot_regions(&self, regions: &[RegionWrites]) {
+        let mut reported = self.hot_regions.lock().unwrap();
+        for region in reported.drain(..) {
+            let _ = HOT_REGION_WRITE_BYTES_GAUGE.remove_label_values(&[&self.name, &region]);
+        }
+        for writes in regions {
+            let region = writes.region_id.to_string();
+            HOT_REGION_WRITE_BYTES_GAUGE
+                .with_label_values(&[&self.name, &region])
+                .set(writes.bytes as f64);
+            reported.push(region);
+        }
+    }
+}
+
+impl Drop for EngineMetrics {
+    fn drop(&mut self) {
+        if self.registered {
+            ENGINE_NAMES.lock().unwrap().remove(&self.name);
+        }
+    }
+}
+
+impl Default for EngineMetrics {
+    fn default() -> EngineMetrics {
+        EngineMetrics::new("raft-engine")
+    }
 }
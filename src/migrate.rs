@@ -0,0 +1,305 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::dictionary;
+use crate::format::{self, CHECKSUM_LEN, SEQUENCE_LEN, TIMESTAMP_LEN};
+use crate::pipe_log::{self, FILE_HEADER_LEN, FILE_MAGIC_HEADER, VERSION};
+use crate::{Error, Result};
+
+// Format of files written before batches carry a sequence and a timestamp, and
+// file headers carry a checksum.
+const V1: (u64, u64, u64) = (1, 0, 0);
+// Headers of v1 files are the magic and the version.
+const OLD_HEADER_LEN: usize = FILE_MAGIC_HEADER.len() + VERSION.len();
+
+/// Rewrite raft log files in `from` into `to` in the current format, and return
+/// the count of migrated files. `to` must be empty or not exist. Besides the
+/// current format, files of v1.0.0 are supported, whose batches get sequences in
+/// the order they are written and unknown timestamps. The engine upgrades files
+/// in place when it opens them, this keeps the original files instead.
+pub fn migrate_dir(from: &str, to: &str) -> Result<usize> {
+    let dest = Path::new(to);
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(box_err!("Migration target directory {} is not empty", to));
+    }
+    let files = pipe_log::list_log_files(Path::new(from))?;
+    fs::create_dir_all(dest)?;
+    // Batches copied as they are may be compressed with dictionaries.
+    dictionary::copy_dir(Path::new(from), dest)?;
+
+    let mut sequence = 0;
+    // Whether there are files of the current format.
+    let mut has_current = false;
+    for (i, (file_num, path)) in files.iter().enumerate() {
+        let content = fs::read(path)?;
+        let migrated = match pipe_log::check_file_header(*file_num, &content) {
+            Ok(_) => {
+                has_current = true;
+                content
+            }
+            Err(Error::UnsupportedVersion(_, ref version, false))
+                if pipe_log::parse_version(version.as_bytes()) == Some(V1) =>
+            {
+                let is_last = i + 1 == files.len();
+                migrate_file(*file_num, &content, is_last, &mut sequence)?
+            }
+            Err(e) => return Err(e),
+        };
+        if has_current && sequence > 0 {
+            return Err(box_err!("Raft log files in {} mix format versions", from));
+        }
+
+        let mut file = File::create(dest.join(path.file_name().unwrap()))?;
+        file.write_all(&migrated)?;
+        file.sync_all()?;
+    }
+    File::open(dest)?.sync_all()?;
+    Ok(files.len())
+}
+
+/// Upgrade raft log files of older formats in `dir` in place, and return the
+/// count of upgraded files. Each file is replaced by a synced temporary one, in
+/// the order of files, so a crash leaves the files up to some one upgraded, and
+/// the next upgrade resumes from it. Run by the engine when it opens `dir`.
+pub(crate) fn upgrade_dir(dir: &Path) -> Result<usize> {
+    let files = pipe_log::list_log_files(dir)?;
+    let mut sequence = None;
+    let mut upgraded = 0;
+    for (i, (file_num, path)) in files.iter().enumerate() {
+        let mut header = Vec::with_capacity(FILE_HEADER_LEN);
+        File::open(path)?
+            .take(FILE_HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+        match pipe_log::check_file_header(*file_num, &header) {
+            Err(Error::UnsupportedVersion(_, ref version, false))
+                if pipe_log::parse_version(version.as_bytes()) == Some(V1) => {}
+            Ok(_) if upgraded > 0 => {
+                return Err(box_err!(
+                    "Raft log files in {} mix format versions",
+                    dir.display()
+                ))
+            }
+            // Other files are left to recovery.
+            _ => continue,
+        }
+        let sequence = match sequence {
+            Some(ref mut sequence) => sequence,
+            None => {
+                // Files before it are upgraded by an interrupted upgrade.
+                let mut last = 0;
+                if i > 0 {
+                    let (prev_num, prev_path) = &files[i - 1];
+                    let content = fs::read(prev_path)?;
+                    let header_len = pipe_log::check_file_header(*prev_num, &content)?;
+                    last = last_sequence(&content[header_len..]);
+                }
+                sequence.get_or_insert(last)
+            }
+        };
+        let content = fs::read(path)?;
+        let is_last = i + 1 == files.len();
+        let migrated = migrate_file(*file_num, &content, is_last, sequence)?;
+
+        let tmp_path = pipe_log::tmp_log_file_path(dir.to_str().unwrap(), *file_num);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&migrated)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        File::open(dir)?.sync_all()?;
+        info!("Upgraded raft log file {} to the current format", file_num);
+        upgraded += 1;
+    }
+    Ok(upgraded)
+}
+
+// The sequence of the last batch in `buf`, batches of a file upgraded from an
+// older format, or 0 if there is none.
+fn last_sequence(mut buf: &[u8]) -> u64 {
+    let mut sequence = 0;
+    while buf.len() >= 8 + SEQUENCE_LEN {
+        let batch_len = (BigEndian::read_u64(buf) >> 8) as usize;
+        if batch_len < SEQUENCE_LEN || batch_len > buf.len() - 8 {
+            break;
+        }
+        sequence = BigEndian::read_u64(&buf[8..]);
+        buf = &buf[8 + batch_len..];
+    }
+    sequence
+}
+
+// Insert the next sequence and an unknown timestamp into the header of each
+// batch of a v1 file. A torn batch ends the last file, as recovery does.
+fn migrate_file(
+    file_num: u64,
+    content: &[u8],
+    is_last: bool,
+    sequence: &mut u64,
+) -> Result<Vec<u8>> {
+    let mut migrated = Vec::with_capacity(content.len());
+    migrated.extend_from_slice(&pipe_log::file_header());
+
+    let mut offset = OLD_HEADER_LEN;
+    while offset < content.len() {
+        let buf = &content[offset..];
+        let batch = if buf.len() >= 8 {
+            let header = BigEndian::read_u64(buf);
+            let batch_len = (header >> 8) as usize;
+            if batch_len > CHECKSUM_LEN && batch_len <= buf.len() - 8 {
+                let batch = &buf[8..8 + batch_len];
+                format::test_batch_checksum(batch)
+                    .ok()
+                    .map(|_| (header, batch))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        let (header, batch) = match batch {
+            Some(b) => b,
+            None if is_last => {
+                warn!(
+                    "Drop torn batches of raft log file {} from offset {}",
+                    file_num, offset
+                );
+                break;
+            }
+            None => {
+                return Err(Error::Corruption(
+                    file_num,
+                    offset as u64,
+                    "bad old batch".to_owned(),
+                ))
+            }
+        };
+
+        *sequence += 1;
+        let items = &batch[..batch.len() - CHECKSUM_LEN];
+        let start = migrated.len();
+        let batch_len = (SEQUENCE_LEN + TIMESTAMP_LEN + items.len() + CHECKSUM_LEN) as u64;
+        migrated.extend_from_slice(&(batch_len << 8 | header & 0xff).to_be_bytes());
+        migrated.extend_from_slice(&[0; SEQUENCE_LEN + TIMESTAMP_LEN]);
+        migrated.extend_from_slice(items);
+        migrated.extend_from_slice(&[0; CHECKSUM_LEN]);
+        format::set_sequence(&mut migrated[start..], *sequence);
+        offset += 8 + batch.len();
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use raft::eraftpb::Entry;
+
+    use crate::engine::FileEngine;
+    use crate::util::ReadableSize;
+    use crate::{Config, LogBatch, RaftEngine};
+
+    // Strip the header checksum, sequences and timestamps from a file of the
+    // current format.
+    fn downgrade(content: &[u8]) -> Vec<u8> {
+        let mut old = FILE_MAGIC_HEADER.to_vec();
+        old.extend_from_slice(b"v1.0.0");
+        let mut buf = &content[FILE_HEADER_LEN..];
+        while !buf.is_empty() {
+            let header = BigEndian::read_u64(buf);
+            let batch_len = (header >> 8) as usize;
+            let batch =
+                buf[8 + SEQUENCE_LEN + TIMESTAMP_LEN..8 + batch_len - CHECKSUM_LEN].to_vec();
+            let old_len = (batch.len() + CHECKSUM_LEN) as u64;
+            old.extend_from_slice(&(old_len << 8 | header & 0xff).to_be_bytes());
+            old.extend_from_slice(&batch);
+            old.extend_from_slice(&crc32fast::hash(&batch).to_le_bytes());
+            buf = &buf[8 + batch_len..];
+        }
+        old
+    }
+
+    fn check_migrated(engine: &FileEngine) {
+        assert_eq!(engine.latest_sequence(), 11);
+        for i in 1..=10 {
+            let e = engine.get_entry(1, i).unwrap().unwrap();
+            assert_eq!(e.get_data().len(), i as usize * 100);
+        }
+        assert_eq!(
+            engine.region_kvs(1).unwrap(),
+            vec![(b"k".to_vec(), b"v".to_vec())]
+        );
+        // Times of migrated batches are unknown.
+        assert!(engine.oldest_batch_time().is_none());
+    }
+
+    #[test]
+    fn test_migrate_dir() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_migrate_dir")
+            .tempdir()
+            .unwrap();
+        let old_dir = dir.path().join("v1").to_str().unwrap().to_owned();
+        let mut cfg = Config::default();
+        cfg.dir = old_dir.clone();
+        cfg.target_file_size = ReadableSize::kb(1);
+        {
+            let engine = FileEngine::new(cfg.clone());
+            let mut entry = Entry::new();
+            for i in 1..=10 {
+                entry.set_index(i);
+                entry.set_data(vec![b'x'; i as usize * 100]);
+                engine.append(1, vec![entry.clone()]).unwrap();
+            }
+            let mut batch = LogBatch::new();
+            batch.put(1, b"k", b"v");
+            engine.consume(&mut batch, true).unwrap();
+        }
+        let files = pipe_log::list_log_files(Path::new(&old_dir)).unwrap();
+        for (_, path) in &files {
+            let content = fs::read(path).unwrap();
+            fs::write(path, downgrade(&content)).unwrap();
+        }
+
+        assert!(files.len() > 2);
+
+        // Files can't be upgraded by readers.
+        match FileEngine::open_observer(cfg.clone()) {
+            Err(Error::UnsupportedVersion(1, v, false)) => assert_eq!(v, "v1.0.0"),
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        let new_dir = format!("{}-migrated", old_dir);
+        assert_eq!(migrate_dir(&old_dir, &new_dir).unwrap(), files.len());
+        assert!(migrate_dir(&old_dir, &new_dir).is_err());
+        let mut new_cfg = cfg.clone();
+        new_cfg.dir = new_dir.clone();
+        check_migrated(&FileEngine::open(new_cfg).unwrap());
+
+        // An upgrade interrupted after the first file resumes on open.
+        let new_files = pipe_log::list_log_files(Path::new(&new_dir)).unwrap();
+        fs::copy(&new_files[0].1, &files[0].1).unwrap();
+        check_migrated(&FileEngine::open(cfg.clone()).unwrap());
+        for (_, path) in &files {
+            assert!(pipe_log::check_file_header(1, &fs::read(path).unwrap()).is_ok());
+        }
+        check_migrated(&FileEngine::open(cfg).unwrap());
+
+        // Files written by a newer version are refused.
+        let current_dir = dir.path().join("v1-migrated");
+        let (_, path) = &pipe_log::list_log_files(&current_dir).unwrap()[0];
+        let mut content = fs::read(path).unwrap();
+        content[FILE_MAGIC_HEADER.len()..OLD_HEADER_LEN].copy_from_slice(b"v9.0.0");
+        let checksum = format::crc32(&content[..OLD_HEADER_LEN]);
+        content[OLD_HEADER_LEN..FILE_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(path, content).unwrap();
+        let newer_dir = dir.path().join("v9").to_str().unwrap().to_owned();
+        match migrate_dir(current_dir.to_str().unwrap(), &newer_dir) {
+            Err(Error::UnsupportedVersion(_, version, true)) => assert_eq!(version, "v9.0.0"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -1,27 +1,62 @@
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
 use std::ffi::CString;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::u64;
 
-use super::log_batch::{LogBatch, LogItemType};
-use super::metrics::*;
-use super::{Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::clock::{Clock, SystemClock};
+use super::cold_storage::ObjectStorage;
+use super::dictionary::{self, Dictionaries, Dictionary};
+use super::dir_lock::DirLock;
+use super::errors::{FileIoContext, FileIoResultExt};
+use super::format::{self, Timestamp};
+use super::log_batch::{EntryRetention, LogBatch, LogItemType};
+use super::migrate;
+use super::util::{to_usize, HashMap};
+use super::{Config, Error, Result};
 
 const LOG_SUFFIX: &str = ".raftlog";
 const LOG_SUFFIX_LEN: usize = 8;
+// Suffix of a log file being created, see `new_log_file`.
+const TMP_SUFFIX: &str = ".tmp";
 const FILE_NUM_LEN: usize = 16;
 const FILE_NAME_LEN: usize = FILE_NUM_LEN + LOG_SUFFIX_LEN;
 pub const FILE_MAGIC_HEADER: &[u8] = b"RAFT-LOG-FILE-HEADER-9986AB3E47F320B394C8E84916EB0ED5";
-pub const VERSION: &[u8] = b"v1.0.0";
+// Format version of log files. Any change of the layout of file headers or
+// batches must bump it in the same change as the check refusing other versions,
+// and teach `migrate` the old one. Since v2.0.0, batch headers carry a sequence
+// and a timestamp, and file headers a checksum.
+pub const VERSION: &[u8] = b"v2.0.0";
+/// Length of the little-endian crc32 of the magic and the version ending the
+/// header of a log file, since v2.0.0.
+pub const FILE_HEADER_CHECKSUM_LEN: usize = 4;
+pub const FILE_HEADER_LEN: usize =
+    FILE_MAGIC_HEADER.len() + VERSION.len() + FILE_HEADER_CHECKSUM_LEN;
+// A header whose magic differs by as many bytes is taken as damaged rather than
+// of another kind of file.
+const MAX_DAMAGED_MAGIC_BYTES: usize = 4;
 const INIT_FILE_NUM: u64 = 1;
 const DEFAULT_FILES_COUNT: usize = 32;
+// Placeholder in `LogManager::all_files` for files moved to cold storage.
+const COLD_FILE_FD: libc::c_int = -1;
+// Ranges with smaller gaps between them are read together.
+const MAX_READ_GAP: u64 = 16 * 1024;
+// Files fetched from cold storage kept in memory.
+const COLD_FILE_CACHE_CAPACITY: usize = 4;
 
 #[cfg(target_os = "linux")]
-const FILE_ALLOCATE_SIZE: usize = 2 * 1024 * 1024;
+const FILE_ALLOCATE_SIZE: u64 = 2 * 1024 * 1024;
 #[cfg(target_os = "linux")]
 const NEW_FILE_MODE: libc::mode_t = libc::S_IRUSR | libc::S_IWUSR;
 #[cfg(not(target_os = "linux"))]
@@ -32,9 +67,11 @@ struct LogManager {
     pub active_file_num: u64,
 
     pub active_log_fd: libc::c_int,
-    pub active_log_size: usize,
-    pub active_log_capacity: usize,
-    pub last_sync_size: usize,
+    pub active_log_size: u64,
+    pub active_log_capacity: u64,
+    pub last_sync_size: u64,
+    // The active file is full, but failed to be rotated.
+    pub rotation_failed: bool,
 
     pub all_files: VecDeque<libc::c_int>,
 }
@@ -48,49 +85,286 @@ impl LogManager {
             active_log_size: 0,
             active_log_capacity: 0,
             last_sync_size: 0,
+            rotation_failed: false,
             all_files: VecDeque::with_capacity(DEFAULT_FILES_COUNT),
         }
     }
 }
 
+// Content of a file fetched from cold storage, `None` until it's fetched.
+type ColdFile = Arc<Mutex<Option<Arc<Vec<u8>>>>>;
+
+// Where purged files go instead of being removed.
+struct Archive {
+    dir: PathBuf,
+    // Oldest archived files are removed when their total size exceeds this. 0 means no limit.
+    retention_size: u64,
+    // Archived files older than this are removed. 0 means no limit.
+    retention_age: Duration,
+}
+
+// Reference counts of files being read. Files purged while being read are removed
+// when the last reader is gone.
+#[derive(Default)]
+struct FileRefs {
+    counts: HashMap<u64, usize>,
+    // file number -> fd
+    purged: HashMap<u64, libc::c_int>,
+}
+
+/// Keeps a file from being removed by `PipeLog::purge_to` while alive.
+pub struct FilePin<'a> {
+    pipe_log: &'a PipeLog,
+    file_num: u64,
+}
+
+impl Drop for FilePin<'_> {
+    fn drop(&mut self) {
+        self.pipe_log.unpin(self.file_num);
+    }
+}
+
+// The file following the active one, created in advance by `prepare_next_file`
+// under its temporary name.
+struct PreparedFile {
+    file_num: u64,
+    fd: libc::c_int,
+    // Space allocated from the start of the file.
+    capacity: u64,
+}
+
 pub struct PipeLog {
     log_manager: RwLock<LogManager>,
+    file_refs: Mutex<FileRefs>,
+    // The first and the last files of ranges pinned by `pin_files` -> counts of
+    // pins. Files from the smallest first one on aren't purged, and pinned files
+    // aren't modified or offloaded either.
+    purge_pins: Mutex<BTreeMap<(u64, u64), usize>>,
 
-    rotate_size: usize,
+    rotate_size: u64,
 
     dir: String,
 
-    bytes_per_sync: usize,
+    bytes_per_sync: u64,
 
     // Used when recovering from disk.
     current_read_file_num: u64,
 
     write_lock: Mutex<()>,
+    // Sequence of the last written batch, only updated with `write_lock` held.
+    sequence: AtomicU64,
+    // Sequence of the last batch known to be synced, which all batches before are
+    // as well.
+    durable_sequence: AtomicU64,
+    // Set once the active file fails to be synced. Data written before may be
+    // dropped by the kernel while later syncs succeed, so nothing is written or
+    // synced since, to never ack lost data as durable.
+    poisoned: AtomicBool,
+    // Timestamp of the last written batch, like the sequence.
+    timestamp: AtomicU64,
+    // File number -> timestamps of the oldest and newest batches in the file.
+    batch_times: Mutex<BTreeMap<u64, (Timestamp, Timestamp)>>,
+    // Whether batches are compressed, and the dictionary for small ones.
+    compression: AtomicBool,
+    dictionary: RwLock<Option<Arc<Dictionary>>>,
+    // Dictionaries to decode batches of this log, and the last file each one may
+    // be used by. Saved ones are removed once that file is purged.
+    dictionaries: Dictionaries,
+    dict_files: Mutex<HashMap<u32, u64>>,
+
+    cold_storage: Option<Arc<dyn ObjectStorage>>,
+    // Files recently fetched from cold storage, or being fetched, the most recent
+    // last. Each is fetched with its own lock held, so that readers of other files
+    // don't wait for it.
+    cold_file_cache: Mutex<VecDeque<(u64, ColdFile)>>,
+
+    archive: Option<Archive>,
+    // Ages of archived files are measured by it.
+    clock: Arc<dyn Clock>,
+    // Name of the engine, in log messages.
+    name: String,
+
+    // Opened to follow files written by another process.
+    read_only: bool,
+    // Held while the directory is opened for writing, until closed.
+    dir_lock: Mutex<Option<DirLock>>,
+    // Whether `scan_file` keeps its reads out of the page cache.
+    scan_bypass_cache: bool,
+    // Whether `fread` serves sealed files from memory maps of them.
+    mmap_sealed_files: bool,
+    // File number -> memory map of the sealed file, created on first read.
+    file_maps: Mutex<HashMap<u64, Arc<FileMap>>>,
+    // Taken by the next rotation if it's the file rotated to.
+    prepared_file: Mutex<Option<PreparedFile>>,
+    preparing: Mutex<()>,
+    // Notified after each rotation, e.g. to prepare the next file.
+    rotation_listener: Mutex<Option<Sender<u64>>>,
+
+    // Cumulative statistics.
+    bytes_written: AtomicU64,
+    // Including file headers and hole headers.
+    disk_bytes_written: AtomicU64,
+    batches_written: AtomicU64,
+    bytes_read: AtomicU64,
+    files_purged: AtomicU64,
 }
 
 impl PipeLog {
-    pub fn new(dir: &str, bytes_per_sync: usize, rotate_size: usize) -> PipeLog {
+    pub fn new(dir: &str, bytes_per_sync: u64, rotate_size: u64) -> PipeLog {
         PipeLog {
             log_manager: RwLock::new(LogManager::new()),
+            file_refs: Mutex::new(FileRefs::default()),
+            purge_pins: Mutex::new(BTreeMap::new()),
             rotate_size,
             dir: dir.to_string(),
             bytes_per_sync,
             current_read_file_num: 0,
             write_lock: Mutex::new(()),
+            sequence: AtomicU64::new(0),
+            durable_sequence: AtomicU64::new(0),
+            poisoned: AtomicBool::new(false),
+            timestamp: AtomicU64::new(0),
+            batch_times: Mutex::new(BTreeMap::new()),
+            compression: AtomicBool::new(true),
+            dictionary: RwLock::new(None),
+            dictionaries: Dictionaries::default(),
+            dict_files: Mutex::new(HashMap::default()),
+            cold_storage: None,
+            cold_file_cache: Mutex::new(VecDeque::new()),
+            archive: None,
+            clock: Arc::new(SystemClock),
+            name: Config::default().name,
+            read_only: false,
+            dir_lock: Mutex::new(None),
+            scan_bypass_cache: false,
+            mmap_sealed_files: false,
+            file_maps: Mutex::new(HashMap::default()),
+            prepared_file: Mutex::new(None),
+            preparing: Mutex::new(()),
+            rotation_listener: Mutex::new(None),
+            bytes_written: AtomicU64::new(0),
+            disk_bytes_written: AtomicU64::new(0),
+            batches_written: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            files_purged: AtomicU64::new(0),
         }
     }
 
-    pub fn open(dir: &str, bytes_per_sync: usize, rotate_size: usize) -> Result<PipeLog> {
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+
+    pub fn set_scan_bypass_cache(&mut self, bypass: bool) {
+        self.scan_bypass_cache = bypass;
+    }
+
+    pub fn set_mmap_sealed_files(&mut self, enabled: bool) {
+        self.mmap_sealed_files = enabled;
+    }
+
+    /// Whether batches written since are compressed if they are large.
+    pub fn set_compression(&self, enabled: bool) {
+        self.compression.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Compress small batches written since with `dict`, which is saved in the
+    /// directory for reading them later.
+    pub fn set_dictionary(&self, dict: Option<Dictionary>) -> Result<()> {
+        let dict = match dict {
+            Some(dict) => {
+                if !self.read_only {
+                    dictionary::save(Path::new(&self.dir), &dict)?;
+                    let active_file_num = self.active_file_num();
+                    let mut dict_files = self.dict_files.lock().unwrap();
+                    let last = dict_files.entry(dict.id()).or_insert(active_file_num);
+                    *last = cmp::max(*last, active_file_num);
+                }
+                let dict = Arc::new(dict);
+                self.dictionaries.register(dict.clone());
+                Some(dict)
+            }
+            None => None,
+        };
+        *self.dictionary.write().unwrap() = dict;
+        Ok(())
+    }
+
+    /// Dictionaries that batches of this log may be compressed with.
+    pub fn dictionaries(&self) -> &Dictionaries {
+        &self.dictionaries
+    }
+
+    /// Move purged files into `dir` instead of removing them.
+    pub fn set_archive(
+        &mut self,
+        dir: &str,
+        retention_size: u64,
+        retention_age: Duration,
+    ) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        self.archive = Some(Archive {
+            dir: PathBuf::from(dir),
+            retention_size,
+            retention_age,
+        });
+        Ok(())
+    }
+
+    pub fn open(dir: &str, bytes_per_sync: u64, rotate_size: u64) -> Result<PipeLog> {
+        PipeLog::open_with_cold_storage(dir, bytes_per_sync, rotate_size, None)
+    }
+
+    /// Like `open`, but files that have been moved to `cold_storage` by `offload_to`
+    /// are also taken into account.
+    pub fn open_with_cold_storage(
+        dir: &str,
+        bytes_per_sync: u64,
+        rotate_size: u64,
+        cold_storage: Option<Arc<dyn ObjectStorage>>,
+    ) -> Result<PipeLog> {
+        PipeLog::open_impl(dir, bytes_per_sync, rotate_size, cold_storage, false)
+    }
+
+    /// Open files written by another `PipeLog` without modifying them. Files
+    /// created later by the writer can be followed with `follow_next_file`.
+    pub fn open_read_only(
+        dir: &str,
+        rotate_size: u64,
+        cold_storage: Option<Arc<dyn ObjectStorage>>,
+    ) -> Result<PipeLog> {
+        PipeLog::open_impl(dir, 0, rotate_size, cold_storage, true)
+    }
+
+    fn open_impl(
+        dir: &str,
+        bytes_per_sync: u64,
+        rotate_size: u64,
+        cold_storage: Option<Arc<dyn ObjectStorage>>,
+        read_only: bool,
+    ) -> Result<PipeLog> {
         let path = Path::new(dir);
+        if !path.exists() && read_only {
+            return Err(box_err!("Raft log directory {} doesn't exist", dir));
+        }
         if !path.exists() {
             info!("Create raft log directory: {}", dir);
-            fs::create_dir(dir)
-                .unwrap_or_else(|e| panic!("Create raft log directory failed, err: {:?}", e));
+            fs::create_dir(dir)?;
         }
 
         if !path.is_dir() {
             return Err(box_err!("Not directory."));
         }
+        let dir_lock = if read_only {
+            None
+        } else {
+            Some(DirLock::acquire(path)?)
+        };
+        let dictionaries = Dictionaries::default();
+        let mut dict_ids = dictionaries.load_dir(path)?;
 
         let mut min_file_num: u64 = u64::MAX;
         let mut max_file_num: u64 = 0;
@@ -104,6 +378,12 @@ impl PipeLog {
             }
 
             let file_name = file_path.file_name().unwrap().to_str().unwrap();
+            if file_name.ends_with(TMP_SUFFIX) && !read_only {
+                // Left by a crash during file creation, the file has never been used.
+                info!("Remove unfinished raft log file {}", file_path.display());
+                fs::remove_file(&file_path)?;
+                continue;
+            }
             if file_name.ends_with(LOG_SUFFIX) && file_name.len() == FILE_NAME_LEN {
                 let file_num = match extract_file_num(file_name) {
                     Ok(num) => num,
@@ -116,17 +396,56 @@ impl PipeLog {
                 log_files.push(file_name.to_string());
             }
         }
+        if !read_only {
+            // Files of older formats are read after being upgraded.
+            migrate::upgrade_dir(path)?;
+        }
+        if let Some(storage) = cold_storage.as_ref() {
+            for file_name in storage.list()? {
+                if dictionary::is_dict_file(&file_name) {
+                    let id = dictionaries.load(&file_name, storage.get(&file_name)?)?;
+                    if !dict_ids.contains(&id) {
+                        dict_ids.push(id);
+                    }
+                    continue;
+                }
+                if file_name.ends_with(LOG_SUFFIX) && file_name.len() == FILE_NAME_LEN {
+                    if let Ok(file_num) = extract_file_num(&file_name) {
+                        min_file_num = cmp::min(min_file_num, file_num);
+                        max_file_num = cmp::max(max_file_num, file_num);
+                        log_files.push(file_name);
+                    }
+                }
+            }
+        }
 
         // Initialize.
         let mut pipe_log = PipeLog::new(dir, bytes_per_sync, rotate_size);
+        pipe_log.cold_storage = cold_storage;
+        pipe_log.read_only = read_only;
+        pipe_log.dir_lock = Mutex::new(dir_lock);
+        pipe_log.dictionaries = dictionaries;
+        if !read_only {
+            // Any file may use a loaded dictionary.
+            let last = cmp::max(max_file_num, pipe_log.active_file_num());
+            let dict_files = pipe_log.dict_files.get_mut().unwrap();
+            dict_files.extend(dict_ids.into_iter().map(|id| (id, last)));
+        }
+        if log_files.is_empty() && read_only {
+            return Err(box_err!("No raft log file in {}", dir));
+        }
         if log_files.is_empty() {
             {
                 let mut manager = pipe_log.log_manager.write().unwrap();
-                let new_fd = new_log_file(&pipe_log.dir, manager.active_file_num);
+                let new_fd = new_log_file(&pipe_log.dir, manager.active_file_num)?;
                 manager.active_log_fd = new_fd;
                 manager.all_files.push_back(new_fd);
+                manager.active_log_size = file_header_len();
+                manager.last_sync_size = manager.active_log_size;
             }
-            pipe_log.write_header()?;
+            pipe_log
+                .disk_bytes_written
+                .fetch_add(file_header_len(), Ordering::Relaxed);
             return Ok(pipe_log);
         }
 
@@ -142,6 +461,11 @@ impl PipeLog {
             manager.active_file_num = max_file_num;
         }
         pipe_log.open_all_files()?;
+        if !read_only && pipe_log.active_log_size() < file_header_len() {
+            // Only files created before headers were written atomically can lack one.
+            pipe_log.truncate_active_log(0)?;
+            pipe_log.write_header()?;
+        }
         Ok(pipe_log)
     }
 
@@ -149,10 +473,18 @@ impl PipeLog {
         let mut manager = self.log_manager.write().unwrap();
         let mut current_file = manager.first_file_num;
         while current_file <= manager.active_file_num {
-            let mut path = PathBuf::from(&self.dir);
-            path.push(generate_file_name(current_file));
+            let path = log_file_path(&self.dir, current_file);
+
+            if self.cold_storage.is_some()
+                && current_file < manager.active_file_num
+                && !path.exists()
+            {
+                manager.all_files.push_back(COLD_FILE_FD);
+                current_file += 1;
+                continue;
+            }
 
-            let mode = if current_file < manager.active_file_num {
+            let mode = if current_file < manager.active_file_num || self.read_only {
                 // Open inactive files with readonly mode.
                 libc::O_RDONLY
             } else {
@@ -161,14 +493,14 @@ impl PipeLog {
             };
 
             let path_cstr = CString::new(path.as_path().to_str().unwrap().as_bytes()).unwrap();
-            let fd = unsafe { libc::open(path_cstr.as_ptr(), mode) };
-            if fd < 0 {
-                panic!("open file failed, err {}", errno::errno().to_string());
-            }
+            let fd = cvt(unsafe { libc::open(path_cstr.as_ptr(), mode) })
+                .file_context(|| file_io_context(&self.dir, "open", current_file, None))?;
             manager.all_files.push_back(fd);
             if current_file == manager.active_file_num {
+                let size = cvt(unsafe { libc::lseek(fd, 0, libc::SEEK_END) })
+                    .file_context(|| file_io_context(&self.dir, "seek", current_file, None))?;
                 manager.active_log_fd = fd;
-                manager.active_log_size = unsafe { libc::lseek(fd, 0, libc::SEEK_END) as usize };
+                manager.active_log_size = size as u64;
                 manager.active_log_capacity = manager.active_log_size;
             }
             current_file += 1;
@@ -176,52 +508,132 @@ impl PipeLog {
         Ok(())
     }
 
-    pub fn fread(&self, file_num: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+    /// Read `ranges` of (offset, len) of the file. Ranges close to each other are
+    /// read by one syscall, which is cheaper than one for each of scattered entries.
+    pub fn fread_many(&self, file_num: u64, ranges: &[(u64, u64)]) -> Result<Vec<Vec<u8>>> {
+        let mut order: Vec<usize> = (0..ranges.len()).collect();
+        order.sort_by_key(|&i| ranges[i].0);
+        let mut results = vec![vec![]; ranges.len()];
+        let mut i = 0;
+        while i < order.len() {
+            let start = ranges[order[i]].0;
+            let mut end = start + ranges[order[i]].1;
+            let mut j = i + 1;
+            while j < order.len() && ranges[order[j]].0 <= end + MAX_READ_GAP {
+                end = cmp::max(end, ranges[order[j]].0 + ranges[order[j]].1);
+                j += 1;
+            }
+            let span = self.fread(file_num, start, end - start)?;
+            for &k in &order[i..j] {
+                let (offset, len) = ranges[k];
+                let begin = (offset - start) as usize;
+                results[k] = span[begin..begin + len as usize].to_vec();
+            }
+            i = j;
+        }
+        Ok(results)
+    }
+
+    /// Advise the OS to drop cached pages of `ranges` of (offset, len) of the file,
+    /// e.g. after they are read for rewriting and won't be read again, so that
+    /// they don't evict hot pages of foreground reads.
+    pub fn drop_page_cache(&self, file_num: u64, ranges: &[(u64, u64)]) {
         let manager = self.log_manager.read().unwrap();
         if file_num < manager.first_file_num || file_num > manager.active_file_num {
+            return;
+        }
+        let fd = manager.all_files[(file_num - manager.first_file_num) as usize];
+        if fd == COLD_FILE_FD {
+            return;
+        }
+        let mut ranges = ranges.to_vec();
+        ranges.sort_unstable();
+        let mut i = 0;
+        while i < ranges.len() {
+            let (start, mut end) = (ranges[i].0, ranges[i].0 + ranges[i].1);
+            i += 1;
+            while i < ranges.len() && ranges[i].0 <= end {
+                end = cmp::max(end, ranges[i].0 + ranges[i].1);
+                i += 1;
+            }
+            drop_cached_pages(fd, start, end - start);
+        }
+    }
+
+    pub fn fread(&self, file_num: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let manager = self.log_manager.read().unwrap();
+        let purged_fd = if file_num < manager.first_file_num {
+            self.file_refs
+                .lock()
+                .unwrap()
+                .purged
+                .get(&file_num)
+                .cloned()
+        } else {
+            None
+        };
+        if purged_fd.is_none()
+            && (file_num < manager.first_file_num || file_num > manager.active_file_num)
+        {
             return Err(box_err!("File not exist, file number {}", file_num));
         }
 
-        let mut result: Vec<u8> = Vec::with_capacity(len as usize);
-        let buf = result.as_mut_ptr();
-        unsafe {
-            let fd = manager.all_files[(file_num - manager.first_file_num) as usize];
-            loop {
-                let ret_size = libc::pread(
-                    fd,
-                    buf as *mut libc::c_void,
-                    len as libc::size_t,
-                    offset as libc::off_t,
-                );
-                if ret_size < 0 {
-                    let err = errno::errno();
-                    if err.0 == libc::EAGAIN {
-                        continue;
-                    }
-                    panic!("pread failed, err {}", err.to_string());
-                }
-                if ret_size as u64 != len {
-                    error!(
-                        "Pread failed, expected return size {}, actual return size {}",
-                        len, ret_size
-                    );
-                    return Err(box_err!(
-                        "Pread failed, expected return size {}, actual return size {}",
-                        len,
-                        ret_size
-                    ));
+        let fd = purged_fd
+            .unwrap_or_else(|| manager.all_files[(file_num - manager.first_file_num) as usize]);
+        if fd == COLD_FILE_FD {
+            // Fetched without the lock, which would block rotations and purges
+            // meanwhile. The pin keeps the file in cold storage instead.
+            drop(manager);
+            let _pin = self.pin(file_num)?;
+            let content = self.fetch_cold_file(file_num)?;
+            return match offset.checked_add(len) {
+                Some(end) if end <= content.len() as u64 => {
+                    Ok(content[offset as usize..end as usize].to_vec())
                 }
-                break;
-            }
-            result.set_len(len as usize);
+                _ => Err(box_err!(
+                    "Read cold file {} out of range, offset {}, len {}, file size {}",
+                    file_num,
+                    offset,
+                    len,
+                    content.len()
+                )),
+            };
         }
 
+        let mut result = vec![0; to_usize(len)?];
+        // Sealed files never change but for holes, which are seen through maps.
+        let map = if self.mmap_sealed_files && file_num < manager.active_file_num {
+            Some(self.file_map(file_num, fd))
+        } else {
+            None
+        };
+        inject_fault(&self.dir, "read")
+            .and_then(|_| match map {
+                Some(map) => map?.read_exact(&mut result, offset),
+                None => pread_exact(fd, &mut result, offset),
+            })
+            .file_context(|| file_io_context(&self.dir, "read", file_num, Some(offset)))
+            .map_err(|e| {
+                error!("[{}] {}", self.name, e);
+                e
+            })?;
+        self.bytes_read.fetch_add(len, Ordering::Relaxed);
         Ok(result)
     }
 
+    fn file_map(&self, file_num: u64, fd: libc::c_int) -> std::io::Result<Arc<FileMap>> {
+        let mut maps = self.file_maps.lock().unwrap();
+        if let Some(map) = maps.get(&file_num) {
+            return Ok(map.clone());
+        }
+        let map = Arc::new(FileMap::new(fd)?);
+        maps.insert(file_num, map.clone());
+        Ok(map)
+    }
+
     pub fn close(&self) -> Result<()> {
         let _write_lock = self.write_lock.lock().unwrap();
-        {
+        if !self.read_only {
             let active_log_size = {
                 let manager = self.log_manager.read().unwrap();
                 manager.active_log_size
@@ -230,14 +642,25 @@ impl PipeLog {
         }
         unsafe {
             let manager = self.log_manager.read().unwrap();
-            for fd in manager.all_files.iter() {
+            for fd in manager.all_files.iter().filter(|fd| **fd != COLD_FILE_FD) {
                 libc::close(*fd);
             }
         }
+        self.file_maps.lock().unwrap().clear();
+        if let Some(prepared) = self.prepared_file.lock().unwrap().take() {
+            discard_prepared_file(&self.dir, prepared);
+        }
+        self.dir_lock.lock().unwrap().take();
         Ok(())
     }
 
     fn append(&self, content: &[u8], sync: bool) -> Result<(u64, u64)> {
+        self.check_poisoned()?;
+        // The active file is left full by a failed rotation, which is retried
+        // before anything is written to it again.
+        if self.log_manager.read().unwrap().rotation_failed {
+            self.rotate_log()?;
+        }
         let (active_log_fd, mut active_log_size, last_sync_size, file_num, offset) = {
             let manager = self.log_manager.read().unwrap();
             (
@@ -245,19 +668,21 @@ impl PipeLog {
                 manager.active_log_size,
                 manager.last_sync_size,
                 manager.active_file_num,
-                manager.active_log_size as u64,
+                manager.active_log_size,
             )
         };
+        let new_size = active_log_size
+            .checked_add(content.len() as u64)
+            .ok_or_else(|| -> Error { box_err!("Active log size overflow") })?;
+        // Make sure the whole write range is addressable before touching the file.
+        to_off_t(new_size)?;
         #[cfg(target_os = "linux")]
         {
             // Use fallocate to pre-allocate disk space for active file. fallocate is faster than File::set_len,
             // because it will not fill the space with 0s, but File::set_len does.
-            let (new_size, mut active_log_capacity) = {
+            let mut active_log_capacity = {
                 let manager = self.log_manager.read().unwrap();
-                (
-                    manager.active_log_size + content.len(),
-                    manager.active_log_capacity,
-                )
+                manager.active_log_capacity
             };
             while active_log_capacity < new_size {
                 let allocate_ret = unsafe {
@@ -269,13 +694,9 @@ impl PipeLog {
                     )
                 };
 
-                if allocate_ret != 0 {
-                    panic!(
-                        "Allocate disk space for active log failed, ret {}, err {}",
-                        allocate_ret,
-                        errno::errno().to_string()
-                    );
-                }
+                cvt(allocate_ret).file_context(|| {
+                    file_io_context(&self.dir, "allocate", file_num, Some(active_log_capacity))
+                })?;
                 {
                     let mut manager = self.log_manager.write().unwrap();
                     manager.active_log_capacity += FILE_ALLOCATE_SIZE;
@@ -285,27 +706,24 @@ impl PipeLog {
         }
 
         // Write to file
-        let mut written_bytes: usize = 0;
-        let len = content.len();
-        while written_bytes < len {
-            let write_ret = unsafe {
-                libc::pwrite(
-                    active_log_fd,
-                    content.as_ptr().add(written_bytes) as *const libc::c_void,
-                    (len - written_bytes) as libc::size_t,
-                    active_log_size as libc::off_t,
-                )
-            };
-            if write_ret >= 0 {
-                active_log_size += write_ret as usize;
-                written_bytes += write_ret as usize;
-                continue;
-            }
-            let err = errno::errno();
-            if err.0 != libc::EAGAIN {
-                panic!("Write to active log failed, err {}", err.to_string());
+        let res = inject_fault(&self.dir, "write")
+            .and_then(|_| pwrite_all(active_log_fd, content, active_log_size))
+            .file_context(|| file_io_context(&self.dir, "write", file_num, Some(active_log_size)));
+        if let Err(e) = res {
+            // Drop whatever is written, so that the next write starts at the tail.
+            let truncated =
+                cvt(unsafe { libc::ftruncate(active_log_fd, to_off_t(active_log_size)?) })
+                    .file_context(|| {
+                        file_io_context(&self.dir, "truncate", file_num, Some(active_log_size))
+                    });
+            if let Err(te) = truncated {
+                self.poison(te);
             }
+            return Err(e);
         }
+        self.disk_bytes_written
+            .fetch_add(content.len() as u64, Ordering::Relaxed);
+        active_log_size = new_size;
         {
             // Update active log size.
             let mut manager = self.log_manager.write().unwrap();
@@ -316,10 +734,11 @@ impl PipeLog {
         if sync
             || self.bytes_per_sync > 0 && active_log_size - last_sync_size >= self.bytes_per_sync
         {
-            let sync_ret = unsafe { libc::fsync(active_log_fd) };
-            if sync_ret != 0 {
-                panic!("fsync failed, err {}", errno::errno().to_string());
-            }
+            inject_fault(&self.dir, "sync")
+                .and_then(|_| cvt(unsafe { libc::fsync(active_log_fd) }))
+                .file_context(|| file_io_context(&self.dir, "sync", file_num, None))
+                .map_err(|e| self.poison(e))?;
+            on_synced(&self.dir, file_num, active_log_size);
             {
                 // Update last sync size.
                 let mut manager = self.log_manager.write().unwrap();
@@ -327,9 +746,17 @@ impl PipeLog {
             }
         }
 
-        // Rotate if needed
+        // Rotate if needed. The content is written already, so it's reported
+        // written even if the rotation fails, and the next write fails instead
+        // until the rotation is done.
         if active_log_size >= self.rotate_size {
-            self.rotate_log();
+            if let Err(e) = self.rotate_log() {
+                warn!(
+                    "[{}] Rotate raft log file {} failed: {}",
+                    self.name, file_num, e
+                );
+                self.log_manager.write().unwrap().rotation_failed = true;
+            }
         }
 
         Ok((file_num, offset))
@@ -337,19 +764,22 @@ impl PipeLog {
 
     fn write_header(&self) -> Result<(u64, u64)> {
         // Write HEADER.
-        let mut header = Vec::with_capacity(FILE_MAGIC_HEADER.len() + VERSION.len());
-        header.extend_from_slice(FILE_MAGIC_HEADER);
-        header.extend_from_slice(VERSION);
-        self.append(header.as_slice(), true)
+        self.append(&file_header(), true)
     }
 
-    fn rotate_log(&self) {
+    // Switch to a new active file. It's all or nothing: the new file is only
+    // installed with its header synced, and the old one is left active as it is
+    // if the new one can't be.
+    fn rotate_log(&self) -> Result<()> {
         {
             let active_log_size = {
                 let manager = self.log_manager.read().unwrap();
                 manager.active_log_size
             };
-            self.truncate_active_log(active_log_size).unwrap();
+            self.truncate_active_log(active_log_size)?;
+            // The file may never be synced after it's rotated, and a torn file in
+            // the middle fails recovery.
+            self.sync()?;
         }
 
         // New log file.
@@ -357,33 +787,160 @@ impl PipeLog {
             let manager = self.log_manager.read().unwrap();
             manager.active_file_num + 1
         };
-        let new_fd = new_log_file(&self.dir, next_file_num);
+        // Held until the new file is active, so that a file prepared meanwhile is
+        // known to be stale.
+        let mut prepared_file = self.prepared_file.lock().unwrap();
+        let prepared = prepared_file.take();
+        let (new_fd, capacity) = match prepared {
+            Some(prepared) if prepared.file_num == next_file_num => {
+                let tmp_path = prepared_log_file_path(&self.dir, next_file_num);
+                if let Err(e) = install_log_file(&self.dir, next_file_num, &tmp_path) {
+                    discard_prepared_file(&self.dir, prepared);
+                    return Err(e);
+                }
+                (prepared.fd, prepared.capacity)
+            }
+            prepared => {
+                if let Some(prepared) = prepared {
+                    discard_prepared_file(&self.dir, prepared);
+                }
+                (new_log_file(&self.dir, next_file_num)?, 0)
+            }
+        };
         {
             let mut manager = self.log_manager.write().unwrap();
             manager.all_files.push_back(new_fd);
             manager.active_log_fd = new_fd;
-            manager.active_log_size = 0;
-            manager.active_log_capacity = 0;
-            manager.last_sync_size = 0;
+            manager.active_log_size = file_header_len();
+            manager.active_log_capacity = capacity;
+            manager.last_sync_size = manager.active_log_size;
             manager.active_file_num = next_file_num;
+            manager.rotation_failed = false;
         }
+        drop(prepared_file);
+        self.disk_bytes_written
+            .fetch_add(file_header_len(), Ordering::Relaxed);
+        if let Some(listener) = self.rotation_listener.lock().unwrap().as_ref() {
+            let _ = listener.send(next_file_num);
+        }
+        Ok(())
+    }
+
+    /// Send the number of the new active file to `listener` after each rotation.
+    pub fn set_rotation_listener(&self, listener: Option<Sender<u64>>) {
+        *self.rotation_listener.lock().unwrap() = listener;
+    }
 
-        // Write Header
-        self.write_header()
-            .unwrap_or_else(|e| panic!("Write header failed, error {:?}", e));
+    /// Create the file following the active one in advance, with its header
+    /// written and its space allocated, so that rotating to it only takes a rename
+    /// instead of stalling the write triggering the rotation. It's left under a
+    /// temporary name until then. Return whether a file is prepared.
+    pub fn prepare_next_file(&self) -> Result<bool> {
+        if self.read_only {
+            return Ok(false);
+        }
+        // Only one file is built at a time, without blocking rotations.
+        let _preparing = self.preparing.lock().unwrap();
+        let next_file_num = self.active_file_num() + 1;
+        {
+            let mut prepared = self.prepared_file.lock().unwrap();
+            match prepared.take() {
+                Some(p) if p.file_num == next_file_num => {
+                    *prepared = Some(p);
+                    return Ok(false);
+                }
+                Some(p) => discard_prepared_file(&self.dir, p),
+                None => {}
+            }
+        }
+        let tmp_path = prepared_log_file_path(&self.dir, next_file_num);
+        let fd = create_log_file(&self.dir, next_file_num, &tmp_path)?;
+        let mut capacity = 0;
+        #[cfg(target_os = "linux")]
+        while capacity < self.rotate_size {
+            let res = cvt(unsafe {
+                libc::fallocate(
+                    fd,
+                    libc::FALLOC_FL_KEEP_SIZE,
+                    capacity as libc::off_t,
+                    FILE_ALLOCATE_SIZE as libc::off_t,
+                )
+            })
+            .file_context(|| file_io_context(&self.dir, "allocate", next_file_num, Some(capacity)));
+            if let Err(e) = res {
+                discard_prepared_file(
+                    &self.dir,
+                    PreparedFile {
+                        file_num: next_file_num,
+                        fd,
+                        capacity,
+                    },
+                );
+                return Err(e);
+            }
+            capacity += FILE_ALLOCATE_SIZE;
+        }
+        let file = PreparedFile {
+            file_num: next_file_num,
+            fd,
+            capacity,
+        };
+        // Rotations take the prepared file with the mutex held, and the active file
+        // is only changed by them.
+        let mut prepared = self.prepared_file.lock().unwrap();
+        if self.active_file_num() + 1 != next_file_num {
+            // Rotated to another file while it's built.
+            discard_prepared_file(&self.dir, file);
+            return Ok(false);
+        }
+        *prepared = Some(file);
+        Ok(true)
     }
 
     pub fn append_log_batch(
         &self,
-        batch: &LogBatch,
+        batch: &mut LogBatch,
         sync: bool,
         file_num: &mut u64,
     ) -> Result<usize> {
-        if let Some(content) = batch.encode_to_bytes() {
+        if self.read_only {
+            return Err(box_err!("Can't write to read-only raft log."));
+        }
+        let compress = self.compression.load(Ordering::Relaxed);
+        let dictionary = self.dictionary.read().unwrap().clone();
+        if let Some(mut content) =
+            batch.encode_to_bytes_with_compression(compress, dictionary.as_deref())
+        {
             let bytes = content.len();
+            // Only the sequence is left to hash with the write lock held.
+            let checksum = format::content_checksum(&content);
             let (cur_file_num, offset) = {
                 let _write_lock = self.write_lock.lock().unwrap();
-                self.append(&content, sync)?
+                let sequence = self.sequence.load(Ordering::Relaxed) + 1;
+                let timestamp =
+                    Timestamp(self.timestamp.load(Ordering::Relaxed)).next(self.clock.now());
+                format::set_sequence_with(&mut content, sequence, timestamp, &checksum);
+                if let Some(dict) = dictionary.as_ref() {
+                    // The batch is written to the active file, or the next one if
+                    // it's rotated first.
+                    self.record_dict_file(dict, self.active_file_num() + 1)?;
+                }
+                let res = self.append(&content, sync)?;
+                self.sequence.store(sequence, Ordering::Release);
+                // Synced by the write itself, or with its file when it's rotated.
+                let synced = {
+                    let manager = self.log_manager.read().unwrap();
+                    manager.active_file_num > res.0
+                        || manager.last_sync_size >= res.1 + bytes as u64
+                };
+                if synced {
+                    self.mark_durable(sequence);
+                }
+                self.record_batch_time(res.0, timestamp);
+                batch.sequence = sequence;
+                batch.timestamp = timestamp;
+                batch.offset = res.1;
+                res
             };
             for item in batch.items.borrow_mut().iter_mut() {
                 match item.item_type {
@@ -392,30 +949,154 @@ impl PipeLog {
                         .as_mut()
                         .unwrap()
                         .update_offset_when_needed(cur_file_num, offset),
-                    LogItemType::KV | LogItemType::CMD => {}
+                    LogItemType::KV => item
+                        .kv
+                        .as_ref()
+                        .unwrap()
+                        .update_offset_when_needed(cur_file_num, offset),
+                    LogItemType::CMD => {}
                 }
             }
             *file_num = cur_file_num;
+            batch.drop_written_entries();
+            // Keep the written batch unless the uncompressed one is kept already.
+            if batch.retention == EntryRetention::Encoded && batch.encoded.borrow().is_none() {
+                *batch.encoded.borrow_mut() = Some(content);
+            }
+            self.bytes_written
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+            self.batches_written.fetch_add(1, Ordering::Relaxed);
             return Ok(bytes);
         }
         Ok(0)
     }
 
+    /// Total bytes of batches written since opened.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes written to disk since opened, including file headers.
+    pub fn disk_bytes_written(&self) -> u64 {
+        self.disk_bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Total count of batches written since opened.
+    pub fn batches_written(&self) -> u64 {
+        self.batches_written.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes read by `fread` since opened.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Total count of files purged since opened.
+    pub fn files_purged(&self) -> u64 {
+        self.files_purged.load(Ordering::Relaxed)
+    }
+
+    /// Sequence of the last written batch.
+    pub fn latest_sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Acquire)
+    }
+
+    /// Record a batch written or recovered in the file, so that later batches get
+    /// later timestamps. Unknown timestamps are ignored.
+    pub fn record_batch_time(&self, file_num: u64, timestamp: Timestamp) {
+        if timestamp == Timestamp::default() {
+            return;
+        }
+        let mut batch_times = self.batch_times.lock().unwrap();
+        let range = batch_times
+            .entry(file_num)
+            .or_insert((timestamp, timestamp));
+        range.0 = cmp::min(range.0, timestamp);
+        range.1 = cmp::max(range.1, timestamp);
+        if timestamp.0 > self.timestamp.load(Ordering::Relaxed) {
+            self.timestamp.store(timestamp.0, Ordering::Relaxed);
+        }
+    }
+
+    /// Timestamps of the oldest and newest batches of each file not purged, from
+    /// the oldest file. Files without batches of known timestamps are left out.
+    pub fn batch_times(&self) -> Vec<(u64, Timestamp, Timestamp)> {
+        let first_file_num = self.first_file_num();
+        let batch_times = self.batch_times.lock().unwrap();
+        batch_times
+            .range(first_file_num..)
+            .map(|(file_num, range)| (*file_num, range.0, range.1))
+            .collect()
+    }
+
+    /// Used after recovery, the next written batch gets `sequence + 1`.
+    pub fn set_latest_sequence(&self, sequence: u64) {
+        let _write_lock = self.write_lock.lock().unwrap();
+        self.sequence.store(sequence, Ordering::Release);
+        self.durable_sequence.store(sequence, Ordering::Release);
+    }
+
+    /// Sequence of the last batch known to be synced, along with all batches
+    /// before it.
+    pub fn durable_sequence(&self) -> u64 {
+        self.durable_sequence.load(Ordering::Acquire)
+    }
+
+    fn mark_durable(&self, sequence: u64) {
+        let mut durable = self.durable_sequence.load(Ordering::Acquire);
+        while durable < sequence {
+            match self.durable_sequence.compare_exchange_weak(
+                durable,
+                sequence,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(d) => durable = d,
+            }
+        }
+    }
+
     pub fn purge_to(&self, file_num: u64) -> Result<()> {
+        if self.read_only {
+            return Err(box_err!("Can't purge read-only raft log."));
+        }
+        self.purge_files_to(file_num)
+    }
+
+    /// For a read-only `PipeLog`, the first file not purged by the writer yet.
+    pub fn writer_first_file_num(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        let mut file_num = manager.first_file_num;
+        // Files are purged oldest first.
+        while file_num < manager.active_file_num
+            && manager.all_files[(file_num - manager.first_file_num) as usize] != COLD_FILE_FD
+            && !log_file_path(&self.dir, file_num).exists()
+        {
+            file_num += 1;
+        }
+        file_num
+    }
+
+    /// For a read-only `PipeLog`, close files before `file_num` purged by the
+    /// writer, once they aren't read any more.
+    pub fn follow_purges(&self, file_num: u64) -> Result<()> {
+        assert!(self.read_only);
+        self.purge_files_to(file_num)
+    }
+
+    fn purge_files_to(&self, file_num: u64) -> Result<()> {
         let (mut first_file_num, active_file_num) = {
             let manager = self.log_manager.read().unwrap();
             (manager.first_file_num, manager.active_file_num)
         };
-        PIPE_FILES_COUNT_GAUGE.set((active_file_num - first_file_num + 1) as f64);
-        if first_file_num >= file_num {
-            debug!("Purge nothing.");
-            EXPIRED_FILES_PURGED_HISTOGRAM.observe(0.0);
-            return Ok(());
-        }
-
         if file_num > active_file_num {
             return Err(box_err!("Can't purge active log."));
         }
+        if first_file_num >= file_num {
+            debug!("[{}] Purge nothing.", self.name);
+            return Ok(());
+        }
 
         let old_first_file_num = first_file_num;
         loop {
@@ -426,105 +1107,379 @@ impl PipeLog {
             // Pop the oldest file.
             let (old_fd, old_file_num) = {
                 let mut manager = self.log_manager.write().unwrap();
+                // Files are pinned with the manager locked too.
+                let pinned = self.purge_pins.lock().unwrap().keys().next().cloned();
+                if pinned.map_or(false, |(n, _)| n <= manager.first_file_num) {
+                    break;
+                }
                 manager.first_file_num += 1;
                 first_file_num = manager.first_file_num;
-                (
-                    manager.all_files.pop_front().unwrap(),
-                    manager.first_file_num - 1,
-                )
+                let old_fd = manager.all_files.pop_front().unwrap();
+                let old_file_num = manager.first_file_num - 1;
+
+                let mut refs = self.file_refs.lock().unwrap();
+                if refs.counts.contains_key(&old_file_num) {
+                    // Removed by the last reader.
+                    refs.purged.insert(old_file_num, old_fd);
+                    continue;
+                }
+                (old_fd, old_file_num)
             };
-            // Close the file.
-            let close_res = unsafe { libc::close(old_fd) };
-            if close_res != 0 {
-                panic!("close file failed, err {}", errno::errno().to_string());
-            }
-
-            // Remove the file
-            let mut path = PathBuf::from(&self.dir);
-            path.push(generate_file_name(old_file_num));
-            fs::remove_file(path)?;
+            self.remove_purged_file(old_file_num, old_fd)?;
+        }
+        {
+            let mut batch_times = self.batch_times.lock().unwrap();
+            *batch_times = batch_times.split_off(&first_file_num);
+        }
+        if !self.read_only {
+            self.remove_unused_dicts(first_file_num)?;
+        }
+        if self.archive.is_some() {
+            self.apply_archive_retention()?;
         }
 
         debug!(
-            "purge {} expired files",
+            "[{}] purge {} expired files",
+            self.name,
             first_file_num - old_first_file_num
         );
-        EXPIRED_FILES_PURGED_HISTOGRAM.observe((first_file_num - old_first_file_num) as f64);
+        self.files_purged
+            .fetch_add(first_file_num - old_first_file_num, Ordering::Relaxed);
         Ok(())
     }
 
-    // Shrink file size and synchronize.
-    pub fn truncate_active_log(&self, offset: usize) -> Result<()> {
-        {
-            let manager = self.log_manager.read().unwrap();
-            assert!(
-                manager.active_log_size >= offset,
-                "attempt to truncate_active_log({}), but active_log_size is {}",
-                offset,
-                manager.active_log_size
-            );
-            if manager.active_log_size == offset {
-                return Ok(());
+    fn record_dict_file(&self, dict: &Dictionary, file_num: u64) -> Result<()> {
+        let mut dict_files = self.dict_files.lock().unwrap();
+        match dict_files.get_mut(&dict.id()) {
+            Some(last) => *last = cmp::max(*last, file_num),
+            None => {
+                // Removed after the batch was compressed with it.
+                dictionary::save(Path::new(&self.dir), dict)?;
+                dict_files.insert(dict.id(), file_num);
             }
-            let truncate_res =
-                unsafe { libc::ftruncate(manager.active_log_fd, offset as libc::off_t) };
-            if truncate_res != 0 {
-                panic!("Ftruncate file failed, err {}", errno::errno().to_string());
+        }
+        Ok(())
+    }
+
+    /// Remove saved dictionaries only used by files before `first_file_num`.
+    fn remove_unused_dicts(&self, first_file_num: u64) -> Result<()> {
+        let current = self.dictionary.read().unwrap().as_ref().map(|d| d.id());
+        let mut dict_files = self.dict_files.lock().unwrap();
+        let unused: Vec<u32> = dict_files
+            .iter()
+            .filter(|(id, last)| **last < first_file_num && Some(**id) != current)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in unused {
+            dictionary::remove(Path::new(&self.dir), id)?;
+            if let Some(storage) = self.cold_storage.as_ref() {
+                // Only uploaded along with offloaded files.
+                let _ = storage.delete(&dictionary::file_name_of(id));
             }
-            let sync_res = unsafe { libc::fsync(manager.active_log_fd) };
-            if sync_res != 0 {
-                panic!("Fsync file failed, err {}", errno::errno().to_string());
+            dict_files.remove(&id);
+            debug!("[{}] remove unused dictionary {:08x}", self.name, id);
+        }
+        Ok(())
+    }
+
+    /// Dictionaries that files since `file_num` may be compressed with.
+    fn dicts_used_since(&self, file_num: u64) -> Vec<Arc<Dictionary>> {
+        let dict_files = self.dict_files.lock().unwrap();
+        dict_files
+            .iter()
+            .filter(|(_, last)| **last >= file_num)
+            .filter_map(|(id, _)| self.dictionaries.get(*id))
+            .collect()
+    }
+
+    fn remove_purged_file(&self, file_num: u64, fd: libc::c_int) -> Result<()> {
+        // Unmapped once readers of the map are done.
+        self.file_maps.lock().unwrap().remove(&file_num);
+        if self.read_only {
+            // Removed by the writer.
+            if fd != COLD_FILE_FD {
+                cvt(unsafe { libc::close(fd) })
+                    .file_context(|| file_io_context(&self.dir, "close", file_num, None))?;
             }
+            return Ok(());
         }
-        {
-            let mut manager = self.log_manager.write().unwrap();
-            manager.active_log_size = offset;
-            manager.active_log_capacity = offset;
-            manager.last_sync_size = manager.active_log_size;
+        if fd == COLD_FILE_FD {
+            return self
+                .cold_storage
+                .as_ref()
+                .unwrap()
+                .delete(&generate_file_name(file_num));
         }
 
+        let ctx = |op| file_io_context(&self.dir, op, file_num, None);
+        // Pages of an archived file, or one still opened by other processes, would
+        // stay cached after it's closed.
+        drop_cached_pages(fd, 0, 0);
+        // Close the file.
+        cvt(unsafe { libc::close(fd) }).file_context(|| ctx("close"))?;
+
+        // Remove the file
+        let path = log_file_path(&self.dir, file_num);
+        match self.archive {
+            Some(ref archive) => {
+                // Archived files are read with dictionaries they're compressed with.
+                for dict in self.dicts_used_since(file_num) {
+                    dictionary::save(&archive.dir, &dict)?;
+                }
+                let target = archive.dir.join(generate_file_name(file_num));
+                if fs::rename(&path, &target).is_err() {
+                    // Maybe on different devices.
+                    fs::copy(&path, &target).file_context(|| ctx("archive"))?;
+                    fs::remove_file(path).file_context(|| ctx("remove"))?;
+                }
+                // Ages of archived files are measured by the clock.
+                OpenOptions::new()
+                    .write(true)
+                    .open(&target)
+                    .and_then(|f| f.set_modified(self.clock.now()))
+                    .file_context(|| ctx("archive"))?;
+            }
+            None => fs::remove_file(path).file_context(|| ctx("remove"))?,
+        }
         Ok(())
     }
 
-    pub fn sync(&self) {
+    /// Keep the file from being removed until the returned pin is dropped. The file
+    /// is still readable if it's purged in the meantime.
+    pub fn pin(&self, file_num: u64) -> Result<FilePin<'_>> {
         let manager = self.log_manager.read().unwrap();
-        let sync_res = unsafe { libc::fsync(manager.active_log_fd) };
-        if sync_res != 0 {
-            panic!("Fsync failed, err {}", errno::errno().to_string());
+        let mut refs = self.file_refs.lock().unwrap();
+        if (file_num < manager.first_file_num && !refs.purged.contains_key(&file_num))
+            || file_num > manager.active_file_num
+        {
+            return Err(box_err!("File not exist, file number {}", file_num));
         }
+        *refs.counts.entry(file_num).or_insert(0) += 1;
+        Ok(FilePin {
+            pipe_log: self,
+            file_num,
+        })
     }
 
-    #[cfg(test)]
-    fn active_log_size(&self) -> usize {
+    /// Keep files from the first one to `up_to_file` from being purged, offloaded
+    /// or punched until `unpin_files` is called with the returned range, e.g.
+    /// while they're copied by a backup.
+    pub fn pin_files(&self, up_to_file: u64) -> Result<(u64, u64)> {
         let manager = self.log_manager.read().unwrap();
-        manager.active_log_size
+        if up_to_file < manager.first_file_num || up_to_file > manager.active_file_num {
+            return Err(box_err!("File not exist, file number {}", up_to_file));
+        }
+        let first_file_num = manager.first_file_num;
+        *self
+            .purge_pins
+            .lock()
+            .unwrap()
+            .entry((first_file_num, up_to_file))
+            .or_insert(0) += 1;
+        Ok((first_file_num, up_to_file))
     }
 
-    #[cfg(test)]
-    fn active_log_capacity(&self) -> usize {
-        let manager = self.log_manager.read().unwrap();
-        manager.active_log_capacity
+    pub fn unpin_files(&self, first_file_num: u64, last_file_num: u64) {
+        let range = (first_file_num, last_file_num);
+        let mut pins = self.purge_pins.lock().unwrap();
+        let count = pins.get_mut(&range).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&range);
+        }
     }
 
-    pub fn active_file_num(&self) -> u64 {
-        let manager = self.log_manager.read().unwrap();
-        manager.active_file_num
+    /// Whether the file is in a range pinned by `pin_files`.
+    pub fn is_pinned(&self, file_num: u64) -> bool {
+        let pins = self.purge_pins.lock().unwrap();
+        pins.keys()
+            .any(|(first, last)| (*first..=*last).contains(&file_num))
     }
 
-    pub fn first_file_num(&self) -> u64 {
-        let manager = self.log_manager.read().unwrap();
-        manager.first_file_num
+    fn unpin(&self, file_num: u64) {
+        let fd = {
+            let mut refs = self.file_refs.lock().unwrap();
+            let count = refs.counts.get_mut(&file_num).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            refs.counts.remove(&file_num);
+            match refs.purged.remove(&file_num) {
+                Some(fd) => fd,
+                None => return,
+            }
+        };
+        // Wait for readers not holding a pin.
+        let _manager = self.log_manager.write().unwrap();
+        if let Err(e) = self.remove_purged_file(file_num, fd) {
+            error!(
+                "[{}] Remove purged file {} failed: {}",
+                self.name, file_num, e
+            );
+        }
     }
 
-    pub fn total_size(&self) -> usize {
-        let manager = self.log_manager.read().unwrap();
-        (manager.active_file_num - manager.first_file_num) as usize * self.rotate_size
-            + manager.active_log_size
+    // Remove archived files exceeding the retention limits, oldest first.
+    fn apply_archive_retention(&self) -> Result<()> {
+        let archive = self.archive.as_ref().unwrap();
+        let mut files = vec![];
+        let mut total_size = 0;
+        for entry in fs::read_dir(&archive.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_str().unwrap_or_default().to_owned();
+            if file_name.ends_with(LOG_SUFFIX) && file_name.len() == FILE_NAME_LEN {
+                let meta = entry.metadata()?;
+                total_size += meta.len();
+                files.push((file_name, meta));
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let now = self.clock.now();
+        for (file_name, meta) in files {
+            let exceed_size = archive.retention_size > 0 && total_size > archive.retention_size;
+            let expired = archive.retention_age > Duration::from_secs(0)
+                && now
+                    .duration_since(meta.modified()?)
+                    .map_or(false, |age| age > archive.retention_age);
+            if !exceed_size && !expired {
+                break;
+            }
+            fs::remove_file(archive.dir.join(&file_name))?;
+            total_size -= meta.len();
+            debug!("[{}] remove archived file {}", self.name, file_name);
+        }
+        Ok(())
     }
 
-    pub fn read_next_file(&mut self) -> Result<Option<Vec<u8>>> {
-        let manager = self.log_manager.read().unwrap();
-        if self.current_read_file_num == 0 {
+    // Shrink file size and synchronize.
+    pub fn truncate_active_log(&self, offset: u64) -> Result<()> {
+        if self.read_only {
+            return Err(box_err!("Can't truncate read-only raft log."));
+        }
+        {
+            let manager = self.log_manager.read().unwrap();
+            assert!(
+                manager.active_log_size >= offset,
+                "attempt to truncate_active_log({}), but active_log_size is {}",
+                offset,
+                manager.active_log_size
+            );
+            if manager.active_log_size == offset {
+                return Ok(());
+            }
+            self.check_poisoned()?;
+            let file_num = manager.active_file_num;
+            let fd = manager.active_log_fd;
+            let res = cvt(unsafe { libc::ftruncate(fd, to_off_t(offset)?) })
+                .file_context(|| file_io_context(&self.dir, "truncate", file_num, Some(offset)))
+                .and_then(|_| {
+                    // The file header is written with the file, a barrier is only
+                    // needed after it.
+                    if offset >= file_header_len() {
+                        pwrite_all(fd, &format::TAIL_BARRIER, offset).file_context(|| {
+                            file_io_context(&self.dir, "write", file_num, Some(offset))
+                        })?;
+                    }
+                    cvt(unsafe { libc::fsync(fd) })
+                        .file_context(|| file_io_context(&self.dir, "sync", file_num, None))
+                });
+            res.map_err(|e| self.poison(e))?;
+            on_synced(
+                &self.dir,
+                manager.active_file_num,
+                offset + barrier_len(offset),
+            );
+        }
+        {
+            let mut manager = self.log_manager.write().unwrap();
+            manager.active_log_size = offset;
+            manager.active_log_capacity = offset + barrier_len(offset);
+            manager.last_sync_size = manager.active_log_size;
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        // Batches up to it are written to the active file, or to older ones synced
+        // when rotated.
+        let sequence = self.latest_sequence();
+        self.check_poisoned()?;
+        let manager = self.log_manager.read().unwrap();
+        inject_fault(&self.dir, "sync")
+            .and_then(|_| cvt(unsafe { libc::fsync(manager.active_log_fd) }))
+            .file_context(|| file_io_context(&self.dir, "sync", manager.active_file_num, None))
+            .map_err(|e| self.poison(e))?;
+        on_synced(&self.dir, manager.active_file_num, manager.active_log_size);
+        self.mark_durable(sequence);
+        Ok(())
+    }
+
+    /// Whether the log refuses writes since the active file failed to be synced.
+    /// Writes failed by it may or may not be recovered, the engine has to be
+    /// reopened to find out.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    fn check_poisoned(&self) -> Result<()> {
+        if self.is_poisoned() {
+            return Err(box_err!(
+                "Raft log {} failed to sync before, reopen it to continue",
+                self.dir
+            ));
+        }
+        Ok(())
+    }
+
+    fn poison(&self, e: Error) -> Error {
+        if !self.poisoned.swap(true, Ordering::AcqRel) {
+            error!("[{}] Poison raft log after {}", self.name, e);
+        }
+        e
+    }
+
+    fn active_log_size(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        manager.active_log_size
+    }
+
+    #[cfg(test)]
+    fn active_log_capacity(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        manager.active_log_capacity
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn active_file_num(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        manager.active_file_num
+    }
+
+    pub fn first_file_num(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        manager.first_file_num
+    }
+
+    pub fn total_size(&self) -> u64 {
+        let manager = self.log_manager.read().unwrap();
+        (manager.active_file_num - manager.first_file_num) * self.rotate_size
+            + manager.active_log_size
+    }
+
+    /// Make `read_next_file` continue from `file_num`, skipping files before it.
+    pub fn seek_read_file(&mut self, file_num: u64) {
+        self.current_read_file_num = file_num;
+    }
+
+    pub fn read_next_file(&mut self) -> Result<Option<Vec<u8>>> {
+        let manager = self.log_manager.read().unwrap();
+        if self.current_read_file_num == 0 {
             self.current_read_file_num = manager.first_file_num;
         }
 
@@ -532,46 +1487,669 @@ impl PipeLog {
             return Ok(None);
         }
 
-        let mut path = PathBuf::from(&self.dir);
-        path.push(generate_file_name(self.current_read_file_num));
+        let file_num = self.current_read_file_num;
         self.current_read_file_num += 1;
-        let meta = fs::metadata(&path)?;
-        let mut vec = Vec::with_capacity(meta.len() as usize);
+        self.read_file(file_num).map(Some)
+    }
+
+    /// Read the whole content of the given log file.
+    pub fn read_file(&self, file_num: u64) -> Result<Vec<u8>> {
+        self.read_file_impl(file_num, false)
+    }
+
+    fn read_file_impl(&self, file_num: u64, bypass_cache: bool) -> Result<Vec<u8>> {
+        let _pin = self.pin(file_num)?;
+        let path = log_file_path(&self.dir, file_num);
+        if let Some(storage) = self.cold_storage.as_ref() {
+            if !path.exists() {
+                return storage.get(&generate_file_name(file_num));
+            }
+        }
+        let ctx = |op| file_io_context(&self.dir, op, file_num, None);
+        let meta = fs::metadata(&path).file_context(|| ctx("stat"))?;
+        let mut vec = Vec::with_capacity(to_usize(meta.len())?);
+
+        let mut file = File::open(&path).file_context(|| ctx("open"))?;
+        #[cfg(target_os = "linux")]
+        if bypass_cache {
+            // Advices are hints, failing to take them doesn't fail the read.
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+        }
+        inject_fault(&self.dir, "read")
+            .and_then(|_| file.read_to_end(&mut vec))
+            .file_context(|| ctx("read"))?;
+        if bypass_cache {
+            // Drop the pages read, unless they are dirty or mapped by others.
+            drop_cached_pages(file.as_raw_fd(), 0, 0);
+        }
+        Ok(vec)
+    }
+
+    /// Like `read_file`, for background scans of whole files, e.g. verifying,
+    /// scrubbing, punching holes in or offloading them. If set by
+    /// `set_scan_bypass_cache`, the pages read are dropped from the OS page cache
+    /// afterwards, so that scans don't evict pages used by foreground reads.
+    pub fn scan_file(&self, file_num: u64) -> Result<Vec<u8>> {
+        self.read_file_impl(file_num, self.scan_bypass_cache)
+    }
+
+    /// Read content of the file from `offset` to its current end.
+    pub fn read_tail(&self, file_num: u64, offset: u64) -> Result<Vec<u8>> {
+        let fd = {
+            let manager = self.log_manager.read().unwrap();
+            if file_num < manager.first_file_num || file_num > manager.active_file_num {
+                return Err(box_err!("File not exist, file number {}", file_num));
+            }
+            manager.all_files[(file_num - manager.first_file_num) as usize]
+        };
+        if fd == COLD_FILE_FD {
+            let _pin = self.pin(file_num)?;
+            let content = self.fetch_cold_file(file_num)?;
+            return Ok(content.get(offset as usize..).unwrap_or_default().to_vec());
+        }
+        let size = cvt(unsafe { libc::lseek(fd, 0, libc::SEEK_END) })
+            .file_context(|| file_io_context(&self.dir, "seek", file_num, None))?
+            as u64;
+        if size <= offset {
+            return Ok(vec![]);
+        }
+        self.fread(file_num, offset, size - offset)
+    }
+
+    /// For a read-only `PipeLog`, open the next file if the writer has created it,
+    /// and make it the active file. Return whether there is a new file.
+    pub fn follow_next_file(&self) -> Result<bool> {
+        assert!(self.read_only);
+        let next_file_num = self.active_file_num() + 1;
+        let path = log_file_path(&self.dir, next_file_num);
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let path_cstr = CString::new(path.as_path().to_str().unwrap().as_bytes()).unwrap();
+        let fd = cvt(unsafe { libc::open(path_cstr.as_ptr(), libc::O_RDONLY) })
+            .file_context(|| file_io_context(&self.dir, "open", next_file_num, None))?;
+        let mut manager = self.log_manager.write().unwrap();
+        manager.all_files.push_back(fd);
+        manager.active_log_fd = fd;
+        manager.active_log_size = 0;
+        manager.active_log_capacity = 0;
+        manager.active_file_num = next_file_num;
+        Ok(true)
+    }
 
-        // Read the whole file.
-        let mut file = File::open(&path)?;
-        file.read_to_end(&mut vec)?;
-        Ok(Some(vec))
+    /// Turn `len` bytes at `offset` of an inactive file into a hole, which readers of
+    /// batches skip. The range must consist of whole batches or holes. On Linux the
+    /// disk space is released by punching a hole in the file.
+    ///
+    /// Holes are recorded by a header written in the file itself rather than in a
+    /// manifest of dead ranges per file. The engine has no manifest, and a header
+    /// synced before the punch keeps the file readable on its own by recovery,
+    /// `check`, `dump` and files copied or offloaded elsewhere, with no second
+    /// file to keep consistent with it across crashes.
+    pub fn punch_hole(&self, file_num: u64, offset: u64, len: u64) -> Result<()> {
+        if self.read_only {
+            return Err(box_err!("Can't punch hole in read-only raft log."));
+        }
+        if len <= format::HOLE_HEADER_LEN as u64 {
+            return Err(box_err!("Hole of {} bytes is too small", len));
+        }
+        // Keep the file from being purged or offloaded.
+        let manager = self.log_manager.read().unwrap();
+        if file_num < manager.first_file_num || file_num >= manager.active_file_num {
+            return Err(box_err!("Can't punch hole in file {}", file_num));
+        }
+        if manager.all_files[(file_num - manager.first_file_num) as usize] == COLD_FILE_FD {
+            return Err(box_err!("Can't punch hole in cold file {}", file_num));
+        }
+        // Files are pinned with the manager locked too.
+        if self.is_pinned(file_num) {
+            return Err(box_err!("Can't punch hole in pinned file {}", file_num));
+        }
+
+        let ctx = |op, offset| file_io_context(&self.dir, op, file_num, offset);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(log_file_path(&self.dir, file_num))
+            .file_context(|| ctx("open", None))?;
+        // Make the hole header durable first, so that zeros are never read as batches.
+        file.write_all_at(&format::encode_hole_header(len), offset)
+            .file_context(|| ctx("write", Some(offset)))?;
+        self.disk_bytes_written
+            .fetch_add(format::HOLE_HEADER_LEN as u64, Ordering::Relaxed);
+        file.sync_data().file_context(|| ctx("sync", None))?;
+        #[cfg(target_os = "linux")]
+        {
+            let hole_offset = offset + format::HOLE_HEADER_LEN as u64;
+            let ret = unsafe {
+                libc::fallocate(
+                    file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    to_off_t(hole_offset)?,
+                    to_off_t(len - format::HOLE_HEADER_LEN as u64)?,
+                )
+            };
+            cvt(ret).file_context(|| ctx("punch hole in", Some(hole_offset)))?;
+        }
+        Ok(())
+    }
+
+    /// Move inactive files before `file_num` to cold storage and remove their local
+    /// copies. Return the count of moved files.
+    pub fn offload_to(&self, file_num: u64) -> Result<usize> {
+        let storage = match self.cold_storage.as_ref() {
+            Some(storage) => storage,
+            None => return Ok(0),
+        };
+        let end = cmp::min(file_num, self.active_file_num());
+
+        let mut count = 0;
+        let mut dicts_uploaded = false;
+        for current_file in self.first_file_num()..end {
+            let fd = {
+                let manager = self.log_manager.read().unwrap();
+                if current_file < manager.first_file_num {
+                    continue;
+                }
+                manager.all_files[(current_file - manager.first_file_num) as usize]
+            };
+            if fd == COLD_FILE_FD || self.is_pinned(current_file) {
+                continue;
+            }
+
+            if !dicts_uploaded {
+                // Offloaded files are read with dictionaries they're compressed with.
+                for dict in self.dicts_used_since(current_file) {
+                    storage.put(&dictionary::file_name_of(dict.id()), dict.content())?;
+                }
+                dicts_uploaded = true;
+            }
+            let file_name = generate_file_name(current_file);
+            storage.put(&file_name, &self.scan_file(current_file)?)?;
+            {
+                let mut manager = self.log_manager.write().unwrap();
+                // Files are pinned with the manager locked too.
+                if current_file < manager.first_file_num || self.is_pinned(current_file) {
+                    // Purged or pinned during uploading.
+                    drop(manager);
+                    storage.delete(&file_name)?;
+                    continue;
+                }
+                let idx = (current_file - manager.first_file_num) as usize;
+                manager.all_files[idx] = COLD_FILE_FD;
+            }
+
+            // No reader can see the fd now.
+            self.file_maps.lock().unwrap().remove(&current_file);
+            let ctx = |op| file_io_context(&self.dir, op, current_file, None);
+            cvt(unsafe { libc::close(fd) }).file_context(|| ctx("close"))?;
+            fs::remove_file(log_file_path(&self.dir, current_file))
+                .file_context(|| ctx("remove"))?;
+            count += 1;
+        }
+        if count > 0 {
+            info!("[{}] offload {} files to cold storage", self.name, count);
+        }
+        Ok(count)
+    }
+
+    fn fetch_cold_file(&self, file_num: u64) -> Result<Arc<Vec<u8>>> {
+        let file = {
+            let mut cache = self.cold_file_cache.lock().unwrap();
+            let file = match cache.iter().position(|(n, _)| *n == file_num) {
+                Some(i) => cache.remove(i).unwrap().1,
+                None => ColdFile::default(),
+            };
+            cache.push_back((file_num, file.clone()));
+            if cache.len() > COLD_FILE_CACHE_CAPACITY {
+                cache.pop_front();
+            }
+            file
+        };
+        let mut content = file.lock().unwrap();
+        if let Some(content) = content.as_ref() {
+            return Ok(content.clone());
+        }
+        let storage = self.cold_storage.as_ref().unwrap();
+        let fetched = Arc::new(storage.get(&generate_file_name(file_num))?);
+        *content = Some(fetched.clone());
+        Ok(fetched)
     }
 
-    pub fn files_before(&self, size: usize) -> u64 {
+    pub fn files_before(&self, size: u64) -> u64 {
         let cur_size = self.total_size();
         if cur_size > size {
             let count = (cur_size - size) / self.rotate_size;
             let manager = self.log_manager.read().unwrap();
-            manager.first_file_num + count as u64
+            manager.first_file_num + count
         } else {
             0
         }
     }
 }
 
-fn new_log_file(dir: &str, file_num: u64) -> libc::c_int {
-    let mut path = PathBuf::from(dir);
-    path.push(generate_file_name(file_num));
+/// Check the magic, the format version and the checksum in the header of a log
+/// file, and return the length of the header. A header partially written or
+/// damaged is told apart from one of a file not written by raft-engine by the
+/// reason of `Error::Corruption`.
+pub fn check_file_header(file_num: u64, content: &[u8]) -> Result<usize> {
+    let corruption =
+        |offset: usize, reason: String| Err(Error::Corruption(file_num, offset as u64, reason));
+    let version_end = FILE_MAGIC_HEADER.len() + VERSION.len();
+    if content.len() < version_end || !content.starts_with(FILE_MAGIC_HEADER) {
+        let len = cmp::min(content.len(), FILE_MAGIC_HEADER.len());
+        let damaged = FILE_MAGIC_HEADER[..len]
+            .iter()
+            .zip(content)
+            .filter(|(a, b)| a != b)
+            .count();
+        return if damaged == 0 {
+            corruption(content.len(), "file header is partially written".to_owned())
+        } else if damaged <= MAX_DAMAGED_MAGIC_BYTES && len == FILE_MAGIC_HEADER.len() {
+            corruption(
+                0,
+                format!("file header is damaged, {} bytes of magic differ", damaged),
+            )
+        } else {
+            corruption(0, "not a raft log file".to_owned())
+        };
+    }
+    let version = &content[FILE_MAGIC_HEADER.len()..version_end];
+    let stored_checksum = content
+        .get(version_end..version_end + FILE_HEADER_CHECKSUM_LEN)
+        .map(LittleEndian::read_u32);
+    let checksum = format::crc32(&content[..version_end]);
+    if version == VERSION {
+        return match stored_checksum {
+            Some(stored) if stored == checksum => Ok(FILE_HEADER_LEN),
+            Some(stored) => corruption(
+                version_end,
+                format!(
+                    "file header is damaged, checksum expected {}, but got {}",
+                    stored, checksum
+                ),
+            ),
+            None => corruption(version_end, "file header is partially written".to_owned()),
+        };
+    }
+    // The header of the current format with the version damaged.
+    if content.get(version_end..FILE_HEADER_LEN) == Some(&file_header()[version_end..]) {
+        return corruption(
+            FILE_MAGIC_HEADER.len(),
+            format!("file header is damaged, version {:?}", version),
+        );
+    }
+    match (parse_version(version), parse_version(VERSION)) {
+        (Some(found), Some(expected)) => Err(Error::UnsupportedVersion(
+            file_num,
+            String::from_utf8_lossy(version).into_owned(),
+            found > expected,
+        )),
+        _ => Err(Error::Corruption(
+            file_num,
+            FILE_MAGIC_HEADER.len() as u64,
+            format!("unknown format version {:?}", version),
+        )),
+    }
+}
 
-    let path_cstr = CString::new(path.as_path().to_str().unwrap().as_bytes()).unwrap();
-    let fd = unsafe {
-        libc::open(
-            path_cstr.as_ptr(),
-            libc::O_RDWR | libc::O_CREAT,
-            NEW_FILE_MODE,
-        )
-    };
-    if fd < 0 {
-        panic!("Open file failed, err {}", errno::errno().to_string());
+// Parse a version like "v1.0.0".
+pub(crate) fn parse_version(version: &[u8]) -> Option<(u64, u64, u64)> {
+    let version = std::str::from_utf8(version).ok()?.strip_prefix('v')?;
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => {
+            Some((major, minor, patch))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN);
+    header.extend_from_slice(FILE_MAGIC_HEADER);
+    header.extend_from_slice(VERSION);
+    let checksum = format::crc32(&header);
+    header.extend_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+fn file_header_len() -> u64 {
+    FILE_HEADER_LEN as u64
+}
+
+// Length of the barrier written after the tail when the active file is truncated
+// to `offset`.
+fn barrier_len(offset: u64) -> u64 {
+    if offset >= file_header_len() {
+        format::TAIL_BARRIER.len() as u64
+    } else {
+        0
+    }
+}
+
+// Record that the first `len` bytes of the file are durable, so that tests can
+// simulate power loss by dropping the rest.
+#[cfg(test)]
+fn on_synced(dir: &str, file_num: u64, len: u64) {
+    let path = PathBuf::from(dir).join(generate_file_name(file_num));
+    crate::crash_test::record_durable(&path, len);
+}
+
+#[cfg(not(test))]
+#[inline]
+fn on_synced(_dir: &str, _file_num: u64, _len: u64) {}
+
+// Fail or delay an operation on log files of the directory as tests inject.
+#[cfg(any(test, feature = "fault-injection"))]
+fn inject_fault(dir: &str, op: &'static str) -> std::io::Result<()> {
+    crate::fault_fs::on_io(Path::new(dir), op)
+}
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[inline]
+fn inject_fault(_dir: &str, _op: &'static str) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Create a log file with the file header written and synced. The file is renamed
+// from a temporary one, so a crash never leaves a log file without the header.
+// Nothing is left if it fails.
+fn new_log_file(dir: &str, file_num: u64) -> Result<libc::c_int> {
+    let tmp_path = tmp_log_file_path(dir, file_num);
+    let fd = create_log_file(dir, file_num, &tmp_path)?;
+    if let Err(e) = install_log_file(dir, file_num, &tmp_path) {
+        remove_tmp_log_file(&tmp_path, fd);
+        return Err(e);
     }
-    fd
+    Ok(fd)
+}
+
+pub(crate) fn tmp_log_file_path(dir: &str, file_num: u64) -> PathBuf {
+    PathBuf::from(dir).join(format!("{}{}", generate_file_name(file_num), TMP_SUFFIX))
+}
+
+// A file prepared in advance has its own temporary name, so that a rotation
+// creating the same file meanwhile doesn't clash with it.
+fn prepared_log_file_path(dir: &str, file_num: u64) -> PathBuf {
+    PathBuf::from(dir).join(format!(
+        "{}.prepared{}",
+        generate_file_name(file_num),
+        TMP_SUFFIX
+    ))
+}
+
+// Create the temporary file of a log file at `tmp_path` with the file header
+// written and synced.
+fn create_log_file(dir: &str, file_num: u64, tmp_path: &Path) -> Result<libc::c_int> {
+    let ctx = |op, offset| file_io_context(dir, op, file_num, offset);
+
+    let path_cstr = CString::new(tmp_path.to_str().unwrap().as_bytes()).unwrap();
+    let fd = inject_fault(dir, "create")
+        .and_then(|_| {
+            cvt(unsafe {
+                libc::open(
+                    path_cstr.as_ptr(),
+                    libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+                    NEW_FILE_MODE,
+                )
+            })
+        })
+        .file_context(|| ctx("create", None))?;
+    let res = pwrite_all(fd, &file_header(), 0)
+        .file_context(|| ctx("write", Some(0)))
+        .and_then(|_| cvt(unsafe { libc::fsync(fd) }).file_context(|| ctx("sync", None)));
+    if let Err(e) = res {
+        remove_tmp_log_file(tmp_path, fd);
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+// Rename the temporary file created by `create_log_file` to the log file. It's
+// renamed back if the rename can't be synced, so that the log file is never left
+// behind a failure.
+fn install_log_file(dir: &str, file_num: u64, tmp_path: &Path) -> Result<()> {
+    let path = log_file_path(dir, file_num);
+    inject_fault(dir, "rename")
+        .and_then(|_| fs::rename(tmp_path, &path))
+        .file_context(|| file_io_context(dir, "rename", file_num, None))?;
+    if let Err(e) = inject_fault(dir, "sync-dir").and_then(|_| File::open(dir)?.sync_all()) {
+        if let Err(e) = fs::rename(&path, tmp_path) {
+            warn!("Rename raft log file {} back failed: {}", path.display(), e);
+        }
+        return Err(e.into());
+    }
+    on_synced(dir, file_num, file_header_len());
+    Ok(())
+}
+
+fn discard_prepared_file(dir: &str, prepared: PreparedFile) {
+    remove_tmp_log_file(&prepared_log_file_path(dir, prepared.file_num), prepared.fd);
+}
+
+fn remove_tmp_log_file(path: &Path, fd: libc::c_int) {
+    unsafe { libc::close(fd) };
+    if let Err(e) = fs::remove_file(path) {
+        warn!(
+            "Remove temporary raft log file {} failed: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// List log files in `dir`, sorted by file number.
+pub fn list_log_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let file_path = entry?.path();
+        if !file_path.is_file() {
+            continue;
+        }
+        let file_name = file_path.file_name().unwrap().to_str().unwrap();
+        if file_name.ends_with(LOG_SUFFIX) && file_name.len() == FILE_NAME_LEN {
+            if let Ok(file_num) = extract_file_num(file_name) {
+                files.push((file_num, file_path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub(crate) fn log_file_path(dir: &str, file_num: u64) -> PathBuf {
+    PathBuf::from(dir).join(generate_file_name(file_num))
+}
+
+fn file_io_context(
+    dir: &str,
+    op: &'static str,
+    file_num: u64,
+    offset: Option<u64>,
+) -> FileIoContext {
+    FileIoContext {
+        op,
+        file_num,
+        path: log_file_path(dir, file_num),
+        offset,
+    }
+}
+
+// Convert the return value of a libc call, negative on failure, to an IO result.
+fn cvt<T: Default + PartialOrd>(ret: T) -> std::io::Result<T> {
+    if ret < T::default() {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Convert an on-disk offset to `off_t`, which is only 32 bits wide on some targets.
+fn to_off_t(offset: u64) -> Result<libc::off_t> {
+    libc::off_t::try_from(offset)
+        .map_err(|_| box_err!("Offset {} overflows off_t on this platform", offset))
+}
+
+// Advise the OS to drop cached pages of the range of the file, 0 `len` meaning
+// to the end. Advices are hints, failing to take them doesn't fail the caller.
+fn drop_cached_pages(fd: libc::c_int, offset: u64, len: u64) {
+    #[cfg(target_os = "linux")]
+    if let (Ok(offset), Ok(len)) = (to_off_t(offset), to_off_t(len)) {
+        unsafe { libc::posix_fadvise(fd, offset, len, libc::POSIX_FADV_DONTNEED) };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (fd, offset, len);
+}
+
+/// A read-only memory map of a whole log file, shared by readers of the file.
+struct FileMap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The map is only read.
+unsafe impl Send for FileMap {}
+unsafe impl Sync for FileMap {}
+
+impl FileMap {
+    fn new(fd: libc::c_int) -> std::io::Result<FileMap> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        cvt(unsafe { libc::fstat(fd, &mut stat) })?;
+        let len = usize::try_from(stat.st_size).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "file too large to map")
+        })?;
+        if len == 0 {
+            return Ok(FileMap {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(FileMap { ptr, len })
+    }
+
+    // Read exactly `buf.len()` bytes at `offset`, as `pread_exact`.
+    fn read_exact(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        match start.checked_add(buf.len()) {
+            Some(end) if end <= self.len => {
+                let content =
+                    unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) };
+                buf.copy_from_slice(&content[start..end]);
+                Ok(())
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "expected {} bytes, got {}",
+                    buf.len(),
+                    self.len.saturating_sub(start)
+                ),
+            )),
+        }
+    }
+}
+
+impl Drop for FileMap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+// Bound of consecutive retries of an interrupted or would-block read or write.
+const MAX_IO_RETRIES: usize = 16;
+
+fn io_retryable(err: &std::io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN))
+}
+
+// Read exactly `buf.len()` bytes at `offset`, continuing after short reads.
+fn pread_exact(fd: libc::c_int, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    let mut read = 0;
+    let mut retries = 0;
+    while read < buf.len() {
+        let pos = offset + read as u64;
+        let off = libc::off_t::try_from(pos).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "offset overflows off_t")
+        })?;
+        let ret = unsafe {
+            libc::pread(
+                fd,
+                buf[read..].as_mut_ptr() as *mut libc::c_void,
+                (buf.len() - read) as libc::size_t,
+                off,
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if io_retryable(&err) && retries < MAX_IO_RETRIES {
+                retries += 1;
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes, got {}", buf.len(), read),
+            ));
+        }
+        read += ret as usize;
+        retries = 0;
+    }
+    Ok(())
+}
+
+// Write the whole `buf` at `offset`, continuing after short writes.
+fn pwrite_all(fd: libc::c_int, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    let mut written = 0;
+    let mut retries = 0;
+    while written < buf.len() {
+        let pos = offset + written as u64;
+        let off = libc::off_t::try_from(pos).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "offset overflows off_t")
+        })?;
+        let ret = unsafe {
+            libc::pwrite(
+                fd,
+                buf[written..].as_ptr() as *const libc::c_void,
+                (buf.len() - written) as libc::size_t,
+                off,
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if io_retryable(&err) && retries < MAX_IO_RETRIES {
+                retries += 1;
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("wrote {} of {} bytes", written, buf.len()),
+            ));
+        }
+        written += ret as usize;
+        retries = 0;
+    }
+    Ok(())
 }
 
 fn generate_file_name(file_num: u64) -> String {
@@ -587,9 +2165,14 @@ fn extract_file_num(file_name: &str) -> Result<u64> {
 
 #[cfg(test)]
 mod tests {
+    use std::panic::AssertUnwindSafe;
+    use std::time::SystemTime;
+
     use tempfile::Builder;
 
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::fault_fs::{Fault, FaultyDir};
 
     #[test]
     fn test_file_name() {
@@ -601,6 +2184,487 @@ mod tests {
         assert!(extract_file_num(invalid_file_name).is_err());
     }
 
+    #[test]
+    fn test_offset_conversion() {
+        assert_eq!(to_off_t(0).unwrap(), 0);
+        assert!(to_off_t(u64::MAX).is_err());
+        assert_eq!(to_usize(1024).unwrap(), 1024);
+        if std::mem::size_of::<usize>() < 8 {
+            assert!(to_usize(u64::from(u32::MAX) + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_file_header() {
+        let mut content = file_header();
+        content.extend_from_slice(b"batches");
+        assert_eq!(check_file_header(1, &content).unwrap(), FILE_HEADER_LEN);
+
+        let reason = |content: &[u8]| match check_file_header(1, content) {
+            Err(Error::Corruption(1, _, reason)) => reason,
+            res => panic!("unexpected result {:?}", res),
+        };
+        for len in &[0, 10, FILE_MAGIC_HEADER.len() + 2, FILE_HEADER_LEN - 1] {
+            assert_eq!(reason(&content[..*len]), "file header is partially written");
+        }
+        // Bit flips in the magic, the version and the checksum.
+        for pos in &[3, FILE_MAGIC_HEADER.len() + 1, FILE_HEADER_LEN - 1] {
+            let mut damaged = content.clone();
+            damaged[*pos] ^= 0x01;
+            assert!(reason(&damaged).starts_with("file header is damaged"));
+        }
+        assert_eq!(
+            reason(b"not a log file, but long enough to hold the header of one"),
+            "not a raft log file"
+        );
+
+        // Files of old formats have no checksum.
+        let mut old = FILE_MAGIC_HEADER.to_vec();
+        old.extend_from_slice(b"v1.0.0");
+        old.extend_from_slice(b"batches");
+        match check_file_header(1, &old) {
+            Err(Error::UnsupportedVersion(1, version, false)) => assert_eq!(version, "v1.0.0"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_purge_to_archive() {
+        let dir = Builder::new()
+            .prefix("test_purge_to_archive")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("raft");
+        let archive_path = dir.path().join("archive");
+
+        let rotate_size = 1024;
+        let mut pipe_log = PipeLog::open(path.to_str().unwrap(), 32 * 1024, rotate_size).unwrap();
+        // Far behind the wall clock.
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        pipe_log.set_clock(Arc::new(clock.clone()));
+        pipe_log
+            .set_archive(archive_path.to_str().unwrap(), 0, Duration::from_secs(0))
+            .unwrap();
+        let content: Vec<u8> = vec![b'a'; 1024];
+        for _ in 0..4 {
+            pipe_log.append(content.as_slice(), false).unwrap();
+        }
+        assert_eq!(pipe_log.active_file_num(), 5);
+
+        pipe_log.purge_to(3).unwrap();
+        assert!(!path.join(generate_file_name(1)).exists());
+        assert!(archive_path.join(generate_file_name(1)).exists());
+        assert!(archive_path.join(generate_file_name(2)).exists());
+
+        // Only keep one archived file.
+        let file_size = fs::metadata(archive_path.join(generate_file_name(1)))
+            .unwrap()
+            .len();
+        pipe_log.archive.as_mut().unwrap().retention_size = file_size;
+        pipe_log.purge_to(4).unwrap();
+        assert!(!archive_path.join(generate_file_name(1)).exists());
+        assert!(!archive_path.join(generate_file_name(2)).exists());
+        assert!(archive_path.join(generate_file_name(3)).exists());
+
+        // Only keep archived files younger than an hour.
+        let archive = pipe_log.archive.as_mut().unwrap();
+        archive.retention_size = 0;
+        archive.retention_age = Duration::from_secs(3600);
+        pipe_log.purge_to(5).unwrap();
+        assert!(archive_path.join(generate_file_name(3)).exists());
+        assert!(archive_path.join(generate_file_name(4)).exists());
+        clock.advance(Duration::from_secs(7200));
+        pipe_log.apply_archive_retention().unwrap();
+        assert!(!archive_path.join(generate_file_name(3)).exists());
+        assert!(!archive_path.join(generate_file_name(4)).exists());
+    }
+
+    #[test]
+    fn test_purge_pinned_file() {
+        let dir = Builder::new()
+            .prefix("test_purge_pinned_file")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let rotate_size = 1024;
+        let pipe_log = PipeLog::open(path, 32 * 1024, rotate_size).unwrap();
+        let content: Vec<u8> = vec![b'a'; 1024];
+        for _ in 0..4 {
+            pipe_log.append(content.as_slice(), false).unwrap();
+        }
+        let header_size = FILE_HEADER_LEN as u64;
+
+        // A reader of file 1 races with purging it.
+        let pin = pipe_log.pin(1).unwrap();
+        pipe_log.purge_to(3).unwrap();
+        assert_eq!(pipe_log.first_file_num(), 3);
+        assert!(dir.path().join(generate_file_name(1)).exists());
+        assert!(!dir.path().join(generate_file_name(2)).exists());
+        assert_eq!(pipe_log.fread(1, header_size, 1024).unwrap(), content);
+        assert!(pipe_log.read_file(1).is_ok());
+        assert!(pipe_log.pin(2).is_err());
+
+        drop(pin);
+        assert!(!dir.path().join(generate_file_name(1)).exists());
+        assert!(pipe_log.fread(1, header_size, 1024).is_err());
+        assert!(pipe_log.pin(1).is_err());
+    }
+
+    #[test]
+    fn test_fread_past_end() {
+        let dir = Builder::new()
+            .prefix("test_fread_past_end")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        let content: Vec<u8> = vec![b'a'; 100];
+        let (file_num, offset) = pipe_log.append(content.as_slice(), true).unwrap();
+        assert_eq!(pipe_log.fread(file_num, offset, 100).unwrap(), content);
+        match pipe_log.fread(file_num, offset + 50, 100) {
+            Err(Error::FileIo(ctx, e)) => {
+                assert_eq!(ctx.op, "read");
+                assert_eq!((ctx.file_num, ctx.offset), (file_num, Some(offset + 50)));
+                assert_eq!(ctx.path, Path::new(path).join(generate_file_name(file_num)));
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_mmap_sealed_files() {
+        let dir = Builder::new()
+            .prefix("test_mmap_sealed_files")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let mut pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        pipe_log.set_mmap_sealed_files(true);
+        let mut positions = vec![];
+        for i in 0..4u8 {
+            let content = vec![i; 1024];
+            positions.push(pipe_log.append(&content, false).unwrap());
+        }
+        assert_eq!(pipe_log.active_file_num(), 5);
+        for (i, (file_num, offset)) in positions.iter().enumerate() {
+            assert_eq!(
+                pipe_log.fread(*file_num, *offset + 10, 100).unwrap(),
+                vec![i as u8; 100]
+            );
+        }
+        // The active file isn't mapped.
+        pipe_log.append(b"active", false).unwrap();
+        assert_eq!(pipe_log.file_maps.lock().unwrap().len(), 4);
+        assert!(!pipe_log.file_maps.lock().unwrap().contains_key(&5));
+        match pipe_log.fread(1, positions[0].1 + 1000, 100) {
+            Err(Error::FileIo(ctx, e)) => {
+                assert_eq!((ctx.op, ctx.file_num), ("read", 1));
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Holes punched in a sealed file are seen through its map.
+        let (file_num, offset) = positions[1];
+        pipe_log.punch_hole(file_num, offset, 1024).unwrap();
+        let content = pipe_log.fread(file_num, offset, 1024).unwrap();
+        assert_eq!(format::decode_hole_header(&content), Some(1024));
+
+        // Maps of a pinned file are kept until it's removed.
+        let pin = pipe_log.pin(1).unwrap();
+        pipe_log.purge_to(3).unwrap();
+        assert_eq!(
+            pipe_log.file_maps.lock().unwrap().keys().min().cloned(),
+            Some(1)
+        );
+        assert_eq!(pipe_log.fread(1, positions[0].1, 10).unwrap(), vec![0; 10]);
+        drop(pin);
+        assert_eq!(
+            pipe_log.file_maps.lock().unwrap().keys().min().cloned(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_scan_file() {
+        let dir = Builder::new().prefix("test_scan_file").tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let mut pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        pipe_log.set_scan_bypass_cache(true);
+        let content: Vec<u8> = vec![b'a'; 2048];
+        pipe_log.append(content.as_slice(), true).unwrap();
+        pipe_log.append(content.as_slice(), true).unwrap();
+        for file_num in 1..=pipe_log.active_file_num() {
+            let scanned = pipe_log.scan_file(file_num).unwrap();
+            assert_eq!(scanned, pipe_log.read_file(file_num).unwrap());
+            // Dropping pages doesn't lose data, read them again from disk.
+            assert_eq!(pipe_log.scan_file(file_num).unwrap(), scanned);
+        }
+        assert!(pipe_log.scan_file(pipe_log.active_file_num() + 1).is_err());
+    }
+
+    #[test]
+    fn test_drop_page_cache() {
+        let dir = Builder::new()
+            .prefix("test_drop_page_cache")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        let content: Vec<u8> = (0..2048).map(|i| i as u8).collect();
+        let (file_num, offset) = pipe_log.append(content.as_slice(), true).unwrap();
+        pipe_log.append(b"next", true).unwrap();
+        // Overlapping, unordered and out of range ones are all fine.
+        let ranges = [(offset + 1024, 1024), (offset, 100), (offset + 50, 100)];
+        pipe_log.drop_page_cache(file_num, &ranges);
+        pipe_log.drop_page_cache(file_num, &[(1 << 40, 1)]);
+        pipe_log.drop_page_cache(pipe_log.active_file_num() + 1, &ranges);
+        assert_eq!(pipe_log.fread(file_num, offset, 2048).unwrap(), content);
+
+        // Purged files are ignored.
+        pipe_log.purge_to(pipe_log.active_file_num()).unwrap();
+        pipe_log.drop_page_cache(file_num, &ranges);
+        assert!(pipe_log.fread(file_num, offset, 2048).is_err());
+    }
+
+    #[test]
+    fn test_fread_many() {
+        let dir = Builder::new().prefix("test_fread_many").tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024 * 1024).unwrap();
+        let mut offsets = vec![];
+        for i in 0..4u8 {
+            let content = vec![i; 100 + MAX_READ_GAP as usize / 2];
+            offsets.push(pipe_log.append(content.as_slice(), true).unwrap());
+        }
+        // A far range is read separately.
+        pipe_log
+            .append(&[0; 2 * MAX_READ_GAP as usize], true)
+            .unwrap();
+        offsets.push(pipe_log.append(&[4; 100], true).unwrap());
+        assert!(offsets.iter().all(|(n, _)| *n == offsets[0].0));
+
+        let ranges: Vec<_> = [4, 2, 0, 2, 1]
+            .iter()
+            .map(|&i: &usize| (offsets[i].1 + 10, 50))
+            .collect();
+        let bytes_read = pipe_log.bytes_read();
+        let contents = pipe_log.fread_many(offsets[0].0, &ranges).unwrap();
+        for (content, &i) in contents.iter().zip(&[4u8, 2, 0, 2, 1]) {
+            assert_eq!(content, &vec![i; 50]);
+        }
+        // Gaps between ranges of the first three files are read.
+        let gap = MAX_READ_GAP / 2 + 100 - 50;
+        assert_eq!(pipe_log.bytes_read() - bytes_read, 50 * 4 + gap * 2);
+
+        assert!(pipe_log.fread_many(offsets[0].0 + 1, &ranges).is_err());
+    }
+
+    #[test]
+    fn test_unfinished_new_file() {
+        let dir = Builder::new()
+            .prefix("test_unfinished_new_file")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        let header_size = FILE_HEADER_LEN as u64;
+
+        {
+            let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+            assert_eq!(pipe_log.active_log_size(), header_size);
+            pipe_log.close().unwrap();
+        }
+        // A crash during creating file 2, and a file 1 of old versions without header.
+        let tmp_name = format!("{}{}", generate_file_name(2), TMP_SUFFIX);
+        fs::write(dir.path().join(&tmp_name), FILE_MAGIC_HEADER).unwrap();
+        fs::write(dir.path().join(generate_file_name(1)), b"").unwrap();
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        assert!(!dir.path().join(&tmp_name).exists());
+        assert_eq!(pipe_log.active_file_num(), 1);
+        assert_eq!(pipe_log.active_log_size(), header_size);
+        assert_eq!(pipe_log.read_file(1).unwrap(), file_header());
+    }
+
+    #[test]
+    fn test_prepare_next_file() {
+        let dir = Builder::new()
+            .prefix("test_prepare_next_file")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        let header_size = FILE_HEADER_LEN as u64;
+        let tmp_path = |file_num| prepared_log_file_path(path, file_num);
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        pipe_log.set_rotation_listener(Some(tx));
+        assert!(pipe_log.prepare_next_file().unwrap());
+        assert!(!pipe_log.prepare_next_file().unwrap());
+        assert!(tmp_path(2).exists());
+        assert!(!log_file_path(path, 2).exists());
+
+        // Rotated to the prepared file, whose space is allocated already.
+        let content = vec![b'a'; 1024];
+        assert_eq!(pipe_log.append(&content, false).unwrap(), (1, header_size));
+        assert_eq!(pipe_log.active_file_num(), 2);
+        assert_eq!(rx.try_recv().unwrap(), 2);
+        assert!(!tmp_path(2).exists());
+        assert_eq!(pipe_log.read_file(2).unwrap(), file_header());
+        #[cfg(target_os = "linux")]
+        assert!(pipe_log.log_manager.read().unwrap().active_log_capacity >= 1024);
+        assert_eq!(pipe_log.append(b"b", false).unwrap(), (2, header_size));
+
+        // Rotated without a prepared file.
+        assert_eq!(
+            pipe_log.append(&content, false).unwrap(),
+            (2, header_size + 1)
+        );
+        assert_eq!(pipe_log.active_file_num(), 3);
+
+        // A prepared file is never seen by recovery.
+        assert!(pipe_log.prepare_next_file().unwrap());
+        assert!(tmp_path(4).exists());
+        drop(pipe_log);
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        assert_eq!(pipe_log.active_file_num(), 3);
+        assert!(!tmp_path(4).exists());
+        assert!(pipe_log.prepare_next_file().unwrap());
+        pipe_log.close().unwrap();
+        assert!(!tmp_path(4).exists());
+    }
+
+    #[test]
+    fn test_rotate_failure() {
+        let dir = Builder::new()
+            .prefix("test_rotate_failure")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        let header_size = FILE_HEADER_LEN as u64;
+        let faulty = FaultyDir::new(dir.path());
+        let content = vec![b'a'; 1024];
+        let mut file_1 = file_header();
+        file_1.extend_from_slice(&content);
+        let no_file = |file_num| {
+            !log_file_path(path, file_num).exists()
+                && !tmp_log_file_path(path, file_num).exists()
+                && !prepared_log_file_path(path, file_num).exists()
+        };
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        for (op, errno) in &[("create", libc::EMFILE), ("rename", libc::ENOSPC)] {
+            faulty.inject(
+                op,
+                Fault {
+                    error_rate: 1.0,
+                    errno: *errno,
+                    ..Default::default()
+                },
+            );
+        }
+        // The write filling the file lands in it, and the file is left active.
+        assert_eq!(pipe_log.append(&content, false).unwrap(), (1, header_size));
+        assert_eq!(pipe_log.active_file_num(), 1);
+        assert!(no_file(2));
+        // Later writes fail as a whole until the next file can be created.
+        match pipe_log.append(b"b", true) {
+            Err(Error::FileIo(ctx, e)) => {
+                assert_eq!(ctx.op, "create");
+                assert_eq!(e.raw_os_error(), Some(libc::EMFILE));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        faulty.clear("create");
+        assert!(pipe_log.append(b"b", true).is_err());
+        assert!(no_file(2));
+        // A prepared file can't be installed either, and is dropped.
+        assert!(pipe_log.prepare_next_file().unwrap());
+        assert!(pipe_log.append(b"b", true).is_err());
+        assert!(no_file(2));
+        assert_eq!(pipe_log.active_file_num(), 1);
+        assert_eq!(pipe_log.read_file(1).unwrap(), file_1);
+
+        // A failure to sync the rename rolls it back.
+        faulty.clear("rename");
+        faulty.inject(
+            "sync-dir",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(pipe_log.append(b"b", true).is_err());
+        assert!(no_file(2));
+        faulty.clear("sync-dir");
+        assert!(!pipe_log.is_poisoned());
+
+        // Written to the new file once it's created.
+        assert_eq!(pipe_log.append(b"b", true).unwrap(), (2, header_size));
+        assert_eq!(pipe_log.active_file_num(), 2);
+        drop(pipe_log);
+        let pipe_log = PipeLog::open(path, 32 * 1024, 1024).unwrap();
+        assert_eq!(pipe_log.first_file_num(), 1);
+        assert_eq!(pipe_log.active_file_num(), 2);
+        assert_eq!(pipe_log.read_file(1).unwrap(), file_1);
+        let mut file_2 = file_header();
+        file_2.push(b'b');
+        assert_eq!(&pipe_log.read_file(2).unwrap()[..file_2.len()], &file_2[..]);
+    }
+
+    #[test]
+    fn test_sync_failure() {
+        let dir = Builder::new()
+            .prefix("test_sync_failure")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().to_str().unwrap();
+        let header_size = FILE_HEADER_LEN as u64;
+        let faulty = FaultyDir::new(dir.path());
+        let pipe_log = PipeLog::open(path, 32 * 1024, 32 * 1024).unwrap();
+
+        // A failed write is dropped, and the next one takes its place.
+        faulty.inject(
+            "write",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(pipe_log.append(b"a", false).is_err());
+        faulty.clear("write");
+        assert_eq!(pipe_log.append(b"a", false).unwrap(), (1, header_size));
+        pipe_log.sync().unwrap();
+
+        // But a failed sync poisons the log until it's reopened, even if the disk
+        // is back.
+        faulty.inject(
+            "sync",
+            Fault {
+                error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        assert!(pipe_log.append(b"b", true).is_err());
+        assert!(pipe_log.is_poisoned());
+        faulty.clear("sync");
+        assert!(pipe_log.append(b"c", false).is_err());
+        assert!(pipe_log.sync().is_err());
+        assert!(pipe_log.truncate_active_log(header_size).is_err());
+        drop(pipe_log);
+
+        let pipe_log = PipeLog::open(path, 32 * 1024, 32 * 1024).unwrap();
+        assert!(!pipe_log.is_poisoned());
+        assert_eq!(pipe_log.read_file(1).unwrap()[FILE_HEADER_LEN..], b"ab"[..]);
+    }
+
     #[test]
     fn test_pipe_log() {
         let dir = Builder::new().prefix("test_pipe_log").tempdir().unwrap();
@@ -612,7 +2676,7 @@ mod tests {
         assert_eq!(pipe_log.first_file_num(), INIT_FILE_NUM);
         assert_eq!(pipe_log.active_file_num(), INIT_FILE_NUM);
 
-        let header_size = (FILE_MAGIC_HEADER.len() + VERSION.len()) as u64;
+        let header_size = FILE_HEADER_LEN as u64;
 
         // generate file 1, 2, 3
         let content: Vec<u8> = vec![b'a'; 1024];
@@ -650,7 +2714,7 @@ mod tests {
         );
         assert_eq!(
             pipe_log.active_log_size(),
-            FILE_MAGIC_HEADER.len() + VERSION.len() + 2 * s_content.len()
+            header_size + 2 * s_content.len() as u64
         );
 
         // fread
@@ -660,38 +2724,36 @@ mod tests {
         assert_eq!(content_readed.as_slice(), s_content.as_ref());
 
         // truncate file
-        pipe_log
-            .truncate_active_log(FILE_MAGIC_HEADER.len() + VERSION.len())
-            .unwrap();
-        assert_eq!(
-            pipe_log.active_log_size(),
-            FILE_MAGIC_HEADER.len() + VERSION.len()
-        );
-        let trunc_big_offset = std::panic::catch_unwind(|| {
-            pipe_log.truncate_active_log(FILE_MAGIC_HEADER.len() + VERSION.len() + s_content.len())
-        });
+        pipe_log.truncate_active_log(header_size).unwrap();
+        assert_eq!(pipe_log.active_log_size(), header_size);
+        let trunc_big_offset = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            pipe_log.truncate_active_log(header_size + s_content.len() as u64)
+        }));
         assert!(trunc_big_offset.is_err());
 
-        // read next file
-        let mut header: Vec<u8> = vec![];
-        header.extend(FILE_MAGIC_HEADER);
-        header.extend(VERSION);
+        // read next file, ended by a barrier after the truncated tail
+        let mut header = file_header();
+        header.extend(&format::TAIL_BARRIER);
         let content = pipe_log.read_next_file().unwrap().unwrap();
         assert_eq!(header, content);
         assert!(pipe_log.read_next_file().unwrap().is_none());
 
         pipe_log.close().unwrap();
 
-        // reopen
+        // reopen, the barrier stays until recovery truncates the tail again
+        let barrier_len = format::TAIL_BARRIER.len() as u64;
         let pipe_log = PipeLog::open(path, bytes_per_sync, rotate_size).unwrap();
         assert_eq!(pipe_log.active_file_num(), 3);
-        assert_eq!(
-            pipe_log.active_log_size(),
-            FILE_MAGIC_HEADER.len() + VERSION.len()
-        );
-        assert_eq!(
-            pipe_log.active_log_capacity(),
-            FILE_MAGIC_HEADER.len() + VERSION.len()
-        );
+        // Another writer is kept out, but readers are not.
+        assert!(matches!(
+            PipeLog::open(path, bytes_per_sync, rotate_size),
+            Err(Error::Locked(_, _, true))
+        ));
+        PipeLog::open_read_only(path, rotate_size, None).unwrap();
+        assert_eq!(pipe_log.active_log_size(), header_size + barrier_len);
+        assert_eq!(pipe_log.active_log_capacity(), header_size + barrier_len);
+        pipe_log.truncate_active_log(header_size).unwrap();
+        assert_eq!(pipe_log.active_log_size(), header_size);
+        assert_eq!(pipe_log.active_log_capacity(), header_size + barrier_len);
     }
 }
@@ -0,0 +1,250 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::memory::{MemoryComponent, MemoryTrace};
+use crate::memtable::EntryIndex;
+use crate::util::HashMap;
+
+// Requests beyond this are dropped if the prefetcher can't keep up.
+const MAX_PENDING_REQUESTS: usize = 64;
+
+/// A range of entries [begin, end) of a region to prefetch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PrefetchRequest {
+    pub region_id: u64,
+    pub begin: u64,
+    pub end: u64,
+    // Tells the request apart from requests of the region before it's removed.
+    seq: u64,
+}
+
+#[derive(Default)]
+struct PrefetchState {
+    // Region -> the end of its last fetch.
+    cursors: HashMap<u64, u64>,
+    pending: VecDeque<PrefetchRequest>,
+    // Sequence -> region of requests taken but not finished yet.
+    in_flight: HashMap<u64, u64>,
+    next_seq: u64,
+    // (file number, batch offset, entry offset in batch) -> (region, encoded entry).
+    entries: HashMap<(u64, u64, u64), (u64, Vec<u8>)>,
+    size: u64,
+}
+
+/// Detects regions fetching entries sequentially, and keeps entries following
+/// their fetches read ahead from files. Entries are keyed by their positions in
+/// files, which never change, so they can't be stale. An entry is dropped once
+/// it's fetched, as a sequential reader doesn't fetch it again.
+pub(crate) struct Prefetcher {
+    capacity: u64,
+    running: AtomicBool,
+    state: Mutex<PrefetchState>,
+    memory_trace: Arc<MemoryTrace>,
+}
+
+impl Prefetcher {
+    pub fn new(capacity: u64, memory_trace: Arc<MemoryTrace>) -> Prefetcher {
+        Prefetcher {
+            capacity,
+            running: AtomicBool::new(false),
+            state: Mutex::new(PrefetchState::default()),
+            memory_trace,
+        }
+    }
+
+    /// Requests are only recorded once started.
+    pub fn start(&self) {
+        self.running.store(true, Ordering::Release);
+    }
+
+    /// Record a fetch of [begin, end) of the region. If it continues the last one,
+    /// request as many entries following it.
+    pub fn on_fetch(&self, region_id: u64, begin: u64, end: u64) {
+        if !self.running.load(Ordering::Acquire) || end <= begin {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.cursors.insert(region_id, end) == Some(begin)
+            && state.pending.len() < MAX_PENDING_REQUESTS
+        {
+            state.next_seq += 1;
+            let seq = state.next_seq;
+            state.pending.push_back(PrefetchRequest {
+                region_id,
+                begin: end,
+                end: end + (end - begin),
+                seq,
+            });
+        }
+    }
+
+    /// Requests are in flight until they're finished, and entries of them are
+    /// only inserted meanwhile.
+    pub fn take_requests(&self) -> Vec<PrefetchRequest> {
+        let mut state = self.state.lock().unwrap();
+        let requests: Vec<_> = state.pending.drain(..).collect();
+        for req in &requests {
+            state.in_flight.insert(req.seq, req.region_id);
+        }
+        requests
+    }
+
+    pub fn finish(&self, req: &PrefetchRequest) {
+        self.state.lock().unwrap().in_flight.remove(&req.seq);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.state.lock().unwrap().size >= self.capacity
+    }
+
+    /// Keep the encoded entry read for the request, return false if it's full or
+    /// the region is removed since the request is taken.
+    pub fn insert(&self, req: &PrefetchRequest, entry_index: &EntryIndex, entry: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let len = entry.len() as u64;
+        if !state.in_flight.contains_key(&req.seq) || state.size + len > self.capacity {
+            return false;
+        }
+        let old = state
+            .entries
+            .insert(position(entry_index), (req.region_id, entry));
+        if old.is_none() {
+            state.size += len;
+            self.memory_trace
+                .alloc(MemoryComponent::PrefetchBuffer, len);
+        }
+        true
+    }
+
+    pub fn take(&self, entry_index: &EntryIndex) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let (_, entry) = state.entries.remove(&position(entry_index))?;
+        state.size -= entry.len() as u64;
+        self.memory_trace
+            .free(MemoryComponent::PrefetchBuffer, entry.len() as u64);
+        Some(entry)
+    }
+
+    /// Drop entries of purged files.
+    pub fn evict_before(&self, file_num: u64) {
+        let mut state = self.state.lock().unwrap();
+        let mut freed = 0;
+        state.entries.retain(|&(n, _, _), (_, entry)| {
+            if n < file_num {
+                freed += entry.len() as u64;
+                return false;
+            }
+            true
+        });
+        state.size -= freed;
+        self.memory_trace
+            .free(MemoryComponent::PrefetchBuffer, freed);
+    }
+
+    /// Forget the region and drop its entries, including those of requests in
+    /// flight, e.g. when it's cleaned.
+    pub fn remove_region(&self, region_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.cursors.remove(&region_id);
+        state.pending.retain(|req| req.region_id != region_id);
+        state.in_flight.retain(|_, r| *r != region_id);
+        let mut freed = 0;
+        state.entries.retain(|_, (r, entry)| {
+            if *r == region_id {
+                freed += entry.len() as u64;
+                return false;
+            }
+            true
+        });
+        state.size -= freed;
+        self.memory_trace
+            .free(MemoryComponent::PrefetchBuffer, freed);
+    }
+
+    #[cfg(test)]
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+}
+
+fn position(entry_index: &EntryIndex) -> (u64, u64, u64) {
+    (
+        entry_index.file_num,
+        entry_index.base_offset,
+        entry_index.offset,
+    )
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        let size = self.state.get_mut().unwrap().size;
+        self.memory_trace
+            .free(MemoryComponent::PrefetchBuffer, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefetcher() {
+        let trace = Arc::new(MemoryTrace::default());
+        let prefetcher = Prefetcher::new(100, trace.clone());
+        prefetcher.on_fetch(1, 1, 11);
+        prefetcher.on_fetch(1, 11, 21);
+        assert!(prefetcher.take_requests().is_empty());
+
+        prefetcher.start();
+        prefetcher.on_fetch(1, 21, 31);
+        prefetcher.on_fetch(1, 31, 41);
+        // Not sequential.
+        prefetcher.on_fetch(1, 50, 60);
+        prefetcher.on_fetch(2, 1, 5);
+        let requests = prefetcher.take_requests();
+        assert_eq!(
+            requests,
+            vec![PrefetchRequest {
+                region_id: 1,
+                begin: 41,
+                end: 51,
+                seq: 1,
+            }]
+        );
+        let index = |file_num, offset| {
+            let mut idx = EntryIndex::default();
+            idx.file_num = file_num;
+            idx.offset = offset;
+            idx
+        };
+        assert!(prefetcher.insert(&requests[0], &index(1, 0), vec![0; 10]));
+        // Entries of the region are dropped, and those still being read aren't kept.
+        prefetcher.remove_region(1);
+        assert_eq!(prefetcher.size(), 0);
+        assert!(!prefetcher.insert(&requests[0], &index(1, 0), vec![0; 10]));
+        prefetcher.on_fetch(1, 60, 70);
+        assert!(prefetcher.take_requests().is_empty());
+
+        prefetcher.on_fetch(2, 5, 9);
+        let req = prefetcher.take_requests().pop().unwrap();
+        assert!(prefetcher.insert(&req, &index(1, 10), vec![0; 40]));
+        assert!(prefetcher.insert(&req, &index(2, 10), vec![0; 40]));
+        assert!(!prefetcher.insert(&req, &index(2, 50), vec![0; 40]));
+        assert!(!prefetcher.is_full());
+        assert!(prefetcher.insert(&req, &index(2, 50), vec![0; 20]));
+        assert!(prefetcher.is_full());
+        assert_eq!(trace.bytes(MemoryComponent::PrefetchBuffer), 100);
+        prefetcher.finish(&req);
+        assert!(!prefetcher.insert(&req, &index(3, 10), vec![]));
+
+        assert!(prefetcher.take(&index(1, 20)).is_none());
+        assert_eq!(prefetcher.take(&index(1, 10)).unwrap().len(), 40);
+        assert!(prefetcher.take(&index(1, 10)).is_none());
+        prefetcher.evict_before(3);
+        assert_eq!(prefetcher.size(), 0);
+        assert_eq!(trace.bytes(MemoryComponent::PrefetchBuffer), 0);
+    }
+}
@@ -0,0 +1,17 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+/// Receives key/value changes and clean commands of regions as they are applied
+/// while an engine recovers from files, in the order they were written, so that
+/// the host application can rebuild state derived from them without scanning
+/// the engine again. Changes written after the engine is opened aren't reported.
+///
+/// A key may be reported more than once, e.g. when it's rewritten, and a later
+/// put overrides earlier ones.
+pub trait RecoveryObserver: Send + Sync {
+    fn on_put(&self, _region_id: u64, _key: &[u8], _value: &[u8]) {}
+
+    fn on_delete(&self, _region_id: u64, _key: &[u8]) {}
+
+    /// All entries and key/value pairs of the region written before are dropped.
+    fn on_clean(&self, _region_id: u64) {}
+}
@@ -0,0 +1,168 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::dictionary::Dictionaries;
+use crate::engine::FileEngine;
+use crate::log_batch::{Command, LogBatch, LogItemType, OpType};
+use crate::pipe_log::{self, FILE_HEADER_LEN};
+use crate::util::HashMap;
+use crate::{Config, Error, RaftEngine, Result};
+
+/// Where a replay stops.
+#[derive(Clone, Copy, Debug)]
+pub enum ReplayTarget {
+    /// Replay batches before the given position of a log file.
+    Position { file_num: u64, offset: u64 },
+    /// Replay files last modified no later than the given time. Batches don't carry
+    /// timestamps, so this works at file granularity.
+    Time(SystemTime),
+}
+
+/// Replay log files in `source_dirs` (typically a raft log directory plus its
+/// archive) into a fresh engine in `cfg.dir`, stopping at `target`.
+pub fn replay(cfg: Config, source_dirs: &[&str], target: ReplayTarget) -> Result<FileEngine> {
+    let mut files = vec![];
+    let dicts = Dictionaries::default();
+    for dir in source_dirs {
+        files.extend(pipe_log::list_log_files(Path::new(dir))?);
+        dicts.load_dir(Path::new(dir))?;
+    }
+    files.sort();
+    files.dedup_by_key(|f| f.0);
+    for w in files.windows(2) {
+        if w[0].0 + 1 != w[1].0 {
+            return Err(box_err!("Raft log file {} is missing", w[0].0 + 1));
+        }
+    }
+
+    let dest = Path::new(&cfg.dir);
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(box_err!("Replay target directory {} is not empty", cfg.dir));
+    }
+    let engine = FileEngine::new(cfg);
+
+    let header_len = FILE_HEADER_LEN;
+    // Sequences of the latest clean command of regions in the source files.
+    let mut last_cleans = HashMap::default();
+    'files: for (file_num, path) in files {
+        match target {
+            ReplayTarget::Position { file_num: n, .. } if file_num > n => break,
+            ReplayTarget::Time(t) if fs::metadata(&path)?.modified()? > t => break,
+            _ => {}
+        }
+        let content = fs::read(&path)?;
+        pipe_log::check_file_header(file_num, &content)?;
+
+        let mut buf = &content[header_len..];
+        let mut offset = header_len as u64;
+        loop {
+            if let ReplayTarget::Position {
+                file_num: n,
+                offset: o,
+            } = target
+            {
+                if file_num == n && offset >= o {
+                    break 'files;
+                }
+            }
+            match LogBatch::from_bytes(&mut buf, file_num, offset, &dicts) {
+                Ok(Some(batch)) => {
+                    engine.consume(&mut rebuild_batch(batch, &mut last_cleans), false)?;
+                    offset = (content.len() - buf.len()) as u64;
+                }
+                Ok(None) => break,
+                Err(e) => return Err(Error::Corruption(file_num, offset, e.to_string())),
+            }
+        }
+    }
+    engine.sync()?;
+    Ok(engine)
+}
+
+// A decoded batch carries entry indexes pointing to its source file, so build a new
+// one to write it again. Sequences differ in the new files, so stale rewritten items
+// are dropped here instead of fenced again.
+fn rebuild_batch(batch: LogBatch, last_cleans: &mut HashMap<u64, u64>) -> LogBatch {
+    let stale = batch.stale_regions(|region_id| last_cleans.get(&region_id).copied().unwrap_or(0));
+    let new_batch = LogBatch::with_capacity(batch.items.borrow().len());
+    for item in batch.items.borrow_mut().drain(..) {
+        match item.item_type {
+            LogItemType::Entries => {
+                let entries = item.entries.unwrap();
+                if stale.contains(&entries.region_id) {
+                    continue;
+                }
+                new_batch.add_entries(entries.region_id, entries.entries);
+            }
+            LogItemType::CMD => match item.command.unwrap() {
+                Command::Clean { region_id } => {
+                    last_cleans.insert(region_id, batch.sequence);
+                    new_batch.clean_region(region_id);
+                }
+                Command::Fence { .. } => {}
+            },
+            LogItemType::KV => {
+                let kv = item.kv.unwrap();
+                if stale.contains(&kv.region_id) {
+                    continue;
+                }
+                match kv.op_type {
+                    OpType::Put => new_batch.put(kv.region_id, &kv.key, &kv.value.unwrap()),
+                    OpType::Del => new_batch.delete(kv.region_id, &kv.key),
+                }
+            }
+        }
+    }
+    new_batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use raft::eraftpb::Entry;
+
+    #[test]
+    fn test_replay_to_position() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_replay")
+            .tempdir()
+            .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().join("raft").to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg.clone());
+        let mut entry = Entry::new();
+        for i in 1..=10 {
+            entry.set_index(i);
+            engine.append(1, vec![entry.clone()]).unwrap();
+        }
+        drop(engine);
+
+        // Find where the 6th batch starts.
+        let content = fs::read(Path::new(&cfg.dir).join("0000000000000001.raftlog")).unwrap();
+        let mut buf = &content[FILE_HEADER_LEN..];
+        for _ in 0..5 {
+            LogBatch::from_bytes(&mut buf, 1, 0, &Dictionaries::default())
+                .unwrap()
+                .unwrap();
+        }
+        let offset = (content.len() - buf.len()) as u64;
+
+        let mut replay_cfg = cfg.clone();
+        replay_cfg.dir = dir.path().join("replay").to_str().unwrap().to_owned();
+        let target = ReplayTarget::Position {
+            file_num: 1,
+            offset,
+        };
+        let replayed = replay(replay_cfg.clone(), &[&cfg.dir], target).unwrap();
+        assert!(replayed.get_entry(1, 5).unwrap().is_some());
+        assert!(replayed.get_entry(1, 6).unwrap().is_none());
+
+        // The target must be fresh.
+        assert!(replay(replay_cfg, &[&cfg.dir], target).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+use kvproto::raft_serverpb::RaftLocalState;
+use protobuf::Message;
+use raft::eraftpb::Entry;
+use rocksdb::{Direction, IteratorMode, Options, DB};
+
+use crate::engine::FileEngine;
+use crate::log_batch::LogBatch;
+use crate::util::RAFT_LOG_STATE_KEY;
+use crate::{RaftEngine, Result};
+
+// Raft keys of TiKV: LOCAL_PREFIX REGION_RAFT_PREFIX region_id suffix [index].
+const LOCAL_PREFIX: u8 = 0x01;
+const REGION_RAFT_PREFIX: u8 = 0x02;
+const RAFT_LOG_SUFFIX: u8 = 0x01;
+const RAFT_STATE_SUFFIX: u8 = 0x02;
+const RAFT_PREFIX_LEN: usize = 2 + 8 + 1;
+
+const ENTRIES_PER_BATCH: usize = 256;
+
+enum RaftKey {
+    Entry(u64),
+    State(u64),
+}
+
+fn decode_raft_key(key: &[u8]) -> Option<RaftKey> {
+    if key.len() < RAFT_PREFIX_LEN || key[0] != LOCAL_PREFIX || key[1] != REGION_RAFT_PREFIX {
+        return None;
+    }
+    let region_id = BigEndian::read_u64(&key[2..10]);
+    match key[10] {
+        RAFT_LOG_SUFFIX if key.len() == RAFT_PREFIX_LEN + 8 => Some(RaftKey::Entry(region_id)),
+        RAFT_STATE_SUFFIX if key.len() == RAFT_PREFIX_LEN => Some(RaftKey::State(region_id)),
+        _ => None,
+    }
+}
+
+impl FileEngine {
+    /// Import raft entries and states from the raft RocksDB of TiKV at `path`, which
+    /// is opened read-only. Other keys are ignored. Return the count of imported entries.
+    pub fn import_from_rocksdb<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let db = match DB::open_for_read_only(&Options::default(), path, false) {
+            Ok(db) => db,
+            Err(e) => return Err(box_err!("Open RocksDB failed: {}", e)),
+        };
+
+        let mut batch = LogBatch::new();
+        let mut entries = vec![];
+        let mut entries_region = 0;
+        let mut imported = 0;
+        let start = [LOCAL_PREFIX, REGION_RAFT_PREFIX];
+        for (key, value) in db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            if !key.starts_with(&start) {
+                break;
+            }
+            let region_id = match decode_raft_key(&key) {
+                Some(RaftKey::Entry(region_id)) => {
+                    let mut entry = Entry::new();
+                    entry.merge_from_bytes(&value)?;
+                    if entries_region != region_id || entries.len() >= ENTRIES_PER_BATCH {
+                        if !entries.is_empty() {
+                            imported += entries.len();
+                            batch.add_entries(entries_region, std::mem::take(&mut entries));
+                            self.consume(&mut batch, false)?;
+                        }
+                        entries_region = region_id;
+                    }
+                    entries.push(entry);
+                    continue;
+                }
+                Some(RaftKey::State(region_id)) => region_id,
+                None => continue,
+            };
+            let mut state = RaftLocalState::new();
+            state.merge_from_bytes(&value)?;
+            batch.put_msg(region_id, RAFT_LOG_STATE_KEY, &state)?;
+        }
+        if !entries.is_empty() {
+            imported += entries.len();
+            batch.add_entries(entries_region, entries);
+        }
+        self.consume(&mut batch, true)?;
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Config;
+
+    fn raft_key(region_id: u64, suffix: u8, index: Option<u64>) -> Vec<u8> {
+        let mut key = vec![LOCAL_PREFIX, REGION_RAFT_PREFIX];
+        key.extend_from_slice(&region_id.to_be_bytes());
+        key.push(suffix);
+        if let Some(index) = index {
+            key.extend_from_slice(&index.to_be_bytes());
+        }
+        key
+    }
+
+    #[test]
+    fn test_import_from_rocksdb() {
+        let dir = tempfile::Builder::new()
+            .prefix("test_import_from_rocksdb")
+            .tempdir()
+            .unwrap();
+        let db_path = dir.path().join("raftdb");
+        {
+            let db = DB::open_default(&db_path).unwrap();
+            for region_id in 1..=2 {
+                for i in 1..=300 {
+                    let mut e = Entry::new();
+                    e.set_index(i);
+                    e.set_term(region_id);
+                    let key = raft_key(region_id, RAFT_LOG_SUFFIX, Some(i));
+                    db.put(&key, e.write_to_bytes().unwrap()).unwrap();
+                }
+                let mut state = RaftLocalState::new();
+                state.set_last_index(300);
+                let key = raft_key(region_id, RAFT_STATE_SUFFIX, None);
+                db.put(&key, state.write_to_bytes().unwrap()).unwrap();
+            }
+            // Not a raft key.
+            db.put([LOCAL_PREFIX, 0x03, 1], b"v").unwrap();
+        }
+
+        let mut cfg = Config::default();
+        cfg.dir = dir.path().join("raft").to_str().unwrap().to_owned();
+        let engine = FileEngine::new(cfg);
+        assert_eq!(engine.import_from_rocksdb(&db_path).unwrap(), 600);
+        for region_id in 1..=2 {
+            let e = engine.get_entry(region_id, 300).unwrap().unwrap();
+            assert_eq!(e.get_term(), region_id);
+            let state = engine.get_raft_state(region_id).unwrap().unwrap();
+            assert_eq!(state.get_last_index(), 300);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::collections::{HashMap as StdHashMap, HashSet as StdHashSet, VecDeque};
+use std::convert::TryFrom;
 use std::fmt::{self, Write};
 use std::hash::BuildHasherDefault;
 use std::ops::{Div, Mul};
@@ -182,6 +183,158 @@ impl<'de> Deserialize<'de> for ReadableSize {
     }
 }
 
+const TIME_MAGNITUDE_1: u64 = 1000;
+const TIME_MAGNITUDE_2: u64 = 60;
+const TIME_MAGNITUDE_3: u64 = 24;
+const MS: u64 = UNIT;
+const SECOND: u64 = MS * TIME_MAGNITUDE_1;
+const MINUTE: u64 = SECOND * TIME_MAGNITUDE_2;
+const HOUR: u64 = MINUTE * TIME_MAGNITUDE_2;
+const DAY: u64 = HOUR * TIME_MAGNITUDE_3;
+const MICROS_PER_MS: u64 = 1000;
+
+#[derive(Clone, Debug, Copy, PartialEq, Default)]
+pub struct ReadableDuration(pub Duration);
+
+impl ReadableDuration {
+    pub const fn micros(micros: u64) -> ReadableDuration {
+        ReadableDuration(Duration::from_micros(micros))
+    }
+
+    pub const fn millis(millis: u64) -> ReadableDuration {
+        ReadableDuration(Duration::from_millis(millis))
+    }
+
+    pub const fn secs(secs: u64) -> ReadableDuration {
+        ReadableDuration(Duration::from_secs(secs))
+    }
+
+    pub const fn minutes(minutes: u64) -> ReadableDuration {
+        ReadableDuration::secs(minutes * 60)
+    }
+
+    pub const fn hours(hours: u64) -> ReadableDuration {
+        ReadableDuration::minutes(hours * 60)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0.as_secs() * SECOND + u64::from(self.0.subsec_millis())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == Duration::from_secs(0)
+    }
+}
+
+impl fmt::Display for ReadableDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut dur = self.as_millis();
+        let micros = self.0.subsec_micros() as u64 % MICROS_PER_MS;
+        let mut written = false;
+        for &(unit, name) in &[(DAY, "d"), (HOUR, "h"), (MINUTE, "m"), (SECOND, "s")] {
+            if dur >= unit {
+                write!(f, "{}{}", dur / unit, name)?;
+                dur %= unit;
+                written = true;
+            }
+        }
+        if dur > 0 || (!written && micros == 0) {
+            write!(f, "{}ms", dur)?;
+        }
+        if micros > 0 {
+            write!(f, "{}us", micros)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ReadableDuration {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl FromStr for ReadableDuration {
+    type Err = String;
+
+    fn from_str(dur_str: &str) -> Result<ReadableDuration, String> {
+        let dur_str = dur_str.trim();
+        if !dur_str.is_ascii() {
+            return Err(format!("unexpect ascii string: {}", dur_str));
+        }
+        let err_msg = "valid duration, only d, h, m, s, ms, us are supported.".to_owned();
+        let mut left = dur_str.as_bytes();
+        // Units in microseconds.
+        let mut last_unit = DAY * MICROS_PER_MS + 1;
+        let mut dur = 0f64;
+        while let Some(idx) = left.iter().position(|c| b"dhmsu".contains(c)) {
+            let (first, second) = left.split_at(idx);
+            let unit = if second.starts_with(b"ms") {
+                left = &left[idx + 2..];
+                MS * MICROS_PER_MS
+            } else if second.starts_with(b"us") {
+                left = &left[idx + 2..];
+                1
+            } else {
+                let u = match second[0] {
+                    b'd' => DAY,
+                    b'h' => HOUR,
+                    b'm' => MINUTE,
+                    b's' => SECOND,
+                    _ => return Err(err_msg),
+                };
+                left = &left[idx + 1..];
+                u * MICROS_PER_MS
+            };
+            if unit >= last_unit {
+                return Err("d, h, m, s, ms, us should occur in given order.".to_owned());
+            }
+            let number_str = std::str::from_utf8(first).unwrap();
+            dur += match number_str.trim().parse::<f64>() {
+                Ok(n) => n * unit as f64,
+                Err(_) => return Err(err_msg),
+            };
+            last_unit = unit;
+        }
+        if !left.is_empty() {
+            return Err(err_msg);
+        }
+        if dur.is_sign_negative() {
+            return Err("duration should be positive.".to_owned());
+        }
+        Ok(ReadableDuration::micros(dur as u64))
+    }
+}
+
+impl<'de> Deserialize<'de> for ReadableDuration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DurVisitor;
+
+        impl<'de> Visitor<'de> for DurVisitor {
+            type Value = ReadableDuration;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("valid duration")
+            }
+
+            fn visit_str<E>(self, dur_str: &str) -> Result<ReadableDuration, E>
+            where
+                E: de::Error,
+            {
+                dur_str.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(DurVisitor)
+    }
+}
+
 /// Take slices in the range.
 ///
 /// ### Panic
@@ -205,4 +358,46 @@ pub fn duration_to_sec(d: Duration) -> f64 {
     d.as_secs() as f64 + (nanos / 1_000_000_000.0)
 }
 
+/// Converts an on-disk length or offset to `usize`, failing instead of truncating
+/// on targets where `usize` is narrower than 64 bits.
+pub fn to_usize(v: u64) -> crate::Result<usize> {
+    usize::try_from(v).map_err(|_| box_err!("{} overflows usize on this platform", v))
+}
+
 pub(crate) const RAFT_LOG_STATE_KEY: &[u8] = b"R";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readable_duration() {
+        let cases = vec![
+            ("0ms", 0),
+            ("100ms", 100),
+            ("1s", SECOND),
+            ("1m30s", MINUTE + 30 * SECOND),
+            ("2h", 2 * HOUR),
+            ("1d1h1m1s1ms", DAY + HOUR + MINUTE + SECOND + 1),
+        ];
+        for (s, millis) in cases {
+            let d: ReadableDuration = s.parse().unwrap();
+            assert_eq!(d.as_millis(), millis, "{}", s);
+            assert_eq!(d.to_string(), s);
+        }
+        for (s, micros) in &[("200us", 200), ("1s1ms1us", 1_001_001)] {
+            let d: ReadableDuration = s.parse().unwrap();
+            assert_eq!(d, ReadableDuration::micros(*micros), "{}", s);
+            assert_eq!(d.to_string(), *s);
+        }
+        assert_eq!(
+            "1.5s".parse::<ReadableDuration>().unwrap().as_millis(),
+            1500
+        );
+
+        let illegal_cases = vec!["1", "1x", "1s1m", "1s1s", "s", "-1s", "1us1ms", "1u"];
+        for s in illegal_cases {
+            assert!(s.parse::<ReadableDuration>().is_err(), "{}", s);
+        }
+    }
+}
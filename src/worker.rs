@@ -0,0 +1,198 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::any::Any;
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Ticks skipped after consecutive panics of a task are doubled up to it.
+const MAX_BACKOFF_TICKS: u32 = 64;
+
+/// Handle of a periodic task, which is stopped when the handle is dropped. The
+/// task runs in a background thread, or is driven by a `Clock` which cancels it
+/// by a callback.
+pub struct Worker {
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    cancel: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Worker {
+    /// Spawn a thread named `name` calling `tick` every `interval` until stopped.
+    pub fn spawn<F>(name: &str, interval: Duration, mut tick: F) -> Worker
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                    tick();
+                }
+            })
+            .unwrap_or_else(|e| panic!("Spawn thread {} failed, err {:?}", name, e));
+        Worker {
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+            cancel: None,
+        }
+    }
+
+    /// A handle of a task run by someone else, calling `cancel` when stopped.
+    pub fn with_cancel<F>(cancel: F) -> Worker
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Worker {
+            stop_tx: None,
+            handle: None,
+            cancel: Some(Box::new(cancel)),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel();
+        }
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+        if let Some(handle) = self.handle.take() {
+            let name = handle.thread().name().unwrap_or_default().to_owned();
+            if let Err(e) = handle.join() {
+                error!("Background thread {} panicked: {:?}", name, e);
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Health of a periodic task run by a `Watchdog`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaskHealth {
+    pub name: String,
+    /// Ticks of the task that panicked.
+    pub panics: u64,
+    pub last_panic: Option<String>,
+    /// Ticks to skip after panics before the task runs again.
+    pub backoff_ticks: u32,
+}
+
+/// Runs the ticks of a periodic task, catching their panics instead of letting
+/// them end the task for good, e.g. leaving files unpurged until the disk is
+/// full. After a panic, ticks are skipped for a while, doubled for each panic in
+/// a row, and the task runs normally again once a tick finishes.
+pub struct Watchdog<F> {
+    tick: F,
+    health: Arc<Mutex<TaskHealth>>,
+    // Ticks to skip after the next panic.
+    backoff: u32,
+}
+
+impl<F: FnMut()> Watchdog<F> {
+    pub fn new(tick: F, health: Arc<Mutex<TaskHealth>>) -> Watchdog<F> {
+        Watchdog {
+            tick,
+            health,
+            backoff: 1,
+        }
+    }
+
+    /// Run the task unless it's backing off, returning the message of its panic
+    /// if it panics.
+    pub fn tick(&mut self) -> Option<String> {
+        {
+            let mut health = self.health.lock().unwrap();
+            if health.backoff_ticks > 0 {
+                health.backoff_ticks -= 1;
+                return None;
+            }
+        }
+        // The task is run again after panics, so it's expected to be left in a
+        // usable state by them.
+        let res = panic::catch_unwind(AssertUnwindSafe(&mut self.tick));
+        let mut health = self.health.lock().unwrap();
+        match res {
+            Ok(()) => {
+                self.backoff = 1;
+                None
+            }
+            Err(payload) => {
+                let msg = panic_message(payload.as_ref());
+                health.panics += 1;
+                health.last_panic = Some(msg.clone());
+                health.backoff_ticks = self.backoff;
+                self.backoff = cmp::min(self.backoff * 2, MAX_BACKOFF_TICKS);
+                Some(msg)
+            }
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        (*msg).to_owned()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let health = Arc::new(Mutex::new(TaskHealth::default()));
+        // (runs, whether to panic)
+        let state = Arc::new(Mutex::new((0, true)));
+        let s = state.clone();
+        let mut watchdog = Watchdog::new(
+            move || {
+                let fail = {
+                    let mut state = s.lock().unwrap();
+                    state.0 += 1;
+                    state.1
+                };
+                if fail {
+                    panic!("tick {}", "failed");
+                }
+            },
+            health.clone(),
+        );
+
+        // 1, 2 and 4 ticks are skipped after panics in a row.
+        let panicked: Vec<_> = (0..6).filter(|_| watchdog.tick().is_some()).collect();
+        assert_eq!(panicked, vec![0, 2, 5]);
+        assert_eq!(state.lock().unwrap().0, 3);
+        {
+            let health = health.lock().unwrap();
+            assert_eq!(health.panics, 3);
+            assert_eq!(health.last_panic.as_deref(), Some("tick failed"));
+            assert_eq!(health.backoff_ticks, 4);
+        }
+
+        // Runs normally again once a tick finishes.
+        state.lock().unwrap().1 = false;
+        for _ in 0..5 {
+            assert!(watchdog.tick().is_none());
+        }
+        assert_eq!(state.lock().unwrap().0, 4);
+        assert_eq!(health.lock().unwrap().backoff_ticks, 0);
+        state.lock().unwrap().1 = true;
+        assert!(watchdog.tick().is_some());
+        assert_eq!(health.lock().unwrap().backoff_ticks, 1);
+        assert_eq!(health.lock().unwrap().panics, 4);
+    }
+}